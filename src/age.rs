@@ -0,0 +1,90 @@
+//! age-encrypted config, through the `age` command, so that backups of /etc don't leak the SMTP
+//! passwords.
+//!
+//! Either the whole config file is encrypted, or just the `smtp_password` values are ASCII-armored
+//! age messages. Both are decrypted with the identity in a root-only file.
+
+use std::io::{self, Write};
+use std::os::unix::fs::PermissionsExt;
+use std::path::Path;
+use std::process::Stdio;
+
+use zeroize::Zeroizing;
+
+pub const IDENTITY_DEFAULT: &str = "/etc/forward-as-attachment-mta.age-identity";
+
+const ARMOR_BEGIN: &str = "-----BEGIN AGE ENCRYPTED FILE-----";
+
+/// Whether `data` is an age message, binary or ASCII-armored.
+pub fn is_encrypted(data: &[u8]) -> bool {
+    let data = data.trim_ascii_start();
+    data.starts_with(b"age-encryption.org/") || data.starts_with(ARMOR_BEGIN.as_bytes())
+}
+
+/// `data` decrypted with the identity in `identity_file`, which only its owner may read.
+pub fn decrypt(identity_file: &Path, data: &[u8]) -> io::Result<Vec<u8>> {
+    let mode = std::fs::metadata(identity_file)?.permissions().mode();
+    if mode & 0o077 != 0 {
+        return Err(io::Error::other(format!(
+            "the identity file {identity_file:?} is accessible by others (mode {:o})",
+            mode & 0o777
+        )));
+    }
+    let mut child = crate::hardening::command("age")
+        .arg("--decrypt")
+        .arg("--identity")
+        .arg(identity_file)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()?;
+    let mut stdin = child.stdin.take().expect("we requested a pipe");
+    let input = data.to_vec();
+    // Written concurrently, age blocks once the output pipe is full.
+    let writer = std::thread::spawn(move || stdin.write_all(&input));
+    let output = child.wait_with_output()?;
+    writer.join().expect("writer doesn't panic")?;
+    if !output.status.success() {
+        return Err(io::Error::other(format!(
+            "age exited with {}: {}",
+            output.status,
+            String::from_utf8_lossy(&output.stderr).trim()
+        )));
+    }
+    Ok(output.stdout)
+}
+
+/// `value` decrypted if it is an armored age message, else as it is.
+pub fn secret(identity_file: &Path, value: &mut String) -> io::Result<()> {
+    if value.trim_start().starts_with(ARMOR_BEGIN) {
+        let decrypted = Zeroizing::new(decrypt(identity_file, value.as_bytes())?);
+        let decrypted = std::str::from_utf8(&decrypted)
+            .map_err(|_| io::Error::other("the decrypted secret isn't UTF-8"))?;
+        *value = decrypted.trim_end_matches(['\r', '\n']).to_owned();
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_identity_permissions() {
+        assert!(is_encrypted(b"age-encryption.org/v1\n-> X25519 ..."));
+        assert!(is_encrypted(
+            b"\n-----BEGIN AGE ENCRYPTED FILE-----\nYWdl\n-----END AGE ENCRYPTED FILE-----\n"
+        ));
+        assert!(!is_encrypted(b"sender_email = \"a@example.com\"\n"));
+
+        let dir = crate::testdir::TestDir::new("age");
+        let identity = dir.join("identity");
+        std::fs::write(&identity, "AGE-SECRET-KEY-1...\n").unwrap();
+        std::fs::set_permissions(&identity, std::fs::Permissions::from_mode(0o644)).unwrap();
+        let e = decrypt(&identity, b"age-encryption.org/v1\n").unwrap_err();
+        assert!(e.to_string().contains("accessible by others"), "{e}");
+        let mut plain = "pass".to_owned();
+        secret(&identity, &mut plain).unwrap();
+        assert_eq!(plain, "pass");
+    }
+}
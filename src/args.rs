@@ -0,0 +1,240 @@
+use std::ffi::{OsStr, OsString};
+use std::os::unix::ffi::OsStrExt;
+use std::path::PathBuf;
+
+pub enum Args {
+    AllUtf8(Vec<String>),
+    Lossy(Vec<String>),
+}
+
+/// sendmail options that take a value, which may be given either attached (`-fuser`)
+/// or as the next argument (`-f user`).
+const OPTIONS_WITH_VALUE: &[char] = &[
+    'B', 'C', 'F', 'L', 'N', 'O', 'R', 'V', 'X', 'f', 'h', 'p', 'r',
+];
+
+impl Args {
+    pub fn from_env() -> Self {
+        let os = split_inputs(std::env::args_os().collect()).0;
+        let maybe_all_utf8: Result<Vec<String>, ()> = os
+            .iter()
+            .map(|os_str| os_str.to_str().ok_or(()).map(|s| s.to_owned()))
+            .collect(); // cancels iteration early on first err
+        match maybe_all_utf8 {
+            Ok(all_utf8) => Args::AllUtf8(all_utf8),
+            Err(_) => Args::Lossy(
+                os.into_iter()
+                    .map(|os_str| os_str.to_string_lossy().to_string())
+                    .collect(),
+            ),
+        }
+    }
+
+    /// The envelope-from given via `-f`, if given exactly once.
+    pub fn envelope_from(&self) -> Option<String> {
+        match self {
+            Args::AllUtf8(ref args) => {
+                let mut first = None;
+                for arg in args {
+                    // sendmail uses -f to denominate envelope-from
+                    let Some(from) = arg.strip_prefix("-f") else {
+                        continue;
+                    };
+                    if first.is_some() {
+                        first = None; // duplicate from, no idea how to handle that
+                        break;
+                    }
+                    first = Some(from.to_owned());
+                }
+                first
+            }
+            Args::Lossy(_) => None,
+        }
+    }
+
+    /// All arguments including `argv[0]`, lossily converted if not valid UTF-8.
+    pub fn as_slice(&self) -> &[String] {
+        match self {
+            Args::AllUtf8(args) | Args::Lossy(args) => args,
+        }
+    }
+
+    /// Whether the recipients are to be read from the original's `To`, `Cc`, and `Bcc` (`-t`).
+    pub fn recipients_from_headers(&self) -> bool {
+        match self {
+            Args::AllUtf8(args) => has_flag(args.get(1..).unwrap_or_default(), 't'),
+            Args::Lossy(_) => false,
+        }
+    }
+
+    /// Whether to report success on stdout (`-v`), as for interactive runs.
+    pub fn verbose(&self) -> bool {
+        match self {
+            Args::AllUtf8(args) => has_flag(args.get(1..).unwrap_or_default(), 'v'),
+            Args::Lossy(_) => false,
+        }
+    }
+
+    /// The recipients given as positional arguments (cron passes `MAILTO` this way).
+    pub fn recipients(&self) -> Vec<String> {
+        match self {
+            Args::AllUtf8(args) => positional_recipients(args.get(1..).unwrap_or_default()),
+            Args::Lossy(_) => vec![],
+        }
+    }
+}
+
+/// Separate the files given with `--input <path>` or `--input=<path>`, to read originals from
+/// instead of stdin, from the sendmail arguments; they are not part of [`Args`].
+pub fn split_inputs(args: Vec<OsString>) -> (Vec<OsString>, Vec<PathBuf>) {
+    let mut rest = Vec::new();
+    let mut inputs = Vec::new();
+    let mut iter = args.into_iter();
+    while let Some(arg) = iter.next() {
+        if arg == "--" {
+            rest.push(arg);
+            rest.extend(iter);
+            break;
+        }
+        let bytes = arg.as_bytes();
+        if arg == "--input" {
+            inputs.extend(iter.next().map(PathBuf::from));
+        } else if let Some(path) = bytes.strip_prefix(b"--input=") {
+            inputs.push(PathBuf::from(OsStr::from_bytes(path)));
+        } else {
+            rest.push(arg);
+        }
+    }
+    (rest, inputs)
+}
+
+/// The options (without the leading `-`, with their values if attached) and the operands.
+fn split(args: &[String]) -> (Vec<&str>, Vec<&str>) {
+    let mut options = Vec::new();
+    let mut operands = Vec::new();
+    let mut iter = args.iter();
+    let mut options_done = false;
+    while let Some(arg) = iter.next() {
+        if !options_done {
+            if arg == "--" {
+                options_done = true;
+                continue;
+            }
+            if let Some(opt) = arg.strip_prefix('-') {
+                let mut chars = opt.chars();
+                if let Some(c) = chars.next() {
+                    if OPTIONS_WITH_VALUE.contains(&c) && chars.as_str().is_empty() {
+                        iter.next(); // value is the next argument
+                    }
+                }
+                options.push(opt);
+                continue;
+            }
+        }
+        operands.push(arg.as_str());
+    }
+    (options, operands)
+}
+
+fn positional_recipients(args: &[String]) -> Vec<String> {
+    // sendmail also accepts comma-separated recipient lists
+    split(args)
+        .1
+        .into_iter()
+        .flat_map(|arg| arg.split(','))
+        .map(str::trim)
+        .filter(|s| !s.is_empty())
+        .map(|s| s.to_owned())
+        .collect()
+}
+
+/// Whether the flag `-<flag>` is given, also combined with other flags like `-t` in `-ti`.
+fn has_flag(args: &[String], flag: char) -> bool {
+    split(args).0.into_iter().any(|opt| {
+        let first = opt.chars().next();
+        !first.is_some_and(|c| OPTIONS_WITH_VALUE.contains(&c) || c == 'o') && opt.contains(flag)
+    })
+}
+
+impl std::fmt::Display for Args {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let (prefix, args) = match self {
+            Args::AllUtf8(args) => ("", args),
+            Args::Lossy(args) => ("(non-utf-8): ", args),
+        };
+        write!(f, "{prefix}: {args:?}",)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_positional_recipients() {
+        let f = |args: &[&str]| {
+            positional_recipients(&args.iter().map(|s| s.to_string()).collect::<Vec<_>>())
+        };
+        assert_eq!(
+            f(&[
+                "-FCronDaemon",
+                "-i",
+                "-B8BITMIME",
+                "-oem",
+                "admin@example.com"
+            ]),
+            vec!["admin@example.com"]
+        );
+        assert_eq!(
+            f(&[
+                "-f",
+                "root",
+                "-F",
+                "Cron Daemon",
+                "a@example.com, b@example.com"
+            ]),
+            vec!["a@example.com", "b@example.com"]
+        );
+        assert_eq!(
+            f(&["-oi", "--", "-weird@example.com"]),
+            vec!["-weird@example.com"]
+        );
+        assert!(f(&["-t", "-i"]).is_empty());
+        let flag = |args: &[&str], flag| {
+            has_flag(
+                &args.iter().map(|s| s.to_string()).collect::<Vec<_>>(),
+                flag,
+            )
+        };
+        assert!(flag(&["-oi", "-t"], 't'));
+        assert!(flag(&["-ti"], 't'));
+        assert!(!flag(&["-oi", "-ftom", "-F", "t", "--", "-t"], 't'));
+        assert!(flag(&["-v", "root"], 'v'));
+        assert!(!flag(&["-oi", "-Vv", "root"], 'v'));
+    }
+
+    #[test]
+    fn test_split_inputs() {
+        let f = |args: &[&str]| split_inputs(args.iter().map(OsString::from).collect());
+        assert_eq!(
+            f(&[
+                "sendmail",
+                "--input",
+                "a.eml",
+                "-t",
+                "--input=b.eml",
+                "--",
+                "--input"
+            ]),
+            (
+                vec![
+                    "sendmail".into(),
+                    "-t".into(),
+                    "--".into(),
+                    "--input".into()
+                ],
+                vec![PathBuf::from("a.eml"), PathBuf::from("b.eml")]
+            )
+        );
+    }
+}
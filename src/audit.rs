@@ -0,0 +1,104 @@
+//! The audit log, with `audit_log`: one JSON object per submission, whatever became of it, for
+//! compliance and troubleshooting. It's independent of the logs, and rotated like `log_file`.
+//!
+//! What's known about the submission is noted as it is found out, and the record is written
+//! when the outcome is decided, see [`exit`].
+
+use std::sync::Mutex;
+use std::time::SystemTime;
+
+use crate::config::Config;
+use crate::json::Value;
+
+#[derive(Debug, Default)]
+pub struct Record {
+    pub queue_id: String,
+    pub uid: u32,
+    pub user: Option<String>,
+    /// Of the arguments, separated by NULs, to tell invocations apart without logging them.
+    pub argv_sha256: String,
+    pub original_sender: Option<String>,
+    pub subject: Option<String>,
+    /// Of the original.
+    pub size: Option<u64>,
+}
+
+static RECORD: Mutex<Option<Record>> = Mutex::new(None);
+
+pub fn begin(record: Record) {
+    *RECORD.lock().unwrap() = Some(record);
+}
+
+/// Add to the record begun with [`begin`].
+pub fn note(f: impl FnOnce(&mut Record)) {
+    if let Some(record) = RECORD.lock().unwrap().as_mut() {
+        f(record);
+    }
+}
+
+/// Write the record with `outcome`, e.g. `delivered` or `quiet-hours`, and the `transport` the
+/// submission went to, e.g. the relay's host name or `spool`.
+pub fn finish(config: &Config, outcome: &str, transport: Option<&str>) {
+    let Some(path) = &config.audit_log else {
+        return;
+    };
+    let Some(record) = RECORD.lock().unwrap().take() else {
+        return;
+    };
+    let line = format!("{}\n", json(&record, SystemTime::now(), outcome, transport));
+    let rotation = crate::logfile::Rotation {
+        path: path.clone(),
+        max_size: config.audit_log_max_kib as u64 * 1024,
+        keep: config.audit_log_keep,
+    };
+    if let Err(e) = rotation.append(line.as_bytes()) {
+        tracing::warn!(%e, ?path, "can't write to the audit log");
+    }
+}
+
+/// [`finish`], then exit with `code`.
+pub fn exit(config: &Config, outcome: &str, transport: Option<&str>, code: i32) -> ! {
+    finish(config, outcome, transport);
+    std::process::exit(code)
+}
+
+fn json(record: &Record, at: SystemTime, outcome: &str, transport: Option<&str>) -> Value {
+    Value::object([
+        ("timestamp", Value::from(crate::time::format_utc(at))),
+        ("queue_id", record.queue_id.as_str().into()),
+        ("uid", record.uid.into()),
+        ("user", record.user.clone().into()),
+        ("argv_sha256", record.argv_sha256.as_str().into()),
+        ("original_sender", record.original_sender.clone().into()),
+        ("subject", record.subject.clone().into()),
+        ("size", record.size.into()),
+        ("outcome", outcome.into()),
+        ("transport", transport.into()),
+    ])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_json() {
+        let record = Record {
+            queue_id: "65A0B1C2D3E4F".to_owned(),
+            uid: 1000,
+            user: Some("alice".to_owned()),
+            argv_sha256: "ab".repeat(32),
+            original_sender: None,
+            subject: Some("Cron <alice@host> backup".to_owned()),
+            size: Some(3594),
+        };
+        let at = SystemTime::UNIX_EPOCH + std::time::Duration::from_secs(1_700_000_000);
+        assert_eq!(
+            json(&record, at, "delivered", Some("smtp.example.com")).to_string(),
+            format!(
+                r#"{{"timestamp":"2023-11-14T22:13:20Z","queue_id":"65A0B1C2D3E4F","uid":1000,"user":"alice","argv_sha256":"{}","original_sender":null,"subject":"Cron <alice@host> backup","size":3594,"outcome":"delivered","transport":"smtp.example.com"}}"#,
+                "ab".repeat(32)
+            )
+        );
+    }
+}
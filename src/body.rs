@@ -0,0 +1,142 @@
+//! The human-readable text part of the wrapper.
+//!
+//! It is rendered from a template (by default the one of the configured language,
+//! see `i18n`) that can be overridden in the config.
+//! The template uses the `%name%` placeholders of the subject rather than a template engine
+//! like minijinja, which isn't a dependency of this setuid binary: there are no conditionals
+//! or loops, only variables.
+//! Variables ending in a block (e.g. `%config_warnings%`) are either empty or consist of
+//! complete lines, so they go at the start of a line without a line break of their own.
+
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::OnceLock;
+
+use regex::Regex;
+
+use crate::template::{self, Vars};
+
+/// Variables that are only available in the body template, in addition to [`Vars`].
+#[derive(Default)]
+pub struct BodyVars(BTreeMap<&'static str, String>);
+
+impl BodyVars {
+    pub fn set(&mut self, name: &'static str, value: impl Into<String>) {
+        self.0.insert(name, value.into());
+    }
+}
+
+pub fn render(template: &str, vars: &Vars, body_vars: &BodyVars) -> String {
+    template::expand(template, |name| {
+        body_vars.0.get(name).cloned().or_else(|| vars.lookup(name))
+    })
+}
+
+/// A simple HTML rendering of the rendered `text`, for the `multipart/alternative` wrapper:
+/// runs of `Key: value` lines become a table, indented blocks (excerpts of the original) a
+/// monospace block, and the other lines paragraphs.
+pub fn html(text: &str) -> String {
+    static KEY_VALUE: OnceLock<Regex> = OnceLock::new();
+    let key_value =
+        KEY_VALUE.get_or_init(|| Regex::new(r"^([A-Za-z][A-Za-z0-9 ._-]{0,40}): (.+)$").unwrap());
+    #[derive(PartialEq)]
+    enum Block {
+        None,
+        Paragraph,
+        Table,
+        Pre,
+    }
+    let close = |html: &mut String, block: &Block| match block {
+        Block::None => (),
+        Block::Paragraph => html.push_str("</p>\n"),
+        Block::Table => html.push_str("</table>\n"),
+        Block::Pre => html.push_str("</pre>\n"),
+    };
+    let mut html = String::from(
+        "<!DOCTYPE html>\n<html><head><meta charset=\"utf-8\"></head><body style=\"font-family: sans-serif\">\n",
+    );
+    let mut block = Block::None;
+    for line in text.lines() {
+        let (next, content) = if line.trim().is_empty() {
+            (Block::None, String::new())
+        } else if let Some(indented) = line.strip_prefix("    ") {
+            (Block::Pre, escape(indented))
+        } else if let Some(caps) = key_value.captures(line) {
+            let row = format!(
+                "<tr><th align=\"left\">{}</th><td>{}</td></tr>",
+                escape(&caps[1]),
+                escape(&caps[2])
+            );
+            (Block::Table, row)
+        } else {
+            (Block::Paragraph, escape(line))
+        };
+        if next != block {
+            close(&mut html, &block);
+            match next {
+                Block::None => (),
+                Block::Paragraph => html.push_str("<p>"),
+                Block::Table => html.push_str("<table>\n"),
+                Block::Pre => html.push_str("<pre style=\"background: #f4f4f4; padding: 0.5em\">"),
+            }
+        } else if next == Block::Paragraph {
+            html.push_str("<br>");
+        }
+        match next {
+            Block::None => (),
+            Block::Paragraph => html.push_str(&content),
+            Block::Table => writeln!(&mut html, "{content}").unwrap(),
+            Block::Pre => writeln!(&mut html, "{content}").unwrap(),
+        }
+        block = next;
+    }
+    close(&mut html, &block);
+    html.push_str("</body></html>\n");
+    html
+}
+
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_html() {
+        let text = "A process <x> invoked sendmail.\nMore.\n\nLast 1 lines:\n\n    error & <b>\n\nQueue ID: 123\nOriginal: 3 bytes\n";
+        let html = html(text);
+        let body = html
+            .split_once("sans-serif\">\n")
+            .unwrap()
+            .1
+            .strip_suffix("</body></html>\n")
+            .unwrap();
+        assert_eq!(
+            body,
+            "<p>A process &lt;x&gt; invoked sendmail.<br>More.</p>\n\
+             <p>Last 1 lines:</p>\n\
+             <pre style=\"background: #f4f4f4; padding: 0.5em\">error &amp; &lt;b&gt;\n</pre>\n\
+             <table>\n<tr><th align=\"left\">Queue ID</th><td>123</td></tr>\n\
+             <tr><th align=\"left\">Original</th><td>3 bytes</td></tr>\n</table>\n"
+        );
+    }
+
+    #[test]
+    fn test_block_variables() {
+        let vars = Vars::default();
+        let mut body_vars = BodyVars::default();
+        let template = "intro\n%block%outro\n";
+        body_vars.set("block", "");
+        assert_eq!(render(template, &vars, &body_vars), "intro\noutro\n");
+        body_vars.set("block", "line 1\nline 2\n");
+        assert_eq!(
+            render(template, &vars, &body_vars),
+            "intro\nline 1\nline 2\noutro\n"
+        );
+    }
+}
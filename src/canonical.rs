@@ -0,0 +1,113 @@
+//! Address rewriting, like postfix's canonical maps.
+//!
+//! `[[recipient_rewrite]]` rules rewrite the recipients given as arguments or via `-t`, before
+//! the allowlist and routing, so that scripts with hard-coded old addresses keep working.
+//! `[generic]` maps local senders to presentable identities, like postfix's genericstable.
+
+use std::collections::BTreeMap;
+
+use lettre::message::Mailbox;
+use regex::Regex;
+
+#[derive(Debug, Clone, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Rewrite {
+    /// Matched against the whole address, case-insensitively.
+    pub pattern: String,
+    /// May refer to groups of `pattern`, e.g. `$1@new.example`.
+    pub replacement: String,
+}
+
+impl Rewrite {
+    fn regex(&self) -> Result<Regex, regex::Error> {
+        Regex::new(&format!("(?i)^(?:{})$", self.pattern))
+    }
+
+    /// Check the pattern, for loading the config.
+    pub fn validate(&self) -> Result<(), String> {
+        self.regex().map(|_| ()).map_err(|e| e.to_string())
+    }
+}
+
+/// `addr` rewritten by the first rule that matches it.
+pub fn rewrite(rules: &[Rewrite], addr: &str) -> String {
+    for rule in rules {
+        let regex = rule.regex().expect("validated");
+        if regex.is_match(addr) {
+            return regex.replace(addr, rule.replacement.as_str()).into_owned();
+        }
+    }
+    addr.to_owned()
+}
+
+/// The identity for the sender from `[generic]`, whose keys are local users or local addresses
+/// like `backup@host`. The sender is the original's `From` if local, else the caller.
+/// Routable senders are left alone.
+pub fn identity<'a>(
+    generic: &'a BTreeMap<String, Mailbox>,
+    from: Option<&str>,
+    username: Option<&str>,
+    hostname: &str,
+) -> Option<&'a Mailbox> {
+    let local_part = match from {
+        Some(from) if crate::headers::routable_address(from, hostname).is_some() => return None,
+        Some(from) => {
+            if let Some(identity) = generic.get(from) {
+                return Some(identity);
+            }
+            from.split_once('@').map_or(from, |(user, _)| user)
+        }
+        None => username?,
+    };
+    generic.get(local_part)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rewrite() {
+        let rule = |pattern: &str, replacement: &str| Rewrite {
+            pattern: pattern.to_owned(),
+            replacement: replacement.to_owned(),
+        };
+        let rules = [
+            rule(r"ops@old\.example", "oncall@example.com"),
+            rule(r"(.*)@old\.example", "$1@example.com"),
+        ];
+        assert!(rules.iter().all(|r| r.validate().is_ok()));
+        assert!(rule("(", "").validate().is_err());
+        assert_eq!(rewrite(&rules, "OPS@old.example"), "oncall@example.com");
+        assert_eq!(rewrite(&rules, "backup@old.example"), "backup@example.com");
+        assert_eq!(rewrite(&rules, "me@old.example.org"), "me@old.example.org");
+    }
+
+    #[test]
+    fn test_identity() {
+        let generic = BTreeMap::from([
+            ("root".to_owned(), "Ops <ops@example.com>".parse().unwrap()),
+            (
+                "backup@db1".to_owned(),
+                "DB backups <backup@example.com>".parse().unwrap(),
+            ),
+        ]);
+        let identity = |from, username| {
+            identity(&generic, from, username, "db1").map(|m: &Mailbox| m.email.to_string())
+        };
+        assert_eq!(
+            identity(Some("root@db1"), None).as_deref(),
+            Some("ops@example.com")
+        );
+        assert_eq!(
+            identity(None, Some("root")).as_deref(),
+            Some("ops@example.com")
+        );
+        assert_eq!(
+            identity(Some("backup@db1"), Some("root")).as_deref(),
+            Some("backup@example.com")
+        );
+        assert_eq!(identity(Some("backup@db2"), None), None);
+        assert_eq!(identity(Some("root@example.org"), None), None);
+    }
+}
@@ -0,0 +1,45 @@
+//! Opt-in capture of the raw input for debugging, with `debug_capture`.
+//!
+//! Each invocation gets a new directory only the owner may access, so that neither other users
+//! nor planted symlinks get at the captured mail.
+
+use std::fs::DirBuilder;
+use std::io::{self, Read};
+use std::os::unix::fs::{DirBuilderExt, OpenOptionsExt};
+use std::path::{Path, PathBuf};
+
+/// Store `input` as `<dir>/<queue_id>/stdin.eml`. Fails if the queue id's directory exists.
+pub fn store(dir: &Path, queue_id: &str, mut input: impl Read) -> io::Result<PathBuf> {
+    DirBuilder::new().recursive(true).mode(0o700).create(dir)?;
+    let invocation = dir.join(queue_id);
+    DirBuilder::new().mode(0o700).create(&invocation)?;
+    let path = invocation.join("stdin.eml");
+    let mut file = std::fs::File::options()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(&path)?;
+    io::copy(&mut input, &mut file)?;
+    Ok(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::PermissionsExt;
+
+    #[test]
+    fn test_store() {
+        let dir = crate::testdir::TestDir::new("capture");
+        let path = store(&dir, "65A0B1C2D3E4F", &b"Subject: hi\r\n\r\nbody\r\n"[..]).unwrap();
+        assert_eq!(path, dir.join("65A0B1C2D3E4F/stdin.eml"));
+        assert_eq!(
+            std::fs::read(&path).unwrap(),
+            b"Subject: hi\r\n\r\nbody\r\n"
+        );
+        let mode = |p: &Path| std::fs::metadata(p).unwrap().permissions().mode() & 0o777;
+        assert_eq!(mode(path.parent().unwrap()), 0o700);
+        assert_eq!(mode(&path), 0o600);
+        assert!(store(&dir, "65A0B1C2D3E4F", &b""[..]).is_err());
+    }
+}
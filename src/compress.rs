@@ -0,0 +1,87 @@
+//! Compression of large attachments, through the `gzip` or `zstd` commands.
+
+use std::io::Read;
+use std::process::Stdio;
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Format {
+    Gzip,
+    #[default]
+    Zstd,
+}
+
+impl Format {
+    pub fn command(self) -> &'static str {
+        match self {
+            Format::Gzip => "gzip",
+            Format::Zstd => "zstd",
+        }
+    }
+
+    pub fn extension(self) -> &'static str {
+        match self {
+            Format::Gzip => "gz",
+            Format::Zstd => "zst",
+        }
+    }
+
+    pub fn content_type(self) -> &'static str {
+        match self {
+            Format::Gzip => "application/gzip",
+            Format::Zstd => "application/zstd",
+        }
+    }
+}
+
+pub fn compress(format: Format, mut data: impl Read) -> std::io::Result<Vec<u8>> {
+    let mut child = crate::hardening::command(format.command())
+        .args(["-c", "-q"])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .spawn()?;
+    let mut stdout = child.stdout.take().expect("we requested a pipe");
+    // Read concurrently, the compressor blocks once the stdout pipe is full.
+    let reader = std::thread::spawn(move || {
+        let mut output = Vec::new();
+        stdout.read_to_end(&mut output).map(|_| output)
+    });
+    let written = std::io::copy(
+        &mut data,
+        &mut child.stdin.take().expect("we requested a pipe"),
+    );
+    let status = child.wait()?;
+    let output = reader.join().expect("reader doesn't panic")?;
+    written?;
+    if !status.success() {
+        return Err(std::io::Error::other(format!(
+            "{} exited with {status}",
+            format.command()
+        )));
+    }
+    Ok(output)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+    use std::process::Command;
+
+    #[test]
+    fn test_gzip_roundtrip() {
+        let data = "line\n".repeat(10_000);
+        let compressed = compress(Format::Gzip, data.as_bytes()).unwrap();
+        assert!(compressed.len() < data.len() / 10);
+        let mut child = Command::new("gzip")
+            .arg("-dc")
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn()
+            .unwrap();
+        child.stdin.take().unwrap().write_all(&compressed).unwrap();
+        let output = child.wait_with_output().unwrap();
+        assert_eq!(output.stdout, data.as_bytes());
+    }
+}
@@ -0,0 +1,807 @@
+use std::collections::{BTreeMap, HashMap};
+use std::path::{Path, PathBuf};
+
+#[derive(Debug, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    pub sender_email: lettre::Address,
+    pub recipient_email: lettre::Address,
+    pub smtp_host: String,
+    pub smtp_username: String,
+    pub smtp_password: crate::secret::Secret,
+    /// Other relay accounts for recipients in their domains, e.g. an internal relay for the
+    /// internal domain. The first that lists the domain applies.
+    #[serde(default, rename = "smarthost")]
+    pub smarthosts: Vec<crate::smtp::Smarthost>,
+    /// Envelope-from (bounce address) of the wrapper, if different from `sender_email`.
+    /// Supports the placeholders `%hostname%`, `%user%`, and `%uid%`, e.g. `bounces+%hostname%@example.com`.
+    #[serde(default)]
+    pub envelope_from: Option<String>,
+    /// The wrapper's subject. Supports the placeholders `%sender%`, `%hostname%`, `%user%`,
+    /// `%uid%`, `%unit%`, `%severity%`, and `%subject%` (of the original).
+    #[serde(default = "default_subject_template")]
+    pub subject_template: String,
+    /// Longer subjects are truncated, ending in an ellipsis.
+    #[serde(default = "default_max_subject_length")]
+    pub max_subject_length: usize,
+    /// Show the last lines of the original's text body in the wrapper text. 0 disables.
+    #[serde(default)]
+    pub tail_preview_lines: usize,
+    /// Environment variables of the caller to show in the wrapper text; everything else is
+    /// excluded to avoid leaking secrets. Entries ending in `*` match by prefix.
+    #[serde(default)]
+    pub env_allowlist: Vec<String>,
+    /// Attach the journal of the caller's systemd unit from the last minutes. 0 disables.
+    #[serde(default)]
+    pub journal_attachment_minutes: u64,
+    /// File name of the attached original. Supports placeholders (`%hostname%`, `%user%`, ...)
+    /// and `strftime` specifiers (`%Y%m%dT%H%M%S`) for the submission time.
+    #[serde(default = "default_attachment_filename")]
+    pub attachment_filename: String,
+    /// Convert bare LF line endings of the original to CRLF before parsing and attaching it.
+    /// Off by default, so that the original is attached verbatim; the inline copy and what is
+    /// relayed as-is are normalized anyway.
+    #[serde(default)]
+    pub normalize_line_endings: bool,
+    /// Remove ANSI escape sequences (colors) from the inline copy and the tail preview.
+    /// The attachment keeps them.
+    #[serde(default = "default_true")]
+    pub strip_ansi: bool,
+    /// Stop reading stdin and send what was read so far if there is no input for this many
+    /// seconds. 0 disables.
+    #[serde(default)]
+    pub stdin_idle_timeout_secs: u64,
+    /// Stop reading stdin and send what was read so far after this many seconds. 0 disables.
+    #[serde(default)]
+    pub stdin_deadline_secs: u64,
+    /// What to do with submissions without any input.
+    #[serde(default)]
+    pub empty_submission: EmptySubmission,
+    /// Originals larger than this many bytes are spooled to the state dir while reading them,
+    /// instead of being held in memory. 0 disables.
+    #[serde(default = "default_stdin_spool_threshold")]
+    pub stdin_spool_threshold: usize,
+    /// Originals larger than this many bytes are attached with their middle cut out, and kept in
+    /// full in the state dir. 0 disables.
+    #[serde(default)]
+    pub max_original_size: usize,
+    /// How much of the beginning and of the end of a truncated original is attached, in KiB.
+    #[serde(default = "default_truncated_keep_kib")]
+    pub truncated_keep_kib: usize,
+    /// Compress the attached original if it is larger than this many bytes. 0 disables.
+    #[serde(default)]
+    pub compress_threshold: usize,
+    #[serde(default)]
+    pub compress_format: crate::compress::Format,
+    /// Encrypt the attached original to the OpenPGP public key in this file, with `gpg`.
+    /// The inline copy is then left out, and the message isn't relayed as-is.
+    #[serde(default)]
+    pub pgp_recipient_key: Option<PathBuf>,
+    /// With `pgp_recipient_key`, keep the tail preview in the encrypted summary,
+    /// `details.txt.gpg`, instead of leaving it out.
+    #[serde(default)]
+    pub pgp_encrypt_preview: bool,
+    /// `[smime]`: sign the wrapper with a certificate and key, and/or encrypt it to the
+    /// recipient's certificate, with `openssl cms`. Relaying as-is is then off.
+    #[serde(default)]
+    pub smime: Option<crate::smime::Smime>,
+    /// `[verify]`: the IMAP mailbox where `forward-as-attachment-mta --verify` waits for its
+    /// message to arrive.
+    #[serde(default)]
+    pub verify: Option<crate::verify::Verify>,
+    /// Request delivery status notifications for the wrapper from the relay, e.g.
+    /// `["failure", "delay"]`, so that the recipient learns when the wrapper itself bounces.
+    #[serde(default)]
+    pub dsn_notify: Vec<crate::smtp::DsnNotify>,
+    /// Attach machine-readable metadata about the invocation as `invocation.json`.
+    #[serde(default)]
+    pub invocation_json: bool,
+    /// Language of the wrapper's text.
+    #[serde(default)]
+    pub language: crate::i18n::Language,
+    /// Template for the wrapper's text, with `%name%` placeholders (not minijinja, which isn't a
+    /// dependency). Defaults to the template of `language`, see `i18n`.
+    #[serde(default)]
+    pub body_template: Option<String>,
+    /// Prepended to the wrapper's subject, separated by a space.
+    #[serde(default)]
+    pub subject_prefix: Option<String>,
+    /// Additional relay accounts that can be referred to by name, e.g., from `[users.*]`.
+    #[serde(default)]
+    pub transports: HashMap<String, Transport>,
+    /// Per-user overrides, keyed by real uid (`[users.1001]`) or user name (`[users."backup"]`).
+    /// A uid match takes precedence over a user name match.
+    #[serde(default)]
+    pub users: HashMap<String, UserOverride>,
+    /// Honor recipients passed as arguments (e.g., cron's `MAILTO`) if they are allowlisted.
+    #[serde(default)]
+    pub recipient_args: RecipientArgs,
+    /// Rules that rewrite the recipients given as arguments or via `-t`, before the allowlist.
+    #[serde(default, rename = "recipient_rewrite")]
+    pub recipient_rewrites: Vec<crate::canonical::Rewrite>,
+    /// Domain part of the wrapper's Message-ID. Defaults to the domain of `sender_email`.
+    #[serde(default)]
+    pub message_id_domain: Option<String>,
+    /// Additional headers for every wrapper, e.g. `X-Environment = "prod"`.
+    /// Values support the placeholders `%hostname%`, `%user%`, and `%uid%`.
+    #[serde(default)]
+    pub headers: BTreeMap<String, String>,
+    /// Thread wrappers for the same job together via `In-Reply-To`/`References`.
+    #[serde(default)]
+    pub threading: bool,
+    /// Set `Reply-To` to the original `From` address if it is routable.
+    #[serde(default)]
+    pub reply_to_original_sender: bool,
+    /// Presentable identities for local senders, used as the wrapper's `From` and `Reply-To`,
+    /// e.g. `root = "Ops <ops@example.com>"`. See `canonical::identity`.
+    #[serde(default)]
+    pub generic: BTreeMap<String, lettre::message::Mailbox>,
+    /// Rules that drop matching submissions instead of forwarding them.
+    #[serde(default, rename = "filter")]
+    pub filters: Vec<crate::filter::Filter>,
+    /// Summarize the submissions dropped by `[[filter]]` in an email sent at most once a day.
+    #[serde(default = "default_true")]
+    pub filter_summary: bool,
+    /// Rules that assign a severity to submissions, the highest of the matching ones applies.
+    #[serde(default, rename = "classifier")]
+    pub classifiers: Vec<crate::severity::Classifier>,
+    #[serde(default)]
+    pub severity_recipients: crate::severity::Recipients,
+    /// Rules that send matching submissions to another recipient, the first matching one
+    /// applies. They take precedence over `severity_recipients`.
+    #[serde(default, rename = "route")]
+    pub routes: Vec<crate::route::Route>,
+    /// Run this WebAssembly module on each submission, after the classifiers; it can drop the
+    /// submission, or change its severity, recipient, or subject. Needs the `plugin` feature.
+    #[serde(default)]
+    pub plugin: Option<PathBuf>,
+    /// After this many instructions, the plugin is stopped and ignored.
+    #[serde(default = "default_plugin_max_instructions")]
+    pub plugin_max_instructions: u64,
+    /// Forward identical submissions (same user, sender, subject, and text) only once within
+    /// this many seconds, followed by a count of the repeats. 0 disables.
+    #[serde(default)]
+    pub dedup_window_secs: u64,
+    /// Only forward a submission once the same user, sender, and subject have occurred this many
+    /// times within `escalate_window_secs`, listing the suppressed occurrences. 0 disables.
+    #[serde(default)]
+    pub escalate_after: u32,
+    #[serde(default = "default_escalate_window_secs")]
+    pub escalate_window_secs: u64,
+    /// Keep submissions for the `--digest` subcommand, e.g. run from a timer, instead of
+    /// forwarding them.
+    #[serde(default)]
+    pub digest: bool,
+    /// Group the digest by local user and subject, attaching only the latest original of each
+    /// group. The others are kept in `state_dir/digested`.
+    #[serde(default)]
+    pub digest_group: bool,
+    /// Forward at most this many submissions per hour and local user, the rest is held and
+    /// sent in a digest at most once an hour. 0 disables.
+    #[serde(default)]
+    pub rate_limit_per_hour: u32,
+    /// A daily window during which non-critical submissions are held in the spool, for
+    /// `sendmail -q` to forward after the window.
+    #[serde(default)]
+    pub quiet_hours: Option<crate::quiet::QuietHours>,
+    /// Whether to wrap submissions, or relay well-formed mail as-is.
+    #[serde(default)]
+    pub mode: crate::passthrough::Mode,
+    /// Rules that override `mode` for matching submissions, the first match wins.
+    #[serde(default)]
+    pub mode_rules: Vec<crate::passthrough::Rule>,
+    /// Add an HTML rendering of the wrapper text, as `multipart/alternative`.
+    #[serde(default)]
+    pub html_body: bool,
+    /// Which sender to show if the original has multiple `From` headers.
+    #[serde(default)]
+    pub multiple_from: MultipleFrom,
+    /// Restrict which local users may submit mail.
+    #[serde(default)]
+    pub submitters: Submitters,
+    #[serde(default)]
+    pub loop_detection: LoopDetection,
+    /// Where persistent state (digests, ...) is kept.
+    #[serde(default = "default_state_dir")]
+    pub state_dir: PathBuf,
+    /// Switch to this user, e.g. a dedicated one owning `state_dir`, after reading the config
+    /// and finding out about the caller. Needs to run as root.
+    #[serde(default)]
+    pub run_as: Option<String>,
+    /// Restrict file access to what's needed, after startup, with Landlock where the kernel
+    /// supports it.
+    #[serde(default)]
+    pub landlock: bool,
+    /// How many days of delivery history, other state, and kept originals `sendmail --db vacuum`
+    /// keeps.
+    #[serde(default = "default_history_days")]
+    pub history_days: u64,
+    /// Keep Prometheus metrics in this file, for node_exporter's textfile collector, e.g.
+    /// `/var/lib/prometheus/node-exporter/forward-as-attachment-mta.prom`.
+    #[serde(default)]
+    pub metrics_textfile: Option<PathBuf>,
+    /// Export a trace of each submission and the delivery counters to this OpenTelemetry
+    /// collector, e.g. `http://localhost:4318`. Needs the `otlp` feature.
+    #[serde(default)]
+    pub otlp_endpoint: Option<String>,
+    /// Report our panics and failed deliveries to this Sentry DSN, e.g.
+    /// `https://<key>@o0.ingest.sentry.io/<project>`. Needs the `sentry` feature.
+    #[serde(default)]
+    pub sentry_dsn: Option<String>,
+    /// Request this URL after each delivery that went through, e.g. an Uptime Kuma push monitor's
+    /// `https://kuma.example.com/api/push/<token>?status=up` or `https://hc-ping.com/<uuid>`.
+    #[serde(default)]
+    pub push_url: Option<String>,
+    /// Request this URL after each delivery that failed, e.g.
+    /// `https://kuma.example.com/api/push/<token>?status=down` or `https://hc-ping.com/<uuid>/fail`.
+    #[serde(default)]
+    pub push_failure_url: Option<String>,
+    /// POST the forwarding health as JSON to the push URLs rather than requesting them, i.e. the
+    /// queue depth, today's delivered and failed messages, and the last error.
+    #[serde(default)]
+    pub push_payload: bool,
+    /// Run this command, e.g. `["/usr/local/sbin/faam-pre-send"]`, with the original on stdin
+    /// before composing the wrapper: exit status 0 forwards it, or what the command printed
+    /// instead, 1 vetoes it, anything else forwards it unchanged.
+    #[serde(default)]
+    pub pre_send_hook: Vec<String>,
+    /// Run this command after each delivery, with the outcome in `FAAM_*` environment variables.
+    #[serde(default)]
+    pub post_send_hook: Vec<String>,
+    /// After this long, the hooks are killed.
+    #[serde(default = "default_hook_timeout_secs")]
+    pub hook_timeout_secs: u64,
+    /// As a last resort, write a short notice to `/dev/console` and the terminals of logged-in
+    /// admins when a delivery fails permanently, or fails while `console_notice_spool_depth` or
+    /// more submissions are waiting in the spool. At most once an hour.
+    #[serde(default)]
+    pub console_notice: bool,
+    #[serde(default = "default_console_notice_spool_depth")]
+    pub console_notice_spool_depth: usize,
+    /// Number the wrappers, in `X-FAAM-Seq` and the body, with a counter in `state_dir`, so that
+    /// the recipient notices gaps, i.e. lost messages.
+    #[serde(default)]
+    pub sequence_numbers: bool,
+    /// Hand submissions off to the daemon listening on this unix socket, e.g.
+    /// `/run/forward-as-attachment-mta.sock`, or inet socket, e.g. `127.0.0.1:2526`, see
+    /// `forward-as-attachment-mta --daemon`. If it isn't running, submissions are forwarded as usual.
+    #[serde(default)]
+    pub daemon_socket: Option<PathBuf>,
+    /// Accept SMTP submissions on these inet sockets, e.g. `127.0.0.1:25`, or unix sockets, see
+    /// `forward-as-attachment-mta --smtpd`.
+    #[serde(default)]
+    pub smtpd_listen: Vec<PathBuf>,
+    /// Speak LMTP instead of SMTP on `smtpd_listen`.
+    #[serde(default)]
+    pub smtpd_protocol: crate::smtpd::Protocol,
+    /// Refuse messages larger than this many bytes on `smtpd_listen`. 0 disables.
+    #[serde(default = "default_smtpd_max_message_size")]
+    pub smtpd_max_message_size: usize,
+    /// With `forward-as-attachment-mta --watch`, forward the journal entries at this priority or
+    /// higher, e.g. `"warning"`, as for `journalctl --priority`.
+    #[serde(default = "default_watch_priority")]
+    pub watch_priority: String,
+    /// With `forward-as-attachment-mta --watch`, only forward the entries of these units, or unit
+    /// patterns like `"smartd*"`. Empty forwards those of all units.
+    #[serde(default)]
+    pub watch_units: Vec<String>,
+    /// With `forward-as-attachment-mta --watch`, entries less than this many seconds apart are
+    /// forwarded together.
+    #[serde(default = "default_watch_burst_secs")]
+    pub watch_burst_secs: u64,
+    /// Append a JSON record of each submission to this file, whatever became of it, e.g.
+    /// `/var/log/forward-as-attachment-mta.audit.jsonl`. Independent of the logs.
+    #[serde(default)]
+    pub audit_log: Option<PathBuf>,
+    /// Rotate `audit_log` to `audit_log.1`, ... before it grows beyond this many KiB.
+    #[serde(default = "default_audit_log_max_kib")]
+    pub audit_log_max_kib: usize,
+    /// How many rotated audit logs to keep.
+    #[serde(default = "default_audit_log_keep")]
+    pub audit_log_keep: usize,
+    /// Print `Email sent successfully` on success, like `-v`, for interactive runs. By default,
+    /// only failures are reported, on stderr.
+    #[serde(default)]
+    pub verbose: bool,
+    /// Where to log once the config is loaded: `"stderr"` (the default), `"syslog"` with facility
+    /// mail, `"journald"` with the fields as `FAAM_*` journal fields, or `"file"`, i.e. `log_file`.
+    #[serde(default)]
+    pub log_target: crate::logging::Target,
+    /// What to log, with the syntax of `RUST_LOG`, which takes precedence, e.g.
+    /// `"forward_as_attachment_mta=debug"`.
+    #[serde(default)]
+    pub log_filter: Option<String>,
+    /// `"text"` (the default), or `"json"` for one JSON object per event. Doesn't apply to
+    /// journald, whose entries have fields anyway.
+    #[serde(default)]
+    pub log_format: crate::logging::Format,
+    /// The file of `log_target = "file"`.
+    #[serde(default = "default_log_file")]
+    pub log_file: PathBuf,
+    /// Rotate `log_file` to `log_file.1`, ... before it grows beyond this many KiB.
+    #[serde(default = "default_log_file_max_kib")]
+    pub log_file_max_kib: usize,
+    /// How many rotated log files to keep.
+    #[serde(default = "default_log_file_keep")]
+    pub log_file_keep: usize,
+    /// Let debug logs contain credentials and message content: lettre's trace of the SMTP
+    /// conversation, and the messages we send. Only for debugging.
+    #[serde(default)]
+    pub log_unsafe_debug: bool,
+    /// Keep the input of each invocation in a new directory only root may access, and name it
+    /// in the wrapper text, for debugging.
+    #[serde(default)]
+    pub debug_capture: bool,
+    /// Where `debug_capture` keeps the input. Defaults to `state_dir/capture`.
+    #[serde(default)]
+    pub debug_capture_dir: Option<PathBuf>,
+}
+
+pub const DEFAULT_SUBJECT_TEMPLATE: &str = "%sender%@%hostname%: %subject%";
+
+fn default_subject_template() -> String {
+    DEFAULT_SUBJECT_TEMPLATE.to_owned()
+}
+
+fn default_max_subject_length() -> usize {
+    200
+}
+
+fn default_attachment_filename() -> String {
+    "stdin.eml".to_owned()
+}
+
+fn default_stdin_spool_threshold() -> usize {
+    16 * 1024 * 1024
+}
+
+fn default_truncated_keep_kib() -> usize {
+    64
+}
+
+fn default_history_days() -> u64 {
+    90
+}
+
+fn default_plugin_max_instructions() -> u64 {
+    100_000_000
+}
+
+fn default_hook_timeout_secs() -> u64 {
+    30
+}
+
+fn default_smtpd_max_message_size() -> usize {
+    64 * 1024 * 1024
+}
+
+fn default_console_notice_spool_depth() -> usize {
+    100
+}
+
+fn default_log_file() -> PathBuf {
+    PathBuf::from(crate::logfile::DEFAULT_PATH)
+}
+
+fn default_log_file_max_kib() -> usize {
+    1024
+}
+
+fn default_log_file_keep() -> usize {
+    5
+}
+
+fn default_audit_log_max_kib() -> usize {
+    10 * 1024
+}
+
+fn default_audit_log_keep() -> usize {
+    10
+}
+
+fn default_escalate_window_secs() -> u64 {
+    60 * 60
+}
+
+fn default_watch_priority() -> String {
+    "err".to_owned()
+}
+
+fn default_watch_burst_secs() -> u64 {
+    30
+}
+
+fn default_true() -> bool {
+    true
+}
+
+fn default_state_dir() -> PathBuf {
+    PathBuf::from(crate::state::DEFAULT_STATE_DIR)
+}
+
+#[derive(Debug, Default, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RecipientArgs {
+    /// Entries are either full addresses or domains; both are matched case-insensitively.
+    #[serde(default)]
+    pub allowlist: Vec<String>,
+    #[serde(default)]
+    pub mode: RecipientArgsMode,
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RecipientArgsMode {
+    /// Deliver to the allowlisted recipients instead of `recipient_email`.
+    #[default]
+    Replace,
+    /// Deliver to the allowlisted recipients in addition to `recipient_email`.
+    Add,
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MultipleFrom {
+    First,
+    Last,
+    /// All of them, comma-separated.
+    Concatenate,
+    /// None of them, as if there was no `From` header.
+    #[default]
+    Reject,
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum EmptySubmission {
+    /// Send a wrapper that says the submission was empty, without an attachment.
+    #[default]
+    Notice,
+    /// Don't send anything.
+    Suppress,
+}
+
+impl RecipientArgs {
+    pub fn allows(&self, addr: &lettre::Address) -> bool {
+        self.allowlist.iter().any(|entry| {
+            // Compare domains in their ASCII form, so that IDNs match either way they are written.
+            let (user, domain) = match entry.rsplit_once('@') {
+                Some((user, domain)) => (Some(user), domain),
+                None => (None, entry.as_str()),
+            };
+            user.is_none_or(|user| user.to_lowercase() == addr.user().to_lowercase())
+                && idna::domain_to_ascii(domain).is_ok_and(|domain| {
+                    idna::domain_to_ascii(addr.domain()).is_ok_and(|d| d == domain)
+                })
+        })
+    }
+}
+
+#[derive(Debug, Default, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Submitters {
+    /// If this or `allow_uids` is non-empty, only matching users may submit.
+    #[serde(default)]
+    pub allow_users: Vec<String>,
+    #[serde(default)]
+    pub allow_uids: Vec<UidRange>,
+    /// Takes precedence over the allow lists.
+    #[serde(default)]
+    pub deny_users: Vec<String>,
+    #[serde(default)]
+    pub deny_uids: Vec<UidRange>,
+    /// Summarize denied submissions in an email sent at most once a day.
+    /// Otherwise, they are only logged.
+    #[serde(default)]
+    pub denied_digest: bool,
+}
+
+impl Submitters {
+    pub fn permits(&self, uid: u32, username: Option<&str>) -> bool {
+        let matches = |users: &[String], uids: &[UidRange]| {
+            uids.iter().any(|r| r.contains(uid))
+                || username.is_some_and(|name| users.iter().any(|u| u == name))
+        };
+        if matches(&self.deny_users, &self.deny_uids) {
+            return false;
+        }
+        if self.allow_users.is_empty() && self.allow_uids.is_empty() {
+            return true;
+        }
+        matches(&self.allow_users, &self.allow_uids)
+    }
+}
+
+/// A single uid (`1000`) or an inclusive range (`"1000-1999"`).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Deserialize)]
+#[serde(try_from = "UidRangeRepr")]
+pub struct UidRange {
+    pub start: u32,
+    pub end: u32,
+}
+
+#[derive(serde::Deserialize)]
+#[serde(untagged)]
+enum UidRangeRepr {
+    Single(u32),
+    Range(String),
+}
+
+impl TryFrom<UidRangeRepr> for UidRange {
+    type Error = String;
+    fn try_from(value: UidRangeRepr) -> Result<Self, Self::Error> {
+        match value {
+            UidRangeRepr::Single(uid) => Ok(UidRange {
+                start: uid,
+                end: uid,
+            }),
+            UidRangeRepr::Range(s) => {
+                let parse = |v: &str| {
+                    v.trim()
+                        .parse::<u32>()
+                        .map_err(|e| format!("invalid uid range {s:?}: {e}"))
+                };
+                let (start, end) = match s.split_once('-') {
+                    Some((start, end)) => (parse(start)?, parse(end)?),
+                    None => (parse(&s)?, parse(&s)?),
+                };
+                if start > end {
+                    return Err(format!("invalid uid range {s:?}: start > end"));
+                }
+                Ok(UidRange { start, end })
+            }
+        }
+    }
+}
+
+impl UidRange {
+    pub fn contains(&self, uid: u32) -> bool {
+        (self.start..=self.end).contains(&uid)
+    }
+}
+
+#[derive(Debug, serde::Deserialize)]
+#[serde(deny_unknown_fields, default)]
+pub struct LoopDetection {
+    /// Value of the `X-Loop` header we add to the wrapper.
+    /// Originals that already contain it are not forwarded again.
+    pub x_loop: String,
+    /// Originals with more `Received` headers than this are not forwarded.
+    pub max_received: usize,
+}
+
+impl Default for LoopDetection {
+    fn default() -> Self {
+        Self {
+            x_loop: "forward-as-attachment-mta".to_owned(),
+            max_received: 20,
+        }
+    }
+}
+
+#[derive(Debug, Clone, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Transport {
+    pub smtp_host: String,
+    pub smtp_username: String,
+    pub smtp_password: crate::secret::Secret,
+}
+
+#[derive(Debug, Clone, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct UserOverride {
+    pub recipient_email: Option<lettre::Address>,
+    pub subject_prefix: Option<String>,
+    /// Name of an entry in `[transports]`.
+    pub transport: Option<String>,
+    pub mode: Option<crate::passthrough::Mode>,
+}
+
+/// Headers that `[headers]` can't set: the wrapper's own, those it relies on, and the addressing
+/// ones, which would make it go or look like it came from elsewhere. `X-FAAM-*` ones, too.
+const RESERVED_HEADERS: &[&str] = &[
+    "From",
+    "Sender",
+    "Reply-To",
+    "To",
+    "Cc",
+    "Bcc",
+    "Subject",
+    "Date",
+    "Message-ID",
+    "In-Reply-To",
+    "References",
+    "MIME-Version",
+    "Content-Type",
+    "Content-Transfer-Encoding",
+    "Content-Disposition",
+    "X-Loop",
+];
+
+impl Config {
+    /// An error for the first entry of `[headers]` that isn't a valid header name or is
+    /// reserved.
+    pub fn validate_headers(&self) -> Result<(), String> {
+        for name in self.headers.keys() {
+            if let Err(e) = crate::headers::RawHeader::from_name(name.clone(), String::new()) {
+                return Err(format!("invalid header {name:?}: {e}"));
+            }
+            let reserved = RESERVED_HEADERS
+                .iter()
+                .any(|reserved| reserved.eq_ignore_ascii_case(name))
+                || name
+                    .get(..7)
+                    .is_some_and(|prefix| prefix.eq_ignore_ascii_case("X-FAAM-"));
+            if reserved {
+                return Err(format!("{name:?} is reserved for the wrapper"));
+            }
+        }
+        Ok(())
+    }
+
+    /// Apply the `[users.*]` section that matches the caller, if any.
+    ///
+    /// Returns the key of the section that was applied.
+    pub fn apply_user_override(
+        &mut self,
+        uid: u32,
+        username: Option<&str>,
+    ) -> Result<Option<String>, String> {
+        let uid_key = uid.to_string();
+        let key = if self.users.contains_key(&uid_key) {
+            uid_key
+        } else {
+            match username {
+                Some(name) if self.users.contains_key(name) => name.to_owned(),
+                _ => return Ok(None),
+            }
+        };
+        let over = self.users[&key].clone();
+        if let Some(recipient_email) = over.recipient_email {
+            self.recipient_email = recipient_email;
+        }
+        if let Some(subject_prefix) = over.subject_prefix {
+            self.subject_prefix = Some(subject_prefix);
+        }
+        if let Some(mode) = over.mode {
+            self.mode = mode;
+        }
+        if let Some(name) = over.transport {
+            let Some(transport) = self.transports.get(&name) else {
+                return Err(format!("users.{key}: unknown transport {name:?}"));
+            };
+            let transport = transport.clone();
+            self.smtp_host = transport.smtp_host;
+            self.smtp_username = transport.smtp_username;
+            self.smtp_password = transport.smtp_password;
+        }
+        Ok(Some(key))
+    }
+
+    /// Decrypt the `smtp_password` and `imap_password` values that are armored age messages.
+    pub fn decrypt_secrets(&mut self, identity_file: &Path) -> std::io::Result<()> {
+        let transports = self.transports.values_mut().map(|t| &mut t.smtp_password);
+        let smarthosts = self.smarthosts.iter_mut().map(|s| &mut s.smtp_password);
+        let verify = self.verify.iter_mut().map(|v| &mut v.imap_password);
+        for password in std::iter::once(&mut self.smtp_password)
+            .chain(transports)
+            .chain(smarthosts)
+            .chain(verify)
+        {
+            crate::age::secret(identity_file, password.expose_mut())?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CONFIG: &str = r#"
+        sender_email = "sender@example.com"
+        recipient_email = "admin@example.com"
+        smtp_host = "smtp.example.com"
+        smtp_username = "user"
+        smtp_password = "pass"
+
+        [transports.internal]
+        smtp_host = "relay.internal"
+        smtp_username = "internal"
+        smtp_password = "secret"
+
+        [users.1001]
+        recipient_email = "uid1001@example.com"
+
+        [users.1003]
+        recipient_email = "jörg@bücher.example"
+
+        [users."backup"]
+        recipient_email = "backup@example.com"
+        subject_prefix = "[backup]"
+        transport = "internal"
+    "#;
+
+    #[test]
+    fn test_user_override() {
+        let mut config: Config = toml::from_str(CONFIG).unwrap();
+        assert_eq!(
+            config.apply_user_override(1001, Some("backup")),
+            Ok(Some("1001".to_owned()))
+        );
+        assert_eq!(config.recipient_email.to_string(), "uid1001@example.com");
+        assert_eq!(config.smtp_host, "smtp.example.com");
+
+        let mut config: Config = toml::from_str(CONFIG).unwrap();
+        assert_eq!(
+            config.apply_user_override(1002, Some("backup")),
+            Ok(Some("backup".to_owned()))
+        );
+        assert_eq!(config.recipient_email.to_string(), "backup@example.com");
+        assert_eq!(config.subject_prefix.as_deref(), Some("[backup]"));
+        assert_eq!(config.smtp_host, "relay.internal");
+
+        let mut config: Config = toml::from_str(CONFIG).unwrap();
+        assert_eq!(config.apply_user_override(0, Some("root")), Ok(None));
+        assert_eq!(config.recipient_email.to_string(), "admin@example.com");
+
+        let mut config: Config = toml::from_str(CONFIG).unwrap();
+        config.apply_user_override(1003, None).unwrap();
+        assert_eq!(config.recipient_email.domain(), "bücher.example");
+    }
+
+    #[test]
+    fn test_validate_headers() {
+        let config = |headers: &str| {
+            let mut config: Config = toml::from_str(CONFIG).unwrap();
+            config.headers = toml::from_str(headers).unwrap();
+            config.validate_headers()
+        };
+        assert_eq!(config(r#"X-Environment = "prod""#), Ok(()));
+        assert!(config(r#""X Environment" = "prod""#).is_err());
+        for name in ["from", "Message-Id", "X-Loop", "x-faam-uid", "Content-Type"] {
+            assert_eq!(
+                config(&format!(r#"{name} = "x""#)),
+                Err(format!("{name:?} is reserved for the wrapper")),
+            );
+        }
+    }
+
+    #[test]
+    fn test_recipient_args_allowlist() {
+        let ra = RecipientArgs {
+            allowlist: vec![
+                "example.com".to_owned(),
+                "Ops@Example.org".to_owned(),
+                "bücher.example".to_owned(),
+            ],
+            mode: RecipientArgsMode::Replace,
+        };
+        let f = |s: &str| ra.allows(&s.parse().unwrap());
+        assert!(f("anyone@EXAMPLE.com"));
+        assert!(f("ops@example.org"));
+        assert!(!f("other@example.org"));
+        assert!(!f("someone@sub.example.com"));
+        assert!(f("jörg@BÜCHER.example"));
+        assert!(f("root@xn--bcher-kva.example"));
+    }
+
+    #[test]
+    fn test_submitters() {
+        let s: Submitters = toml::from_str(
+            r#"
+            allow_uids = [0, "1000-1999"]
+            allow_users = ["backup"]
+            deny_uids = ["1500"]
+            "#,
+        )
+        .unwrap();
+        assert!(s.permits(0, Some("root")));
+        assert!(s.permits(1000, None));
+        assert!(s.permits(34, Some("backup")));
+        assert!(!s.permits(1500, Some("backup")));
+        assert!(!s.permits(33, Some("www-data")));
+        assert!(Submitters::default().permits(33, Some("www-data")));
+        assert!(toml::from_str::<Submitters>(r#"allow_uids = ["2-1"]"#).is_err());
+    }
+}
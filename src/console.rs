@@ -0,0 +1,149 @@
+//! The last resort with `console_notice`: when a delivery fails permanently, or while the spool
+//! backs up, write a short notice to `/dev/console` and, like `wall`, to the terminals of the
+//! logged-in admins, so that someone at the host learns that mail is broken.
+//!
+//! At most one notice per `NOTICE_INTERVAL`, kept as the mtime of `state_dir/console-notice`.
+
+use std::ffi::CStr;
+use std::io::{self, Write};
+use std::os::unix::fs::{MetadataExt, OpenOptionsExt};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+use tracing::{debug, warn};
+use users::os::unix::GroupExt;
+
+use crate::config::Config;
+
+const NOTICE_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+/// Members of these groups, and root, count as admins.
+const ADMIN_GROUPS: &[&str] = &["sudo", "wheel", "adm"];
+
+/// Of the error, in the notice.
+const ERROR_CHARS: usize = 200;
+
+/// Write the notice if the failed delivery calls for it, see `console_notice`.
+pub fn failed(config: &Config, hostname: &str, error: &crate::smtp::Error) {
+    if !config.console_notice {
+        return;
+    }
+    let depth = crate::spool::depth(&config.state_dir).unwrap_or(0);
+    if error.class() != "rejected" && depth < config.console_notice_spool_depth {
+        return;
+    }
+    match due(&config.state_dir) {
+        Ok(true) => (),
+        Ok(false) => return debug!("a console notice was written recently"),
+        Err(e) => return warn!(%e, "can't check when the last console notice was written"),
+    }
+    let notice = notice(hostname, &error.to_string(), depth);
+    let mut written = 0;
+    for tty in std::iter::once(PathBuf::from("/dev/console")).chain(admin_terminals()) {
+        match write(&tty, &notice) {
+            Ok(()) => written += 1,
+            Err(e) => debug!(%e, ?tty, "can't write the console notice"),
+        }
+    }
+    warn!(
+        terminals = written,
+        "wrote a notice that mail is broken to the terminals"
+    );
+}
+
+/// Whether the last notice is older than `NOTICE_INTERVAL`; if so, now is the last one.
+fn due(state_dir: &Path) -> io::Result<bool> {
+    let marker = state_dir.join("console-notice");
+    match std::fs::metadata(&marker).and_then(|m| m.modified()) {
+        Ok(at) if at.elapsed().unwrap_or_default() < NOTICE_INTERVAL => return Ok(false),
+        Ok(_) => (),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => (),
+        Err(e) => return Err(e),
+    }
+    std::fs::File::create(&marker)?.set_modified(SystemTime::now())?;
+    Ok(true)
+}
+
+fn notice(hostname: &str, error: &str, depth: usize) -> String {
+    // Terminals are in raw mode at times, and the error comes from the relay.
+    let hostname = crate::headers::sanitize_subject(hostname, 64);
+    let error = crate::headers::sanitize_subject(error, ERROR_CHARS);
+    format!(
+        "\r\n\x07forward-as-attachment-mta on {hostname} at {}: mail is broken!\r\n\
+         The last delivery failed: {error}\r\n\
+         {depth} submissions are waiting in the spool. See `journalctl -t forward-as-attachment-mta`.\r\n\r\n",
+        crate::time::format_utc_and_local(SystemTime::now())
+    )
+}
+
+fn write(tty: &Path, notice: &str) -> io::Result<()> {
+    let mut file = std::fs::File::options()
+        .write(true)
+        .custom_flags(libc::O_NOCTTY | libc::O_NONBLOCK)
+        .open(tty)?;
+    file.write_all(notice.as_bytes())
+}
+
+/// The terminals of the logged-in admins that accept messages, see `mesg(1)`.
+fn admin_terminals() -> Vec<PathBuf> {
+    let groups: Vec<users::Group> = ADMIN_GROUPS
+        .iter()
+        .filter_map(users::get_group_by_name)
+        .collect();
+    let is_admin = |user: &str| {
+        user == "root"
+            || groups
+                .iter()
+                .any(|g| g.members().iter().any(|m| *m == *user))
+            || users::get_user_by_name(user)
+                .is_some_and(|u| groups.iter().any(|g| g.gid() == u.primary_group_id()))
+    };
+    let mut sessions = Vec::new();
+    // SAFETY: each entry is copied out before the next call; `getutxent` isn't reentrant, but
+    // nothing else reads the utmp database
+    unsafe {
+        libc::setutxent();
+        loop {
+            let entry = libc::getutxent();
+            if entry.is_null() {
+                break;
+            }
+            if (*entry).ut_type == libc::USER_PROCESS {
+                let user = CStr::from_ptr((*entry).ut_user.as_ptr());
+                let line = CStr::from_ptr((*entry).ut_line.as_ptr());
+                sessions.push((
+                    user.to_string_lossy().into_owned(),
+                    line.to_string_lossy().into_owned(),
+                ));
+            }
+        }
+        libc::endutxent();
+    }
+    let mut terminals: Vec<PathBuf> = sessions
+        .into_iter()
+        // E.g. `tty1` or `pts/0`, relative to `/dev`.
+        .filter(|(_, line)| !line.is_empty() && !line.starts_with('/') && !line.contains(".."))
+        .filter(|(user, _)| is_admin(user))
+        .map(|(_, line)| Path::new("/dev").join(line))
+        .collect();
+    terminals.sort();
+    terminals.dedup();
+    // `mesg n` clears the group's write permission.
+    terminals.retain(|tty| std::fs::metadata(tty).is_ok_and(|m| m.mode() & 0o020 != 0));
+    terminals
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_notice() {
+        let notice = notice("vm", "Permanent error (554): \x1b]0;owned\x07rejected", 3);
+        assert!(notice.starts_with("\r\n\x07forward-as-attachment-mta on vm at "));
+        assert!(notice.contains(
+            "\r\nThe last delivery failed: Permanent error (554): ]0;ownedrejected\r\n3 submissions are waiting in the spool."
+        ));
+        assert_eq!(notice.matches('\x1b').count(), 0);
+    }
+}
@@ -0,0 +1,143 @@
+//! Crash reports: if we panic, the panic message, backtrace, and invocation go to
+//! `state_dir/crashes`, and the next wrapper that is delivered starts with them, so that
+//! failures of the forwarder itself don't go unnoticed. They are removed once delivered.
+
+use std::io::Write;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::SystemTime;
+
+/// So that a crash loop doesn't fill the disk; later crashes are only on stderr.
+const MAX_REPORTS: usize = 10;
+
+/// Of each report, in the wrapper.
+const MAX_REPORT_LEN: usize = 16 * 1024;
+
+/// The state dir to write to: the default until the config is loaded.
+static STATE_DIR: Mutex<Option<PathBuf>> = Mutex::new(None);
+
+/// Install the panic hook, in addition to the default one, which prints to stderr.
+pub fn install() {
+    let default = std::panic::take_hook();
+    std::panic::set_hook(Box::new(move |info| {
+        let backtrace = std::backtrace::Backtrace::force_capture();
+        let state_dir = STATE_DIR
+            .lock()
+            .ok()
+            .and_then(|dir| dir.clone())
+            .unwrap_or_else(|| PathBuf::from(crate::state::DEFAULT_STATE_DIR));
+        let report = format!("{info}\n\n{}\nBacktrace:\n{backtrace}", invocation());
+        if let Err(e) = write(&state_dir, &report) {
+            eprintln!("forward-as-attachment-mta: can't write the crash report: {e}");
+        }
+        #[cfg(feature = "sentry")]
+        crate::sentry::report_panic(&report);
+        default(info);
+    }));
+}
+
+/// Write the reports to `state_dir` rather than the default.
+pub fn set_state_dir(state_dir: &Path) {
+    if let Ok(mut dir) = STATE_DIR.lock() {
+        *dir = Some(state_dir.to_owned());
+    }
+}
+
+fn invocation() -> String {
+    let now = SystemTime::now();
+    let args: Vec<_> = std::env::args_os().collect();
+    format!(
+        "Time: {}\nVersion: {}\nQueue ID: {}\nArgs: {args:?}\nuid:{} euid:{} pid:{}\n",
+        crate::time::format_utc(now),
+        env!("CARGO_PKG_VERSION"),
+        crate::logging::queue_id().unwrap_or("-"),
+        users::get_current_uid(),
+        users::get_effective_uid(),
+        std::process::id(),
+    )
+}
+
+fn write(state_dir: &Path, report: &str) -> std::io::Result<()> {
+    let dir = crate::state::subdir_in(state_dir, "crashes")?;
+    if std::fs::read_dir(&dir)?.count() >= MAX_REPORTS {
+        return Ok(());
+    }
+    let name = format!(
+        "{}-{}.txt",
+        crate::time::unix_secs(SystemTime::now()),
+        std::process::id()
+    );
+    let mut file = std::fs::File::options()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(dir.join(name))?;
+    file.write_all(report.as_bytes())?;
+    file.sync_all()
+}
+
+/// The reports of previous crashes, oldest first.
+pub fn previous(state_dir: &Path) -> Vec<(PathBuf, String)> {
+    let Ok(entries) = std::fs::read_dir(state_dir.join("crashes")) else {
+        return vec![];
+    };
+    let mut paths: Vec<PathBuf> = entries
+        .filter_map(|e| Some(e.ok()?.path()))
+        .filter(|p| p.extension().is_some_and(|ext| ext == "txt"))
+        .collect();
+    paths.sort();
+    paths
+        .into_iter()
+        .filter_map(|path| {
+            let report = std::fs::read(&path).ok()?;
+            let report = String::from_utf8_lossy(&report[..report.len().min(MAX_REPORT_LEN)]);
+            Some((path, report.into_owned()))
+        })
+        .collect()
+}
+
+/// The start of the wrapper body with the `reports`, for which `heading` introduces each.
+pub fn section(reports: &[(PathBuf, String)], heading: &str) -> String {
+    let mut section = String::new();
+    for (path, report) in reports {
+        let path = path.display().to_string();
+        section.push_str(&crate::i18n::fill(heading, &[("path", &path)]));
+        section.push_str("\n\n");
+        for line in report.lines() {
+            if !line.is_empty() {
+                section.push_str("    ");
+            }
+            section.push_str(line);
+            section.push('\n');
+        }
+        section.push('\n');
+    }
+    section
+}
+
+/// Remove the `reports`, now that they are delivered.
+pub fn clear(reports: &[(PathBuf, String)]) {
+    for (path, _) in reports {
+        if let Err(e) = std::fs::remove_file(path) {
+            tracing::warn!(%e, ?path, "can't remove the crash report");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_section() {
+        let reports = [(
+            PathBuf::from("/var/lib/forward-as-attachment-mta/crashes/1700000000-42.txt"),
+            "panicked at src/main.rs:1:1:\noops\n\nBacktrace:\n".to_owned(),
+        )];
+        assert_eq!(
+            section(&reports, crate::i18n::EN.crashed),
+            "A previous invocation of forward-as-attachment-mta on this host crashed, see the report (/var/lib/forward-as-attachment-mta/crashes/1700000000-42.txt):\n\n    panicked at src/main.rs:1:1:\n    oops\n\n    Backtrace:\n\n"
+        );
+    }
+}
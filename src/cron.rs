@@ -0,0 +1,71 @@
+//! Context that Vixie cron puts into the mails it sends.
+
+use std::fmt::Write;
+use std::sync::OnceLock;
+
+use mailparse::{MailHeaderMap, ParsedMail};
+use regex::Regex;
+
+use crate::i18n::Catalog;
+
+/// `X-Cron-Env: <SHELL=/bin/sh>`
+fn parse_env(value: &str) -> Option<(&str, &str)> {
+    let value = value.trim();
+    let inner = value.strip_prefix('<')?.strip_suffix('>')?;
+    inner.split_once('=')
+}
+
+/// `Subject: Cron <user@host> command`
+fn parse_subject(subject: &str) -> Option<(&str, &str)> {
+    static RE: OnceLock<Regex> = OnceLock::new();
+    let re = RE.get_or_init(|| Regex::new(r"^Cron <([^>]*)> (.*)$").unwrap());
+    let caps = re.captures(subject.trim())?;
+    Some((caps.get(1).unwrap().as_str(), caps.get(2).unwrap().as_str()))
+}
+
+/// A "Cron context" section, followed by a blank line.
+/// Empty if the original doesn't look like it was sent by cron.
+pub fn context(parsed: &ParsedMail, catalog: &Catalog) -> String {
+    let headers = parsed.get_headers();
+    let env = headers.get_all_values("X-Cron-Env");
+    let subject = headers.get_first_value("Subject");
+    let crontab = subject.as_deref().and_then(parse_subject);
+    if env.is_empty() && crontab.is_none() {
+        return String::new();
+    }
+    let mut section = format!("{}\n", catalog.cron_context);
+    if let Some((owner, command)) = crontab {
+        writeln!(&mut section, "    crontab of: {owner}").unwrap();
+        writeln!(&mut section, "    command:    {command}").unwrap();
+    }
+    for value in &env {
+        match parse_env(value) {
+            Some((key, value)) => writeln!(&mut section, "    {key}={value}").unwrap(),
+            None => writeln!(&mut section, "    {}", value.trim()).unwrap(),
+        }
+    }
+    section.push('\n');
+    section
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_context() {
+        let raw = "From: root (Cron Daemon)\r\n\
+            Subject: Cron <root@host1> /usr/local/bin/backup --all\r\n\
+            X-Cron-Env: <SHELL=/bin/sh>\r\n\
+            X-Cron-Env: <LOGNAME=root>\r\n\
+            \r\n\
+            output\r\n";
+        let parsed = mailparse::parse_mail(raw.as_bytes()).unwrap();
+        assert_eq!(
+            context(&parsed, &crate::i18n::EN),
+            "Cron context:\n    crontab of: root@host1\n    command:    /usr/local/bin/backup --all\n    SHELL=/bin/sh\n    LOGNAME=root\n\n"
+        );
+        let parsed = mailparse::parse_mail(b"Subject: hi\r\n\r\nbody").unwrap();
+        assert_eq!(context(&parsed, &crate::i18n::EN), "");
+    }
+}
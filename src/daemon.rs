@@ -0,0 +1,726 @@
+//! The submission daemon, with `daemon_socket`: `forward-as-attachment-mta --daemon`, run as
+//! root, accepts submissions on that unix socket, or on the sockets systemd passes to it, and
+//! keeps the connection to the relay open between them.
+//!
+//! `sendmail` hands the arguments and stdin off to the daemon and exits as soon as the daemon has
+//! stored the submission in `state_dir/daemon`. The daemon then forwards each entry by running us
+//! again, like `sendmail -q`, with a socket through which we use the daemon's relay connection
+//! instead of connecting ourselves. Entries that fail to forward are moved to the spool.
+//!
+//! On the socket, everything is sent in frames: the length as a big-endian `u32`, then the data.
+
+use std::io::{self, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::os::fd::{AsFd, AsRawFd, FromRawFd, OwnedFd};
+use std::os::unix::fs::PermissionsExt;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::os::unix::process::CommandExt;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::sync::mpsc;
+use std::sync::{Condvar, Mutex, OnceLock};
+use std::time::{Duration, SystemTime};
+
+use lettre::address::Envelope;
+use tracing::{debug, info, warn};
+
+use crate::config::Config;
+use crate::{hardening, smtp, spool, sysexits, systemd};
+
+/// Set for the first attempt at forwarding an entry, to the file descriptor of the socket to the
+/// daemon's relay connection.
+const RELAY_FD_ENV: &str = "FORWARD_AS_ATTACHMENT_MTA_RELAY_FD";
+
+/// The `reason` of the entries, see `spool::Meta`.
+const REASON: &str = "handed off to the daemon";
+
+/// For the frames of the arguments and the original, which come from any local user.
+const MAX_FRAME: usize = 1024 * 1024;
+
+const CHUNK: usize = 64 * 1024;
+
+/// The submitter of submissions on inet sockets, whom we can't tell: nobody.
+pub const INET_UID: u32 = 65534;
+
+/// Clients that write stdin more slowly than this between frames are dropped, so that they
+/// can't hold on to a connection forever.
+const TIMEOUT: Duration = Duration::from_secs(5 * 60);
+
+/// Clients served at a time; more wait in the listen backlog.
+const MAX_CLIENTS: usize = 64;
+
+fn write_frame(w: &mut impl Write, data: &[u8]) -> io::Result<()> {
+    let len = u32::try_from(data.len()).map_err(io::Error::other)?;
+    w.write_all(&len.to_be_bytes())?;
+    w.write_all(data)
+}
+
+fn read_frame(r: &mut impl Read, max: usize) -> io::Result<Vec<u8>> {
+    let mut len = [0; 4];
+    r.read_exact(&mut len)?;
+    let len = u32::from_be_bytes(len) as usize;
+    if len > max {
+        return Err(io::Error::other(format!("frame of {len} bytes")));
+    }
+    let mut data = vec![0; len];
+    r.read_exact(&mut data)?;
+    Ok(data)
+}
+
+/// The original, sent in frames of up to [`CHUNK`] bytes, ending with an empty one, so that a
+/// client that goes away before the end isn't mistaken for a complete submission.
+struct Chunks<R> {
+    inner: R,
+    chunk: Vec<u8>,
+    pos: usize,
+    done: bool,
+}
+
+impl<R: Read> Read for Chunks<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.pos == self.chunk.len() {
+            if self.done {
+                return Ok(0);
+            }
+            self.chunk = read_frame(&mut self.inner, CHUNK)?;
+            self.pos = 0;
+            self.done = self.chunk.is_empty();
+        }
+        let n = buf.len().min(self.chunk.len() - self.pos);
+        buf[..n].copy_from_slice(&self.chunk[self.pos..self.pos + n]);
+        self.pos += n;
+        Ok(n)
+    }
+}
+
+/// Where the daemon keeps the entries until they are forwarded.
+pub fn dir(state_dir: &Path) -> io::Result<PathBuf> {
+    crate::state::subdir_in(state_dir, "daemon")
+}
+
+/// Hand stdin off to the daemon at `socket`, a path or, for an inet socket, an address like
+/// `127.0.0.1:2526`. Returns the exit code, or `None` if the daemon isn't running, so that we
+/// forward the submission ourselves.
+pub fn hand_off(config: &Config, socket: &Path) -> Option<i32> {
+    let inet = socket.to_str().and_then(|s| s.parse::<SocketAddr>().ok());
+    let connected = match inet {
+        Some(addr) => TcpStream::connect(addr).map(Stream::Tcp),
+        None => UnixStream::connect(socket).map(Stream::Unix),
+    };
+    let mut stream = match connected {
+        Ok(stream) => stream,
+        Err(e) => {
+            warn!(%e, ?socket, "can't reach the daemon, forwarding the submission ourselves");
+            return None;
+        }
+    };
+    match submit(config, &mut stream) {
+        Ok(queue_id) => {
+            debug!(queue_id, "handed the submission off to the daemon");
+            Some(0)
+        }
+        Err(e) => {
+            eprintln!("forward-as-attachment-mta: failed to hand off to the daemon: {e}");
+            Some(sysexits::EX_TEMPFAIL)
+        }
+    }
+}
+
+/// A connection to or from the daemon, or the `smtpd` listener.
+pub enum Stream {
+    Unix(UnixStream),
+    Tcp(TcpStream),
+}
+
+impl Read for Stream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Stream::Unix(s) => s.read(buf),
+            Stream::Tcp(s) => s.read(buf),
+        }
+    }
+}
+
+impl Write for Stream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Stream::Unix(s) => s.write(buf),
+            Stream::Tcp(s) => s.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Stream::Unix(s) => s.flush(),
+            Stream::Tcp(s) => s.flush(),
+        }
+    }
+}
+
+impl Stream {
+    pub fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        match self {
+            Stream::Unix(s) => s.set_read_timeout(timeout),
+            Stream::Tcp(s) => s.set_read_timeout(timeout),
+        }
+    }
+}
+
+/// Returns the queue id the daemon assigned.
+fn submit(config: &Config, stream: &mut Stream) -> io::Result<String> {
+    // The daemon only believes this if we run as root, see `receive`.
+    let uid = crate::smtpd::submitter_from_env().unwrap_or_else(users::get_current_uid);
+    write_frame(stream, uid.to_string().as_bytes())?;
+    let args = crate::args::Args::from_env();
+    write_frame(stream, args.as_slice().join("\0").as_bytes())?;
+    let secs = |secs| (secs > 0).then(|| Duration::from_secs(secs));
+    // Unbuffered, see `original::Deadline`.
+    let mut stdin = crate::original::Deadline::new(
+        std::fs::File::from(io::stdin().as_fd().try_clone_to_owned()?),
+        secs(config.stdin_idle_timeout_secs),
+        secs(config.stdin_deadline_secs),
+        None,
+    );
+    let mut chunk = vec![0; CHUNK];
+    loop {
+        let n = stdin.read(&mut chunk)?;
+        write_frame(stream, &chunk[..n])?;
+        if n == 0 {
+            break;
+        }
+    }
+    if let Some(timed_out) = stdin.timed_out() {
+        warn!(
+            ?timed_out,
+            "stopped reading stdin, handing off what was read so far"
+        );
+    }
+    let reply = String::from_utf8_lossy(&read_frame(stream, MAX_FRAME)?).into_owned();
+    match reply.split_once(' ') {
+        Some(("ok", queue_id)) => Ok(queue_id.to_owned()),
+        Some(("error", e)) => Err(io::Error::other(e.to_owned())),
+        _ => Err(io::Error::other(format!("unexpected reply {reply:?}"))),
+    }
+}
+
+/// `forward-as-attachment-mta --daemon`, for root only. Returns the exit code, if it returns.
+pub fn serve(config: &Config, caller_uid: u32) -> i32 {
+    if let Err(code) = hardening::require_root(caller_uid, "--daemon") {
+        return code;
+    }
+    let activated = systemd::listen_fds();
+    let listeners = match (activated.is_empty(), &config.daemon_socket) {
+        (false, _) => activated.into_iter().map(Listener::activated).collect(),
+        (true, Some(socket)) => bind(socket, client_mode()).map(|listener| vec![listener]),
+        (true, None) => {
+            eprintln!("forward-as-attachment-mta: --daemon: daemon_socket isn't set, and systemd passed no sockets");
+            return sysexits::EX_USAGE;
+        }
+    };
+    match listeners.and_then(|listeners| listen(config, listeners)) {
+        Ok(()) => 0,
+        Err(e) => {
+            eprintln!("forward-as-attachment-mta: --daemon: {e}");
+            sysexits::EX_TEMPFAIL
+        }
+    }
+}
+
+pub enum Listener {
+    Unix(UnixListener),
+    Tcp(TcpListener),
+}
+
+impl Listener {
+    /// A socket from systemd, depending on its address family.
+    pub fn activated(fd: OwnedFd) -> io::Result<Listener> {
+        let mut domain: libc::c_int = 0;
+        let mut len = std::mem::size_of::<libc::c_int>() as libc::socklen_t;
+        // SAFETY: `domain` and `len` are valid for writes of the sizes given
+        let ret = unsafe {
+            libc::getsockopt(
+                fd.as_raw_fd(),
+                libc::SOL_SOCKET,
+                libc::SO_DOMAIN,
+                (&mut domain as *mut libc::c_int).cast(),
+                &mut len,
+            )
+        };
+        if ret != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        match domain {
+            libc::AF_UNIX => Ok(Listener::Unix(fd.into())),
+            libc::AF_INET | libc::AF_INET6 => Ok(Listener::Tcp(fd.into())),
+            domain => Err(io::Error::other(format!(
+                "systemd passed a socket of address family {domain}"
+            ))),
+        }
+    }
+
+    /// The next client, with its uid if it can be told.
+    pub fn accept(&self) -> io::Result<(Stream, Option<u32>)> {
+        match self {
+            Listener::Unix(listener) => {
+                let (stream, _) = listener.accept()?;
+                let uid = peer_uid(&stream)?;
+                Ok((Stream::Unix(stream), Some(uid)))
+            }
+            Listener::Tcp(listener) => {
+                let (stream, addr) = listener.accept()?;
+                // Anyone who can reach it could submit, and pose as a local process.
+                if !addr.ip().is_loopback() {
+                    return Err(io::Error::other(format!(
+                        "refusing {addr}, not on loopback"
+                    )));
+                }
+                Ok((Stream::Tcp(stream), None))
+            }
+        }
+    }
+}
+
+/// The mode of the daemon's unix socket. With a setuid installation, the clients connect as
+/// root, so no one else needs to; otherwise any local user does, whose uid we take from the
+/// socket, see `receive`.
+fn client_mode() -> u32 {
+    let setuid = std::env::current_exe()
+        .and_then(std::fs::metadata)
+        .is_ok_and(|metadata| metadata.permissions().mode() & 0o4000 != 0);
+    if setuid {
+        0o600
+    } else {
+        0o666
+    }
+}
+
+/// Bind `socket`, an address like `127.0.0.1:2526` or a path, which gets `mode`.
+pub fn bind(socket: &Path, mode: u32) -> io::Result<Listener> {
+    if let Some(addr) = socket.to_str().and_then(|s| s.parse::<SocketAddr>().ok()) {
+        return Ok(Listener::Tcp(TcpListener::bind(addr)?));
+    }
+    // Left by a previous run.
+    match std::fs::remove_file(socket) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+        _ => (),
+    }
+    let listener = UnixListener::bind(socket)?;
+    std::fs::set_permissions(socket, std::fs::Permissions::from_mode(mode))?;
+    Ok(Listener::Unix(listener))
+}
+
+fn listen(config: &Config, listeners: Vec<Listener>) -> io::Result<()> {
+    let dir = dir(&config.state_dir)?;
+    let (queue, pending) = mpsc::channel();
+    for meta in spool::list(&dir)? {
+        queue.send(meta.queue_id).unwrap();
+    }
+    let clients = Clients::default();
+    std::thread::scope(|scope| {
+        scope.spawn(|| work(config, &dir, pending));
+        for listener in &listeners {
+            let (dir, queue, clients) = (&dir, queue.clone(), &clients);
+            scope.spawn(move || loop {
+                clients.wait_for_slot();
+                let (mut stream, peer) = match listener.accept() {
+                    Ok(accepted) => accepted,
+                    Err(e) => {
+                        clients.done();
+                        warn!(%e, "accept");
+                        continue;
+                    }
+                };
+                if let Err(e) = stream.set_read_timeout(Some(TIMEOUT)) {
+                    clients.done();
+                    warn!(%e, "set the client's read timeout");
+                    continue;
+                }
+                let queue = queue.clone();
+                // Clients may take their time writing stdin.
+                scope.spawn(move || {
+                    let reply = match receive(dir, &mut stream, peer) {
+                        Ok(queue_id) => {
+                            debug!(queue_id, "accepted a submission");
+                            queue.send(queue_id.clone()).unwrap();
+                            format!("ok {queue_id}")
+                        }
+                        Err(e) => {
+                            warn!(%e, "failed to accept a submission");
+                            format!("error {e}")
+                        }
+                    };
+                    if let Err(e) = write_frame(&mut stream, reply.as_bytes()) {
+                        debug!(%e, "client went away");
+                    }
+                    clients.done();
+                });
+            });
+        }
+        info!(listeners = listeners.len(), "accepting submissions");
+        systemd::notify("READY=1");
+    });
+    Ok(())
+}
+
+/// The number of clients being served, at most [`MAX_CLIENTS`], also by the `smtpd` listener.
+#[derive(Default)]
+pub struct Clients {
+    count: Mutex<usize>,
+    changed: Condvar,
+}
+
+impl Clients {
+    pub fn wait_for_slot(&self) {
+        let count = self.count.lock().unwrap();
+        let mut count = self
+            .changed
+            .wait_while(count, |count| *count >= MAX_CLIENTS)
+            .unwrap();
+        *count += 1;
+    }
+
+    pub fn done(&self) {
+        *self.count.lock().unwrap() -= 1;
+        self.changed.notify_one();
+    }
+}
+
+fn peer_uid(stream: &UnixStream) -> io::Result<u32> {
+    let mut cred = libc::ucred {
+        pid: 0,
+        uid: 0,
+        gid: 0,
+    };
+    let mut len = std::mem::size_of::<libc::ucred>() as libc::socklen_t;
+    // SAFETY: `cred` and `len` are valid for writes of the sizes given
+    let ret = unsafe {
+        libc::getsockopt(
+            stream.as_raw_fd(),
+            libc::SOL_SOCKET,
+            libc::SO_PEERCRED,
+            (&mut cred as *mut libc::ucred).cast(),
+            &mut len,
+        )
+    };
+    if ret != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(cred.uid)
+}
+
+/// Store a submission from a client, whose uid is `peer` if known. Returns its queue id.
+fn receive(dir: &Path, stream: &mut Stream, peer: Option<u32>) -> io::Result<String> {
+    let claimed = String::from_utf8_lossy(&read_frame(stream, MAX_FRAME)?).into_owned();
+    let uid = submitter(peer, &claimed)?;
+    let args = String::from_utf8_lossy(&read_frame(stream, MAX_FRAME)?)
+        .split('\0')
+        .map(str::to_owned)
+        .collect();
+    let meta = spool::Meta {
+        queue_id: queue_id(),
+        submitted_at: crate::time::unix_secs(SystemTime::now()),
+        uid,
+        username: users::get_user_by_uid(uid).map(|u| u.name().to_string_lossy().to_string()),
+        args,
+        reason: REASON.to_owned(),
+    };
+    let chunks = Chunks {
+        inner: stream,
+        chunk: Vec::new(),
+        pos: 0,
+        done: false,
+    };
+    spool::store_in(dir, &meta, chunks)?;
+    Ok(meta.queue_id)
+}
+
+/// The uid to submit as, from the `peer`'s uid (`None` for inet clients) and the uid the client
+/// `claimed`. Only root, i.e. a setuid installation of the client, may claim a uid, its caller's;
+/// the claims of other peers are ignored, they are who they are.
+fn submitter(peer: Option<u32>, claimed: &str) -> io::Result<u32> {
+    match peer {
+        Some(0) => claimed
+            .parse()
+            .map_err(|e| io::Error::other(format!("uid {claimed:?}: {e}"))),
+        Some(peer) => Ok(peer),
+        None => Ok(INET_UID),
+    }
+}
+
+/// Like `new_queue_id`, unique although submissions are received concurrently.
+pub fn queue_id() -> String {
+    static LAST: Mutex<String> = Mutex::new(String::new());
+    let mut last = LAST.lock().unwrap();
+    let mut queue_id = crate::new_queue_id();
+    while queue_id == *last {
+        queue_id = crate::new_queue_id();
+    }
+    last.clone_from(&queue_id);
+    queue_id
+}
+
+/// Forward the entries one at a time, on one relay connection.
+fn work(config: &Config, dir: &Path, pending: mpsc::Receiver<String>) {
+    let exe = std::env::current_exe().expect("own executable");
+    let mut watchdog = systemd::Watchdog::new();
+    let mut relay = None::<smtp::Relay>;
+    loop {
+        let Ok(queue_id) = systemd::recv(&pending, None, &mut watchdog) else {
+            return;
+        };
+        // The relay may have closed the connection while we were idle.
+        if !relay.as_mut().is_some_and(smtp::Relay::is_alive) {
+            relay = None;
+        }
+        let connected = match relay.take() {
+            Some(relay) => Ok(relay),
+            None => smtp::connect(config),
+        };
+        let mut connected = connected.inspect_err(|e| warn!(%e, "can't connect to the relay"));
+        match forward(dir, &exe, &queue_id, connected.as_mut()) {
+            Ok(true) => debug!(queue_id, "forwarded submission"),
+            Ok(false) => {
+                warn!(queue_id, "failed to forward submission, spooling it");
+                if let Err(e) = move_to_spool(config, dir, &queue_id) {
+                    warn!(%e, queue_id, "failed to spool the submission, keeping it");
+                }
+            }
+            Err(e) => warn!(%e, queue_id, "failed to forward submission, keeping it"),
+        }
+        relay = connected.ok();
+        if let Some(watchdog) = &mut watchdog {
+            watchdog.ping();
+        }
+    }
+}
+
+/// Forward one entry, removing it if that succeeded.
+fn forward(
+    dir: &Path,
+    exe: &Path,
+    queue_id: &str,
+    relay: Result<&mut smtp::Relay, &mut smtp::Error>,
+) -> io::Result<bool> {
+    let (mut ours, theirs) = UnixStream::pair()?;
+    let fd = theirs.as_raw_fd();
+    let mut command = Command::new(exe);
+    command
+        .env(spool::FROM_SPOOL_ENV, queue_id)
+        .env(RELAY_FD_ENV, fd.to_string())
+        .env_remove("LISTEN_PID")
+        .env_remove("LISTEN_FDS")
+        .env_remove("LISTEN_FDNAMES")
+        .stdin(Stdio::from(std::fs::File::open(
+            dir.join(format!("{queue_id}.eml")),
+        )?));
+    // SAFETY: `fcntl` is async-signal-safe
+    unsafe {
+        command.pre_exec(move || match libc::fcntl(fd, libc::F_SETFD, 0) {
+            -1 => Err(io::Error::last_os_error()),
+            _ => Ok(()),
+        });
+    }
+    let mut child = command.spawn()?;
+    drop(theirs);
+    if let Err(e) = relay_for(&mut ours, relay) {
+        warn!(%e, queue_id, "relaying for the forwarding process");
+    }
+    drop(ours);
+    if !child.wait()?.success() {
+        return Ok(false);
+    }
+    std::fs::remove_file(dir.join(format!("{queue_id}.toml")))?;
+    std::fs::remove_file(dir.join(format!("{queue_id}.eml")))?;
+    Ok(true)
+}
+
+/// For `sendmail -q` to retry.
+fn move_to_spool(config: &Config, dir: &Path, queue_id: &str) -> io::Result<()> {
+    let spool = crate::state::subdir(config, "spool")?;
+    // The metadata goes last, like in `spool::store_in`.
+    for ext in ["eml", "toml"] {
+        let name = format!("{queue_id}.{ext}");
+        std::fs::rename(dir.join(&name), spool.join(&name))?;
+    }
+    Ok(())
+}
+
+fn error_frame(e: &smtp::Error) -> String {
+    format!("error {} {e}", e.class())
+}
+
+/// Do the transactions of the forwarding process on `relay` until it exits. First, it's told the
+/// relay's extensions, or why there is no connection.
+fn relay_for(
+    stream: &mut UnixStream,
+    mut relay: Result<&mut smtp::Relay, &mut smtp::Error>,
+) -> io::Result<()> {
+    let hello = match &relay {
+        Ok(relay) => format!("ok\n{}", relay.extensions().join("\n")),
+        Err(e) => error_frame(e),
+    };
+    write_frame(stream, hello.as_bytes())?;
+    loop {
+        let from = match read_frame(stream, usize::MAX) {
+            Ok(from) => String::from_utf8_lossy(&from).into_owned(),
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(()),
+            Err(e) => return Err(e),
+        };
+        let to = String::from_utf8_lossy(&read_frame(stream, usize::MAX)?).into_owned();
+        let message = read_frame(stream, usize::MAX)?;
+        let envelope =
+            envelope(&from, &to).map_err(|e| io::Error::other(format!("envelope: {e}")))?;
+        let reply = match &mut relay {
+            Ok(relay) => match relay.send(&envelope, &message) {
+                Ok(()) => "ok".to_owned(),
+                Err(e) => error_frame(&e),
+            },
+            Err(e) => error_frame(e),
+        };
+        write_frame(stream, reply.as_bytes())?;
+    }
+}
+
+fn envelope(from: &str, to: &str) -> Result<Envelope, String> {
+    let from = match from {
+        "" => None,
+        from => Some(from.parse().map_err(|e| format!("{from:?}: {e}"))?),
+    };
+    let to = to
+        .lines()
+        .map(|to| to.parse().map_err(|e| format!("{to:?}: {e}")))
+        .collect::<Result<_, _>>()?;
+    Envelope::new(from, to).map_err(|e| e.to_string())
+}
+
+fn parse_error(frame: &[u8]) -> smtp::Error {
+    let frame = String::from_utf8_lossy(frame);
+    let (class, message) = frame
+        .strip_prefix("error ")
+        .and_then(|e| e.split_once(' '))
+        .unwrap_or(("connection", &frame));
+    smtp::Error::Daemon {
+        class: smtp::CLASSES
+            .iter()
+            .find(|c| **c == class)
+            .unwrap_or(&"connection"),
+        message: message.to_owned(),
+    }
+}
+
+fn io_error(e: io::Error) -> smtp::Error {
+    smtp::Error::Daemon {
+        class: "connection",
+        message: format!("the daemon's relay connection: {e}"),
+    }
+}
+
+/// Whether we are forwarding a submission handed off to the daemon, for the first time.
+pub fn handed_off() -> bool {
+    std::env::var_os(RELAY_FD_ENV).is_some()
+}
+
+/// The daemon's relay connection, if we are forwarding for it, see `smtp::connect`.
+pub fn relay(config: &Config) -> Option<Result<smtp::Relay, smtp::Error>> {
+    type Hello = Result<(UnixStream, Vec<String>), Vec<u8>>;
+    static HELLO: OnceLock<Option<Hello>> = OnceLock::new();
+    let hello = HELLO.get_or_init(|| {
+        let fd = std::env::var(RELAY_FD_ENV).ok()?.parse().ok()?;
+        // Only the daemon sets it, see `spool::from_env`.
+        if users::get_current_uid() != users::get_effective_uid() {
+            return None;
+        }
+        // SAFETY: the daemon passes the socket as this file descriptor, and it's only taken here
+        let mut stream = unsafe { UnixStream::from_raw_fd(fd) };
+        let hello = read_frame(&mut stream, usize::MAX).unwrap_or_else(|e| {
+            format!("error connection the daemon's relay connection: {e}").into_bytes()
+        });
+        Some(match hello.strip_prefix(b"ok") {
+            Some(extensions) => {
+                let extensions = String::from_utf8_lossy(extensions);
+                Ok((
+                    stream,
+                    extensions.lines().skip(1).map(str::to_owned).collect(),
+                ))
+            }
+            None => Err(hello),
+        })
+    });
+    Some(match hello.as_ref()? {
+        Ok((stream, extensions)) => stream
+            .try_clone()
+            .map(|stream| smtp::Relay::through_daemon(stream, extensions.clone(), config))
+            .map_err(io_error),
+        Err(hello) => Err(parse_error(hello)),
+    })
+}
+
+/// A transaction on the daemon's relay connection.
+pub fn send(
+    stream: &mut UnixStream,
+    envelope: &Envelope,
+    message: &[u8],
+) -> Result<(), smtp::Error> {
+    let from = envelope.from().map(|a| a.to_string()).unwrap_or_default();
+    let to = envelope
+        .to()
+        .iter()
+        .map(|a| a.to_string())
+        .collect::<Vec<_>>()
+        .join("\n");
+    write_frame(stream, from.as_bytes()).map_err(io_error)?;
+    write_frame(stream, to.as_bytes()).map_err(io_error)?;
+    write_frame(stream, message).map_err(io_error)?;
+    let reply = read_frame(stream, usize::MAX).map_err(io_error)?;
+    match reply.as_slice() {
+        b"ok" => Ok(()),
+        reply => Err(parse_error(reply)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_submitter() {
+        // A non-root peer can't claim to be someone else, root in particular.
+        assert_eq!(submitter(Some(1000), "0").unwrap(), 1000);
+        assert_eq!(submitter(Some(1000), "garbage").unwrap(), 1000);
+        // A setuid client submits as its caller.
+        assert_eq!(submitter(Some(0), "1000").unwrap(), 1000);
+        assert_eq!(submitter(Some(0), "0").unwrap(), 0);
+        assert!(submitter(Some(0), "root").is_err());
+        assert_eq!(submitter(None, "0").unwrap(), INET_UID);
+    }
+
+    #[test]
+    fn test_chunks() {
+        let mut sent = Vec::new();
+        for chunk in [&b"Subject: hi\r\n"[..], b"\r\nbody\r\n", b""] {
+            write_frame(&mut sent, chunk).unwrap();
+        }
+        let mut received = Vec::new();
+        let mut chunks = Chunks {
+            inner: sent.as_slice(),
+            chunk: Vec::new(),
+            pos: 0,
+            done: false,
+        };
+        chunks.read_to_end(&mut received).unwrap();
+        assert_eq!(received, b"Subject: hi\r\n\r\nbody\r\n");
+        // Without the empty frame at the end, the submission is incomplete.
+        let truncated = &sent[..sent.len() - 4];
+        let mut chunks = Chunks {
+            inner: truncated,
+            chunk: Vec::new(),
+            pos: 0,
+            done: false,
+        };
+        assert!(chunks.read_to_end(&mut Vec::new()).is_err());
+        assert_eq!(
+            parse_error(b"error deferred 451 try again later").class(),
+            "deferred"
+        );
+    }
+}
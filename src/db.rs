@@ -0,0 +1,433 @@
+//! The state database in `state_dir/db`: the submissions and the delivery history, next to the per-feature state
+//! in `state_dir` (dedup, rate limit, escalation), with a schema version and migrations, and the
+//! maintenance subcommands `sendmail --db dump` and `sendmail --db vacuum`, for root only.
+//!
+//! It is plain files rather than SQLite or sled, which would be new dependencies of a setuid
+//! binary, like the rest of the state: the history is append-only, one tab-separated line per
+//! delivery, and an exclusive `flock(2)` is held while the database is open. So dedup, the rate
+//! limit, and escalation keep their own files, one per key, and the database only covers them
+//! for `dump` and `vacuum`, which drops the entries untouched for `history_days`. Threading
+//! needs no state, its anchors are derived from the job, see `headers::thread_anchor`. `vacuum`
+//! also drops the full originals of truncated submissions after `history_days`.
+
+use std::fs::File;
+use std::io::{self, Write as _};
+use std::os::fd::AsRawFd;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+use tracing::{debug, warn};
+
+use crate::config::Config;
+use crate::json::Value;
+use crate::{hardening, sysexits};
+
+/// The state of other features that `db dump` and `db vacuum` cover, by subdirectory.
+const TABLES: &[&str] = &["dedup", "ratelimit", "escalation"];
+
+/// Other state that `db vacuum` drops after `history_days`, but `db dump` doesn't print.
+const KEPT_FILES: &[&str] = &["originals"];
+
+/// Each migration brings the database from its index to the next version.
+const MIGRATIONS: &[fn(&Path) -> io::Result<()>] = &[
+    // 1: the delivery history.
+    |db| create_new(&db.join("history")),
+    // 2: the submissions, for `sendmail --stats`.
+    |db| create_new(&db.join("submissions")),
+];
+
+fn create_new(path: &Path) -> io::Result<()> {
+    match File::options().write(true).create_new(true).open(path) {
+        Err(e) if e.kind() != io::ErrorKind::AlreadyExists => Err(e),
+        _ => Ok(()),
+    }
+}
+
+/// One delivery attempt.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Delivery {
+    /// Unix time.
+    pub at: u64,
+    pub queue_id: String,
+    /// `None` if delivered, else the class, see `smtp::Error::class`.
+    pub failure: Option<String>,
+    pub uid: u32,
+    pub size: u64,
+    pub sender: String,
+}
+
+impl Delivery {
+    fn parse(line: &str) -> Option<Delivery> {
+        let mut fields = line.splitn(6, '\t');
+        Some(Delivery {
+            at: fields.next()?.parse().ok()?,
+            queue_id: fields.next()?.to_owned(),
+            failure: match fields.next()? {
+                "-" => None,
+                class => Some(class.to_owned()),
+            },
+            uid: fields.next()?.parse().ok()?,
+            size: fields.next()?.parse().ok()?,
+            sender: fields.next()?.to_owned(),
+        })
+    }
+
+    fn line(&self) -> String {
+        format!(
+            "{}\t{}\t{}\t{}\t{}\t{}\n",
+            self.at,
+            self.queue_id,
+            self.failure.as_deref().unwrap_or("-"),
+            self.uid,
+            self.size,
+            self.sender.replace(['\t', '\r', '\n'], " "),
+        )
+    }
+
+    fn json(&self) -> Value {
+        Value::object([
+            ("table", Value::from("history")),
+            ("at", self.at.into()),
+            ("queue_id", self.queue_id.as_str().into()),
+            ("failure", self.failure.clone().into()),
+            ("uid", self.uid.into()),
+            ("size", self.size.into()),
+            ("sender", self.sender.as_str().into()),
+        ])
+    }
+}
+
+/// A submission, not counting the forwarding of spooled ones.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Submission {
+    /// Unix time.
+    pub at: u64,
+    pub queue_id: String,
+    pub uid: u32,
+}
+
+impl Submission {
+    fn parse(line: &str) -> Option<Submission> {
+        let mut fields = line.splitn(3, '\t');
+        Some(Submission {
+            at: fields.next()?.parse().ok()?,
+            queue_id: fields.next()?.to_owned(),
+            uid: fields.next()?.parse().ok()?,
+        })
+    }
+
+    fn line(&self) -> String {
+        format!("{}\t{}\t{}\n", self.at, self.queue_id, self.uid)
+    }
+
+    fn json(&self) -> Value {
+        Value::object([
+            ("table", Value::from("submissions")),
+            ("at", self.at.into()),
+            ("queue_id", self.queue_id.as_str().into()),
+            ("uid", self.uid.into()),
+        ])
+    }
+}
+
+/// Holds the lock of the database until dropped.
+pub struct Db {
+    dir: PathBuf,
+    _lock: File,
+}
+
+/// Open the database in `state_dir`, migrating it to the current schema.
+pub fn open(state_dir: &Path) -> io::Result<Db> {
+    let dir = crate::state::subdir_in(state_dir, "db")?;
+    let lock = File::options()
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .mode(0o600)
+        .open(dir.join("lock"))?;
+    // SAFETY: the file descriptor is open
+    if unsafe { libc::flock(lock.as_raw_fd(), libc::LOCK_EX) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let version_path = dir.join("version");
+    let version = match std::fs::read_to_string(&version_path) {
+        Ok(s) => s
+            .trim()
+            .parse::<usize>()
+            .map_err(|e| io::Error::other(format!("{version_path:?}: {e}")))?,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => 0,
+        Err(e) => return Err(e),
+    };
+    if version > MIGRATIONS.len() {
+        return Err(io::Error::other(format!(
+            "schema version {version} is newer than ours, {}",
+            MIGRATIONS.len()
+        )));
+    }
+    for (from, migrate) in MIGRATIONS.iter().enumerate().skip(version) {
+        debug!(from, "migrating the state database");
+        migrate(&dir)?;
+        std::fs::write(&version_path, format!("{}\n", from + 1))?;
+    }
+    Ok(Db { dir, _lock: lock })
+}
+
+impl Db {
+    fn append(&self, table: &str, line: &str) -> io::Result<()> {
+        File::options()
+            .append(true)
+            .open(self.dir.join(table))?
+            .write_all(line.as_bytes())
+    }
+
+    /// The lines of `table`, skipping malformed ones.
+    fn read<T>(&self, table: &str, parse: fn(&str) -> Option<T>) -> io::Result<Vec<T>> {
+        Ok(std::fs::read_to_string(self.dir.join(table))?
+            .lines()
+            .filter_map(parse)
+            .collect())
+    }
+
+    fn replace(&self, table: &str, contents: String) -> io::Result<()> {
+        let tmp = self.dir.join(format!("{table}.tmp"));
+        std::fs::write(&tmp, contents)?;
+        std::fs::rename(&tmp, self.dir.join(table))
+    }
+
+    pub fn record(&self, delivery: &Delivery) -> io::Result<()> {
+        self.append("history", &delivery.line())
+    }
+
+    pub fn record_submission(&self, submission: &Submission) -> io::Result<()> {
+        self.append("submissions", &submission.line())
+    }
+
+    /// The deliveries since `since`, oldest first.
+    pub fn history(&self, since: SystemTime) -> io::Result<Vec<Delivery>> {
+        let since = crate::time::unix_secs(since);
+        let mut history = self.read("history", Delivery::parse)?;
+        history.retain(|delivery| delivery.at >= since);
+        Ok(history)
+    }
+
+    /// The submissions since `since`, oldest first.
+    pub fn submissions(&self, since: SystemTime) -> io::Result<Vec<Submission>> {
+        let since = crate::time::unix_secs(since);
+        let mut submissions = self.read("submissions", Submission::parse)?;
+        submissions.retain(|submission| submission.at >= since);
+        Ok(submissions)
+    }
+}
+
+/// Record a delivery attempt in the history. Failures are logged.
+pub fn record(config: &Config, delivery: &Delivery) {
+    if let Err(e) = open(&config.state_dir).and_then(|db| db.record(delivery)) {
+        warn!(%e, "can't record the delivery in the state database");
+    }
+}
+
+/// Record a submission. Failures are logged.
+pub fn record_submission(config: &Config, submission: &Submission) {
+    if let Err(e) = open(&config.state_dir).and_then(|db| db.record_submission(submission)) {
+        warn!(%e, "can't record the submission in the state database");
+    }
+}
+
+/// `sendmail --db dump|vacuum`, for root only, since the history has every user's submissions.
+/// Returns the exit code.
+pub fn command(config: &Config, caller_uid: u32, command: Option<&str>) -> i32 {
+    if let Err(code) = hardening::require_root(caller_uid, "--db") {
+        return code;
+    }
+    let result = match command {
+        Some("dump") => dump(config, &mut io::stdout().lock()),
+        Some("vacuum") => vacuum(config),
+        _ => {
+            eprintln!("usage: sendmail --db dump|vacuum");
+            return sysexits::EX_USAGE;
+        }
+    };
+    match result {
+        Ok(()) => 0,
+        Err(e) => {
+            eprintln!("forward-as-attachment-mta: --db: {e}");
+            sysexits::EX_TEMPFAIL
+        }
+    }
+}
+
+/// The time `days` ago, if it's representable.
+pub fn days_ago(days: u64) -> Option<SystemTime> {
+    let age = Duration::from_secs(days.checked_mul(24 * 60 * 60)?);
+    SystemTime::now().checked_sub(age)
+}
+
+/// The entries of a table, skipping temporary files.
+fn entries(config: &Config, table: &str) -> io::Result<Vec<(String, PathBuf)>> {
+    let mut entries = Vec::new();
+    for entry in std::fs::read_dir(crate::state::subdir(config, table)?)? {
+        let path = entry?.path();
+        let Some(key) = path.file_name().and_then(|n| n.to_str()) else {
+            continue;
+        };
+        if path.extension().is_none() {
+            entries.push((key.to_owned(), path.clone()));
+        }
+    }
+    entries.sort();
+    Ok(entries)
+}
+
+/// Everything, as JSON lines: the schema version, the history, and the other tables.
+fn dump(config: &Config, out: &mut impl io::Write) -> io::Result<()> {
+    let db = open(&config.state_dir)?;
+    writeln!(
+        out,
+        "{}",
+        Value::object([("schema_version", Value::from(MIGRATIONS.len()))])
+    )?;
+    for submission in db.submissions(SystemTime::UNIX_EPOCH)? {
+        writeln!(out, "{}", submission.json())?;
+    }
+    for delivery in db.history(SystemTime::UNIX_EPOCH)? {
+        writeln!(out, "{}", delivery.json())?;
+    }
+    for table in TABLES {
+        for (key, path) in entries(config, table)? {
+            let value = std::fs::read_to_string(path)?;
+            let entry = Value::object([
+                ("table", Value::from(*table)),
+                ("key", key.into()),
+                ("value", value.trim_end().into()),
+            ]);
+            writeln!(out, "{entry}")?;
+        }
+    }
+    Ok(())
+}
+
+/// Drop the history and submissions older than `history_days`, if that's a time at all, the
+/// entries of the other tables and the kept files not written to since, and temporary files
+/// left by interrupted writes.
+fn vacuum(config: &Config) -> io::Result<()> {
+    let db = open(&config.state_dir)?;
+    let since = days_ago(config.history_days).unwrap_or(SystemTime::UNIX_EPOCH);
+    let kept = db.history(since)?;
+    db.replace("history", kept.iter().map(Delivery::line).collect())?;
+    let submissions = db.submissions(since)?;
+    db.replace(
+        "submissions",
+        submissions.iter().map(Submission::line).collect(),
+    )?;
+    let (mut removed, mut stale, mut originals) = (0, 0, 0);
+    for dir in KEPT_FILES {
+        for entry in std::fs::read_dir(crate::state::subdir(config, dir)?)? {
+            let entry = entry?;
+            if entry.metadata()?.modified()? < since {
+                std::fs::remove_file(entry.path())?;
+                originals += 1;
+            }
+        }
+    }
+    for table in TABLES {
+        for entry in std::fs::read_dir(crate::state::subdir(config, table)?)? {
+            let entry = entry?;
+            let path = entry.path();
+            if path.extension().is_some_and(|ext| ext == "tmp") {
+                std::fs::remove_file(&path)?;
+                removed += 1;
+            } else if entry.metadata()?.modified()? < since {
+                std::fs::remove_file(&path)?;
+                stale += 1;
+            }
+        }
+    }
+    println!(
+        "kept {} submissions and {} deliveries of the last {} days, removed {stale} older dedup, rate limit, and escalation entries, {originals} kept originals, and {removed} temporary files",
+        submissions.len(),
+        kept.len(),
+        config.history_days
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_history() {
+        let dir = crate::testdir::TestDir::new("db");
+        let delivery = Delivery {
+            at: 1_700_000_000,
+            queue_id: "65A0B1C2D3E4F".to_owned(),
+            failure: Some("deferred".to_owned()),
+            uid: 1000,
+            size: 3594,
+            sender: "root\t<root@host>".to_owned(),
+        };
+        {
+            let db = open(&dir).unwrap();
+            db.record(&delivery).unwrap();
+            db.record(&Delivery {
+                failure: None,
+                at: 1_700_000_060,
+                ..delivery.clone()
+            })
+            .unwrap();
+        }
+        // Migrations only run once.
+        let db = open(&dir).unwrap();
+        assert_eq!(
+            std::fs::read_to_string(dir.join("db/version")).unwrap(),
+            "2\n"
+        );
+        let history = db.history(SystemTime::UNIX_EPOCH).unwrap();
+        assert_eq!(history.len(), 2);
+        assert_eq!(history[0].sender, "root <root@host>");
+        assert_eq!(history[0].failure.as_deref(), Some("deferred"));
+        assert_eq!(history[1].failure, None);
+        let since = SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_030);
+        assert_eq!(db.history(since).unwrap().len(), 1);
+        // Too many days to subtract keep everything.
+        assert_eq!(days_ago(u64::MAX / 1000), None);
+        assert!(days_ago(1).unwrap() < SystemTime::now());
+        drop(db);
+    }
+
+    #[test]
+    fn test_vacuum() {
+        let dir = crate::testdir::TestDir::new("vacuum");
+        let config = toml::from_str::<Config>(&format!(
+            r#"
+            sender_email = "sender@example.com"
+            recipient_email = "admin@example.com"
+            smtp_host = "smtp.example.com"
+            smtp_username = "user"
+            smtp_password = "pass"
+            state_dir = {:?}
+            history_days = 1
+            "#,
+            &*dir
+        ))
+        .unwrap();
+        let ratelimit = crate::state::subdir(&config, "ratelimit").unwrap();
+        std::fs::write(ratelimit.join("1000"), "").unwrap();
+        let old = File::create(ratelimit.join("1001")).unwrap();
+        old.set_modified(days_ago(2).unwrap()).unwrap();
+        std::fs::write(ratelimit.join("1002.tmp"), "").unwrap();
+        let originals = crate::state::subdir(&config, "originals").unwrap();
+        let old = File::create(originals.join("6ACF000000000.eml")).unwrap();
+        old.set_modified(days_ago(2).unwrap()).unwrap();
+        std::fs::write(originals.join("6ACF000000001.eml"), "").unwrap();
+        vacuum(&config).unwrap();
+        assert_eq!(
+            entries(&config, "ratelimit").unwrap(),
+            [("1000".to_owned(), ratelimit.join("1000"))]
+        );
+        assert!(!ratelimit.join("1002.tmp").exists());
+        assert!(!originals.join("6ACF000000000.eml").exists());
+        assert!(originals.join("6ACF000000001.eml").exists());
+    }
+}
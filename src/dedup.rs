@@ -0,0 +1,225 @@
+//! Coalescing of identical submissions, e.g. a cron job that fails the same way every minute.
+//!
+//! The first submission is forwarded, identical ones within `dedup_window_secs` are only counted
+//! in `state_dir/dedup/<key>`. Once the window has passed, a follow-up says how often the message
+//! was repeated. An exclusive `flock(2)` on the directory makes concurrent submissions take
+//! turns, so that only one of them is forwarded.
+
+use std::fs::File;
+use std::os::fd::AsRawFd;
+use std::path::Path;
+use std::time::{Duration, SystemTime};
+
+use tracing::{debug, warn};
+
+use crate::config::Config;
+
+/// What makes submissions identical.
+pub struct Submission<'a> {
+    pub uid: u32,
+    pub sender: &'a str,
+    pub subject: &'a str,
+    /// The text of the original, without its headers, which differ between runs.
+    pub body: &'a str,
+}
+
+impl Submission<'_> {
+    fn key(&self) -> String {
+        let key = format!(
+            "{}\0{}\0{}\0{}",
+            self.uid, self.sender, self.subject, self.body
+        );
+        let digest = ring::digest::digest(&ring::digest::SHA256, key.as_bytes());
+        data_encoding::HEXLOWER.encode(&digest.as_ref()[..16])
+    }
+}
+
+/// The state of a forwarded submission.
+#[derive(Debug, PartialEq)]
+struct Entry {
+    /// Unix time.
+    forwarded_at: u64,
+    repeated: u64,
+    subject: String,
+}
+
+impl Entry {
+    fn parse(s: &str) -> Option<Entry> {
+        let mut fields = s.trim_end_matches('\n').splitn(3, '\t');
+        Some(Entry {
+            forwarded_at: fields.next()?.parse().ok()?,
+            repeated: fields.next()?.parse().ok()?,
+            subject: fields.next()?.to_owned(),
+        })
+    }
+
+    fn write(&self, path: &Path) -> std::io::Result<()> {
+        let tmp = path.with_extension("tmp");
+        let subject = self.subject.replace(['\t', '\r', '\n'], " ");
+        std::fs::write(
+            &tmp,
+            format!("{}\t{}\t{subject}\n", self.forwarded_at, self.repeated),
+        )?;
+        std::fs::rename(&tmp, path)
+    }
+}
+
+/// Lock the entries in `dir` until the returned file is dropped.
+fn lock(dir: &Path) -> std::io::Result<File> {
+    let lock = File::open(dir)?;
+    // SAFETY: the file descriptor is open
+    if unsafe { libc::flock(lock.as_raw_fd(), libc::LOCK_EX) } != 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(lock)
+}
+
+/// Whether `submission` repeats one forwarded within the window, in which case it is counted
+/// and shouldn't be forwarded. Otherwise, it is recorded as forwarded.
+pub fn is_repeat(config: &Config, submission: &Submission) -> std::io::Result<bool> {
+    let dir = crate::state::subdir(config, "dedup")?;
+    let _lock = lock(&dir)?;
+    let path = dir.join(submission.key());
+    let now = crate::time::unix_secs(SystemTime::now());
+    let entry = match std::fs::read_to_string(&path) {
+        Ok(s) => Entry::parse(&s),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => None,
+        Err(e) => return Err(e),
+    };
+    match entry {
+        Some(mut entry) if now.saturating_sub(entry.forwarded_at) < config.dedup_window_secs => {
+            entry.repeated += 1;
+            debug!(?entry, "repeated submission");
+            entry.write(&path)?;
+            Ok(true)
+        }
+        _ => {
+            // A follow-up for an expired entry was sent by `send_follow_ups` at startup.
+            let entry = Entry {
+                forwarded_at: now,
+                repeated: 0,
+                subject: submission.subject.to_owned(),
+            };
+            entry.write(&path)?;
+            Ok(false)
+        }
+    }
+}
+
+/// Send the follow-ups for the entries whose window has passed, and remove them.
+pub fn send_follow_ups(config: &Config, hostname: &str) -> std::io::Result<()> {
+    let dir = crate::state::subdir(config, "dedup")?;
+    let now = crate::time::unix_secs(SystemTime::now());
+    for dir_entry in std::fs::read_dir(&dir)? {
+        let path = dir_entry?.path();
+        if path.extension().is_some() {
+            continue; // a temporary file
+        }
+        let Some(entry) = std::fs::read_to_string(&path)
+            .ok()
+            .and_then(|s| Entry::parse(&s))
+        else {
+            debug!(?path, "skipping malformed dedup entry");
+            continue;
+        };
+        if now.saturating_sub(entry.forwarded_at) < config.dedup_window_secs {
+            continue;
+        }
+        if entry.repeated > 0 {
+            let hours = Duration::from_secs(now - entry.forwarded_at).as_secs_f64() / 3600.0;
+            let body = format!(
+                "The previous message {:?} on host {hostname:?} (forwarded at {}) was repeated {} times in the last {hours:.0} hours.\n\
+                 The repeats were identical and are not forwarded, see dedup_window_secs.\n",
+                entry.subject,
+                crate::time::format_utc(SystemTime::UNIX_EPOCH + Duration::from_secs(entry.forwarded_at)),
+                entry.repeated,
+            );
+            let subject = format!(
+                "{hostname}: previous message repeated {} times: {}",
+                entry.repeated, entry.subject
+            );
+            if let Err(e) = crate::send_notice(config, subject, body) {
+                warn!(%e, "failed to send dedup follow-up, will retry");
+                continue;
+            }
+        }
+        // Not while sending, which can take a while; a submission may have replaced it since.
+        let _lock = lock(&dir)?;
+        let current = std::fs::read_to_string(&path)
+            .ok()
+            .and_then(|s| Entry::parse(&s));
+        if current.is_some_and(|current| current.forwarded_at == entry.forwarded_at) {
+            std::fs::remove_file(&path)?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_entry() {
+        let entry = Entry {
+            forwarded_at: 1_700_000_000,
+            repeated: 27,
+            subject: "cron: backup failed".to_owned(),
+        };
+        let dir = crate::testdir::TestDir::new("dedup");
+        let path = dir.join("key");
+        entry.write(&path).unwrap();
+        let read_back = Entry::parse(&std::fs::read_to_string(&path).unwrap());
+        assert_eq!(read_back, Some(entry));
+
+        let submission = |body| Submission {
+            uid: 0,
+            sender: "root",
+            subject: "s",
+            body,
+        };
+        assert_eq!(submission("a").key(), submission("a").key());
+        assert_ne!(submission("a").key(), submission("b").key());
+    }
+
+    #[test]
+    fn test_concurrent() {
+        let dir = crate::testdir::TestDir::new("dedup-concurrent");
+        let config = toml::from_str::<Config>(&format!(
+            r#"
+            sender_email = "sender@example.com"
+            recipient_email = "admin@example.com"
+            smtp_host = "smtp.example.com"
+            smtp_username = "user"
+            smtp_password = "pass"
+            state_dir = {:?}
+            dedup_window_secs = 3600
+            "#,
+            &*dir
+        ))
+        .unwrap();
+        for round in 0..50 {
+            let body = round.to_string();
+            let submission = Submission {
+                uid: 0,
+                sender: "root",
+                subject: "s",
+                body: &body,
+            };
+            let barrier = std::sync::Barrier::new(2);
+            let repeats = std::thread::scope(|scope| {
+                let check = || {
+                    barrier.wait();
+                    is_repeat(&config, &submission).unwrap()
+                };
+                let other = scope.spawn(check);
+                [check(), other.join().unwrap()]
+            });
+            assert_eq!(
+                repeats.iter().filter(|&&repeat| !repeat).count(),
+                1,
+                "round {round}"
+            );
+        }
+    }
+}
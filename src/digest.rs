@@ -0,0 +1,211 @@
+//! Digest mode: submissions are kept in `state_dir/digest` instead of being forwarded, and the
+//! `--digest` subcommand, e.g. run by root from a timer, sends them in a single message.
+
+use std::fmt::Write as _;
+use std::io::{self, Read};
+use std::path::PathBuf;
+
+use mailparse::MailHeaderMap;
+use regex::Regex;
+use tracing::{debug, warn};
+
+use crate::config::Config;
+use crate::{hardening, spool, sysexits};
+
+/// Keep a submission for the next digest.
+pub fn hold(config: &Config, meta: &spool::Meta, original: impl Read) -> io::Result<PathBuf> {
+    spool::store_in(&crate::state::subdir(config, "digest")?, meta, original)
+}
+
+/// Send the pending submissions as attachments of one message, with a summary table, for root
+/// only, since they are every user's. Returns the exit code of the `--digest` subcommand.
+pub fn send(config: &Config, caller_uid: u32, hostname: &str) -> i32 {
+    if let Err(code) = hardening::require_root(caller_uid, "--digest") {
+        return code;
+    }
+    match try_send(config, hostname) {
+        Ok(()) => 0,
+        Err(e) => {
+            warn!(%e, "failed to send the digest, will retry");
+            sysexits::EX_TEMPFAIL
+        }
+    }
+}
+
+fn try_send(config: &Config, hostname: &str) -> io::Result<()> {
+    let dir = crate::state::subdir(config, "digest")?;
+    let pending = spool::list(&dir)?;
+    if pending.is_empty() {
+        debug!("no submissions for the digest");
+        return Ok(());
+    }
+
+    let mut originals = Vec::new();
+    for meta in &pending {
+        originals.push(std::fs::read(dir.join(format!("{}.eml", meta.queue_id)))?);
+    }
+    let subjects = originals.iter().map(|o| subject(o)).collect::<Vec<_>>();
+
+    let mut body = String::new();
+    let mut attachments = Vec::new();
+    // Entries not attached, moved to `state_dir/digested` once the digest is sent.
+    let mut kept = Vec::new();
+    if config.digest_group {
+        let keys = pending
+            .iter()
+            .zip(&subjects)
+            .map(|(meta, subject)| (user(meta), normalize_subject(subject)))
+            .collect::<Vec<_>>();
+        let groups = group(&keys);
+        writeln!(
+            &mut body,
+            "{} submissions in {} groups on host {hostname:?} since the last digest, the latest original of each group is attached:",
+            pending.len(),
+            groups.len()
+        )
+        .unwrap();
+        writeln!(&mut body).unwrap();
+        writeln!(
+            &mut body,
+            "{:>5}  {:<20}  {:<20}  {:<12}  subject",
+            "count", "first (UTC)", "last (UTC)", "user"
+        )
+        .unwrap();
+        for indices in &groups {
+            let (first, last) = (indices[0], indices[indices.len() - 1]);
+            let (user, subject) = &keys[first];
+            writeln!(
+                &mut body,
+                "{:>5}  {:<20}  {:<20}  {:<12}  {subject}",
+                indices.len(),
+                crate::time::format_utc(pending[first].submitted_at()),
+                crate::time::format_utc(pending[last].submitted_at()),
+                user,
+            )
+            .unwrap();
+            attachments.push((
+                format!("{}.eml", pending[last].queue_id),
+                originals[last].clone(),
+            ));
+            kept.extend(indices[..indices.len() - 1].iter().map(|&i| &pending[i]));
+        }
+        if !kept.is_empty() {
+            writeln!(&mut body).unwrap();
+            writeln!(
+                &mut body,
+                "The other originals are kept in {:?}.",
+                config.state_dir.join("digested")
+            )
+            .unwrap();
+        }
+    } else {
+        writeln!(
+            &mut body,
+            "{} submissions on host {hostname:?} since the last digest, the originals are attached:",
+            pending.len()
+        )
+        .unwrap();
+        writeln!(&mut body).unwrap();
+        writeln!(
+            &mut body,
+            "{:<20}  {:<12}  {:>8}  subject",
+            "submitted (UTC)", "user", "size"
+        )
+        .unwrap();
+        for ((meta, original), subject) in pending.iter().zip(originals).zip(&subjects) {
+            writeln!(
+                &mut body,
+                "{:<20}  {:<12}  {:>8}  {subject}",
+                crate::time::format_utc(meta.submitted_at()),
+                user(meta),
+                original.len(),
+            )
+            .unwrap();
+            attachments.push((format!("{}.eml", meta.queue_id), original));
+        }
+    }
+
+    crate::send_notice_with_attachments(
+        config,
+        format!("{hostname}: digest of {} submissions", pending.len()),
+        body,
+        attachments,
+    )
+    .map_err(|e| io::Error::other(e.to_string()))?;
+    for meta in &pending {
+        if kept.iter().any(|k| k.queue_id == meta.queue_id) {
+            let digested = crate::state::subdir(config, "digested")?;
+            // The metadata goes last, like in `spool::store_in`.
+            for ext in ["eml", "toml"] {
+                let name = format!("{}.{ext}", meta.queue_id);
+                std::fs::rename(dir.join(&name), digested.join(&name))?;
+            }
+        } else {
+            spool::remove(&dir, meta)?;
+        }
+    }
+    Ok(())
+}
+
+fn user(meta: &spool::Meta) -> String {
+    meta.username
+        .clone()
+        .unwrap_or_else(|| meta.uid.to_string())
+}
+
+/// The subject with numbers, e.g. dates and counts, replaced by `#`, so that the subjects of
+/// the runs of a job are equal.
+fn normalize_subject(subject: &str) -> String {
+    let numbers = Regex::new(r"[0-9]+").unwrap();
+    let subject = numbers.replace_all(subject, "#");
+    subject.split_whitespace().collect::<Vec<_>>().join(" ")
+}
+
+/// The indices of equal keys, groups ordered by their first occurrence.
+fn group<K: PartialEq>(keys: &[K]) -> Vec<Vec<usize>> {
+    let mut groups: Vec<Vec<usize>> = Vec::new();
+    for (i, key) in keys.iter().enumerate() {
+        match groups.iter_mut().find(|g| keys[g[0]] == *key) {
+            Some(g) => g.push(i),
+            None => groups.push(vec![i]),
+        }
+    }
+    groups
+}
+
+/// The subject of a stored original, on one line.
+fn subject(original: &[u8]) -> String {
+    mailparse::parse_headers(original)
+        .ok()
+        .and_then(|(headers, _)| headers.get_first_value("Subject"))
+        .map_or_else(
+            || "(no subject)".to_owned(),
+            |s| s.replace(['\r', '\n'], " "),
+        )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_subject() {
+        assert_eq!(
+            subject(b"Subject: =?utf-8?q?caf=C3=A9?=\r\n continued\r\n\r\nbody"),
+            "café continued"
+        );
+        assert_eq!(subject(b"\x00\x01binary"), "(no subject)");
+    }
+
+    #[test]
+    fn test_group() {
+        assert_eq!(
+            normalize_subject("backup of 2026-10-14:  3 errors"),
+            "backup of #-#-#: # errors"
+        );
+        assert_eq!(
+            group(&["a", "b", "a", "c", "b"]),
+            [vec![0, 2], vec![1, 4], vec![3]]
+        );
+    }
+}
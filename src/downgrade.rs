@@ -0,0 +1,273 @@
+//! Re-encoding 8bit content for relays without 8BITMIME (RFC 6152, section 5).
+//!
+//! Leaf parts with 8bit bodies are base64-encoded. Multiparts and message/rfc822 parts may only
+//! have the identity encodings, so their parts are downgraded one by one instead, and the
+//! 8bit header values they contain become RFC 2047 encoded words.
+
+use std::borrow::Cow;
+
+use crate::mime;
+
+/// Bytes of UTF-8 per encoded word, which may be at most 75 characters long.
+const WORD_BYTES: usize = 45;
+
+/// `entity`, a message or MIME part, with only 7bit content. None if a header isn't UTF-8.
+pub fn downgrade(entity: &[u8]) -> Option<Vec<u8>> {
+    if entity.is_ascii() {
+        return Some(entity.to_vec());
+    }
+    let entity = mime::crlf(entity);
+    let (header, body) = match entity.strip_prefix(b"\r\n") {
+        Some(body) => (&b""[..], body),
+        None => match entity.windows(4).position(|w| w == b"\r\n\r\n") {
+            Some(end) => (&entity[..end + 2], &entity[end + 4..]),
+            None => (&entity[..], &b""[..]),
+        },
+    };
+    let fields = fields(header);
+    let value_of = |name: &str| {
+        fields
+            .iter()
+            .find(|(n, _)| n.eq_ignore_ascii_case(name.as_bytes()))
+            .map(|(_, field)| String::from_utf8_lossy(&unfold(field)).into_owned())
+    };
+    let ctype = mailparse::parse_content_type(&value_of("Content-Type").unwrap_or_default());
+    // The encoding to declare instead, or None to keep the declared one.
+    let (body, encoding) = if ctype.mimetype.starts_with("multipart/") {
+        let boundary = ctype.params.get("boundary")?;
+        (Cow::Owned(multipart(body, boundary)?), Some(""))
+    } else if ctype.mimetype == "message/rfc822" {
+        (Cow::Owned(downgrade(body)?), Some(""))
+    } else if body.is_ascii() {
+        (Cow::Borrowed(body), None)
+    } else {
+        let mut encoded = Vec::with_capacity(mime::encoded_len(body.len()) + 2);
+        mime::encode_base64(body, &mut encoded).expect("reading a slice doesn't fail");
+        encoded.extend_from_slice(b"\r\n");
+        (Cow::Owned(encoded), Some("base64"))
+    };
+    let mut out = Vec::with_capacity(header.len() + body.len() + 64);
+    for (name, field) in &fields {
+        if encoding.is_some() && name.eq_ignore_ascii_case(b"Content-Transfer-Encoding") {
+            continue;
+        }
+        if field.is_ascii() {
+            out.extend_from_slice(field);
+            continue;
+        }
+        let name = std::str::from_utf8(name).ok()?;
+        let value = String::from_utf8(unfold(field)).ok()?;
+        out.extend_from_slice(format!("{name}: ").as_bytes());
+        out.extend_from_slice(encode_words(value.trim(), name.len() + 2).as_bytes());
+        out.extend_from_slice(b"\r\n");
+    }
+    if encoding == Some("base64") {
+        out.extend_from_slice(b"Content-Transfer-Encoding: base64\r\n");
+    }
+    out.extend_from_slice(b"\r\n");
+    out.extend_from_slice(&body);
+    Some(out)
+}
+
+/// The header fields, with their names, each with its continuation lines and line ending.
+fn fields(header: &[u8]) -> Vec<(&[u8], &[u8])> {
+    let mut fields = Vec::new();
+    let (mut start, mut pos) = (0, 0);
+    while pos < header.len() {
+        if pos > start && !matches!(header[pos], b' ' | b'\t') {
+            fields.push(&header[start..pos]);
+            start = pos;
+        }
+        pos = match header[pos..].windows(2).position(|w| w == b"\r\n") {
+            Some(i) => pos + i + 2,
+            None => header.len(),
+        };
+    }
+    if start < header.len() {
+        fields.push(&header[start..]);
+    }
+    fields
+        .into_iter()
+        .map(|field| {
+            let colon = field.iter().position(|&b| b == b':').unwrap_or(field.len());
+            (field[..colon].trim_ascii(), field)
+        })
+        .collect()
+}
+
+/// The value of `field`, without its line breaks.
+fn unfold(field: &[u8]) -> Vec<u8> {
+    let colon = field.iter().position(|&b| b == b':').map_or(0, |i| i + 1);
+    let value = &field[colon..];
+    value
+        .iter()
+        .copied()
+        .filter(|b| !matches!(b, b'\r' | b'\n'))
+        .collect()
+}
+
+/// The body of a multipart with its parts downgraded. An 8bit preamble or epilogue is dropped.
+fn multipart(body: &[u8], boundary: &str) -> Option<Vec<u8>> {
+    let delimiter = format!("--{boundary}");
+    // The delimiter lines: where they start, where they end after their CRLF, and whether it's
+    // the close delimiter.
+    let mut lines = Vec::new();
+    let mut pos = 0;
+    while pos < body.len() {
+        let end = match body[pos..].windows(2).position(|w| w == b"\r\n") {
+            Some(i) => pos + i + 2,
+            None => body.len(),
+        };
+        if let Some(rest) = body[pos..end].strip_prefix(delimiter.as_bytes()) {
+            let rest = rest.strip_suffix(b"\r\n").unwrap_or(rest);
+            let (close, padding) = match rest.strip_prefix(b"--") {
+                Some(padding) => (true, padding),
+                None => (false, rest),
+            };
+            if padding.iter().all(|&b| matches!(b, b' ' | b'\t')) {
+                lines.push((pos, end, close));
+                if close {
+                    break;
+                }
+            }
+        }
+        pos = end;
+    }
+    let Some(&(first, _, _)) = lines.first() else {
+        return body.is_ascii().then(|| body.to_vec());
+    };
+    let mut out = Vec::with_capacity(body.len() * 4 / 3);
+    if body[..first].is_ascii() {
+        out.extend_from_slice(&body[..first]);
+    }
+    for (i, &(start, end, close)) in lines.iter().enumerate() {
+        out.extend_from_slice(&body[start..end]);
+        if close {
+            if body[end..].is_ascii() {
+                out.extend_from_slice(&body[end..]);
+            }
+            break;
+        }
+        // The CRLF before the next delimiter belongs to it.
+        let next = lines.get(i + 1).map_or(body.len(), |&(next, _, _)| next);
+        let part_end = match body[end..next].ends_with(b"\r\n") {
+            true => next - 2,
+            false => next,
+        };
+        out.extend_from_slice(&downgrade(&body[end..part_end])?);
+        out.extend_from_slice(&body[part_end..next]);
+    }
+    Some(out)
+}
+
+/// `value` with its non-ASCII words as encoded words, folded so that lines stay short where
+/// possible. `line` is the length of what precedes it on its first line.
+fn encode_words(value: &str, mut line: usize) -> String {
+    let mut tokens = Vec::new();
+    // Non-ASCII words next to each other are encoded together, keeping the spaces between them.
+    let mut run: Vec<&str> = Vec::new();
+    let encode_run = |run: &mut Vec<&str>, tokens: &mut Vec<String>| {
+        let joined = run.join(" ");
+        let mut rest = joined.as_str();
+        while !rest.is_empty() {
+            let mut end = rest.len().min(WORD_BYTES);
+            while !rest.is_char_boundary(end) {
+                end -= 1;
+            }
+            let encoded = data_encoding::BASE64.encode(&rest.as_bytes()[..end]);
+            tokens.push(format!("=?utf-8?b?{encoded}?="));
+            rest = &rest[end..];
+        }
+        run.clear();
+    };
+    for word in value.split(' ') {
+        if word.is_ascii() {
+            encode_run(&mut run, &mut tokens);
+            tokens.push(word.to_owned());
+        } else {
+            run.push(word);
+        }
+    }
+    encode_run(&mut run, &mut tokens);
+    let mut out = String::with_capacity(value.len() * 2);
+    for (i, token) in tokens.iter().enumerate() {
+        if i > 0 {
+            if line + 1 + token.len() > 78 {
+                out.push_str("\r\n");
+                line = 0;
+            }
+            out.push(' ');
+            line += 1;
+        }
+        out.push_str(token);
+        line += token.len();
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_downgrade() {
+        let original = "From: Jörg Müller <jm@example.com>\r\n\
+            Subject: Grüße aus der Küche, diesmal mit einem sehr langen Betreff, der umbricht\r\n\
+            Content-Transfer-Encoding: 8bit\r\n\
+            \r\n\
+            Käse\r\n";
+        let message = format!(
+            "Subject: wrapper\r\n\
+            Content-Type: multipart/mixed; boundary=\"b\"\r\n\
+            \r\n\
+            --b\r\n\
+            Content-Type: text/plain; charset=utf-8\r\n\
+            Content-Transfer-Encoding: 8bit\r\n\
+            \r\n\
+            Zusammenfassung: ✓\r\n\
+            --b\r\n\
+            Content-Type: message/rfc822\r\n\
+            Content-Transfer-Encoding: 8bit\r\n\
+            \r\n\
+            {original}\
+            --b\r\n\
+            Content-Type: application/octet-stream\r\n\
+            Content-Transfer-Encoding: base64\r\n\
+            \r\n\
+            AAEC\r\n\
+            --b--\r\n"
+        );
+        let downgraded = downgrade(message.as_bytes()).unwrap();
+        assert!(downgraded.is_ascii());
+        assert!(downgraded
+            .split(|&b| b == b'\n')
+            .all(|line| line.len() <= 79));
+
+        let parsed = mailparse::parse_mail(&downgraded).unwrap();
+        assert_eq!(parsed.subparts.len(), 3);
+        assert_eq!(parsed.subparts[0].get_body().unwrap(), "Zusammenfassung: ✓");
+        let embedded = parsed.subparts[1].get_body_raw().unwrap();
+        let embedded = mailparse::parse_mail(&embedded).unwrap();
+        let header = |name| {
+            embedded
+                .headers
+                .iter()
+                .find(|h| h.get_key() == name)
+                .unwrap()
+        };
+        assert_eq!(header("From").get_value(), "Jörg Müller <jm@example.com>");
+        assert_eq!(
+            header("Subject").get_value(),
+            "Grüße aus der Küche, diesmal mit einem sehr langen Betreff, der umbricht"
+        );
+        // The line ending before the delimiter belongs to it.
+        assert_eq!(embedded.get_body_raw().unwrap(), "Käse".as_bytes());
+        assert_eq!(parsed.subparts[2].get_body_raw().unwrap(), [0, 1, 2]);
+
+        assert_eq!(
+            downgrade(b"Subject: hi\r\n\r\nho\r\n").unwrap(),
+            b"Subject: hi\r\n\r\nho\r\n"
+        );
+        assert_eq!(downgrade(b"Subject: \xff\r\n\r\nho\r\n"), None);
+    }
+}
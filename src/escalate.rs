@@ -0,0 +1,136 @@
+//! Escalation threshold for flappy jobs: a class of submissions is only forwarded once it has
+//! occurred `escalate_after` times within `escalate_window_secs`.
+//!
+//! The occurrences are recorded in `state_dir/escalation/<key>`. The forwarded submission lists
+//! the suppressed ones.
+
+use std::io::{self, Read, Seek, Write as _};
+use std::os::fd::AsRawFd;
+use std::os::unix::fs::OpenOptionsExt;
+use std::time::{Duration, SystemTime};
+
+use tracing::debug;
+
+use crate::config::Config;
+
+/// What makes submissions the same class. Unlike for dedup, the text doesn't matter.
+pub struct Class<'a> {
+    pub uid: u32,
+    pub sender: &'a str,
+    pub subject: &'a str,
+}
+
+impl Class<'_> {
+    fn key(&self) -> String {
+        let key = format!("{}\0{}\0{}", self.uid, self.sender, self.subject);
+        let digest = ring::digest::digest(&ring::digest::SHA256, key.as_bytes());
+        data_encoding::HEXLOWER.encode(&digest.as_ref()[..16])
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Occurrence {
+    /// Unix time.
+    pub at: u64,
+    pub queue_id: String,
+}
+
+impl Occurrence {
+    pub fn time(&self) -> SystemTime {
+        SystemTime::UNIX_EPOCH + Duration::from_secs(self.at)
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Decision {
+    /// Below the threshold, don't forward.
+    Suppress,
+    /// The threshold is reached, forward, listing the earlier occurrences.
+    Forward(Vec<Occurrence>),
+}
+
+/// Add `occurrence` to those still within the window and decide.
+fn decide(
+    mut occurrences: Vec<Occurrence>,
+    occurrence: Occurrence,
+    after: u32,
+    window_secs: u64,
+) -> (Vec<Occurrence>, Decision) {
+    occurrences.retain(|o| occurrence.at.saturating_sub(o.at) < window_secs);
+    if occurrences.len() + 1 >= after as usize {
+        (Vec::new(), Decision::Forward(occurrences))
+    } else {
+        occurrences.push(occurrence);
+        (occurrences, Decision::Suppress)
+    }
+}
+
+/// Record an occurrence of `class` and decide whether to forward it.
+pub fn check(config: &Config, class: &Class, queue_id: &str) -> io::Result<Decision> {
+    let path = crate::state::subdir(config, "escalation")?.join(class.key());
+    let mut file = std::fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .mode(0o600)
+        .open(path)?;
+    // Concurrent occurrences take turns.
+    if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let mut contents = String::new();
+    file.read_to_string(&mut contents)?;
+    let occurrences = contents
+        .lines()
+        .filter_map(|line| {
+            let (at, queue_id) = line.split_once(' ')?;
+            Some(Occurrence {
+                at: at.parse().ok()?,
+                queue_id: queue_id.to_owned(),
+            })
+        })
+        .collect();
+    let occurrence = Occurrence {
+        at: crate::time::unix_secs(SystemTime::now()),
+        queue_id: queue_id.to_owned(),
+    };
+    let (occurrences, decision) = decide(
+        occurrences,
+        occurrence,
+        config.escalate_after,
+        config.escalate_window_secs,
+    );
+    debug!(?decision, pending = occurrences.len(), "escalation");
+    // Truncated rather than removed, a concurrent invocation may be waiting for the lock.
+    file.set_len(0)?;
+    file.rewind()?;
+    for o in &occurrences {
+        writeln!(file, "{} {}", o.at, o.queue_id)?;
+    }
+    Ok(decision)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_decide() {
+        let at = |at| Occurrence {
+            at,
+            queue_id: format!("Q{at}"),
+        };
+        let (pending, decision) = decide(vec![], at(100), 3, 600);
+        assert_eq!(decision, Decision::Suppress);
+        let (pending, decision) = decide(pending, at(200), 3, 600);
+        assert_eq!(decision, Decision::Suppress);
+        // the first one fell out of the window
+        let (pending, decision) = decide(pending, at(700), 3, 600);
+        assert_eq!(decision, Decision::Suppress);
+        assert_eq!(pending, vec![at(200), at(700)]);
+        let (pending, decision) = decide(pending, at(750), 3, 600);
+        assert_eq!(decision, Decision::Forward(vec![at(200), at(700)]));
+        assert!(pending.is_empty());
+    }
+}
@@ -0,0 +1,118 @@
+//! The failed deliveries since the last one that went through, in `state_dir/failures/log`, one
+//! per line: the time, queue id, error class, error, and subject, separated by tabs. The next wrapper
+//! that is delivered lists them, since whoever reads it may not have seen the logs.
+
+use std::io::{self, Read, Seek, Write};
+use std::os::fd::AsRawFd;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::Path;
+use std::time::SystemTime;
+
+/// Of the failures, the most recent ones that are listed in the wrapper.
+const MAX_LISTED: usize = 50;
+
+fn open(state_dir: &Path) -> io::Result<std::fs::File> {
+    let file = std::fs::File::options()
+        .read(true)
+        .append(true)
+        .create(true)
+        .mode(0o600)
+        .open(crate::state::subdir_in(state_dir, "failures")?.join("log"))?;
+    // SAFETY: the file descriptor is open
+    if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(file)
+}
+
+/// Note the failed delivery of `queue_id`.
+pub fn record(
+    state_dir: &Path,
+    queue_id: &str,
+    error: &crate::smtp::Error,
+    subject: &str,
+) -> io::Result<()> {
+    let field = |s: &str| s.replace(['\t', '\r', '\n'], " ");
+    let line = format!(
+        "{}\t{queue_id}\t{}\t{}\t{}\n",
+        crate::time::format_utc(SystemTime::now()),
+        error.class(),
+        field(&error.to_string()),
+        field(subject),
+    );
+    open(state_dir)?.write_all(line.as_bytes())
+}
+
+/// The failures noted since the last time they were cleared, oldest first.
+pub fn previous(state_dir: &Path) -> Vec<String> {
+    let mut contents = String::new();
+    match std::fs::File::open(state_dir.join("failures/log")) {
+        Ok(mut file) => {
+            if let Err(e) = file.read_to_string(&mut contents) {
+                tracing::warn!(%e, "can't read the previous delivery failures");
+            }
+        }
+        Err(e) if e.kind() == io::ErrorKind::NotFound => (),
+        Err(e) => tracing::warn!(%e, "can't read the previous delivery failures"),
+    }
+    contents.lines().map(str::to_owned).collect()
+}
+
+/// The section of the wrapper body with the `failures`, which `heading` introduces.
+pub fn section(failures: &[String], heading: &str) -> String {
+    if failures.is_empty() {
+        return String::new();
+    }
+    let n = failures.len().to_string();
+    let mut section = crate::i18n::fill(heading, &[("n", &n)]);
+    section.push_str("\n\n");
+    if failures.len() > MAX_LISTED {
+        section.push_str("    ...\n");
+    }
+    for failure in &failures[failures.len().saturating_sub(MAX_LISTED)..] {
+        let fields: Vec<&str> = failure.splitn(5, '\t').collect();
+        let line = match fields[..] {
+            [time, queue_id, class, error, subject] => {
+                format!("{time} {queue_id} {subject:?}: {class}: {error}")
+            }
+            _ => failure.clone(),
+        };
+        section.push_str(&format!("    {line}\n"));
+    }
+    section.push('\n');
+    section
+}
+
+/// Remove the `failures`, now that they were delivered, but not those noted in the meantime.
+pub fn clear(state_dir: &Path, failures: &[String]) -> io::Result<()> {
+    if failures.is_empty() {
+        return Ok(());
+    }
+    let mut file = open(state_dir)?;
+    let mut contents = String::new();
+    file.read_to_string(&mut contents)?;
+    let rest: String = contents
+        .lines()
+        .filter(|line| !failures.iter().any(|f| f == line))
+        .map(|line| format!("{line}\n"))
+        .collect();
+    file.set_len(0)?;
+    file.rewind()?;
+    file.write_all(rest.as_bytes())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_section() {
+        let failures = [
+            "2024-01-01T00:00:00Z\t65A0B1C2D3E4F\tdeferred\tConnection error: timed out\tvm: backup".to_owned()
+        ];
+        assert_eq!(
+            section(&failures, crate::i18n::EN.failures),
+            "Deliveries that failed since the last one that went through (1):\n\n    2024-01-01T00:00:00Z 65A0B1C2D3E4F \"vm: backup\": deferred: Connection error: timed out\n\n"
+        );
+    }
+}
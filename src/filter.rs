@@ -0,0 +1,232 @@
+//! `[[filter]]` rules that drop noisy submissions, e.g. the nightly "0 packages upgraded" mail.
+//!
+//! Dropped submissions are counted, the counts are sent in a summary at most once a day.
+
+use std::collections::BTreeMap;
+use std::fmt::Write as _;
+use std::fs::OpenOptions;
+use std::io::Write as _;
+use std::time::{Duration, SystemTime};
+
+use regex::Regex;
+use tracing::{debug, warn};
+
+use crate::config::Config;
+
+const SUMMARY_INTERVAL: Duration = Duration::from_secs(24 * 60 * 60);
+
+#[derive(Debug, Clone, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Filter {
+    /// Shown in the log and the summary.
+    pub name: String,
+    /// Regexes, all given ones must match. At least one must be given.
+    pub subject: Option<String>,
+    pub body: Option<String>,
+    pub sender: Option<String>,
+    #[serde(default)]
+    pub action: Action,
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Action {
+    #[default]
+    Drop,
+    /// Keep the submission in the state dir (`filtered/`) instead of forwarding it.
+    SpoolOnly,
+}
+
+/// What a filter is matched against.
+pub struct Submission<'a> {
+    pub subject: &'a str,
+    pub body: &'a str,
+    pub sender: &'a str,
+}
+
+/// Regexes that a submission matches if all given ones match, like those of a [`Filter`].
+#[derive(Debug, Clone, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Patterns {
+    pub subject: Option<String>,
+    pub body: Option<String>,
+    pub sender: Option<String>,
+}
+
+impl Patterns {
+    /// Check the regexes, for loading the config. At least one must be given.
+    pub fn validate(&self) -> Result<(), String> {
+        validate([&self.subject, &self.body, &self.sender])
+    }
+
+    pub fn matches(&self, submission: &Submission) -> bool {
+        matches([&self.subject, &self.body, &self.sender], submission)
+    }
+}
+
+impl Filter {
+    /// Check the regexes, for loading the config.
+    pub fn validate(&self) -> Result<(), String> {
+        validate([&self.subject, &self.body, &self.sender])
+    }
+
+    fn matches(&self, submission: &Submission) -> bool {
+        matches([&self.subject, &self.body, &self.sender], submission)
+    }
+}
+
+/// The subject, body, and sender regexes.
+pub type Regexes<'a> = [&'a Option<String>; 3];
+
+/// Check the given `patterns`, at least one must be given.
+pub fn validate(patterns: Regexes) -> Result<(), String> {
+    if patterns.iter().all(|p| p.is_none()) {
+        return Err("needs at least one of subject, body, or sender".to_owned());
+    }
+    for pattern in patterns.into_iter().flatten() {
+        Regex::new(pattern).map_err(|e| e.to_string())?;
+    }
+    Ok(())
+}
+
+/// Whether all given `patterns` match.
+pub fn matches(patterns: Regexes, submission: &Submission) -> bool {
+    let texts = [submission.subject, submission.body, submission.sender];
+    patterns
+        .into_iter()
+        .zip(texts)
+        .all(|(pattern, text)| match pattern {
+            Some(p) => Regex::new(p).expect("validated").is_match(text),
+            None => true,
+        })
+}
+
+/// The first filter that matches `submission`.
+pub fn find<'a>(filters: &'a [Filter], submission: &Submission) -> Option<&'a Filter> {
+    filters.iter().find(|f| f.matches(submission))
+}
+
+/// Count the filtered submission for the summary.
+pub fn record(config: &Config, filter: &Filter) -> std::io::Result<()> {
+    let dir = crate::state::subdir(config, "filtered")?;
+    let now = crate::time::unix_secs(SystemTime::now());
+    let last_sent = dir.join("last-summary");
+    if !last_sent.exists() {
+        // the first summary goes out one interval after the first filtered submission
+        std::fs::write(&last_sent, now.to_string())?;
+    }
+    let mut pending = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(dir.join("pending"))?;
+    writeln!(pending, "{}", filter.name.replace(['\r', '\n'], " "))
+}
+
+/// Send the summary of filtered submissions if it is due.
+pub fn maybe_send_summary(config: &Config, hostname: &str) -> std::io::Result<()> {
+    let dir = crate::state::subdir(config, "filtered")?;
+    let pending_path = dir.join("pending");
+    let last_sent_path = dir.join("last-summary");
+    let now = SystemTime::now();
+    let last_sent = match std::fs::read_to_string(&last_sent_path) {
+        Ok(s) => s.trim().parse::<u64>().unwrap_or(0),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e),
+    };
+    let since = crate::time::unix_secs(now).saturating_sub(last_sent);
+    if since < SUMMARY_INTERVAL.as_secs() {
+        return Ok(());
+    }
+
+    // Take ownership of the pending entries so concurrent invocations start a new file.
+    let sending_path = dir.join(format!("sending.{}", std::process::id()));
+    match std::fs::rename(&pending_path, &sending_path) {
+        Ok(()) => (),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e),
+    }
+    let entries = std::fs::read_to_string(&sending_path)?;
+    let counts = count(&entries);
+    let total: usize = counts.values().sum();
+    debug!(?counts, "sending filter summary");
+
+    let mut body = String::new();
+    writeln!(
+        &mut body,
+        "The following numbers of submissions on host {hostname:?} were filtered by the [[filter]] config in the last {} hours:",
+        since / 3600
+    )
+    .unwrap();
+    writeln!(&mut body).unwrap();
+    for (name, n) in &counts {
+        writeln!(&mut body, "{n:>6}  {name}").unwrap();
+    }
+
+    match crate::send_notice(
+        config,
+        format!("{hostname}: {total} filtered submissions"),
+        body,
+    ) {
+        Ok(()) => {
+            std::fs::remove_file(&sending_path)?;
+            std::fs::write(&last_sent_path, crate::time::unix_secs(now).to_string())?;
+        }
+        Err(e) => {
+            warn!(%e, "failed to send filter summary, will retry");
+            let mut pending = OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(&pending_path)?;
+            pending.write_all(entries.as_bytes())?;
+            std::fs::remove_file(&sending_path)?;
+        }
+    }
+    Ok(())
+}
+
+fn count(entries: &str) -> BTreeMap<&str, usize> {
+    let mut counts = BTreeMap::new();
+    for name in entries.lines() {
+        *counts.entry(name).or_default() += 1;
+    }
+    counts
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_find() {
+        let filter = |name: &str, subject: Option<&str>, body: Option<&str>| Filter {
+            name: name.to_owned(),
+            subject: subject.map(str::to_owned),
+            body: body.map(str::to_owned),
+            sender: None,
+            action: Action::Drop,
+        };
+        let filters = [
+            filter("apt", Some("^apticron"), Some(r"\b0 packages upgraded")),
+            filter("backup", Some("(?i)backup ok"), None),
+        ];
+        assert!(filters.iter().all(|f| f.validate().is_ok()));
+        assert!(filter("none", None, None).validate().is_err());
+        assert!(filter("bad", Some("("), None).validate().is_err());
+        let submission = |subject, body| Submission {
+            subject,
+            body,
+            sender: "root@host",
+        };
+        let found = |s| find(&filters, &s).map(|f| f.name.as_str());
+        assert_eq!(
+            found(submission("apticron", "0 packages upgraded")),
+            Some("apt")
+        );
+        assert_eq!(found(submission("apticron", "3 packages upgraded")), None);
+        assert_eq!(found(submission("Nightly BACKUP OK", "")), Some("backup"));
+        assert_eq!(
+            count("apt\nbackup\napt\n"),
+            [("apt", 2), ("backup", 1)].into()
+        );
+    }
+}
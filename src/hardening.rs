@@ -0,0 +1,189 @@
+//! Hardening for running setuid root: the environment, the umask, the binary's permissions, and
+//! switching to a service user.
+
+use std::ffi::{OsStr, OsString};
+use std::io;
+use std::os::unix::fs::MetadataExt;
+use std::process::Command;
+
+/// Whether we run with another effective uid than the caller's, i.e. setuid.
+pub fn is_setuid() -> bool {
+    users::get_current_uid() != users::get_effective_uid()
+}
+
+/// `PATH` for the commands we run (gpg, openssl, zstd, ...).
+const SAFE_PATH: &str = "/usr/local/sbin:/usr/local/bin:/usr/sbin:/usr/bin:/sbin:/bin";
+
+/// A command for one of those, found on [`SAFE_PATH`] and run with an otherwise empty
+/// environment: even without setuid we may run as root for a caller, e.g. in the daemon, and
+/// neither their `PATH` nor their other variables should pick what it runs or loads.
+pub fn command(program: &str) -> Command {
+    let mut command = Command::new(program);
+    command.env_clear().env("PATH", SAFE_PATH);
+    command
+}
+
+/// Variables that make us, or the commands we run, load code or data chosen by the caller.
+/// Entries ending in `*` match by prefix.
+const DANGEROUS_ENV: &[&str] = &[
+    "LD_*",
+    "MALLOC_*",
+    "GCONV_PATH",
+    "NLSPATH",
+    "LOCPATH",
+    "HOSTALIASES",
+    "LOCALDOMAIN",
+    "RES_OPTIONS",
+    "IFS",
+    "ENV",
+    "BASH_ENV",
+    "TMPDIR",
+    "GNUPGHOME",
+    "OPENSSL_*",
+    "SSL_CERT_*",
+    "ZSTD_*",
+    "GZIP",
+];
+
+/// Our variables that point us at other files, like the config.
+const OVERRIDES_ENV: &[&str] = &[
+    "FORWARD_AS_ATTACHMENT_MTA_CONFIG_FILE",
+    "FORWARD_AS_ATTACHMENT_MTA_AGE_IDENTITY",
+];
+
+fn dangerous(name: &OsStr) -> bool {
+    let name = name.as_encoded_bytes();
+    DANGEROUS_ENV
+        .iter()
+        .any(|entry| match entry.strip_suffix('*') {
+            Some(prefix) => name.starts_with(prefix.as_bytes()),
+            None => name == entry.as_bytes(),
+        })
+}
+
+/// If setuid, remove the dangerous variables from the environment and set a safe `PATH`.
+/// Returns the environment as it was, for the report.
+///
+/// Must be called before other threads are spawned.
+pub fn sanitize_environment(setuid: bool) -> Vec<(OsString, OsString)> {
+    let invoking = std::env::vars_os().collect::<Vec<_>>();
+    if !setuid {
+        return invoking;
+    }
+    for name in OVERRIDES_ENV {
+        if std::env::var_os(name).is_some() {
+            panic!("{name} is only honored without setuid");
+        }
+    }
+    for (name, _) in &invoking {
+        if dangerous(name) {
+            std::env::remove_var(name);
+        }
+    }
+    std::env::set_var("PATH", SAFE_PATH);
+    invoking
+}
+
+/// Open `path` for reading with the caller's permissions rather than ours, which differ if setuid.
+pub fn open_as_caller(path: &std::path::Path) -> io::Result<std::fs::File> {
+    // SAFETY: these take no pointers, and only affect this thread's file system access
+    let (fsgid, fsuid) = unsafe {
+        (
+            libc::setfsgid(libc::getgid()),
+            libc::setfsuid(libc::getuid()),
+        )
+    };
+    let file = std::fs::File::open(path);
+    // SAFETY: as above
+    unsafe {
+        libc::setfsuid(fsuid as libc::uid_t);
+        libc::setfsgid(fsgid as libc::gid_t);
+    }
+    file
+}
+
+/// For the subcommands that read every user's mail or state, or act on the host's behalf: only
+/// the real root may run them, not whoever can run the setuid binary. Returns the exit code if
+/// `caller_uid` isn't root.
+pub fn require_root(caller_uid: u32, subcommand: &str) -> Result<(), i32> {
+    if caller_uid == 0 {
+        return Ok(());
+    }
+    eprintln!("forward-as-attachment-mta: {subcommand}: only root may run this");
+    Err(crate::sysexits::EX_NOPERM)
+}
+
+/// Files we create, e.g. spool entries, are only for their owner.
+pub fn set_umask() {
+    // SAFETY: umask is always successful
+    unsafe { libc::umask(0o077) };
+}
+
+/// An error if someone other than root could replace our binary, which would then run with
+/// root's privileges.
+pub fn check_binary() -> Result<(), String> {
+    let exe = std::env::current_exe().map_err(|e| format!("can't find our binary: {e}"))?;
+    let dir = exe.parent().unwrap_or(&exe);
+    for path in [exe.as_path(), dir] {
+        let metadata = std::fs::metadata(path).map_err(|e| format!("{path:?}: {e}"))?;
+        if metadata.uid() != 0 || metadata.mode() & 0o022 != 0 {
+            return Err(format!(
+                "{path:?} is writable by non-root (owner {}, mode {:o})",
+                metadata.uid(),
+                metadata.mode() & 0o7777
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// Switch to `user` for good, with its primary group and no supplementary groups.
+pub fn switch_user(user: &str) -> io::Result<()> {
+    let user = users::get_user_by_name(user)
+        .ok_or_else(|| io::Error::other(format!("unknown user {user:?}")))?;
+    let (uid, gid) = (user.uid(), user.primary_group_id());
+    if users::get_effective_uid() == uid {
+        return Ok(());
+    }
+    // SAFETY: the group list is a valid pointer to one gid
+    let switched = unsafe {
+        libc::setgroups(1, &gid) == 0
+            && libc::setresgid(gid, gid, gid) == 0
+            && libc::setresuid(uid, uid, uid) == 0
+    };
+    if !switched {
+        return Err(io::Error::last_os_error());
+    }
+    // SAFETY: no pointers involved
+    if uid != 0 && unsafe { libc::setresuid(0, 0, 0) } == 0 {
+        return Err(io::Error::other("could switch back to root"));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_dangerous() {
+        assert!(dangerous(OsStr::new("LD_PRELOAD")));
+        assert!(dangerous(OsStr::new("OPENSSL_CONF")));
+        assert!(dangerous(OsStr::new("TMPDIR")));
+        assert!(!dangerous(OsStr::new("TMPDIRS")));
+        assert!(!dangerous(OsStr::new("MAILTO")));
+        let env = sanitize_environment(false);
+        assert_eq!(env, std::env::vars_os().collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn test_command() {
+        // Cargo sets plenty of variables for the test, none of which may reach the command.
+        let output = command("env").output().unwrap();
+        assert!(output.status.success());
+        assert_eq!(
+            String::from_utf8(output.stdout).unwrap(),
+            format!("PATH={SAFE_PATH}\n")
+        );
+    }
+}
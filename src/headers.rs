@@ -0,0 +1,297 @@
+use std::sync::OnceLock;
+use std::time::SystemTime;
+
+use regex::Regex;
+
+use lettre::message::header::{HeaderName, HeaderValue};
+use tracing::debug;
+
+/// Adapter to put arbitrary headers into lettre messages.
+#[derive(Clone)]
+pub struct RawHeader(HeaderName, String);
+
+impl lettre::message::header::Header for RawHeader {
+    fn name() -> HeaderName {
+        unimplemented!("not needed, we only use display")
+    }
+
+    fn parse(_: &str) -> Result<Self, Box<dyn std::error::Error + Send + Sync>> {
+        unimplemented!("not needed, we only use display")
+    }
+
+    fn display(&self) -> lettre::message::header::HeaderValue {
+        HeaderValue::new(self.0.clone(), self.1.clone())
+    }
+}
+
+impl RawHeader {
+    pub fn new(hdr: &mailparse::MailHeader) -> Option<Self> {
+        let header_name = HeaderName::new_from_ascii(hdr.get_key()).ok().or_else(|| {
+            debug!(hdr=?hdr.get_key(), "header is not ascii");
+            None
+        })?;
+        let header_value = hdr.get_value_utf8().ok().or_else(|| {
+            debug!(hdr=?hdr, "header value is not utf-8");
+            None
+        })?;
+        Some(Self(header_name, header_value))
+    }
+
+    pub fn from_name(name: String, value: String) -> Result<Self, String> {
+        let name = HeaderName::new_from_ascii(name).map_err(|e| format!("header name: {e}"))?;
+        Ok(Self(name, value))
+    }
+
+    pub fn from_static(name: &'static str, value: String) -> Self {
+        Self(HeaderName::new_from_ascii_str(name), value)
+    }
+}
+
+/// A Message-ID of the form `<timestamp.hash@domain>`.
+///
+/// It only depends on the submission, so that retries of the same submission produce
+/// the same ID and can be collapsed by the receiving side.
+/// Using a configured domain avoids leaking the internal hostname.
+///
+/// `original_sha256` is the hex digest of the original, see [`sha256_hex`].
+pub fn message_id(submitted_at: SystemTime, original_sha256: &str, domain: &str) -> String {
+    let hash = &original_sha256[..24];
+    format!("<{}.{hash}@{domain}>", crate::time::unix_secs(submitted_at))
+}
+
+pub fn sha256_hex(data: &[u8]) -> String {
+    data_encoding::HEXLOWER.encode(ring::digest::digest(&ring::digest::SHA256, data).as_ref())
+}
+
+/// A Message-ID that serves as the common parent (`In-Reply-To`, `References`) of all wrappers
+/// for the same job, so that mail clients thread repeated runs together.
+pub fn thread_anchor(hostname: &str, sender: &str, subject: &str, domain: &str) -> String {
+    let key = format!("{hostname}\0{sender}\0{}", normalize_subject(subject));
+    let digest = ring::digest::digest(&ring::digest::SHA256, key.as_bytes());
+    let hash = data_encoding::HEXLOWER.encode(&digest.as_ref()[..12]);
+    format!("<thread.{hash}@{domain}>")
+}
+
+/// Strip reply/forward prefixes, case, whitespace differences, and numbers
+/// (dates, counts, durations) that vary between runs of the same job.
+fn normalize_subject(subject: &str) -> String {
+    static RE_PREFIX: OnceLock<Regex> = OnceLock::new();
+    static RE_DIGITS: OnceLock<Regex> = OnceLock::new();
+    let re_prefix =
+        RE_PREFIX.get_or_init(|| Regex::new(r"(?i)^\s*((re|fwd?|aw|wg)\s*:\s*)+").unwrap());
+    let re_digits = RE_DIGITS.get_or_init(|| Regex::new(r"\d+").unwrap());
+    let subject = re_prefix.replace(subject, "");
+    let subject = re_digits.replace_all(&subject, "#");
+    subject
+        .split_whitespace()
+        .collect::<Vec<_>>()
+        .join(" ")
+        .to_lowercase()
+}
+
+/// The address if it looks like it is reachable from outside the host,
+/// i.e., not a bare local user or a host-local domain.
+pub fn routable_address(addr: &str, hostname: &str) -> Option<lettre::Address> {
+    let addr: lettre::Address = addr.parse().ok()?;
+    let domain = addr.domain().trim_end_matches('.').to_ascii_lowercase();
+    let local = !domain.contains('.')
+        || domain == hostname.to_ascii_lowercase()
+        || [".local", ".localdomain", ".localhost", ".internal", ".lan"]
+            .iter()
+            .any(|suffix| domain.ends_with(suffix));
+    (!local).then_some(addr)
+}
+
+/// Replace line breaks and tabs with spaces, drop other control characters, and limit
+/// the result to `max_chars` characters, ending in an ellipsis if truncated.
+pub fn sanitize_subject(subject: &str, max_chars: usize) -> String {
+    let mut out = String::with_capacity(subject.len().min(max_chars * 4));
+    for c in subject.chars() {
+        match c {
+            '\r' | '\n' | '\t' => {
+                if !out.ends_with(' ') {
+                    out.push(' ');
+                }
+            }
+            c if c.is_control() => (),
+            c => out.push(c),
+        }
+    }
+    let out = out.trim();
+    if out.chars().count() <= max_chars {
+        return out.to_owned();
+    }
+    let mut truncated: String = out.chars().take(max_chars.saturating_sub(1)).collect();
+    truncated.truncate(truncated.trim_end().len());
+    truncated.push('…');
+    truncated
+}
+
+/// Decode RFC 2047 encoded-words that are left in an already-unfolded header value.
+///
+/// mailparse only decodes encoded-words that are separate tokens; this also handles
+/// ones that are glued to surrounding text, e.g. `[cron]=?UTF-8?B?...?=`.
+/// Encoded-words that can't be decoded (unknown charset, bad encoding) are kept verbatim.
+pub fn decode_rfc2047(value: &str) -> String {
+    static RE: OnceLock<Regex> = OnceLock::new();
+    let re = RE.get_or_init(|| Regex::new(r"=\?([^?\s]+)\?([bBqQ])\?([^?\s]*)\?=").unwrap());
+    let mut out = String::with_capacity(value.len());
+    let mut last_end = 0;
+    let mut last_was_decoded = false;
+    for caps in re.captures_iter(value) {
+        let m = caps.get(0).unwrap();
+        let between = &value[last_end..m.start()];
+        let decoded = decode_encoded_word(&caps[1], &caps[2], &caps[3]);
+        // whitespace between adjacent encoded-words is not part of the text
+        if !(last_was_decoded && decoded.is_some() && between.trim().is_empty()) {
+            out.push_str(between);
+        }
+        match &decoded {
+            Some(text) => out.push_str(text),
+            None => out.push_str(m.as_str()),
+        }
+        last_was_decoded = decoded.is_some();
+        last_end = m.end();
+    }
+    out.push_str(&value[last_end..]);
+    out
+}
+
+fn decode_encoded_word(charset: &str, encoding: &str, text: &str) -> Option<String> {
+    // RFC 2231 allows a language suffix, e.g. `UTF-8*en`
+    let charset = charset.split('*').next().unwrap_or(charset);
+    let charset = charset::Charset::for_label(charset.as_bytes())?;
+    let bytes = if encoding.eq_ignore_ascii_case("b") {
+        data_encoding::BASE64
+            .decode(text.as_bytes())
+            .or_else(|_| data_encoding::BASE64_NOPAD.decode(text.trim_end_matches('=').as_bytes()))
+            .ok()?
+    } else {
+        let mut bytes = Vec::with_capacity(text.len());
+        let mut iter = text.bytes();
+        while let Some(b) = iter.next() {
+            match b {
+                b'_' => bytes.push(b' '),
+                b'=' => {
+                    let hex = [iter.next()?, iter.next()?];
+                    let hex = std::str::from_utf8(&hex).ok()?;
+                    bytes.push(u8::from_str_radix(hex, 16).ok()?);
+                }
+                b => bytes.push(b),
+            }
+        }
+        bytes
+    };
+    let (decoded, had_errors) = charset.decode_without_bom_handling(&bytes);
+    (!had_errors).then(|| decoded.into_owned())
+}
+
+/// The headers of an original that mailparse rejects, e.g. because it starts with whitespace:
+/// the first block of lines that look like headers, with continuation lines unfolded.
+pub struct LenientHeaders(Vec<(String, String)>);
+
+pub fn lenient_headers(data: &[u8]) -> LenientHeaders {
+    static RE: OnceLock<Regex> = OnceLock::new();
+    let re = RE.get_or_init(|| Regex::new(r"^([!-9;-~]+):[ \t]*(.*)$").unwrap());
+    let head = String::from_utf8_lossy(&data[..data.len().min(64 * 1024)]);
+    let mut headers: Vec<(String, String)> = Vec::new();
+    for line in head.lines() {
+        let line = line.trim_end_matches('\r');
+        if let Some(caps) = re.captures(line) {
+            headers.push((caps[1].to_owned(), caps[2].trim_end().to_owned()));
+        } else if line.starts_with([' ', '\t']) && !headers.is_empty() {
+            let (_, value) = headers.last_mut().expect("checked");
+            value.push(' ');
+            value.push_str(line.trim());
+        } else if !headers.is_empty() {
+            break;
+        }
+    }
+    LenientHeaders(headers)
+}
+
+impl LenientHeaders {
+    pub fn get_all(&self, name: &str) -> Vec<String> {
+        self.0
+            .iter()
+            .filter(|(n, _)| n.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.clone())
+            .collect()
+    }
+
+    pub fn get_first(&self, name: &str) -> Option<&str> {
+        self.0
+            .iter()
+            .find(|(n, _)| n.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.as_str())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::{Duration, UNIX_EPOCH};
+
+    #[test]
+    fn test_lenient_headers() {
+        let data = b"  indented junk\nFrom: Cron Daemon <root@host>\nSubject: a long\n\tsubject\r\nDate: today\nnot a header\nX-Late: no\n";
+        assert!(mailparse::parse_mail(data).is_err());
+        let headers = lenient_headers(data);
+        assert_eq!(headers.get_first("subject"), Some("a long subject"));
+        assert_eq!(headers.get_first("From"), Some("Cron Daemon <root@host>"));
+        assert_eq!(headers.get_all("Date"), vec!["today"]);
+        assert_eq!(headers.get_first("X-Late"), None);
+    }
+
+    #[test]
+    fn test_message_id() {
+        let t = UNIX_EPOCH + Duration::from_secs(1700000000);
+        let id = message_id(t, &sha256_hex(b"hello"), "example.com");
+        assert_eq!(id, "<1700000000.2cf24dba5fb0a30e26e83b2a@example.com>");
+        assert_ne!(id, message_id(t, &sha256_hex(b"hello!"), "example.com"));
+    }
+
+    #[test]
+    fn test_normalize_subject() {
+        let f = normalize_subject;
+        assert_eq!(
+            f("Re: Cron <root@host> /usr/local/bin/backup  --run 42"),
+            f("cron <root@host> /usr/local/bin/backup --run 43")
+        );
+        assert_eq!(f("Fwd: RE: Disk 3 at 95%"), "disk # at #%");
+    }
+
+    #[test]
+    fn test_routable_address() {
+        let f = |s| routable_address(s, "myhost.example.com").map(|a| a.to_string());
+        assert_eq!(f("alice@example.com").as_deref(), Some("alice@example.com"));
+        assert_eq!(f("root"), None);
+        assert_eq!(f("root@myhost"), None);
+        assert_eq!(f("root@MyHost.example.com"), None);
+        assert_eq!(f("root@myhost.localdomain"), None);
+    }
+
+    #[test]
+    fn test_decode_rfc2047() {
+        let f = decode_rfc2047;
+        assert_eq!(f("plain subject"), "plain subject");
+        assert_eq!(f("[cron]=?UTF-8?B?w6TDtsO8?="), "[cron]äöü");
+        assert_eq!(f("=?utf-8?q?ab?=c"), "abc");
+        assert_eq!(f("=?UTF-8?Q?a?= =?ISO-8859-1?Q?=E4_b?= c"), "aä b c");
+        assert_eq!(f("=?unknown-cs?Q?abc?= x"), "=?unknown-cs?Q?abc?= x");
+        assert_eq!(f("=?UTF-8?B?!!!?="), "=?UTF-8?B?!!!?=");
+    }
+
+    #[test]
+    fn test_sanitize_subject() {
+        let f = sanitize_subject;
+        assert_eq!(
+            f("a\r\nBcc: evil@example.com", 100),
+            "a Bcc: evil@example.com"
+        );
+        assert_eq!(f("bell\x07 \x1b[31mred", 100), "bell [31mred");
+        assert_eq!(f("äöü long subject", 6), "äöü l…");
+        assert_eq!(f("abc  def", 5), "abc…");
+        assert_eq!(f("exact", 5), "exact");
+    }
+}
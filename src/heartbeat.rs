@@ -0,0 +1,69 @@
+//! The `--heartbeat` subcommand, e.g. run by root from a daily systemd timer: a short message
+//! that the forwarding path is alive, with the last day's numbers, so that silence from a host
+//! stands out.
+
+use std::time::{Duration, SystemTime};
+
+use tracing::warn;
+
+use crate::config::Config;
+use crate::{hardening, sysexits};
+
+const PERIOD: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// Returns the exit code of the `--heartbeat` subcommand, which is for root only, since it sends
+/// everyone's numbers.
+pub fn send(config: &Config, caller_uid: u32, hostname: &str) -> i32 {
+    if let Err(code) = hardening::require_root(caller_uid, "--heartbeat") {
+        return code;
+    }
+    let since = SystemTime::now() - PERIOD;
+    let summary = crate::db::open(&config.state_dir).and_then(|db| {
+        let queue_depth = crate::spool::depth(&config.state_dir)?;
+        Ok(crate::stats::summarize(
+            since,
+            &db.submissions(since)?,
+            &db.history(since)?,
+            queue_depth,
+        ))
+    });
+    // The heartbeat is about the forwarding path, so it goes out even without the numbers.
+    let summary = summary.unwrap_or_else(|e| {
+        warn!(%e, "can't read the state database for the heartbeat");
+        format!("The state database can't be read: {e}\n")
+    });
+    match crate::send_notice(
+        config,
+        format!("{hostname}: heartbeat"),
+        body(hostname, &summary),
+    ) {
+        Ok(()) => 0,
+        Err(e) => {
+            eprintln!("forward-as-attachment-mta: failed to send the heartbeat: {e}");
+            sysexits::EX_TEMPFAIL
+        }
+    }
+}
+
+fn body(hostname: &str, summary: &str) -> String {
+    format!(
+        "The forwarding path from host {hostname:?} is alive.\n\
+         \n\
+         In the last 24 hours:\n\
+         \n\
+         {summary}"
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_body() {
+        assert_eq!(
+            body("vm", "submitted               3\n"),
+            "The forwarding path from host \"vm\" is alive.\n\nIn the last 24 hours:\n\nsubmitted               3\n"
+        );
+    }
+}
@@ -0,0 +1,183 @@
+//! `pre_send_hook` and `post_send_hook`: site-specific commands around each submission.
+//!
+//! The pre-send hook gets the original on stdin, before the wrapper is composed. Exit status 0
+//! forwards the original, or what the hook printed instead if it printed anything; exit status
+//! 1 vetoes the submission; anything else, or no answer within `hook_timeout_secs`, is logged and
+//! the original is forwarded unchanged, so that a broken hook can't lose mail. The post-send hook
+//! gets the outcome of the delivery in `FAAM_*` environment variables; it can't change it.
+//!
+//! Hooks run as root, or `run_as`, with real and effective ids alike, and only `PATH` and the
+//! `FAAM_*` variables in their environment: if setuid, the rest is the caller's.
+
+use std::io::{self, Read, Write};
+use std::os::unix::process::CommandExt;
+use std::process::{ExitStatus, Stdio};
+use std::time::{Duration, Instant};
+
+use tracing::{debug, warn};
+
+use crate::config::Config;
+
+/// What the pre-send hook decided.
+pub enum Verdict {
+    Forward,
+    /// Forward this instead of the original.
+    Replace(Vec<u8>),
+    Veto,
+}
+
+/// Run `pre_send_hook` with `original` on stdin, and `env`, e.g. the queue id.
+pub fn pre_send(
+    config: &Config,
+    original: &mut (dyn Read + Send),
+    env: &[(&str, String)],
+) -> Verdict {
+    let timeout = Duration::from_secs(config.hook_timeout_secs);
+    match run(&config.pre_send_hook, env, Some(original), timeout) {
+        Ok((status, stdout)) => match status.code() {
+            Some(0) if stdout.is_empty() => Verdict::Forward,
+            Some(0) => {
+                debug!(
+                    size = stdout.len(),
+                    "the pre-send hook replaced the original"
+                );
+                Verdict::Replace(stdout)
+            }
+            Some(1) => Verdict::Veto,
+            _ => {
+                warn!(%status, "the pre-send hook failed, forwarding the original unchanged");
+                Verdict::Forward
+            }
+        },
+        Err(e) => {
+            warn!(%e, "can't run the pre-send hook, forwarding the original unchanged");
+            Verdict::Forward
+        }
+    }
+}
+
+/// Run `post_send_hook` with `env`, i.e. the outcome.
+pub fn post_send(config: &Config, env: &[(&str, String)]) {
+    let timeout = Duration::from_secs(config.hook_timeout_secs);
+    match run(&config.post_send_hook, env, None, timeout) {
+        Ok((status, _)) if status.success() => debug!("ran the post-send hook"),
+        Ok((status, _)) => warn!(%status, "the post-send hook failed"),
+        Err(e) => warn!(%e, "can't run the post-send hook"),
+    }
+}
+
+/// Run `command`, killing it after `timeout`. Returns the exit status and what it printed.
+fn run(
+    command: &[String],
+    env: &[(&str, String)],
+    input: Option<&mut (dyn Read + Send)>,
+    timeout: Duration,
+) -> io::Result<(ExitStatus, Vec<u8>)> {
+    let (program, args) = command
+        .split_first()
+        .ok_or_else(|| io::Error::other("the hook command is empty"))?;
+    // If setuid, the group and supplementary groups are still the caller's; `run_as` set them.
+    let uid = users::get_effective_uid();
+    let gid = match uid {
+        0 => users::get_user_by_uid(0).map_or(0, |root| root.primary_group_id()),
+        _ => users::get_effective_gid(),
+    };
+    let mut command = crate::hardening::command(program);
+    // SAFETY: these are async-signal-safe, and the group list is a valid pointer to one gid
+    unsafe {
+        command.pre_exec(move || {
+            if (uid == 0 && libc::setgroups(1, &gid) == -1)
+                || libc::setresgid(gid, gid, gid) == -1
+                || libc::setresuid(uid, uid, uid) == -1
+            {
+                return Err(io::Error::last_os_error());
+            }
+            Ok(())
+        });
+    }
+    let mut child = command
+        .args(args)
+        .envs(env.iter().map(|(k, v)| (k, v)))
+        .stdin(if input.is_some() {
+            Stdio::piped()
+        } else {
+            Stdio::null()
+        })
+        .stdout(Stdio::piped())
+        // So that a timeout kills what it started, too, which would keep stdout open.
+        .process_group(0)
+        .spawn()?;
+    let stdin = child.stdin.take();
+    let mut stdout = child.stdout.take().expect("we requested a pipe");
+    std::thread::scope(|scope| {
+        let writer = stdin.zip(input).map(|(mut stdin, input)| {
+            scope.spawn(move || match io::copy(input, &mut stdin) {
+                // The hook doesn't have to read all of it.
+                Err(e) if e.kind() == io::ErrorKind::BrokenPipe => Ok(()),
+                result => result.and_then(|_| stdin.flush()),
+            })
+        });
+        let reader = scope.spawn(move || {
+            let mut output = Vec::new();
+            stdout.read_to_end(&mut output).map(|_| output)
+        });
+        let deadline = Instant::now() + timeout;
+        let status = loop {
+            if let Some(status) = child.try_wait()? {
+                break status;
+            }
+            if Instant::now() > deadline {
+                // SAFETY: no pointers; the group is the child's, which we haven't reaped yet
+                unsafe { libc::kill(-(child.id() as libc::pid_t), libc::SIGKILL) };
+                let _ = child.wait();
+                return Err(io::Error::new(
+                    io::ErrorKind::TimedOut,
+                    "the hook timed out",
+                ));
+            }
+            std::thread::sleep(Duration::from_millis(50));
+        };
+        if let Some(writer) = writer {
+            if let Err(e) = writer.join().expect("writer doesn't panic") {
+                warn!(%e, "writing the original to the hook");
+            }
+        }
+        let output = reader.join().expect("reader doesn't panic")?;
+        Ok((status, output))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_run() {
+        let command = ["sh", "-c", "tr a-z A-Z; exit $FAAM_TEST_EXIT"].map(str::to_owned);
+        let (status, output) = run(
+            &command,
+            &[("FAAM_TEST_EXIT", "1".to_owned())],
+            Some(&mut &b"subject: hi\n"[..]),
+            Duration::from_secs(10),
+        )
+        .unwrap();
+        assert_eq!(
+            (status.code(), output.as_slice()),
+            (Some(1), &b"SUBJECT: HI\n"[..])
+        );
+        let sleep = ["sleep", "10"].map(str::to_owned);
+        let e = run(&sleep, &[], None, Duration::from_millis(100)).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::TimedOut);
+        // Cargo sets this for us, like a caller could set anything.
+        assert!(std::env::var_os("CARGO_MANIFEST_DIR").is_some());
+        let env = ["sh", "-c", "echo \"$CARGO_MANIFEST_DIR|$FAAM_TEST\""].map(str::to_owned);
+        let (_, output) = run(
+            &env,
+            &[("FAAM_TEST", "1".to_owned())],
+            None,
+            Duration::from_secs(10),
+        )
+        .unwrap();
+        assert_eq!(output, b"|1\n");
+    }
+}
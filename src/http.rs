@@ -0,0 +1,130 @@
+//! A minimal HTTP/1.1 client, for the monitoring integrations: one request per connection,
+//! over TLS for `https://`, and only the status of the response.
+
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::TcpStream;
+use std::time::Duration;
+
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The parts of `http[s]://<host>[:<port>][<path and query>]`.
+#[derive(Debug, PartialEq)]
+pub struct Url<'a> {
+    pub tls: bool,
+    pub host: &'a str,
+    pub port: u16,
+    pub path: &'a str,
+}
+
+impl<'a> Url<'a> {
+    pub fn parse(url: &'a str) -> io::Result<Self> {
+        let invalid = || io::Error::other(format!("invalid URL {url:?}"));
+        let (tls, rest) = if let Some(rest) = url.strip_prefix("https://") {
+            (true, rest)
+        } else if let Some(rest) = url.strip_prefix("http://") {
+            (false, rest)
+        } else {
+            return Err(invalid());
+        };
+        let (authority, path) = match rest.find(['/', '?']) {
+            Some(i) => (&rest[..i], &rest[i..]),
+            None => (rest, "/"),
+        };
+        let (host, port) = match authority.rsplit_once(':') {
+            Some((host, port)) => (host, port.parse().map_err(|_| invalid())?),
+            None => (authority, if tls { 443 } else { 80 }),
+        };
+        if host.is_empty() || host.contains('@') || path.contains([' ', '\r', '\n']) {
+            return Err(invalid());
+        }
+        Ok(Url {
+            tls,
+            host,
+            port,
+            path,
+        })
+    }
+}
+
+/// Send a request to `url`, and return the status code of the response.
+pub fn request(method: &str, url: &str, headers: &[(&str, &str)], body: &[u8]) -> io::Result<u16> {
+    let url = Url::parse(url)?;
+    let mut request = format!(
+        "{method} {} HTTP/1.1\r\nHost: {}\r\nUser-Agent: forward-as-attachment-mta/{}\r\n",
+        // A query without a path, as in `https://example.com?status=up`.
+        if url.path.starts_with('?') {
+            format!("/{}", url.path)
+        } else {
+            url.path.to_owned()
+        },
+        if url.port == if url.tls { 443 } else { 80 } {
+            url.host.to_owned()
+        } else {
+            format!("{}:{}", url.host, url.port)
+        },
+        env!("CARGO_PKG_VERSION"),
+    );
+    for (name, value) in headers {
+        request.push_str(&format!("{name}: {value}\r\n"));
+    }
+    request.push_str(&format!(
+        "Content-Length: {}\r\nConnection: close\r\n\r\n",
+        body.len()
+    ));
+    let mut request = request.into_bytes();
+    request.extend_from_slice(body);
+    let status = if url.tls {
+        let stream = crate::verify::tls_connect(url.host, url.port)?;
+        stream.sock.set_read_timeout(Some(TIMEOUT))?;
+        stream.sock.set_write_timeout(Some(TIMEOUT))?;
+        exchange(stream, &request)?
+    } else {
+        let stream = TcpStream::connect((url.host, url.port))?;
+        stream.set_read_timeout(Some(TIMEOUT))?;
+        stream.set_write_timeout(Some(TIMEOUT))?;
+        exchange(stream, &request)?
+    };
+    status
+        .split(' ')
+        .nth(1)
+        .and_then(|code| code.parse().ok())
+        .ok_or_else(|| io::Error::other(format!("invalid response {status:?}")))
+}
+
+/// Returns the status line.
+fn exchange<S: Read + Write>(mut stream: S, request: &[u8]) -> io::Result<String> {
+    stream.write_all(request)?;
+    stream.flush()?;
+    let mut status = String::new();
+    BufReader::new(stream).read_line(&mut status)?;
+    Ok(status.trim_end().to_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(
+            Url::parse("https://kuma.example.com/api/push/0123abcd?status=up&msg=OK").unwrap(),
+            Url {
+                tls: true,
+                host: "kuma.example.com",
+                port: 443,
+                path: "/api/push/0123abcd?status=up&msg=OK",
+            }
+        );
+        assert_eq!(
+            Url::parse("http://localhost:8000?x").unwrap(),
+            Url {
+                tls: false,
+                host: "localhost",
+                port: 8000,
+                path: "?x",
+            }
+        );
+        assert!(Url::parse("ftp://example.com/").is_err());
+        assert!(Url::parse("https://user@example.com/").is_err());
+    }
+}
@@ -0,0 +1,375 @@
+//! Translations of the fixed sentences in the wrapper text.
+//!
+//! Sentences with parameters use `%name%` placeholders, see [`fill`].
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Language {
+    #[default]
+    En,
+    De,
+    Fr,
+    Es,
+}
+
+pub struct Catalog {
+    /// The default body template, see `body`.
+    pub template: &'static str,
+    pub config_too_lax: &'static str,
+    pub config_permissions_unknown: &'static str,
+    pub attached: &'static str,
+    pub attached_inline: &'static str,
+    pub binary: &'static str,
+    pub empty: &'static str,
+    pub stdin_timed_out: &'static str,
+    pub sender_source: &'static str,
+    pub multiple_from: &'static str,
+    pub escalated: &'static str,
+    pub routed: &'static str,
+    pub encrypted: &'static str,
+    pub details_encrypted: &'static str,
+    pub captured: &'static str,
+    pub input: &'static str,
+    pub historical: &'static str,
+    pub crashed: &'static str,
+    pub failures: &'static str,
+    pub recipient_args_ignored: &'static str,
+    pub overrides_applied: &'static str,
+    pub truncated: &'static str,
+    pub truncated_for_relay: &'static str,
+    pub kept_at: &'static str,
+    pub not_kept: &'static str,
+    pub compressed: &'static str,
+    pub journal_attached: &'static str,
+    pub tail_preview: &'static str,
+    pub original_headers: &'static str,
+    pub cron_context: &'static str,
+    pub invoking_process: &'static str,
+    pub environment: &'static str,
+    pub submitted_at: &'static str,
+    pub delivered_at: &'static str,
+    pub sequence: &'static str,
+}
+
+impl Language {
+    pub fn catalog(self) -> &'static Catalog {
+        match self {
+            Language::En => &EN,
+            Language::De => &DE,
+            Language::Fr => &FR,
+            Language::Es => &ES,
+        }
+    }
+}
+
+/// Replace the `%name%` placeholders in `msg`.
+pub fn fill(msg: &str, params: &[(&str, &str)]) -> String {
+    crate::template::expand(msg, |name| {
+        params
+            .iter()
+            .find(|(k, _)| *k == name)
+            .map(|(_, v)| v.to_string())
+    })
+}
+
+pub static EN: Catalog = Catalog {
+    template: "\
+A process on host \"%hostname%\" invoked the sendmail binary.
+On that host, the sendmail binary is provided by the forwad-as-attachment-mta package.
+%config_warnings%
+%attachment_note%
+
+%original_headers%%cron_context%%tail_preview%%provenance%%environment%%timestamps%Invocation args: %args%
+Queue ID: %queue_id%
+%sequence%Original: %original_size% bytes, SHA-256 %original_sha256%
+%notes%
+uid:%uid% gid:%gid% euid:%euid% egid:%egid%
+username: %username%
+groupname: %groupname%
+effective username: %effective_username%
+effective groupname: %effective_groupname%
+
+hostname: %system_hostname%
+device name: %device_name%
+distro: %distro%
+platform: %platform%
+
+",
+    config_too_lax: "WARNING: the config file contains SMTP credentials and has too-lax permissions: %permissions%",
+    config_permissions_unknown: "WARNING: could not determine permissions of the config file, they may or may not be too lax: %error%",
+    attached: "The original message is attached to this wrapper message.",
+    attached_inline: " For convenience, a re-encoded copy is attached inline.",
+    binary: "The input doesn't look like a mail message or text, it is attached as-is as %filename%.",
+    empty: "The submission was empty (zero bytes), nothing is attached.",
+    stdin_timed_out: "Reading the input timed out (%reason%), the attached original may be incomplete.",
+    sender_source: "There is no usable From header, the sender %sender% is taken from the %header% header.",
+    multiple_from: "The original has multiple From headers: %values%",
+    escalated: "This message occurred %count% times within %secs% seconds (escalate_after), the earlier occurrences were not forwarded:",
+    routed: "Sent to %recipient% as per the [[route]] rule %rule%.",
+    encrypted: "The original is encrypted with OpenPGP to pgp_recipient_key, see %filename%.",
+    details_encrypted: "The summary of this submission, with the journal and the invocation details, is encrypted as well, see %filename%.",
+    captured: "The input was captured to %path% for debugging.",
+    input: "The original was read from %path% (--input).",
+    historical: "This is historical mail: it was delivered to %source% on this host at %time%, and forwarded with migrate-spool.",
+    crashed: "A previous invocation of forward-as-attachment-mta on this host crashed, see the report (%path%):",
+    failures: "Deliveries that failed since the last one that went through (%n%):",
+    recipient_args_ignored: "Recipient args not in allowlist, ignored: %args%",
+    overrides_applied: "Applied config overrides: %section%",
+    truncated: "The original (%size% bytes) exceeds %max% bytes, only its first and last %keep% KiB are attached.",
+    truncated_for_relay: "The relay accepts messages of at most %limit% bytes, so only the first and last %keep% KiB of the original (%size% bytes) are attached.",
+    kept_at: "The full original is kept on this host at %path%.",
+    not_kept: "The full original could not be kept on this host, see the log.",
+    compressed: "The original (%size% bytes) is attached compressed as %filename% (%compressed_size% bytes).",
+    journal_attached: "The journal of %unit% from the last %minutes% minutes is attached as journal.txt.",
+    tail_preview: "Last %n% lines of the original message:",
+    original_headers: "Original headers:",
+    cron_context: "Cron context:",
+    invoking_process: "Invoking process (pid %pid%):",
+    environment: "Environment (allowlisted variables only):",
+    submitted_at: "Submitted at: %time%",
+    delivered_at: "Delivered at: %time% (delayed by %secs%s)",
+    sequence: "Sequence number: %seq%",
+};
+
+pub static DE: Catalog = Catalog {
+    template: "\
+Ein Prozess auf dem Host \"%hostname%\" hat das sendmail-Programm aufgerufen.
+Auf diesem Host wird sendmail vom Paket forward-as-attachment-mta bereitgestellt.
+%config_warnings%
+%attachment_note%
+
+%original_headers%%cron_context%%tail_preview%%provenance%%environment%%timestamps%Aufrufargumente: %args%
+Queue-ID: %queue_id%
+%sequence%Original: %original_size% Bytes, SHA-256 %original_sha256%
+%notes%
+uid:%uid% gid:%gid% euid:%euid% egid:%egid%
+Benutzername: %username%
+Gruppenname: %groupname%
+effektiver Benutzername: %effective_username%
+effektiver Gruppenname: %effective_groupname%
+
+Hostname: %system_hostname%
+Gerätename: %device_name%
+Distribution: %distro%
+Plattform: %platform%
+
+",
+    config_too_lax: "WARNUNG: die Konfigurationsdatei enthält SMTP-Zugangsdaten und hat zu offene Berechtigungen: %permissions%",
+    config_permissions_unknown: "WARNUNG: die Berechtigungen der Konfigurationsdatei konnten nicht ermittelt werden, sie sind möglicherweise zu offen: %error%",
+    attached: "Die ursprüngliche Nachricht ist als Anhang beigefügt.",
+    attached_inline: " Zur Ansicht ist zusätzlich eine neu kodierte Kopie inline angehängt.",
+    binary: "Die Eingabe sieht nicht nach einer E-Mail oder Text aus, sie ist unverändert als %filename% angehängt.",
+    empty: "Die Einreichung war leer (null Bytes), es ist nichts angehängt.",
+    stdin_timed_out: "Beim Lesen der Eingabe kam es zu einer Zeitüberschreitung (%reason%), das angehängte Original ist möglicherweise unvollständig.",
+    sender_source: "Es gibt keinen verwendbaren From-Header, der Absender %sender% stammt aus dem %header%-Header.",
+    multiple_from: "Das Original hat mehrere From-Header: %values%",
+    escalated: "Diese Nachricht trat %count% Mal innerhalb von %secs% Sekunden auf (escalate_after), die früheren Vorkommen wurden nicht weitergeleitet:",
+    routed: "An %recipient% gesendet gemäß der [[route]]-Regel %rule%.",
+    encrypted: "Das Original ist mit OpenPGP für pgp_recipient_key verschlüsselt, siehe %filename%.",
+    details_encrypted: "Die Zusammenfassung dieser Einlieferung, mit dem Journal und den Details des Aufrufs, ist ebenfalls verschlüsselt, siehe %filename%.",
+    captured: "Die Eingabe wurde zur Fehlersuche in %path% gespeichert.",
+    input: "Das Original wurde aus %path% gelesen (--input).",
+    historical: "Dies ist historische Post: Sie wurde auf diesem Host am %time% an %source% zugestellt und mit migrate-spool weitergeleitet.",
+    crashed: "Ein früherer Aufruf von forward-as-attachment-mta auf diesem Host ist abgestürzt, siehe den Bericht (%path%):",
+    failures: "Zustellungen, die seit der letzten erfolgreichen fehlgeschlagen sind (%n%):",
+    recipient_args_ignored: "Empfänger-Argumente nicht in der Allowlist, ignoriert: %args%",
+    overrides_applied: "Angewendete Konfigurationsüberschreibungen: %section%",
+    truncated: "Das Original (%size% Bytes) ist größer als %max% Bytes, nur die ersten und letzten %keep% KiB sind angehängt.",
+    truncated_for_relay: "Der Relay-Server akzeptiert Nachrichten bis höchstens %limit% Bytes, daher sind nur die ersten und letzten %keep% KiB des Originals (%size% Bytes) angehängt.",
+    kept_at: "Das vollständige Original liegt auf diesem Host unter %path%.",
+    not_kept: "Das vollständige Original konnte auf diesem Host nicht aufbewahrt werden, siehe Log.",
+    compressed: "Das Original (%size% Bytes) ist komprimiert als %filename% angehängt (%compressed_size% Bytes).",
+    journal_attached: "Das Journal von %unit% der letzten %minutes% Minuten ist als journal.txt angehängt.",
+    tail_preview: "Letzte %n% Zeilen der ursprünglichen Nachricht:",
+    original_headers: "Ursprüngliche Header:",
+    cron_context: "Cron-Kontext:",
+    invoking_process: "Aufrufender Prozess (PID %pid%):",
+    environment: "Umgebung (nur freigegebene Variablen):",
+    submitted_at: "Eingeliefert: %time%",
+    delivered_at: "Zugestellt: %time% (um %secs%s verzögert)",
+    sequence: "Laufende Nummer: %seq%",
+};
+
+pub static FR: Catalog = Catalog {
+    template: "\
+Un processus sur l'hôte \"%hostname%\" a appelé le programme sendmail.
+Sur cet hôte, sendmail est fourni par le paquet forward-as-attachment-mta.
+%config_warnings%
+%attachment_note%
+
+%original_headers%%cron_context%%tail_preview%%provenance%%environment%%timestamps%Arguments d'appel : %args%
+ID de file : %queue_id%
+%sequence%Original : %original_size% octets, SHA-256 %original_sha256%
+%notes%
+uid:%uid% gid:%gid% euid:%euid% egid:%egid%
+nom d'utilisateur : %username%
+nom de groupe : %groupname%
+nom d'utilisateur effectif : %effective_username%
+nom de groupe effectif : %effective_groupname%
+
+nom d'hôte : %system_hostname%
+nom de l'appareil : %device_name%
+distribution : %distro%
+plateforme : %platform%
+
+",
+    config_too_lax: "ATTENTION : le fichier de configuration contient des identifiants SMTP et ses permissions sont trop larges : %permissions%",
+    config_permissions_unknown: "ATTENTION : impossible de déterminer les permissions du fichier de configuration, elles sont peut-être trop larges : %error%",
+    attached: "Le message original est joint à ce message.",
+    attached_inline: " Pour plus de commodité, une copie réencodée est jointe en ligne.",
+    binary: "L'entrée ne ressemble ni à un e-mail ni à du texte, elle est jointe telle quelle en tant que %filename%.",
+    empty: "La soumission était vide (zéro octet), rien n'est joint.",
+    stdin_timed_out: "La lecture de l'entrée a expiré (%reason%), l'original joint est peut-être incomplet.",
+    sender_source: "Il n'y a pas d'en-tête From utilisable, l'expéditeur %sender% est tiré de l'en-tête %header%.",
+    multiple_from: "L'original a plusieurs en-têtes From : %values%",
+    escalated: "Ce message est apparu %count% fois en %secs% secondes (escalate_after), les occurrences précédentes n'ont pas été transférées :",
+    routed: "Envoyé à %recipient% selon la règle [[route]] %rule%.",
+    encrypted: "L'original est chiffré avec OpenPGP pour pgp_recipient_key, voir %filename%.",
+    details_encrypted: "Le résumé de cette soumission, avec le journal et les détails de l'appel, est également chiffré, voir %filename%.",
+    captured: "L'entrée a été enregistrée dans %path% pour le débogage.",
+    input: "L'original a été lu depuis %path% (--input).",
+    historical: "Ceci est un courrier historique : il a été distribué dans %source% sur cet hôte le %time%, et transféré avec migrate-spool.",
+    crashed: "Une invocation précédente de forward-as-attachment-mta sur cet hôte a planté, voir le rapport (%path%) :",
+    failures: "Distributions échouées depuis la dernière qui a abouti (%n%) :",
+    recipient_args_ignored: "Destinataires en argument absents de la liste autorisée, ignorés : %args%",
+    overrides_applied: "Surcharges de configuration appliquées : %section%",
+    truncated: "L'original (%size% octets) dépasse %max% octets, seuls ses %keep% premiers et derniers Kio sont joints.",
+    truncated_for_relay: "Le relais accepte des messages d'au plus %limit% octets, seuls les %keep% premiers et derniers Kio de l'original (%size% octets) sont donc joints.",
+    kept_at: "L'original complet est conservé sur cet hôte dans %path%.",
+    not_kept: "L'original complet n'a pas pu être conservé sur cet hôte, voir le journal.",
+    compressed: "L'original (%size% octets) est joint compressé sous le nom %filename% (%compressed_size% octets).",
+    journal_attached: "Le journal de %unit% des %minutes% dernières minutes est joint en tant que journal.txt.",
+    tail_preview: "%n% dernières lignes du message original :",
+    original_headers: "En-têtes originaux :",
+    cron_context: "Contexte cron :",
+    invoking_process: "Processus appelant (pid %pid%) :",
+    environment: "Environnement (variables autorisées uniquement) :",
+    submitted_at: "Soumis le : %time%",
+    delivered_at: "Distribué le : %time% (retardé de %secs% s)",
+    sequence: "Numéro de séquence : %seq%",
+};
+
+pub static ES: Catalog = Catalog {
+    template: "\
+Un proceso en el host \"%hostname%\" invocó el programa sendmail.
+En ese host, sendmail lo proporciona el paquete forward-as-attachment-mta.
+%config_warnings%
+%attachment_note%
+
+%original_headers%%cron_context%%tail_preview%%provenance%%environment%%timestamps%Argumentos de invocación: %args%
+ID de cola: %queue_id%
+%sequence%Original: %original_size% bytes, SHA-256 %original_sha256%
+%notes%
+uid:%uid% gid:%gid% euid:%euid% egid:%egid%
+nombre de usuario: %username%
+nombre de grupo: %groupname%
+nombre de usuario efectivo: %effective_username%
+nombre de grupo efectivo: %effective_groupname%
+
+nombre de host: %system_hostname%
+nombre del dispositivo: %device_name%
+distribución: %distro%
+plataforma: %platform%
+
+",
+    config_too_lax: "ADVERTENCIA: el archivo de configuración contiene credenciales SMTP y tiene permisos demasiado amplios: %permissions%",
+    config_permissions_unknown: "ADVERTENCIA: no se pudieron determinar los permisos del archivo de configuración, podrían ser demasiado amplios: %error%",
+    attached: "El mensaje original se adjunta a este mensaje.",
+    attached_inline: " Para mayor comodidad, se adjunta en línea una copia recodificada.",
+    binary: "La entrada no parece un correo ni texto, se adjunta tal cual como %filename%.",
+    empty: "El envío estaba vacío (cero bytes), no se adjunta nada.",
+    stdin_timed_out: "La lectura de la entrada superó el tiempo límite (%reason%), el original adjunto puede estar incompleto.",
+    sender_source: "No hay una cabecera From utilizable, el remitente %sender% se toma de la cabecera %header%.",
+    multiple_from: "El original tiene varias cabeceras From: %values%",
+    escalated: "Este mensaje apareció %count% veces en %secs% segundos (escalate_after), las apariciones anteriores no se reenviaron:",
+    routed: "Enviado a %recipient% según la regla [[route]] %rule%.",
+    encrypted: "El original está cifrado con OpenPGP para pgp_recipient_key, véase %filename%.",
+    details_encrypted: "El resumen de este envío, con el journal y los detalles de la invocación, también está cifrado, véase %filename%.",
+    captured: "La entrada se guardó en %path% para depuración.",
+    input: "El original se leyó de %path% (--input).",
+    historical: "Este es correo histórico: se entregó a %source% en este host el %time%, y se reenvió con migrate-spool.",
+    crashed: "Una invocación anterior de forward-as-attachment-mta en este host falló, vea el informe (%path%):",
+    failures: "Entregas fallidas desde la última que se completó (%n%):",
+    recipient_args_ignored: "Destinatarios en argumentos fuera de la lista permitida, ignorados: %args%",
+    overrides_applied: "Sobrescrituras de configuración aplicadas: %section%",
+    truncated: "El original (%size% bytes) supera los %max% bytes, solo se adjuntan sus primeros y últimos %keep% KiB.",
+    truncated_for_relay: "El relay acepta mensajes de como máximo %limit% bytes, por lo que solo se adjuntan los primeros y últimos %keep% KiB del original (%size% bytes).",
+    kept_at: "El original completo se guarda en este host en %path%.",
+    not_kept: "No se pudo guardar el original completo en este host, consulte el registro.",
+    compressed: "El original (%size% bytes) se adjunta comprimido como %filename% (%compressed_size% bytes).",
+    journal_attached: "El journal de %unit% de los últimos %minutes% minutos se adjunta como journal.txt.",
+    tail_preview: "Últimas %n% líneas del mensaje original:",
+    original_headers: "Cabeceras originales:",
+    cron_context: "Contexto de cron:",
+    invoking_process: "Proceso invocante (pid %pid%):",
+    environment: "Entorno (solo variables permitidas):",
+    submitted_at: "Enviado: %time%",
+    delivered_at: "Entregado: %time% (con %secs% s de retraso)",
+    sequence: "Número de secuencia: %seq%",
+};
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::BTreeSet;
+
+    fn placeholders(s: &str) -> BTreeSet<String> {
+        let found = std::cell::RefCell::new(BTreeSet::new());
+        crate::template::expand(s, |name| {
+            found.borrow_mut().insert(name.to_owned());
+            Some(String::new())
+        });
+        found.into_inner()
+    }
+
+    #[test]
+    fn test_catalogs_use_the_same_placeholders() {
+        for lang in [Language::De, Language::Fr, Language::Es] {
+            let (en, other) = (Language::En.catalog(), lang.catalog());
+            let pairs = [
+                (en.template, other.template),
+                (en.config_too_lax, other.config_too_lax),
+                (
+                    en.config_permissions_unknown,
+                    other.config_permissions_unknown,
+                ),
+                (en.recipient_args_ignored, other.recipient_args_ignored),
+                (en.overrides_applied, other.overrides_applied),
+                (en.truncated, other.truncated),
+                (en.truncated_for_relay, other.truncated_for_relay),
+                (en.kept_at, other.kept_at),
+                (en.compressed, other.compressed),
+                (en.binary, other.binary),
+                (en.stdin_timed_out, other.stdin_timed_out),
+                (en.sender_source, other.sender_source),
+                (en.multiple_from, other.multiple_from),
+                (en.escalated, other.escalated),
+                (en.routed, other.routed),
+                (en.encrypted, other.encrypted),
+                (en.details_encrypted, other.details_encrypted),
+                (en.captured, other.captured),
+                (en.input, other.input),
+                (en.historical, other.historical),
+                (en.crashed, other.crashed),
+                (en.failures, other.failures),
+                (en.journal_attached, other.journal_attached),
+                (en.tail_preview, other.tail_preview),
+                (en.invoking_process, other.invoking_process),
+                (en.submitted_at, other.submitted_at),
+                (en.delivered_at, other.delivered_at),
+                (en.sequence, other.sequence),
+            ];
+            for (en, other) in pairs {
+                assert_eq!(placeholders(en), placeholders(other), "{lang:?}: {other}");
+            }
+        }
+    }
+
+    #[test]
+    fn test_fill() {
+        assert_eq!(
+            fill(EN.tail_preview, &[("n", "3")]),
+            "Last 3 lines of the original message:"
+        );
+    }
+}
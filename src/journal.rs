@@ -0,0 +1,94 @@
+//! Access to the systemd journal, through `journalctl`.
+
+use std::io::Read;
+use std::process::Stdio;
+use std::time::{Duration, Instant};
+
+use crate::process::SystemdUnit;
+
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Run `journalctl` with the given arguments, killing it if it takes too long.
+pub fn journalctl(args: &[String]) -> std::io::Result<Vec<u8>> {
+    let mut child = crate::hardening::command("journalctl")
+        .args(["--no-pager", "--quiet"])
+        .args(args)
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .spawn()?;
+    let mut stdout = child.stdout.take().expect("we requested a pipe");
+    let reader = std::thread::spawn(move || {
+        let mut output = Vec::new();
+        stdout.read_to_end(&mut output).map(|_| output)
+    });
+    let deadline = Instant::now() + TIMEOUT;
+    let status = loop {
+        if let Some(status) = child.try_wait()? {
+            break status;
+        }
+        if Instant::now() > deadline {
+            let _ = child.kill();
+            let _ = child.wait();
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::TimedOut,
+                "journalctl timed out",
+            ));
+        }
+        std::thread::sleep(Duration::from_millis(50));
+    };
+    let output = reader.join().expect("reader doesn't panic")?;
+    if !status.success() {
+        return Err(std::io::Error::other(format!(
+            "journalctl exited with {status}"
+        )));
+    }
+    Ok(output)
+}
+
+/// The journal entries of `unit` from the last `minutes`. Only those logged by `caller_uid` if
+/// that isn't root: as setuid root we can read the whole journal, the caller maybe not, and they
+/// could have placed themselves into any cgroup's unit name.
+pub fn recent_entries(
+    unit: &SystemdUnit,
+    minutes: u64,
+    caller_uid: u32,
+) -> std::io::Result<Vec<u8>> {
+    journalctl(&recent_entries_args(unit, minutes, caller_uid))
+}
+
+fn recent_entries_args(unit: &SystemdUnit, minutes: u64, caller_uid: u32) -> Vec<String> {
+    let field = if unit.user_manager {
+        "_SYSTEMD_USER_UNIT"
+    } else {
+        "_SYSTEMD_UNIT"
+    };
+    let mut args = vec![
+        "--output=short-iso".to_owned(),
+        format!("--since=-{minutes}min"),
+        format!("{field}={}", unit.unit),
+    ];
+    // Matches on different fields must all hold.
+    if caller_uid != 0 {
+        args.push(format!("_UID={caller_uid}"));
+    }
+    args
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_recent_entries_args() {
+        let unit = SystemdUnit {
+            unit: "backup.service".to_owned(),
+            slice: None,
+            user_manager: false,
+        };
+        let args = recent_entries_args(&unit, 5, 1000);
+        assert_eq!(args[2..], ["_SYSTEMD_UNIT=backup.service", "_UID=1000"]);
+        let args = recent_entries_args(&unit, 5, 0);
+        assert_eq!(args[2..], ["_SYSTEMD_UNIT=backup.service"]);
+    }
+}
@@ -0,0 +1,106 @@
+//! The backend of `log_target = "journald"`: native journal entries, so that `journalctl` and
+//! alerting rules can match on the fields, e.g. `journalctl FAAM_RESULT=failed`.
+//!
+//! An event's fields become `FAAM_<NAME>` fields, next to `FAAM_QUEUE_ID`. The protocol is
+//! documented at <https://systemd.io/JOURNAL_NATIVE_PROTOCOL/>.
+
+use std::os::unix::net::UnixDatagram;
+use std::sync::OnceLock;
+
+use tracing::{Event, Level, Subscriber};
+use tracing_subscriber::layer::Context;
+
+use crate::logging::{self, Fields};
+
+const SOCKET: &str = "/run/systemd/journal/socket";
+
+pub struct Layer;
+
+impl<S: Subscriber> tracing_subscriber::Layer<S> for Layer {
+    fn on_event(&self, event: &Event<'_>, _: Context<'_, S>) {
+        static CLIENT: OnceLock<Option<UnixDatagram>> = OnceLock::new();
+        let Some(socket) = CLIENT.get_or_init(|| UnixDatagram::unbound().ok()) else {
+            return;
+        };
+        let entry = entry(*event.metadata().level(), &Fields::of(event));
+        // Logging mustn't fail the delivery; without a journal, entries are lost.
+        let _ = socket.send_to(&entry, SOCKET);
+    }
+}
+
+fn priority(level: Level) -> &'static str {
+    match level {
+        Level::ERROR => "3",
+        Level::WARN => "4",
+        Level::INFO => "6",
+        Level::DEBUG | Level::TRACE => "7",
+    }
+}
+
+/// `to` becomes `FAAM_TO`, `original_sender` `FAAM_ORIGINAL_SENDER`.
+fn field_name(name: &str) -> String {
+    let name = name
+        .chars()
+        .map(|c| match c {
+            'a'..='z' | 'A'..='Z' | '0'..='9' => c.to_ascii_uppercase(),
+            _ => '_',
+        })
+        .collect::<String>();
+    format!("FAAM_{name}")
+}
+
+fn push(entry: &mut Vec<u8>, name: &str, value: &str) {
+    entry.extend_from_slice(name.as_bytes());
+    if value.contains('\n') {
+        // Multi-line values are length-prefixed instead of `=`-separated.
+        entry.push(b'\n');
+        entry.extend_from_slice(&(value.len() as u64).to_le_bytes());
+    } else {
+        entry.push(b'=');
+    }
+    entry.extend_from_slice(value.as_bytes());
+    entry.push(b'\n');
+}
+
+fn entry(level: Level, fields: &Fields) -> Vec<u8> {
+    let mut entry = Vec::new();
+    push(&mut entry, "MESSAGE", &fields.message);
+    push(&mut entry, "PRIORITY", priority(level));
+    push(&mut entry, "SYSLOG_IDENTIFIER", "forward-as-attachment-mta");
+    // Mail, like the syslog target.
+    push(&mut entry, "SYSLOG_FACILITY", "2");
+    push(&mut entry, "SYSLOG_PID", &std::process::id().to_string());
+    if let Some(queue_id) = logging::queue_id() {
+        push(&mut entry, "FAAM_QUEUE_ID", queue_id);
+    }
+    for (name, value) in &fields.fields {
+        push(&mut entry, &field_name(name), &logging::text(value));
+    }
+    entry
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_entry() {
+        let fields = Fields {
+            message: "delivery failed".to_owned(),
+            fields: vec![
+                ("original_sender", "root@host".into()),
+                ("e", "550\nno".into()),
+            ],
+        };
+        let entry = String::from_utf8(entry(Level::WARN, &fields)).unwrap();
+        let pid = std::process::id();
+        assert_eq!(
+            entry,
+            format!(
+                "MESSAGE=delivery failed\nPRIORITY=4\nSYSLOG_IDENTIFIER=forward-as-attachment-mta\n\
+                 SYSLOG_FACILITY=2\nSYSLOG_PID={pid}\nFAAM_ORIGINAL_SENDER=root@host\n\
+                 FAAM_E\n\x06\0\0\0\0\0\0\0550\nno\n"
+            )
+        );
+    }
+}
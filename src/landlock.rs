@@ -0,0 +1,258 @@
+//! Landlock sandboxing: after startup, the process (and the commands it runs) can only access
+//! the files it needs, so that even a compromised process can't read arbitrary files as root.
+//!
+//! Network access isn't restricted. The constants are from `linux/landlock.h`, which the `libc`
+//! crate doesn't expose.
+
+use std::io;
+use std::os::fd::{AsRawFd, OwnedFd};
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
+
+use crate::config::Config;
+
+const CREATE_RULESET_VERSION: u32 = 1 << 0;
+const RULE_PATH_BENEATH: libc::c_int = 1;
+
+const ACCESS_FS_EXECUTE: u64 = 1 << 0;
+const ACCESS_FS_WRITE_FILE: u64 = 1 << 1;
+const ACCESS_FS_READ_FILE: u64 = 1 << 2;
+const ACCESS_FS_READ_DIR: u64 = 1 << 3;
+/// `REMOVE_DIR` up to `MAKE_SYM`, all ABI 1.
+const ACCESS_FS_MODIFY_DIR: u64 = 0x1ff0;
+/// ABI 2.
+const ACCESS_FS_REFER: u64 = 1 << 13;
+/// ABI 3.
+const ACCESS_FS_TRUNCATE: u64 = 1 << 14;
+/// The rights that apply to files, as opposed to directories.
+const ACCESS_FILE: u64 =
+    ACCESS_FS_EXECUTE | ACCESS_FS_WRITE_FILE | ACCESS_FS_READ_FILE | ACCESS_FS_TRUNCATE;
+
+#[repr(C)]
+struct RulesetAttr {
+    handled_access_fs: u64,
+}
+
+#[repr(C, packed)]
+struct PathBeneathAttr {
+    allowed_access: u64,
+    parent_fd: libc::c_int,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Access {
+    Read,
+    /// Read and execute, for the commands we run and their libraries.
+    Execute,
+    /// Anything, e.g. for `state_dir`.
+    Write,
+}
+
+impl Access {
+    fn rights(self) -> u64 {
+        let read = ACCESS_FS_READ_FILE | ACCESS_FS_READ_DIR;
+        match self {
+            Access::Read => read,
+            Access::Execute => read | ACCESS_FS_EXECUTE,
+            Access::Write => u64::MAX,
+        }
+    }
+}
+
+/// What the forwarder needs after startup, with `config_file` the config we read.
+pub fn rules(config: &Config, config_file: &Path) -> Vec<(PathBuf, Access)> {
+    let mut rules = vec![
+        (config_file.to_owned(), Access::Read),
+        (config.state_dir.clone(), Access::Write),
+        // gpg's home directory.
+        (std::env::temp_dir(), Access::Write),
+        ("/dev/null".into(), Access::Write),
+        ("/dev/urandom".into(), Access::Read),
+        // Where the parent and the systemd unit are looked up.
+        ("/proc".into(), Access::Read),
+        ("/var/log/journal".into(), Access::Read),
+        ("/run/log/journal".into(), Access::Read),
+    ];
+    for dir in ["/usr", "/bin", "/sbin", "/lib", "/lib32", "/lib64"] {
+        rules.push((dir.into(), Access::Execute));
+    }
+    for path in [
+        // Resolver and user lookups.
+        "/etc/resolv.conf",
+        "/etc/hosts",
+        "/etc/host.conf",
+        "/etc/nsswitch.conf",
+        "/etc/gai.conf",
+        "/etc/services",
+        "/etc/passwd",
+        "/etc/group",
+        "/etc/ld.so.cache",
+        "/etc/localtime",
+        "/etc/machine-id",
+        // CA certificates and the OpenSSL config.
+        "/etc/ssl",
+        "/etc/pki",
+        "/etc/ca-certificates",
+    ] {
+        rules.push((path.into(), Access::Read));
+    }
+    if config.log_target == crate::logging::Target::File {
+        // Rotation renames within the log file's directory.
+        if let Some(dir) = config.log_file.parent() {
+            rules.push((dir.to_owned(), Access::Write));
+        }
+    }
+    // The hooks, wherever they are installed.
+    for hook in [&config.pre_send_hook, &config.post_send_hook] {
+        if let Some(program) = hook.first().filter(|p| p.starts_with('/')) {
+            rules.push((program.into(), Access::Execute));
+        }
+    }
+    // The textfile is renamed into place.
+    if let Some(dir) = config.metrics_textfile.as_ref().and_then(|f| f.parent()) {
+        rules.push((dir.to_owned(), Access::Write));
+    }
+    // Rotated like the log file.
+    if let Some(dir) = config.audit_log.as_ref().and_then(|f| f.parent()) {
+        rules.push((dir.to_owned(), Access::Write));
+    }
+    if let Some(dir) = &config.debug_capture_dir {
+        rules.push((dir.clone(), Access::Write));
+    }
+    let smime = config
+        .smime
+        .iter()
+        .flat_map(|s| [&s.cert, &s.key, &s.recipient_cert]);
+    for path in [&config.pgp_recipient_key, &config.plugin]
+        .into_iter()
+        .chain(smime)
+        .flatten()
+    {
+        rules.push((path.clone(), Access::Read));
+    }
+    rules
+}
+
+/// Restrict the calling thread, the threads it spawns, and the commands they run to `rules`.
+/// Paths that don't exist are skipped.
+///
+/// Returns the Landlock ABI version, or `None` if the kernel doesn't support Landlock.
+pub fn restrict(rules: &[(PathBuf, Access)]) -> io::Result<Option<i32>> {
+    // SAFETY: with the version flag, the attributes are null
+    let abi = unsafe {
+        libc::syscall(
+            libc::SYS_landlock_create_ruleset,
+            std::ptr::null::<RulesetAttr>(),
+            0,
+            CREATE_RULESET_VERSION,
+        )
+    };
+    if abi < 1 {
+        return Ok(None);
+    }
+    let abi = abi as i32;
+    let mut handled = ACCESS_FS_EXECUTE
+        | ACCESS_FS_WRITE_FILE
+        | ACCESS_FS_READ_FILE
+        | ACCESS_FS_READ_DIR
+        | ACCESS_FS_MODIFY_DIR;
+    if abi >= 2 {
+        handled |= ACCESS_FS_REFER;
+    }
+    if abi >= 3 {
+        handled |= ACCESS_FS_TRUNCATE;
+    }
+    let attr = RulesetAttr {
+        handled_access_fs: handled,
+    };
+    // SAFETY: the attributes are valid for their size
+    let ruleset = unsafe {
+        libc::syscall(
+            libc::SYS_landlock_create_ruleset,
+            &attr,
+            std::mem::size_of::<RulesetAttr>(),
+            0,
+        )
+    };
+    if ruleset < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    // SAFETY: the syscall returned a new file descriptor
+    let ruleset: OwnedFd = unsafe { std::os::fd::FromRawFd::from_raw_fd(ruleset as i32) };
+
+    for (path, access) in rules {
+        let file = match std::fs::File::options()
+            .read(true)
+            .custom_flags(libc::O_PATH)
+            .open(path)
+        {
+            Ok(file) => file,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+            Err(e) => return Err(io::Error::new(e.kind(), format!("{path:?}: {e}"))),
+        };
+        let mut allowed = access.rights() & handled;
+        if !file.metadata()?.is_dir() {
+            allowed &= ACCESS_FILE;
+        }
+        let attr = PathBeneathAttr {
+            allowed_access: allowed,
+            parent_fd: file.as_raw_fd(),
+        };
+        // SAFETY: the attributes are valid, and the file descriptors open
+        let added = unsafe {
+            libc::syscall(
+                libc::SYS_landlock_add_rule,
+                ruleset.as_raw_fd(),
+                RULE_PATH_BENEATH,
+                &attr,
+                0,
+            )
+        };
+        if added != 0 {
+            let e = io::Error::last_os_error();
+            return Err(io::Error::new(e.kind(), format!("{path:?}: {e}")));
+        }
+    }
+
+    // Required to restrict ourselves without CAP_SYS_ADMIN. We don't run setuid commands.
+    // SAFETY: no pointers involved
+    if unsafe { libc::prctl(libc::PR_SET_NO_NEW_PRIVS, 1, 0, 0, 0) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    // SAFETY: the file descriptor is open
+    if unsafe { libc::syscall(libc::SYS_landlock_restrict_self, ruleset.as_raw_fd(), 0) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(Some(abi))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_restrict() {
+        let dir = crate::testdir::TestDir::new("landlock");
+        std::fs::create_dir_all(dir.join("allowed")).unwrap();
+        std::fs::write(dir.join("secret"), "secret").unwrap();
+        let allowed = dir.join("allowed");
+        // Landlock applies to the thread that restricts itself.
+        let (read_allowed, read_secret, write_allowed) = std::thread::spawn(move || {
+            let rules = [(allowed.clone(), Access::Write)];
+            if restrict(&rules).unwrap().is_none() {
+                return (true, false, true);
+            }
+            (
+                std::fs::read_dir(&allowed).is_ok(),
+                std::fs::read(allowed.join("../secret")).is_ok(),
+                std::fs::write(allowed.join("file"), "").is_ok(),
+            )
+        })
+        .join()
+        .unwrap();
+        assert!(read_allowed);
+        assert!(!read_secret);
+        assert!(write_allowed);
+        assert!(std::fs::read(dir.join("secret")).is_ok());
+    }
+}
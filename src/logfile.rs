@@ -0,0 +1,154 @@
+//! The backend of `log_target = "file"`, for hosts without journald, where stderr of cron jobs
+//! is lost: `log_file`, rotated by size to `log_file.1` up to `log_file.<log_file_keep>`.
+//!
+//! Concurrent invocations share the file: each line is written under an exclusive `flock(2)`,
+//! which is also held while rotating.
+
+use std::fs::File;
+use std::io::{self, Write};
+use std::os::fd::AsRawFd;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
+
+use tracing::{Event, Subscriber};
+use tracing_subscriber::layer::Context;
+
+use crate::logging::{self, Fields};
+
+pub const DEFAULT_PATH: &str = "/var/log/forward-as-attachment-mta.log";
+
+#[derive(Debug)]
+pub struct Rotation {
+    pub path: PathBuf,
+    /// Rotate before the file grows beyond this many bytes.
+    pub max_size: u64,
+    /// How many rotated files to keep.
+    pub keep: usize,
+}
+
+static ROTATION: OnceLock<Rotation> = OnceLock::new();
+
+pub fn configure(rotation: Rotation) {
+    let _ = ROTATION.set(rotation);
+}
+
+pub struct Layer;
+
+impl<S: Subscriber> tracing_subscriber::Layer<S> for Layer {
+    fn on_event(&self, event: &Event<'_>, _: Context<'_, S>) {
+        let Some(rotation) = ROTATION.get() else {
+            return;
+        };
+        let fields = Fields::of(event);
+        let line = match logging::format_is(logging::Format::Json) {
+            true => format!("{}\n", fields.json(event.metadata())),
+            false => format!(
+                "{} {} {}\n",
+                crate::time::format_utc(std::time::SystemTime::now()),
+                event.metadata().level(),
+                fields.render(event.metadata())
+            ),
+        };
+        if let Err(e) = rotation.append(line.as_bytes()) {
+            // Not via tracing, that would recurse.
+            eprintln!("can't write to log file {:?}: {e}", rotation.path);
+        }
+    }
+}
+
+/// Hold an exclusive lock on `file` until dropped.
+struct Lock<'a>(&'a File);
+
+impl<'a> Lock<'a> {
+    fn new(file: &'a File) -> io::Result<Self> {
+        // SAFETY: the file descriptor is open
+        if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(Self(file))
+    }
+}
+
+impl Drop for Lock<'_> {
+    fn drop(&mut self) {
+        // SAFETY: the file descriptor is open
+        unsafe { libc::flock(self.0.as_raw_fd(), libc::LOCK_UN) };
+    }
+}
+
+impl Rotation {
+    fn open(&self) -> io::Result<File> {
+        File::options().create(true).append(true).open(&self.path)
+    }
+
+    fn rotated(&self, n: usize) -> PathBuf {
+        let mut path = self.path.clone().into_os_string();
+        path.push(format!(".{n}"));
+        path.into()
+    }
+
+    /// Append `line`, rotating first if it wouldn't fit.
+    pub fn append(&self, line: &[u8]) -> io::Result<()> {
+        loop {
+            let file = self.open()?;
+            let _lock = Lock::new(&file)?;
+            // Another invocation may have rotated it since we opened it.
+            if !same_file(&file, &self.path)? {
+                continue;
+            }
+            let size = file.metadata()?.len();
+            if size > 0 && size + line.len() as u64 > self.max_size {
+                self.rotate()?;
+                continue;
+            }
+            return (&file).write_all(line);
+        }
+    }
+
+    /// Shift `path.N` to `path.N+1`, dropping the oldest, and `path` to `path.1`.
+    fn rotate(&self) -> io::Result<()> {
+        if self.keep == 0 {
+            return std::fs::remove_file(&self.path);
+        }
+        for n in (1..self.keep).rev() {
+            match std::fs::rename(self.rotated(n), self.rotated(n + 1)) {
+                Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+                _ => (),
+            }
+        }
+        std::fs::rename(&self.path, self.rotated(1))
+    }
+}
+
+fn same_file(file: &File, path: &Path) -> io::Result<bool> {
+    let (open, current) = (file.metadata()?, std::fs::metadata(path));
+    match current {
+        Ok(current) => Ok(open.dev() == current.dev() && open.ino() == current.ino()),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(false),
+        Err(e) => Err(e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rotate() {
+        let dir = crate::testdir::TestDir::new("logfile");
+        let rotation = Rotation {
+            path: dir.join("log"),
+            max_size: 10,
+            keep: 2,
+        };
+        for line in ["one\n", "two\n", "three\n", "four\n", "five six seven\n"] {
+            rotation.append(line.as_bytes()).unwrap();
+        }
+        let read = |name| std::fs::read_to_string(dir.join(name)).unwrap();
+        assert_eq!(read("log"), "five six seven\n");
+        assert_eq!(read("log.1"), "four\n");
+        assert_eq!(read("log.2"), "three\n");
+        assert!(!dir.join("log.3").exists());
+    }
+}
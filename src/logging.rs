@@ -0,0 +1,318 @@
+//! Logging setup.
+//!
+//! Logs don't contain credentials or message content unless `log_unsafe_debug` is set: until
+//! then, lettre's trace of the SMTP conversation, which includes `AUTH` and the message, and our
+//! events with [`CONTENT_TARGET`] are dropped.
+//!
+//! Until the config is loaded, logs go to stderr; then to `log_target`.
+
+use std::fmt::Write as _;
+use std::sync::atomic::{AtomicBool, AtomicU8, Ordering};
+use std::sync::OnceLock;
+
+use tracing::field::{Field, Visit};
+use tracing::level_filters::LevelFilter;
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::util::SubscriberInitExt;
+use tracing_subscriber::{reload, EnvFilter, Layer};
+
+use crate::config::Config;
+use crate::json::Value;
+
+/// The target of our events that contain message content.
+pub const CONTENT_TARGET: &str = "forward_as_attachment_mta::content";
+
+/// The module of lettre that traces the SMTP conversation.
+const LETTRE_PROTOCOL_TARGET: &str = "lettre::transport::smtp::client";
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Target {
+    #[default]
+    Stderr,
+    /// Facility mail, as `queue id: message key=value ...`.
+    Syslog,
+    /// Native journal entries, with the fields as `FAAM_*` fields.
+    Journald,
+    /// `log_file`, rotated by size.
+    File,
+}
+
+static UNSAFE_DEBUG: AtomicBool = AtomicBool::new(false);
+static TARGET: AtomicU8 = AtomicU8::new(Target::Stderr as u8);
+static QUEUE_ID: OnceLock<String> = OnceLock::new();
+
+fn sensitive(target: &str) -> bool {
+    target == CONTENT_TARGET || target.starts_with(LETTRE_PROTOCOL_TARGET)
+}
+
+fn target_is(target: Target) -> bool {
+    TARGET.load(Ordering::Relaxed) == target as u8
+}
+
+/// Replaces the filter of the targets.
+type Reload = Box<dyn Fn(EnvFilter) -> Result<(), reload::Error> + Send + Sync>;
+
+static RELOAD: OnceLock<Reload> = OnceLock::new();
+
+/// `RUST_LOG` if set, else `directives`, at `default` for what neither mentions.
+fn env_filter(directives: &str, default: LevelFilter) -> EnvFilter {
+    let builder = EnvFilter::builder().with_default_directive(default.into());
+    match std::env::var("RUST_LOG") {
+        Ok(env) => builder.parse_lossy(env),
+        Err(_) => builder.parse_lossy(directives),
+    }
+}
+
+/// Log to stderr, filtered by `RUST_LOG`.
+pub fn init() {
+    let redact = tracing_subscriber::filter::filter_fn(|metadata| {
+        UNSAFE_DEBUG.load(Ordering::Relaxed) || !sensitive(metadata.target())
+    });
+    let targets = tracing_subscriber::fmt::layer()
+        .with_writer(std::io::stderr)
+        .with_filter(tracing_subscriber::filter::filter_fn(|_| {
+            target_is(Target::Stderr) && format_is(Format::Text)
+        }))
+        .and_then(
+            JsonStderr.with_filter(tracing_subscriber::filter::filter_fn(|_| {
+                target_is(Target::Stderr) && format_is(Format::Json)
+            })),
+        )
+        .and_then(
+            crate::syslog::Layer.with_filter(tracing_subscriber::filter::filter_fn(|_| {
+                target_is(Target::Syslog)
+            })),
+        )
+        .and_then(
+            crate::logfile::Layer.with_filter(tracing_subscriber::filter::filter_fn(|_| {
+                target_is(Target::File)
+            })),
+        )
+        .and_then(
+            crate::journald::Layer.with_filter(tracing_subscriber::filter::filter_fn(|_| {
+                target_is(Target::Journald)
+            })),
+        );
+    let (filter, handle) = reload::Layer::new(env_filter("", LevelFilter::ERROR));
+    let _ = RELOAD.set(Box::new(move |filter| handle.reload(filter)));
+    // Not subject to the log filter, so that the trace is complete.
+    #[cfg(feature = "otlp")]
+    let otlp = Some(crate::otlp::Layer.with_filter(LevelFilter::INFO));
+    #[cfg(not(feature = "otlp"))]
+    let otlp = None::<tracing_subscriber::layer::Identity>;
+    tracing_subscriber::registry()
+        .with(redact)
+        .with(otlp)
+        .with(targets.with_filter(filter))
+        .init();
+}
+
+/// Apply the logging settings of the config. Other targets than stderr log at `info` unless
+/// `log_filter` or `RUST_LOG` say otherwise.
+pub fn configure(config: &Config) -> Result<(), String> {
+    if let Some(directives) = &config.log_filter {
+        EnvFilter::builder()
+            .parse(directives)
+            .map_err(|e| format!("log_filter: {e}"))?;
+    }
+    let default = match config.log_target {
+        Target::Stderr => LevelFilter::ERROR,
+        _ => LevelFilter::INFO,
+    };
+    let filter = env_filter(config.log_filter.as_deref().unwrap_or(""), default);
+    if let Some(reload) = RELOAD.get() {
+        reload(filter).map_err(|e| format!("log_filter: {e}"))?;
+    }
+    if config.log_target == Target::File {
+        crate::logfile::configure(crate::logfile::Rotation {
+            path: config.log_file.clone(),
+            max_size: config.log_file_max_kib as u64 * 1024,
+            keep: config.log_file_keep,
+        });
+    }
+    TARGET.store(config.log_target as u8, Ordering::Relaxed);
+    FORMAT.store(config.log_format as u8, Ordering::Relaxed);
+    UNSAFE_DEBUG.store(config.log_unsafe_debug, Ordering::Relaxed);
+    Ok(())
+}
+
+/// Name the submission in the log lines, once its queue id is known.
+pub fn set_queue_id(queue_id: &str) {
+    let _ = QUEUE_ID.set(queue_id.to_owned());
+}
+
+pub fn queue_id() -> Option<&'static str> {
+    QUEUE_ID.get().map(String::as_str)
+}
+
+/// How the stderr, syslog, and file targets format events.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Format {
+    #[default]
+    Text,
+    /// One JSON object per event, for log shippers.
+    Json,
+}
+
+static FORMAT: AtomicU8 = AtomicU8::new(Format::Text as u8);
+
+pub fn format_is(format: Format) -> bool {
+    FORMAT.load(Ordering::Relaxed) == format as u8
+}
+
+/// An event's message and fields, for the targets that format events themselves.
+#[derive(Default)]
+pub struct Fields {
+    pub message: String,
+    pub fields: Vec<(&'static str, Value)>,
+}
+
+/// A field's value as text, i.e. strings without quotes.
+pub fn text(value: &Value) -> std::borrow::Cow<'_, str> {
+    match value {
+        Value::String(s) => s.into(),
+        value => value.to_string().into(),
+    }
+}
+
+impl Fields {
+    pub fn of(event: &tracing::Event<'_>) -> Self {
+        let mut fields = Self::default();
+        event.record(&mut fields);
+        fields
+    }
+
+    /// On one line, as `message key=value ...`, like postfix logs.
+    pub fn line(&self) -> String {
+        let mut line = self.message.clone();
+        for (name, value) in &self.fields {
+            write!(&mut line, " {name}=").unwrap();
+            let value = text(value);
+            if value.is_empty() || value.contains([' ', '"', '=']) {
+                write!(&mut line, "{value:?}").unwrap();
+            } else {
+                line.push_str(&value);
+            }
+        }
+        line.replace(['\r', '\n'], " ")
+    }
+
+    /// As a JSON object, with the time, level, target, and queue id first.
+    pub fn json(&self, metadata: &tracing::Metadata<'_>) -> String {
+        let mut entries = vec![
+            (
+                "timestamp",
+                Value::from(crate::time::format_utc(std::time::SystemTime::now())),
+            ),
+            ("level", metadata.level().as_str().into()),
+            ("target", metadata.target().into()),
+            ("queue_id", queue_id().into()),
+            ("message", self.message.as_str().into()),
+        ];
+        entries.extend(self.fields.iter().cloned());
+        Value::object(entries).to_string()
+    }
+
+    /// [`Self::json`], or [`Self::line`] prefixed with the queue id, as configured.
+    pub fn render(&self, metadata: &tracing::Metadata<'_>) -> String {
+        if format_is(Format::Json) {
+            return self.json(metadata);
+        }
+        match queue_id() {
+            Some(queue_id) => format!("{queue_id}: {}", self.line()),
+            None => self.line(),
+        }
+    }
+
+    fn push(&mut self, field: &Field, value: Value) {
+        match (field.name(), value) {
+            ("message", value) => self.message = text(&value).into_owned(),
+            (name, value) => self.fields.push((name, value)),
+        }
+    }
+}
+
+impl Visit for Fields {
+    fn record_str(&mut self, field: &Field, value: &str) {
+        self.push(field, value.into());
+    }
+
+    fn record_u64(&mut self, field: &Field, value: u64) {
+        self.push(field, value.into());
+    }
+
+    fn record_i64(&mut self, field: &Field, value: i64) {
+        self.push(field, value.into());
+    }
+
+    fn record_bool(&mut self, field: &Field, value: bool) {
+        self.push(field, value.into());
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn std::fmt::Debug) {
+        self.push(field, format!("{value:?}").into());
+    }
+}
+
+/// The stderr target with `log_format = "json"`.
+struct JsonStderr;
+
+impl<S: tracing::Subscriber> tracing_subscriber::Layer<S> for JsonStderr {
+    fn on_event(&self, event: &tracing::Event<'_>, _: tracing_subscriber::layer::Context<'_, S>) {
+        eprintln!("{}", Fields::of(event).json(event.metadata()));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{Arc, Mutex};
+
+    #[test]
+    fn test_sensitive() {
+        assert!(sensitive(CONTENT_TARGET));
+        assert!(sensitive("lettre::transport::smtp::client::connection"));
+        assert!(!sensitive("lettre::transport::smtp::transport"));
+        assert!(!sensitive("forward_as_attachment_mta"));
+        assert!(!sensitive("forward_as_attachment_mta::spool"));
+    }
+
+    #[test]
+    fn test_format() {
+        struct Collect(Arc<Mutex<Vec<String>>>);
+        impl<S: tracing::Subscriber> tracing_subscriber::Layer<S> for Collect {
+            fn on_event(
+                &self,
+                event: &tracing::Event<'_>,
+                _: tracing_subscriber::layer::Context<'_, S>,
+            ) {
+                let fields = Fields::of(event);
+                let json = fields.json(event.metadata());
+                // Without the timestamp.
+                let (_, json) = json.split_once(',').unwrap();
+                self.0
+                    .lock()
+                    .unwrap()
+                    .extend([fields.line(), json.to_owned()]);
+            }
+        }
+        let lines = Arc::new(Mutex::new(Vec::new()));
+        let subscriber = tracing_subscriber::registry().with(Collect(Arc::clone(&lines)));
+        tracing::subscriber::with_default(subscriber, || {
+            let to = "admin@example.com";
+            tracing::info!(%to, size = 1234, "delivered");
+            tracing::warn!(e = "550 no such\nuser", "delivery failed");
+        });
+        assert_eq!(
+            *lines.lock().unwrap(),
+            [
+                "delivered to=admin@example.com size=1234",
+                r#""level":"INFO","target":"forward_as_attachment_mta::logging::tests","queue_id":null,"message":"delivered","to":"admin@example.com","size":1234}"#,
+                "delivery failed e=\"550 no such\\nuser\"",
+                r#""level":"WARN","target":"forward_as_attachment_mta::logging::tests","queue_id":null,"message":"delivery failed","e":"550 no such\nuser"}"#,
+            ]
+        );
+    }
+}
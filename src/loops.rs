@@ -0,0 +1,102 @@
+//! Detect mail loops, e.g., when the recipient mailbox forwards back to the host.
+
+use std::time::{Duration, SystemTime};
+
+use mailparse::{MailHeaderMap, ParsedMail};
+use tracing::{debug, warn};
+
+use crate::config::{Config, LoopDetection};
+
+const WARNING_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+#[derive(Debug)]
+pub enum Loop {
+    XLoop,
+    TooManyReceived(usize),
+}
+
+impl std::fmt::Display for Loop {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Loop::XLoop => write!(f, "the message already contains our X-Loop header"),
+            Loop::TooManyReceived(n) => write!(f, "the message has {n} Received headers"),
+        }
+    }
+}
+
+pub fn detect(config: &LoopDetection, parsed: &ParsedMail) -> Option<Loop> {
+    let headers = parsed.get_headers();
+    if headers
+        .get_all_values("X-Loop")
+        .iter()
+        .any(|v| v.trim().eq_ignore_ascii_case(&config.x_loop))
+    {
+        return Some(Loop::XLoop);
+    }
+    let received = headers.get_all_headers("Received").len();
+    if received > config.max_received {
+        return Some(Loop::TooManyReceived(received));
+    }
+    None
+}
+
+/// Send a warning about the loop unless we already did within the last hour.
+pub fn warn_rate_limited(config: &Config, hostname: &str, detected: &Loop, subject: Option<&str>) {
+    let res = (|| {
+        let path = crate::state::subdir(config, "loop")?.join("last-warning");
+        let now = crate::time::unix_secs(SystemTime::now());
+        match std::fs::read_to_string(&path) {
+            Ok(s) => {
+                let last = s.trim().parse::<u64>().unwrap_or(0);
+                if now.saturating_sub(last) < WARNING_INTERVAL.as_secs() {
+                    debug!("loop warning already sent recently");
+                    return Ok(());
+                }
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => (),
+            Err(e) => return Err(e),
+        }
+        std::fs::write(&path, now.to_string())?;
+        let body = format!(
+            "forward-as-attachment-mta on host {hostname:?} refused to forward a message because {detected}.\n\
+             This usually means that the recipient mailbox forwards mail back to the host.\n\
+             \n\
+             Subject of the refused message: {}\n\
+             \n\
+             Further loops within the next hour will only be logged.\n",
+            subject.unwrap_or("(none)")
+        );
+        if let Err(e) = crate::send_notice(config, format!("{hostname}: mail loop detected"), body)
+        {
+            warn!(%e, "failed to send loop warning");
+        }
+        Ok::<_, std::io::Error>(())
+    })();
+    if let Err(e) = res {
+        warn!(%e, "loop warning state");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_detect() {
+        let config = LoopDetection {
+            x_loop: "faam".to_owned(),
+            max_received: 2,
+        };
+        let f = |raw: &str| detect(&config, &mailparse::parse_mail(raw.as_bytes()).unwrap());
+        assert!(f("Subject: hi\r\n\r\nbody\r\n").is_none());
+        assert!(matches!(
+            f("X-Loop: FAAM\r\nSubject: hi\r\n\r\nbody\r\n"),
+            Some(Loop::XLoop)
+        ));
+        assert!(f("X-Loop: other\r\nReceived: a\r\nReceived: b\r\n\r\nbody\r\n").is_none());
+        assert!(matches!(
+            f("Received: a\r\nReceived: b\r\nReceived: c\r\n\r\nbody\r\n"),
+            Some(Loop::TooManyReceived(3))
+        ));
+    }
+}
@@ -1,11 +1,13 @@
 use core::panic;
 use lettre::address::Envelope;
 use lettre::message::header::{
-    ContentDisposition, ContentTransferEncoding, ContentType, HeaderName, HeaderValue,
+    ContentDisposition, ContentTransferEncoding, ContentType, Headers, MessageId, ReplyTo,
 };
-use lettre::message::{Body, MaybeString, MultiPart, SinglePart};
-use lettre::{Message, Transport};
+use lettre::message::{Body, Mailbox, Mailboxes, MultiPart, SinglePart};
+use lettre::Message;
+use std::os::fd::{AsFd, AsRawFd};
 use std::os::unix::fs::MetadataExt;
+use std::os::unix::process::CommandExt;
 
 use mailparse::MailHeaderMap;
 use regex::Regex;
@@ -15,23 +17,111 @@ use std::ffi::OsString;
 use std::env::VarError;
 use std::fmt::Write;
 use std::io::{self, Read};
-use std::sync::OnceLock;
-use tracing::debug;
-
-#[derive(Debug, serde::Deserialize)]
-#[serde(deny_unknown_fields)]
-struct Config {
-    sender_email: lettre::Address,
-    recipient_email: lettre::Address,
-    smtp_host: String,
-    smtp_username: String,
-    smtp_password: String,
-}
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, OnceLock};
+use std::time::Duration;
+use tracing::{debug, info, warn};
+use zeroize::Zeroizing;
+
+mod age;
+mod args;
+mod audit;
+mod body;
+mod canonical;
+mod capture;
+mod compress;
+mod config;
+mod console;
+mod crash;
+mod cron;
+mod daemon;
+mod db;
+mod dedup;
+mod digest;
+mod downgrade;
+mod escalate;
+mod failures;
+mod filter;
+mod hardening;
+mod headers;
+mod heartbeat;
+mod hooks;
+mod http;
+mod i18n;
+mod journal;
+mod journald;
+mod json;
+mod landlock;
+mod logfile;
+mod logging;
+mod loops;
+mod marker;
+mod metrics;
+mod migrate;
+mod mime;
+mod original;
+#[cfg(feature = "otlp")]
+mod otlp;
+mod passthrough;
+mod pgp;
+mod plugin;
+mod preview;
+mod process;
+mod push;
+mod quiet;
+mod ratelimit;
+mod route;
+mod secret;
+#[cfg(feature = "sentry")]
+mod sentry;
+mod seq;
+mod severity;
+mod signals;
+mod smime;
+mod smtp;
+mod smtpd;
+mod spool;
+mod state;
+mod stats;
+mod submitters;
+mod sysexits;
+mod syslog;
+mod systemd;
+mod template;
+#[cfg(test)]
+mod testdir;
+mod time;
+mod truncate;
+mod verify;
+#[cfg(feature = "plugin")]
+mod wasm;
+mod watch;
+
+use args::Args;
+use config::{Config, EmptySubmission, MultipleFrom, RecipientArgsMode};
+use headers::RawHeader;
 
 fn main() {
-    tracing_subscriber::fmt::init();
+    logging::init();
+    crash::install();
+    // We hold the relay passwords, keep them out of core dumps even when run as root.
+    // SAFETY: PR_SET_DUMPABLE takes no pointers
+    unsafe { libc::prctl(libc::PR_SET_DUMPABLE, 0, 0, 0, 0) };
+    hardening::set_umask();
+    let setuid = hardening::is_setuid();
+    if setuid {
+        if let Err(e) = hardening::check_binary() {
+            panic!("refusing to run setuid: {e}");
+        }
+    }
+    let invoking_env = hardening::sanitize_environment(setuid);
+    if let Some(exit_code) = push::from_env() {
+        std::process::exit(exit_code);
+    }
 
-    debug!("loading config");
+    let submitted_at = std::time::SystemTime::now();
+    let queue_id = new_queue_id();
+    debug!(%queue_id, "loading config");
     let config_location_default = "/etc/forward-as-attachment-mta.config.toml".to_owned();
     let config_location = match std::env::var("FORWARD_AS_ATTACHMENT_MTA_CONFIG_FILE") {
         Ok(v) => v,
@@ -42,161 +132,538 @@ fn main() {
         Ok(fd) => fd,
         Err(e) => panic!("open config file at {config_location:?}\n{e:?}"),
     };
-    let config_string = match std::fs::read_to_string(&config_location) {
-        Ok(c) => c,
+    // The plaintext config contains the passwords, too.
+    let config_bytes = match std::fs::read(&config_location) {
+        Ok(c) => Zeroizing::new(c),
         Err(e) => panic!("read config at {config_location:?}\n{e:?}"),
     };
-    let config: Config = match toml::from_str(&config_string) {
-        Ok(c) => c,
-        Err(e) => panic!("{e:?}"),
-    };
-
-    enum Args {
-        AllUtf8(Vec<String>),
-        Lossy(Vec<String>),
-    }
-    let args: Args = {
-        let os: Vec<std::ffi::OsString> = std::env::args_os().collect();
-        let maybe_all_utf8: Result<Vec<String>, ()> = os
-            .iter()
-            .map(|os_str| os_str.to_str().ok_or(()).map(|s| s.to_owned()))
-            .collect(); // cancels iteration early on first err
-        match maybe_all_utf8 {
-            Ok(all_utf8) => Args::AllUtf8(all_utf8),
-            Err(_) => Args::Lossy(
-                os.into_iter()
-                    .map(|os_str| os_str.to_string_lossy().to_string())
-                    .collect(),
-            ),
+    let age_identity = match std::env::var("FORWARD_AS_ATTACHMENT_MTA_AGE_IDENTITY") {
+        Ok(v) => PathBuf::from(v),
+        Err(VarError::NotPresent) => PathBuf::from(age::IDENTITY_DEFAULT),
+        e @ Err(VarError::NotUnicode(_)) => panic!("{e:?}"),
+    };
+    let config_bytes = if age::is_encrypted(&config_bytes) {
+        match age::decrypt(&age_identity, &config_bytes) {
+            Ok(c) => Zeroizing::new(c),
+            Err(e) => panic!("decrypt config at {config_location:?}\n{e}"),
         }
+    } else {
+        config_bytes
+    };
+    let config_string = match std::str::from_utf8(&config_bytes) {
+        Ok(c) => c,
+        Err(e) => panic!("config at {config_location:?} isn't UTF-8\n{e:?}"),
+    };
+    let mut config: Config = match toml::from_str(config_string) {
+        Ok(c) => c,
+        // Not `Debug`, which shows the whole config, with the passwords, e.g. in crash reports.
+        Err(e) => panic!("{e}"),
     };
-    impl std::fmt::Display for Args {
-        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-            let (prefix, args) = match self {
-                Args::AllUtf8(args) => ("", args),
-                Args::Lossy(args) => ("(non-utf-8): ", args),
+    crash::set_state_dir(&config.state_dir);
+    #[cfg(feature = "sentry")]
+    if let Some(dsn) = &config.sentry_dsn {
+        sentry::set_dsn(dsn);
+    }
+    if let Err(e) = config.decrypt_secrets(&age_identity) {
+        panic!("decrypt the smtp_password values\n{e}");
+    }
+    if let Err(e) = logging::configure(&config) {
+        panic!("{e}");
+    }
+    if std::env::args_os().nth(1).is_some_and(|arg| arg == "-q") {
+        // It forwards everyone's spooled submissions, as their submitters.
+        if let Err(code) = hardening::require_root(users::get_current_uid(), "-q") {
+            std::process::exit(code);
+        }
+        let exit_code = spool::flush(&config);
+        metrics::record(&config, metrics::Event::Flushed);
+        std::process::exit(exit_code);
+    }
+    if std::env::args_os()
+        .nth(1)
+        .is_some_and(|arg| arg == "--digest")
+    {
+        std::process::exit(digest::send(&config, users::get_current_uid(), &hostname()));
+    }
+    if std::env::args_os()
+        .nth(1)
+        .is_some_and(|arg| arg == "--heartbeat")
+    {
+        std::process::exit(heartbeat::send(
+            &config,
+            users::get_current_uid(),
+            &hostname(),
+        ));
+    }
+    if std::env::args_os()
+        .nth(1)
+        .is_some_and(|arg| arg == "--verify")
+    {
+        std::process::exit(verify::command(
+            &config,
+            users::get_current_uid(),
+            &hostname(),
+        ));
+    }
+    if std::env::args_os()
+        .nth(1)
+        .is_some_and(|arg| arg == "--daemon")
+    {
+        std::process::exit(daemon::serve(&config, users::get_current_uid()));
+    }
+    if std::env::args_os()
+        .nth(1)
+        .is_some_and(|arg| arg == "--smtpd")
+    {
+        std::process::exit(smtpd::serve(&config, users::get_current_uid(), &hostname()));
+    }
+    if std::env::args_os()
+        .nth(1)
+        .is_some_and(|arg| arg == "--migrate-spool")
+    {
+        let mailboxes = std::env::args_os()
+            .skip(2)
+            .map(PathBuf::from)
+            .collect::<Vec<_>>();
+        std::process::exit(migrate::command(
+            &config,
+            users::get_current_uid(),
+            &mailboxes,
+        ));
+    }
+    if std::env::args_os()
+        .nth(1)
+        .is_some_and(|arg| arg == "--watch")
+    {
+        std::process::exit(watch::follow(&config, users::get_current_uid()));
+    }
+    if std::env::args_os().nth(1).is_some_and(|arg| arg == "--db") {
+        let command = std::env::args().nth(2);
+        std::process::exit(db::command(
+            &config,
+            users::get_current_uid(),
+            command.as_deref(),
+        ));
+    }
+    if std::env::args_os()
+        .nth(1)
+        .is_some_and(|arg| arg == "--push")
+    {
+        let outcome = std::env::args().nth(2);
+        std::process::exit(push::command(
+            &config,
+            users::get_current_uid(),
+            outcome.as_deref(),
+        ));
+    }
+    if std::env::args_os()
+        .nth(1)
+        .is_some_and(|arg| arg == "--stats")
+    {
+        let days = std::env::args().nth(2);
+        std::process::exit(stats::command(
+            &config,
+            users::get_current_uid(),
+            days.as_deref(),
+        ));
+    }
+    // With one `--input`, the file takes the place of stdin; more are forwarded one at a time.
+    let input = match args::split_inputs(std::env::args_os().collect()) {
+        (_, inputs) if inputs.is_empty() => None,
+        (_, mut inputs) if inputs.len() == 1 => {
+            let path = inputs.pop().unwrap();
+            let file = match hardening::open_as_caller(&path) {
+                Ok(file) => file,
+                Err(e) => {
+                    eprintln!("forward-as-attachment-mta: --input {path:?}: {e}");
+                    std::process::exit(sysexits::EX_NOINPUT);
+                }
             };
-            write!(f, "{prefix}: {args:?}",)
+            // SAFETY: both are open file descriptors
+            if unsafe { libc::dup2(file.as_raw_fd(), libc::STDIN_FILENO) } == -1 {
+                panic!("--input {path:?}: {}", io::Error::last_os_error());
+            }
+            Some(path)
+        }
+        (args, inputs) => std::process::exit(forward_inputs(&args, &inputs)),
+    };
+    // Forwarding a spooled submission, on behalf of its submitter.
+    let from_spool = spool::from_env(&config);
+    if let (None, Some(socket)) = (&from_spool, &config.daemon_socket) {
+        if let Some(exit_code) = daemon::hand_off(&config, socket) {
+            std::process::exit(exit_code);
+        }
+    }
+    // Unlike those handed off to the daemon, the submissions in the spool were accepted before.
+    let accepted_before = from_spool.is_some() && !daemon::handed_off();
+    let (submitted_at, queue_id) = match &from_spool {
+        Some(meta) => (meta.submitted_at(), meta.queue_id.clone()),
+        None => (submitted_at, queue_id),
+    };
+    logging::set_queue_id(&queue_id);
+    // The phases of the submission, for the trace with `otlp_endpoint`.
+    let submission_span =
+        tracing::info_span!("submission", %queue_id, result = tracing::field::Empty).entered();
+    if !accepted_before {
+        metrics::record(&config, metrics::Event::Submitted);
+        db::record_submission(
+            &config,
+            &db::Submission {
+                at: time::unix_secs(submitted_at),
+                queue_id: queue_id.clone(),
+                uid: from_spool.as_ref().map_or_else(
+                    || smtpd::submitter_from_env().unwrap_or_else(users::get_current_uid),
+                    |meta| meta.uid,
+                ),
+            },
+        );
+    }
+    let (caller_uid, caller_username) = match &from_spool {
+        Some(meta) => (meta.uid, meta.username.clone()),
+        None => {
+            // From `--smtpd`, on behalf of its client.
+            let uid = smtpd::submitter_from_env().unwrap_or_else(users::get_current_uid);
+            let username =
+                users::get_user_by_uid(uid).map(|u| u.name().to_string_lossy().to_string());
+            (uid, username)
+        }
+    };
+    let user_override = match config.apply_user_override(caller_uid, caller_username.as_deref()) {
+        Ok(applied) => applied,
+        Err(e) => panic!("{e}"),
+    };
+    debug!(?user_override, "applied per-user config");
+    if config.dsn_notify.len() > 1 && config.dsn_notify.contains(&smtp::DsnNotify::Never) {
+        panic!("dsn_notify: \"never\" can't be combined with other values");
+    }
+    if let Err(e) = config.validate_headers() {
+        panic!("[headers]: {e}");
+    }
+    for filter in &config.filters {
+        if let Err(e) = filter.validate() {
+            panic!("[[filter]] {:?}: {e}", filter.name);
+        }
+    }
+    for (i, classifier) in config.classifiers.iter().enumerate() {
+        if let Err(e) = classifier.validate() {
+            panic!("[[classifier]] #{}: {e}", i + 1);
+        }
+    }
+    for (i, rewrite) in config.recipient_rewrites.iter().enumerate() {
+        if let Err(e) = rewrite.validate() {
+            panic!("[[recipient_rewrite]] #{}: {e}", i + 1);
+        }
+    }
+    for (i, route) in config.routes.iter().enumerate() {
+        if let Err(e) = route.validate() {
+            panic!("[[route]] #{}: {e}", i + 1);
         }
     }
+    if let Some(Err(e)) = config.quiet_hours.as_ref().map(|q| q.validate()) {
+        panic!("[quiet_hours]: {e}");
+    }
+    if let Some(Err(e)) = config.smime.as_ref().map(|s| s.validate()) {
+        panic!("[smime]: {e}");
+    }
+
+    let args = match &from_spool {
+        Some(meta) => Args::AllUtf8(meta.args.clone()),
+        None => Args::from_env(),
+    };
     tracing::debug!(%args, "args");
+    audit::begin(audit::Record {
+        queue_id: queue_id.clone(),
+        uid: caller_uid,
+        user: caller_username.clone(),
+        argv_sha256: headers::sha256_hex(args.as_slice().join("\0").as_bytes()),
+        ..Default::default()
+    });
+    let parent_process = process::ProcessInfo::parent(caller_uid);
+    let systemd_unit = process::ProcessInfo::read(std::process::id()).systemd_unit();
+    debug!(?parent_process, ?systemd_unit, "invoked by");
+    let environment = process::env_snapshot(&invoking_env, &config.env_allowlist);
+    drop(invoking_env);
+    // Now that we know about the caller, and have read what only root may read.
+    if let Some(user) = &config.run_as {
+        if let Err(e) = hardening::switch_user(user) {
+            panic!("run_as: switch to user {user:?}: {e}");
+        }
+    }
+    // Before other threads are spawned, so that they are restricted, too.
+    if config.landlock {
+        match landlock::restrict(&landlock::rules(&config, Path::new(&config_location))) {
+            Ok(Some(abi)) => debug!(abi, "restricted file access with landlock"),
+            Ok(None) => debug!("landlock isn't supported by the kernel"),
+            Err(e) => panic!("landlock: {e}"),
+        }
+    }
 
+    let parse_span = tracing::info_span!("parse").entered();
     enum OriginalMessageBody {
-        Read(Vec<u8>),
+        Read(Arc<Vec<u8>>),
+        Spooled(original::Spooled),
         Error(std::io::Error),
     }
-    let stdin_raw: OriginalMessageBody = {
-        let mut stdin_content = Vec::new();
-        match io::stdin().read_to_end(&mut stdin_content) {
-            Ok(_) => {
-                std::fs::write("/tmp/debug.eml", &stdin_content).expect("IO error");
-                OriginalMessageBody::Read(stdin_content)
+    let spool_threshold = match config.stdin_spool_threshold {
+        0 => usize::MAX,
+        n => n,
+    };
+    let interrupt = signals::install()
+        .map_err(|e| warn!(%e, "can't handle termination signals"))
+        .ok();
+    let secs = |secs| (secs > 0).then(|| Duration::from_secs(secs));
+    // Unbuffered, see `original::Deadline`.
+    let mut stdin = original::Deadline::new(
+        io::stdin()
+            .as_fd()
+            .try_clone_to_owned()
+            .map(std::fs::File::from)
+            .expect("dup stdin"),
+        secs(config.stdin_idle_timeout_secs),
+        secs(config.stdin_deadline_secs),
+        interrupt,
+    );
+    // With `debug_capture`, the input as it was read.
+    let capture_input = |input: &mut dyn Read| {
+        let dir = match &config.debug_capture_dir {
+            Some(dir) => dir.clone(),
+            None => config.state_dir.join("capture"),
+        };
+        capture::store(&dir, &queue_id, input)
+            .map_err(|e| warn!(%e, ?dir, "failed to capture the input"))
+            .ok()
+    };
+    let mut captured = None;
+    let stdin_raw: OriginalMessageBody = match original::read(
+        &mut stdin,
+        spool_threshold,
+        config.normalize_line_endings,
+        || state::subdir(&config, "tmp"),
+    ) {
+        Ok(original::Input::InMemory(mut stdin_content)) => {
+            if config.debug_capture {
+                captured = capture_input(&mut stdin_content.as_slice());
+            }
+            if config.normalize_line_endings && !original::looks_binary(&stdin_content) {
+                stdin_content = original::normalize_line_endings(stdin_content);
+            }
+            OriginalMessageBody::Read(Arc::new(stdin_content))
+        }
+        Ok(original::Input::Spooled(spooled)) => {
+            debug!(len = spooled.len(), "spooled stdin");
+            if config.debug_capture {
+                captured = match spooled.reader() {
+                    Ok(mut reader) => capture_input(&mut reader),
+                    Err(e) => {
+                        warn!(%e, "failed to capture the input");
+                        None
+                    }
+                };
             }
-            Err(e) => OriginalMessageBody::Error(e),
+            OriginalMessageBody::Spooled(spooled)
         }
+        Err(e) => OriginalMessageBody::Error(e),
     };
-    impl From<OriginalMessageBody> for MaybeString {
-        fn from(val: OriginalMessageBody) -> Self {
-            match val {
-                OriginalMessageBody::Read(b) => MaybeString::Binary(b),
-                OriginalMessageBody::Error(e) => MaybeString::String(format!(
-                    "forward-as-attachment-mta failed to read stdin: {e:?}"
-                )),
+    let stdin_timed_out = stdin.timed_out();
+    let stdin_interrupted = stdin.interrupted();
+    drop(stdin);
+    // Not again for spooled submissions, which it already saw; interrupted ones are spooled below.
+    let stdin_raw = if config.pre_send_hook.is_empty() || from_spool.is_some() || stdin_interrupted
+    {
+        stdin_raw
+    } else {
+        let env = [
+            ("FAAM_QUEUE_ID", queue_id.clone()),
+            ("FAAM_UID", caller_uid.to_string()),
+            ("FAAM_USER", caller_username.clone().unwrap_or_default()),
+            ("FAAM_HOSTNAME", hostname()),
+        ];
+        let verdict = match &stdin_raw {
+            OriginalMessageBody::Read(b) => hooks::pre_send(&config, &mut b.as_slice(), &env),
+            OriginalMessageBody::Spooled(spooled) => match spooled.reader() {
+                Ok(mut r) => hooks::pre_send(&config, &mut r, &env),
+                Err(e) => {
+                    warn!(%e, "can't read the spooled original for the pre-send hook");
+                    hooks::Verdict::Forward
+                }
+            },
+            OriginalMessageBody::Error(_) => hooks::Verdict::Forward,
+        };
+        match verdict {
+            hooks::Verdict::Forward => stdin_raw,
+            hooks::Verdict::Replace(replaced) => OriginalMessageBody::Read(Arc::new(replaced)),
+            hooks::Verdict::Veto => {
+                warn!("the pre-send hook vetoed the submission, not forwarding");
+                audit::exit(&config, "vetoed", None, 0);
             }
         }
+    };
+    let spool_meta = |reason: &str| spool::Meta {
+        queue_id: queue_id.clone(),
+        submitted_at: time::unix_secs(submitted_at),
+        uid: caller_uid,
+        username: caller_username.clone(),
+        args: args.as_slice().to_vec(),
+        reason: reason.to_owned(),
+    };
+    // Stores the original in the spool, for `sendmail -q`.
+    let spool_original = |reason: &str| {
+        let meta = spool_meta(reason);
+        match &stdin_raw {
+            OriginalMessageBody::Read(b) => spool::store(&config.state_dir, &meta, b.as_slice()),
+            OriginalMessageBody::Spooled(spooled) => spooled
+                .reader()
+                .and_then(|r| spool::store(&config.state_dir, &meta, r)),
+            OriginalMessageBody::Error(e) => Err(io::Error::new(e.kind(), e.to_string())),
+        }
+    };
+    if stdin_interrupted {
+        let stored = spool_original("terminated while reading stdin");
+        match stored {
+            Ok(path) => eprintln!("forward-as-attachment-mta: terminated, spooled to {path:?}"),
+            Err(e) => warn!(%e, "failed to spool the submission"),
+        }
+        audit::exit(&config, "terminated", Some("spool"), sysexits::EX_TEMPFAIL);
     }
-    let original_parsed = match &stdin_raw {
-        OriginalMessageBody::Read(body_raw) => match mailparse::parse_mail(body_raw) {
-            Ok(msg) => Some(msg),
-            Err(_) => None,
+    match &stdin_raw {
+        OriginalMessageBody::Read(b) => signals::pending(signals::Pending {
+            state_dir: config.state_dir.clone(),
+            meta: spool_meta("terminated"),
+            original: signals::Original::InMemory(Arc::clone(b)),
+        }),
+        OriginalMessageBody::Spooled(spooled) => match spooled.file().try_clone() {
+            Ok(file) => signals::pending(signals::Pending {
+                state_dir: config.state_dir.clone(),
+                meta: spool_meta("terminated"),
+                original: signals::Original::File(file),
+            }),
+            Err(e) => warn!(%e, "can't spool the submission on termination"),
         },
+        OriginalMessageBody::Error(_) => (),
+    }
+    if let Some(timed_out) = stdin_timed_out {
+        warn!(
+            ?timed_out,
+            "stopped reading stdin, sending what was read so far"
+        );
+    }
+    let (original_size, original_sha256) = match &stdin_raw {
+        OriginalMessageBody::Read(b) => (b.len(), headers::sha256_hex(b)),
+        OriginalMessageBody::Spooled(spooled) => (spooled.len(), spooled.sha256().to_owned()),
+        OriginalMessageBody::Error(_) => (0, headers::sha256_hex(&[])),
+    };
+    audit::note(|record| record.size = Some(original_size as u64));
+    // Parsing binary data would only produce a garbage summary.
+    let binary = match &stdin_raw {
+        OriginalMessageBody::Read(b) => original::looks_binary(b),
+        OriginalMessageBody::Spooled(spooled) => original::looks_binary(spooled.head()),
+        OriginalMessageBody::Error(_) => false,
+    };
+    let empty = matches!(&stdin_raw, OriginalMessageBody::Read(b) if b.is_empty());
+    let original_parsed = match &stdin_raw {
+        _ if binary || empty => None,
+        OriginalMessageBody::Read(body_raw) => mailparse::parse_mail(body_raw).ok(),
+        // Only the headers are of interest, the body is incomplete.
+        OriginalMessageBody::Spooled(spooled) => mailparse::parse_mail(spooled.head()).ok(),
         OriginalMessageBody::Error(_) => None,
     };
+    // Still try to find a meaningful subject for the wrapper.
+    let lenient = match (&original_parsed, &stdin_raw) {
+        (Some(_), _) => None,
+        _ if binary || empty => None,
+        (None, OriginalMessageBody::Read(b)) => Some(headers::lenient_headers(b)),
+        (None, OriginalMessageBody::Spooled(spooled)) => {
+            Some(headers::lenient_headers(spooled.head()))
+        }
+        (None, OriginalMessageBody::Error(_)) => None,
+    };
     tracing::debug!(
+        binary,
         could_parse = original_parsed.is_some(),
         ?original_parsed,
         "parsed message"
     );
 
-    // Try to create an inline attachment for the receivers's convenience of not
-    // having to double-click the attachment.
-    //
-    // This is surprisingly tricky, as the message/rfc822 MIME type only allows
-    // Content-Transfer-Encoding 7bit, 8bit or binary.
-    // Any other encoding (quoted-printable, base64) will break in
-    // Gmail and AppleMail, probably elsewhere. The exact kind of breakage depends:
-    // in AppleMail, only the `From`, `To`, and `Subject`
-    // headers are shown inline, and the rest of the message is not visible / accessible.
-    // In Gmail, it always shows as an attachment and one gets an error when clicking on it.
-    let re_encoded = (|| {
-        let Some(original_parsed) = &original_parsed else {
-            debug!("not parseable");
-            return None;
-        };
-        if original_parsed.ctype.mimetype != "text/plain" {
-            // TODO: implement support.
-            // Multi-part would be tricky as we'd possible need to use different
-            // boundaries to avoid collisions with the boundaries that our wrapper
-            // message will add.
-            debug!("not text/plain content-type");
-            return None;
-        }
-        let mut builder = SinglePart::builder();
-        for header in &original_parsed.headers {
-            #[derive(Clone)]
-            struct RawHeader(HeaderName, String);
-            impl lettre::message::header::Header for RawHeader {
-                fn name() -> HeaderName {
-                    unimplemented!("not needed, we only use display")
-                }
-
-                fn parse(_: &str) -> Result<Self, Box<dyn std::error::Error + Send + Sync>> {
-                    unimplemented!("not needed, we only use display")
-                }
+    let hostname = hostname();
 
-                fn display(&self) -> lettre::message::header::HeaderValue {
-                    HeaderValue::new(self.0.clone(), self.1.clone())
-                }
-            }
-            impl RawHeader {
-                fn new(hdr: &mailparse::MailHeader) -> Option<Self> {
-                    let header_name =
-                        HeaderName::new_from_ascii(hdr.get_key()).ok().or_else(|| {
-                            debug!(hdr=?hdr.get_key(), "header is not ascii");
-                            None
-                        })?;
-                    let header_value = hdr.get_value_utf8().ok().or_else(|| {
-                        debug!(hdr=?hdr, "header value is not utf-8");
-                        None
-                    })?;
-                    Some(Self(header_name, header_value))
-                }
+    if config.submitters.denied_digest {
+        if let Err(e) = submitters::maybe_send_digest(&config, &hostname) {
+            warn!(%e, "denied submissions digest");
+        }
+    }
+    if !config.filters.is_empty() && config.filter_summary {
+        if let Err(e) = filter::maybe_send_summary(&config, &hostname) {
+            warn!(%e, "filter summary");
+        }
+    }
+    if config.dedup_window_secs > 0 {
+        if let Err(e) = dedup::send_follow_ups(&config, &hostname) {
+            warn!(%e, "dedup follow-ups");
+        }
+    }
+    if config.rate_limit_per_hour > 0 {
+        if let Err(e) = ratelimit::maybe_send_digest(&config, &hostname) {
+            warn!(%e, "rate limit digest");
+        }
+    }
+    if !config
+        .submitters
+        .permits(caller_uid, caller_username.as_deref())
+    {
+        let subject = original_parsed
+            .as_ref()
+            .and_then(|p| p.headers.get_first_value("Subject"));
+        warn!(
+            uid = caller_uid,
+            username = caller_username,
+            ?subject,
+            "submission denied by config"
+        );
+        if config.submitters.denied_digest {
+            let denied = submitters::Denied {
+                uid: caller_uid,
+                username: caller_username.as_deref(),
+                subject: subject.as_deref(),
+            };
+            if let Err(e) = submitters::record(&config, &denied) {
+                warn!(%e, "failed to record denied submission for digest");
             }
-            builder = builder.header(RawHeader::new(header).or_else(|| {
-                debug!("can't adapt libraries into each other");
-                None
-            })?);
         }
-        Some(
-            builder.body(
-                Body::new_with_encoding(
-                    original_parsed.get_body().ok().or_else(|| {
-                        debug!("cannot get body");
-                        None
-                    })?,
-                    lettre::message::header::ContentTransferEncoding::Base64,
-                )
-                .unwrap(),
-            ),
-        )
-    })();
+        eprintln!("forward-as-attachment-mta: submissions by uid {caller_uid} are not permitted");
+        audit::exit(&config, "denied", None, sysexits::EX_NOPERM);
+    }
+
+    if let Some(detected) = original_parsed
+        .as_ref()
+        .and_then(|p| loops::detect(&config.loop_detection, p))
+    {
+        let subject = original_parsed
+            .as_ref()
+            .and_then(|p| p.headers.get_first_value("Subject"));
+        warn!(%detected, ?subject, "mail loop detected, not forwarding");
+        loops::warn_rate_limited(&config, &hostname, &detected, subject.as_deref());
+        // Exit successfully, a failure would likely produce yet another message.
+        audit::exit(&config, "loop", None, 0);
+    }
+
+    if empty && config.empty_submission == EmptySubmission::Suppress {
+        warn!(uid = caller_uid, "empty submission, not forwarding");
+        audit::exit(&config, "empty", None, 0);
+    }
+
+    drop(parse_span);
+    let compose_span = tracing::info_span!("compose").entered();
 
     // Put together the wrapper message
-    let sender = {
-        // Some(unambiguous `From` header)
-        let original_parsed_from = original_parsed.as_ref().and_then(|org| {
-            match org.get_headers().get_all_headers("From").as_slice() {
-                [unambiguous] => match mailparse::addrparse_header(unambiguous) {
+    // The address of each `From` header, with its value for the notes.
+    let original_froms: Vec<(String, Option<String>)> = match (&original_parsed, &lenient) {
+        (Some(org), _) => org
+            .get_headers()
+            .get_all_headers("From")
+            .into_iter()
+            .map(|header| {
+                let value = header.get_value();
+                let addr = match mailparse::addrparse_header(header) {
                     Ok(list) => {
                         let maybe_from = list.extract_single_info();
                         debug!(?maybe_from);
@@ -205,7 +672,7 @@ fn main() {
                     Err(e) => {
                         debug!(%e, "parse From header error");
                         // best-effort: handle typical Cron format
-                        match unambiguous.get_value_utf8() {
+                        match header.get_value_utf8() {
                             Ok(unambigous) => {
                                 debug!(?unambigous, "trying to parse Cron format");
                                 try_extract_cron_from_header(&unambigous).map(|s| s.to_string())
@@ -213,230 +680,1868 @@ fn main() {
                             Err(_) => None,
                         }
                     }
-                },
-                _ => None,
-            }
-        });
-        let args_from: Option<String> = match args {
-            Args::AllUtf8(ref args) => {
-                let mut first = None;
-                for arg in args {
-                    // sendmail uses -f to denominate envelope-from
-                    let Some(from) = arg.strip_prefix("-f") else {
-                        continue;
-                    };
-                    if first.is_some() {
-                        first = None; // duplicate from, no idea how to handle that
-                        break;
-                    }
-                    first = Some(from.to_owned());
+                };
+                (value, addr)
+            })
+            .collect(),
+        (None, Some(lenient)) => lenient
+            .get_all("From")
+            .into_iter()
+            .map(|from| {
+                let addr = match mailparse::addrparse(&from) {
+                    Ok(list) => list
+                        .extract_single_info()
+                        .map(|single_info| single_info.addr),
+                    Err(_) => try_extract_cron_from_header(&from).map(|s| s.to_string()),
+                };
+                (from, addr)
+            })
+            .collect(),
+        (None, None) => vec![],
+    };
+    let original_parsed_from = match original_froms.as_slice() {
+        [] => None,
+        [(_, unambiguous)] => unambiguous.clone(),
+        multiple => {
+            let mut addrs = multiple.iter().filter_map(|(_, addr)| addr.clone());
+            match config.multiple_from {
+                MultipleFrom::First => addrs.next(),
+                MultipleFrom::Last => addrs.next_back(),
+                MultipleFrom::Concatenate => {
+                    Some(addrs.collect::<Vec<_>>().join(", ")).filter(|s| !s.is_empty())
                 }
-                first
+                MultipleFrom::Reject => None,
             }
-            Args::Lossy(_) => None,
-        };
-        debug!(?original_parsed_from, ?args_from, "prepare sender");
-        match (
-            args_from.as_deref().map(escape_parens),
-            original_parsed_from.as_deref().map(escape_parens),
-        ) {
-            (Some(a), Some(h)) if a == h => format!("evlp+hdr({a})"),
-            (Some(a), Some(h)) => format!("evlp({a})+hdr({h})"),
-            (Some(a), None) => format!("evlp({a})"),
-            (None, Some(h)) => format!("hdr({h})"),
-            (None, None) => "???".to_owned(),
         }
     };
+    // Without a usable From header, fall back to other headers that name the sender.
+    // Some(header name) if one of them was used.
+    let mut original_from_fallback = None;
+    let original_parsed_from = original_parsed_from.or_else(|| {
+        let values = |name: &str| match (&original_parsed, &lenient) {
+            (Some(parsed), _) => parsed.get_headers().get_all_values(name),
+            (None, Some(lenient)) => lenient.get_all(name),
+            (None, None) => vec![],
+        };
+        let (name, addr) = FROM_FALLBACK_HEADERS.iter().find_map(|&name| {
+            let [value] = values(name).try_into().ok()?;
+            let addr = mailparse::addrparse(&value)
+                .ok()?
+                .extract_single_info()
+                .map(|single_info| single_info.addr)
+                .filter(|addr| !addr.is_empty())?;
+            Some((name, addr))
+        })?;
+        debug!(
+            header = name,
+            ?addr,
+            "no usable From header, using fallback"
+        );
+        original_from_fallback = Some(name);
+        Some(addr)
+    });
+    let args_from = args.envelope_from();
+    debug!(?original_parsed_from, ?args_from, "prepare sender");
+    let sender = match (
+        args_from.as_deref().map(escape_parens),
+        original_parsed_from.as_deref().map(escape_parens),
+    ) {
+        (Some(a), Some(h)) if a == h => format!("evlp+hdr({a})"),
+        (Some(a), Some(h)) => format!("evlp({a})+hdr({h})"),
+        (Some(a), None) => format!("evlp({a})"),
+        (None, Some(h)) => format!("hdr({h})"),
+        (None, None) => "???".to_owned(),
+    };
     let summary = match &original_parsed {
         Some(parsed) => match parsed.get_headers().get_all_values("Subject").as_slice() {
-            [unambiguous] => unambiguous.clone(),
+            [unambiguous] => headers::decode_rfc2047(unambiguous),
             _x => "(multiple Subject headers)".to_owned(),
         },
-        None => "(unparseable message)".to_owned(),
+        None if binary => "(binary data)".to_owned(),
+        None if empty => "(empty submission)".to_owned(),
+        None => match lenient.as_ref().and_then(|l| l.get_first("Subject")) {
+            Some(subject) => headers::decode_rfc2047(subject),
+            None => "(unparseable message)".to_owned(),
+        },
     };
-    let hostname = hostname::get()
-        .map(|os_str| os_str.to_string_lossy().to_string())
-        .unwrap_or("???".to_string());
-
-    let subject = format!("{sender}@{hostname}: {summary}");
-
-    let body = (|| {
-        let mut body = String::new();
-        writeln!(
-            &mut body,
-            "A process on host {hostname:?} invoked the sendmail binary."
-        )?;
-        writeln!(
-            &mut body,
-            "On that host, the sendmail binary is provided by the forwad-as-attachment-mta package."
-        )?;
-        match config_fd.metadata() {
-            Ok(md) => {
-                // Rust std widens the mode bits to the biggest common type across all supported platforms.
-                // https://github.com/rust-lang/rust/commit/aa23c98450063992473d40d707273903f8a3937d
-                let mode = md.mode();
-                let more_than_user_has_access = (mode & (libc::S_IRWXG as u32 | libc::S_IRWXO as u32)) != 0;
-                if more_than_user_has_access {
-                    writeln!(&mut body, "WARNING: the config file contains SMTP credentials and has too-lax permissions: {}",
-                        uucore::fs::display_permissions(&md, false)
-                    )?;
+    audit::note(|record| {
+        record.original_sender = original_parsed_from.clone().or(args_from.clone());
+        record.subject = Some(summary.clone());
+    });
+    // For matching submissions against `[[filter]]`, `[[classifier]]`, and each other.
+    let original_text = match (&original_parsed, &stdin_raw) {
+        _ if !matches_text(&config) => String::new(),
+        (Some(parsed), _) => preview::text_body(parsed).unwrap_or_default(),
+        (None, OriginalMessageBody::Read(raw)) => String::from_utf8_lossy(raw).into_owned(),
+        (None, OriginalMessageBody::Spooled(spooled)) => {
+            String::from_utf8_lossy(spooled.head()).into_owned()
+        }
+        (None, OriginalMessageBody::Error(_)) => String::new(),
+    };
+    let (mut recipients, recipient_args_ignored) = {
+        let mut allowed = Vec::new();
+        let mut ignored = Vec::new();
+        let mut given = args.recipients();
+        if args.recipients_from_headers() {
+            let headers = original_parsed.as_ref().map(|p| p.get_headers());
+            for name in ["To", "Cc", "Bcc"] {
+                for header in headers.iter().flat_map(|h| h.get_all_headers(name)) {
+                    match mailparse::addrparse_header(header) {
+                        Ok(list) => given.extend(list.iter().flat_map(|addr| match addr {
+                            mailparse::MailAddr::Single(info) => vec![info.addr.clone()],
+                            mailparse::MailAddr::Group(group) => {
+                                group.addrs.iter().map(|info| info.addr.clone()).collect()
+                            }
+                        })),
+                        Err(e) => warn!(%e, header = name, "-t: can't parse recipients"),
+                    }
                 }
-            },
-            Err(e) => {
-                writeln!(&mut body, "WARNING: could not determine permissions of the config file, they may or may not be too lax: {e}")?;
-            },
+            }
         }
-        writeln!(&mut body)?;
-        {
-            write!(&mut body, "The original message is attached to this wrapper message.")?;
-            if re_encoded.is_some() {
-                write!(&mut body, " For convenience, a re-encoded copy is attached inline.")?;
+        for arg in given {
+            let arg = canonical::rewrite(&config.recipient_rewrites, &arg);
+            match arg.parse::<lettre::Address>() {
+                Ok(addr) if config.recipient_args.allows(&addr) => allowed.push(addr),
+                _ => ignored.push(arg),
             }
-            writeln!(&mut body)?;
         }
-        writeln!(&mut body)?;
-        writeln!(&mut body, "Invocation args: {args}")?;
-        writeln!(&mut body)?;
-        writeln!(
-            &mut body,
-            "uid:{} gid:{} euid:{} egid:{}",
-            users::get_current_uid(),
-            users::get_current_gid(),
-            users::get_effective_uid(),
-            users::get_effective_gid()
-        )?;
-        let mut display_or_none = |what, value: Option<OsString>| {
-            writeln!(
-                &mut body,
-                "{what}: {}",
-                value
-                    .as_ref()
-                    .map(|s| s.to_string_lossy())
-                    .unwrap_or(Cow::Borrowed(""))
-            )
+        let recipients = match (config.recipient_args.mode, allowed.is_empty()) {
+            (_, true) => vec![config.recipient_email.clone()],
+            (RecipientArgsMode::Replace, false) => allowed,
+            (RecipientArgsMode::Add, false) => {
+                let mut all = vec![config.recipient_email.clone()];
+                all.extend(allowed.into_iter().filter(|a| *a != config.recipient_email));
+                all
+            }
         };
-        display_or_none("username", users::get_current_username())?;
-        display_or_none("groupname", users::get_current_groupname())?;
-        display_or_none("effective username", users::get_effective_username())?;
-        display_or_none("effective groupname", users::get_effective_groupname())?;
-        writeln!(&mut body)?;
-        writeln!(&mut body, "hostname: {}", whoami::hostname())?;
-        writeln!(&mut body, "device name: {}", whoami::devicename())?;
-        writeln!(&mut body, "distro: {}", whoami::distro())?;
-        writeln!(&mut body, "platform: {}", whoami::platform())?;
-        writeln!(&mut body)?;
-        std::result::Result::<_, std::fmt::Error>::Ok(body)
-    })()
-    .expect("this is all in-memory and we don't expect formatting to fail");
-
-    let envelope = Envelope::new(
-        Some(config.sender_email.clone()),
-        vec![config.recipient_email.clone()],
-    )
-    .expect("as per api docs, this can't fail");
-    let email_message = Message::builder()
-        .from(config.sender_email.into())
-        .to(config.recipient_email.into())
-        .subject(subject)
-        .envelope(envelope)
-        .multipart({
-            let mut mp_builder = MultiPart::mixed().singlepart(SinglePart::plain(body));
-
-            mp_builder = {
-                if let Some(re_encoded) = re_encoded {
-                    mp_builder.singlepart(
-                        SinglePart::builder()
-                            .header(ContentType::parse("message/rfc822").unwrap())
-                            .header(ContentDisposition::inline())
-                            // Not dangerous because we used Base64 encoding to build the `re_encoded` => EigthBit safe
-                            .body(Body::dangerous_pre_encoded(
-                                re_encoded.formatted(),
-                                ContentTransferEncoding::EightBit,
-                            )),
-                    )
-                } else {
-                    debug!("can't inline the attachment, see previous log messages");
-                    mp_builder
-                }
-            };
-
-            mp_builder = mp_builder.singlepart(
-                SinglePart::builder()
-                    // (Stdin may not necessarily be a correct email to begin with, so, octet-stream is a reasonable default.)
-                    .header(ContentType::parse("application/octet-stream").unwrap())
-                    .header(ContentDisposition::attachment("stdin.eml"))
-                    .body(
-                        Body::new_with_encoding(
-                            stdin_raw,
-                            lettre::message::header::ContentTransferEncoding::Base64,
-                        )
-                        .unwrap(),
-                    ),
-            );
-
-            mp_builder
-        })
-        .expect("Failed to attach stdin email message");
-
+        (recipients, ignored)
+    };
     debug!(
-        message=%String::from_utf8_lossy(&email_message.formatted()),
-        "sending message",
+        ?recipients,
+        ?recipient_args_ignored,
+        "determined recipients"
     );
-
-    let smtp_transport = lettre::SmtpTransport::starttls_relay(&config.smtp_host)
-        .unwrap()
-        .authentication(vec![
-            lettre::transport::smtp::authentication::Mechanism::Plain,
-        ])
-        .credentials(lettre::transport::smtp::authentication::Credentials::new(
-            config.smtp_username,
-            config.smtp_password,
-        ))
-        .build();
-
-    let result = smtp_transport.send(&email_message);
-    if result.is_ok() {
-        println!("Email sent successfully");
-    } else {
-        println!("Failed to send email: {:?}", result);
-    }
-}
-
-fn try_extract_cron_from_header(from_header_value: &str) -> Option<&str> {
-    static RE: OnceLock<Regex> = OnceLock::new();
-    let re = RE.get_or_init(|| Regex::new(r"(\S+) \(Cron Daemon\)").unwrap());
-    match re.captures(from_header_value) {
-        None => None,
-        Some(caps) => Some(caps.get(1).unwrap().as_str()),
+    let submission = filter::Submission {
+        subject: &summary,
+        body: &original_text,
+        sender: original_parsed_from
+            .as_deref()
+            .or(args_from.as_deref())
+            .unwrap_or(""),
+    };
+    let severity = severity::classify(&config.classifiers, &submission);
+    debug!(?severity, "classified");
+    let decision = plugin::run(
+        &config,
+        &plugin::Submission {
+            original: original_parsed.as_ref(),
+            subject: &summary,
+            body: &original_text,
+            sender: submission.sender,
+            recipients: &recipients,
+            severity,
+            uid: caller_uid,
+            user: caller_username.as_deref(),
+            hostname: &hostname,
+        },
+    );
+    if decision.drop {
+        warn!("the plugin dropped the submission, not forwarding");
+        signals::done();
+        audit::exit(&config, "filtered", None, 0);
     }
-}
-
-fn escape_parens(output: &str) -> Cow<'_, str> {
-    let output = Cow::Borrowed(output);
-    let output = if output.contains('(') {
-        Cow::Owned(output.replace('(', r"\("))
-    } else {
-        output
+    let severity = decision.severity.unwrap_or(severity);
+    let summary = decision.subject.unwrap_or(summary);
+    // The later rules see the plugin's subject.
+    let submission = filter::Submission {
+        subject: &summary,
+        body: &original_text,
+        sender: original_parsed_from
+            .as_deref()
+            .or(args_from.as_deref())
+            .unwrap_or(""),
     };
-    let output = if output.contains(')') {
-        Cow::Owned(output.replace(')', r"\)"))
-    } else {
-        output
+    let route = match decision.recipient {
+        Some(_) => None,
+        None => route::find(&config.routes, &submission),
     };
-    output
-}
-
-#[cfg(test)]
-mod tests {
-    use std::borrow::Cow;
-
-    use super::*;
-
-    #[test]
-    fn test_cron_from_header() {
-        let f = try_extract_cron_from_header;
-        assert_eq!(f("root (Cron Daemon)"), Some("root"));
-        assert_eq!(f("(foo) (Cron Daemon))"), Some("(foo)"));
+    let routed = match (&decision.recipient, &route) {
+        (Some(recipient), _) => Some(recipient),
+        (None, Some((_, route))) => Some(&route.recipient),
+        (None, None) => config.severity_recipients.get(severity),
+    };
+    if let Some(routed) = routed {
+        for recipient in recipients.iter_mut() {
+            if *recipient == config.recipient_email {
+                *recipient = routed.clone();
+            }
+        }
+        debug!(
+            ?recipients,
+            route = route.as_ref().map(|(name, _)| name),
+            "routed"
+        );
+    }
+    // Critical submissions are not held by the rate limit or quiet hours.
+    let critical = severity == severity::Severity::Critical;
+    if !config.filters.is_empty() {
+        if let Some(matched) = filter::find(&config.filters, &submission) {
+            warn!(filter = matched.name, action = ?matched.action, "submission filtered, not forwarding");
+            if matched.action == filter::Action::SpoolOnly {
+                let meta = spool_meta(&format!("filter {:?}", matched.name));
+                let stored = state::subdir(&config, "filtered").and_then(|dir| match &stdin_raw {
+                    OriginalMessageBody::Read(b) => spool::store_in(&dir, &meta, b.as_slice()),
+                    OriginalMessageBody::Spooled(spooled) => spooled
+                        .reader()
+                        .and_then(|r| spool::store_in(&dir, &meta, r)),
+                    OriginalMessageBody::Error(e) => Err(io::Error::new(e.kind(), e.to_string())),
+                });
+                match stored {
+                    Ok(path) => debug!(?path, "kept filtered submission"),
+                    Err(e) => warn!(%e, "failed to keep the filtered submission"),
+                }
+            }
+            if config.filter_summary {
+                if let Err(e) = filter::record(&config, matched) {
+                    warn!(%e, "failed to record filtered submission for the summary");
+                }
+            }
+            signals::done();
+            audit::exit(&config, "filtered", None, 0);
+        }
+    }
+    if config.dedup_window_secs > 0 {
+        let submission = dedup::Submission {
+            uid: caller_uid,
+            sender: &sender,
+            subject: &summary,
+            body: &original_text,
+        };
+        match dedup::is_repeat(&config, &submission) {
+            Ok(true) => {
+                warn!("identical to a recent submission, not forwarding");
+                signals::done();
+                audit::exit(&config, "repeat", None, 0);
+            }
+            Ok(false) => (),
+            Err(e) => warn!(%e, "dedup state"),
+        }
+    }
+    // Submissions forwarded by `sendmail -q` were accepted before, so they are not held again.
+    let mut escalated = None;
+    if config.escalate_after > 1 && !accepted_before {
+        let class = escalate::Class {
+            uid: caller_uid,
+            sender: &sender,
+            subject: &summary,
+        };
+        match escalate::check(&config, &class, &queue_id) {
+            Ok(escalate::Decision::Suppress) => {
+                warn!("below escalate_after, not forwarding");
+                signals::done();
+                audit::exit(&config, "suppressed", None, 0);
+            }
+            Ok(escalate::Decision::Forward(occurrences)) => escalated = Some(occurrences),
+            Err(e) => warn!(%e, "escalation state"),
+        }
+    }
+    if config.digest && !accepted_before {
+        let meta = spool_meta("digest");
+        let held = match &stdin_raw {
+            OriginalMessageBody::Read(b) => digest::hold(&config, &meta, b.as_slice()),
+            OriginalMessageBody::Spooled(spooled) => spooled
+                .reader()
+                .and_then(|r| digest::hold(&config, &meta, r)),
+            OriginalMessageBody::Error(e) => Err(io::Error::new(e.kind(), e.to_string())),
+        };
+        match held {
+            Ok(path) => {
+                debug!(?path, "kept the submission for the digest");
+                signals::done();
+                audit::exit(&config, "digest", Some("digest"), 0);
+            }
+            Err(e) => warn!(%e, "failed to keep the submission for the digest, forwarding it"),
+        }
+    }
+    if config.rate_limit_per_hour > 0 && !accepted_before && !critical {
+        match ratelimit::take(&config, caller_uid) {
+            Ok(true) => (),
+            Ok(false) => {
+                warn!(
+                    uid = caller_uid,
+                    "rate limit exceeded, holding the submission for the digest"
+                );
+                let meta = spool_meta("rate limit");
+                let held = match &stdin_raw {
+                    OriginalMessageBody::Read(b) => ratelimit::hold(&config, &meta, b.as_slice()),
+                    OriginalMessageBody::Spooled(spooled) => spooled
+                        .reader()
+                        .and_then(|r| ratelimit::hold(&config, &meta, r)),
+                    OriginalMessageBody::Error(e) => Err(io::Error::new(e.kind(), e.to_string())),
+                };
+                match held {
+                    Ok(path) => {
+                        debug!(?path, "held rate-limited submission");
+                        signals::done();
+                        audit::exit(&config, "rate-limited", Some("digest"), 0);
+                    }
+                    Err(e) => warn!(%e, "failed to hold the submission, forwarding it"),
+                }
+            }
+            Err(e) => warn!(%e, "rate limit state"),
+        }
+    }
+    if let Some(quiet_hours) = config.quiet_hours.as_ref().filter(|_| !accepted_before) {
+        if quiet_hours.is_quiet(std::time::SystemTime::now())
+            && !critical
+            && !quiet_hours.is_critical(&submission)
+        {
+            match spool_original(quiet::SPOOL_REASON) {
+                Ok(path) => {
+                    warn!(?path, "quiet hours, spooled the submission");
+                    signals::done();
+                    audit::exit(&config, "quiet-hours", Some("spool"), 0);
+                }
+                Err(e) => {
+                    warn!(%e, "failed to spool the submission for quiet hours, forwarding it")
+                }
+            }
+        }
+    }
+    // Only now that no rule holds the submission, since the relay's extensions shape the
+    // message.
+    let relay = smtp::connect(&config);
+
+    // Try to create an inline attachment for the receivers's convenience of not
+    // having to double-click the attachment.
+    //
+    // This is surprisingly tricky, as the message/rfc822 MIME type only allows
+    // Content-Transfer-Encoding 7bit, 8bit or binary.
+    // Any other encoding (quoted-printable, base64) will break in
+    // Gmail and AppleMail, probably elsewhere. The exact kind of breakage depends:
+    // in AppleMail, only the `From`, `To`, and `Subject`
+    // headers are shown inline, and the rest of the message is not visible / accessible.
+    // In Gmail, it always shows as an attachment and one gets an error when clicking on it.
+    //
+    // If the relay supports 8BITMIME (or the original is 7bit anyway), the original can usually
+    // be inlined verbatim, only with its line endings normalized.
+    enum Inline<'a> {
+        Verbatim(Cow<'a, [u8]>),
+        /// The formatted part, with a placeholder for the base64-encoded body.
+        ReEncoded(Vec<u8>, String),
+        /// The decoded body of a text/html original, inlined as a text/html part instead, as
+        /// e.g. apticron sends them.
+        Html(String),
+    }
+    let inline_placeholder = mime::Placeholder::random();
+    let re_encoded = (|| {
+        if let OriginalMessageBody::Spooled(_) = stdin_raw {
+            debug!("spooled, too large to inline");
+            return None;
+        }
+        let Some(original_parsed) = &original_parsed else {
+            debug!("not parseable");
+            return None;
+        };
+        if original_parsed.ctype.mimetype == "text/html" {
+            let body = original_parsed.get_body().ok().or_else(|| {
+                debug!("cannot get body");
+                None
+            })?;
+            let body = match mime::inline_text(Cow::Borrowed(body.as_bytes()), config.strip_ansi) {
+                Cow::Borrowed(_) => body,
+                Cow::Owned(cleaned) => String::from_utf8(cleaned).expect("only ASCII is replaced"),
+            };
+            return Some(Inline::Html(body));
+        }
+        if original_parsed.ctype.mimetype != "text/plain" {
+            // TODO: implement support.
+            // Multi-part would be tricky as we'd possible need to use different
+            // boundaries to avoid collisions with the boundaries that our wrapper
+            // message will add.
+            debug!("not text/plain content-type");
+            return None;
+        }
+        // Legacy charsets are decoded, so that the inline copy is UTF-8.
+        let transcode = original_parsed
+            .get_body_raw()
+            .is_ok_and(|body| mime::needs_transcoding(&original_parsed.ctype, &body));
+        // Binary junk would corrupt the inline copy, the attachment keeps it.
+        if let (false, OriginalMessageBody::Read(raw)) = (transcode, &stdin_raw) {
+            let eight_bit_ok =
+                raw.is_ascii() || relay.as_ref().is_ok_and(smtp::Relay::eight_bit_mime);
+            let inline = mime::inline_text(mime::crlf(raw), config.strip_ansi);
+            if eight_bit_ok && mime::eight_bit_safe(&inline) {
+                return Some(Inline::Verbatim(inline));
+            }
+            debug!(eight_bit_ok, "can't inline verbatim, re-encoding");
+        }
+        debug!(
+            transcode,
+            charset = original_parsed.ctype.charset,
+            "re-encoding"
+        );
+        let mut builder = SinglePart::builder();
+        for header in &original_parsed.headers {
+            builder = builder.header(RawHeader::new(header).or_else(|| {
+                debug!("can't adapt libraries into each other");
+                None
+            })?);
+        }
+        if transcode {
+            builder = builder
+                .header(ContentType::parse(&mime::utf8_content_type(&original_parsed.ctype)).ok()?);
+        }
+        let body = original_parsed.get_body().ok().or_else(|| {
+            debug!("cannot get body");
+            None
+        })?;
+        let body = match mime::inline_text(Cow::Borrowed(body.as_bytes()), config.strip_ansi) {
+            Cow::Borrowed(_) => body,
+            Cow::Owned(cleaned) => String::from_utf8(cleaned).expect("only ASCII is replaced"),
+        };
+        // The body is filled in when splicing, see below.
+        let part = builder.body(inline_placeholder.body(ContentTransferEncoding::Base64));
+        Some(Inline::ReEncoded(part.formatted(), body))
+    })();
+
+    let vars = template::Vars {
+        hostname: &hostname,
+        user: caller_username.as_deref(),
+        uid: caller_uid,
+        sender: Some(&sender),
+        subject: Some(&summary),
+        unit: systemd_unit.as_ref().map(|u| u.unit.as_str()),
+        severity: Some(severity.as_str()),
+    };
+
+    let subject = match &config.subject_prefix {
+        Some(prefix) => format!("{prefix} {}", vars.expand(&config.subject_template)),
+        None => vars.expand(&config.subject_template),
+    };
+    let subject = headers::sanitize_subject(&subject, config.max_subject_length);
+
+    let mut journal = match (&systemd_unit, config.journal_attachment_minutes) {
+        (Some(unit), minutes @ 1..) => match journal::recent_entries(unit, minutes, caller_uid) {
+            Ok(entries) if !entries.is_empty() => Some(entries),
+            Ok(_) => None,
+            Err(e) => {
+                warn!(%e, unit = unit.unit, "failed to get journal entries");
+                None
+            }
+        },
+        _ => None,
+    };
+
+    let attachment_filename = vars.expand_filename(&config.attachment_filename, submitted_at);
+    let attachment_filename = match binary {
+        true => match attachment_filename.strip_suffix(".eml") {
+            Some(stem) => format!("{stem}.bin"),
+            None => format!("{attachment_filename}.bin"),
+        },
+        false => attachment_filename,
+    };
+
+    // What's left of the relay's size limit for the original, after the other parts.
+    let size_budget = relay
+        .as_ref()
+        .ok()
+        .and_then(smtp::Relay::size_limit)
+        .map(|limit| {
+            let journal = journal.as_ref().map_or(0, |j| mime::encoded_len(j.len()));
+            limit.saturating_sub(OTHER_PARTS_RESERVE + journal)
+        });
+    debug!(?size_budget, "relay size limit");
+    let fits = |len: usize| size_budget.is_none_or(|budget| mime::encoded_len(len) <= budget);
+
+    let kept_at = std::cell::OnceCell::new();
+    let keep_original = || {
+        kept_at.get_or_init(|| {
+            let kept = match &stdin_raw {
+                OriginalMessageBody::Read(raw) => {
+                    truncate::keep_original(&config, &queue_id, raw.as_slice())
+                }
+                OriginalMessageBody::Spooled(spooled) => spooled
+                    .reader()
+                    .and_then(|r| truncate::keep_original(&config, &queue_id, r)),
+                OriginalMessageBody::Error(_) => return None,
+            };
+            kept.map_err(|e| warn!(%e, "failed to keep the full original"))
+                .ok()
+        });
+    };
+    let head_and_tail = |keep: usize| {
+        keep_original();
+        let data = match &stdin_raw {
+            OriginalMessageBody::Read(raw) => Ok(truncate::head_and_tail(raw, keep)),
+            OriginalMessageBody::Spooled(spooled) if spooled.len() <= 2 * keep => {
+                spooled.read_at(0, spooled.len())
+            }
+            OriginalMessageBody::Spooled(spooled) => spooled
+                .read_at(0, keep)
+                .and_then(|head| Ok(truncate::join(&head, &spooled.tail(keep)?, spooled.len()))),
+            OriginalMessageBody::Error(_) => return None,
+        };
+        data.map_err(|e| warn!(%e, "failed to read spooled original, attaching it in full"))
+            .ok()
+    };
+    let compress = |truncated: &Option<Vec<u8>>| {
+        let input: io::Result<Box<dyn Read + '_>> = match (truncated, &stdin_raw) {
+            (Some(data), _) => Ok(Box::new(data.as_slice())),
+            (None, OriginalMessageBody::Read(raw)) => Ok(Box::new(raw.as_slice())),
+            (None, OriginalMessageBody::Spooled(spooled)) => {
+                spooled.reader().map(|r| Box::new(r) as _)
+            }
+            (None, OriginalMessageBody::Error(_)) => return None,
+        };
+        match input.and_then(|input| compress::compress(config.compress_format, input)) {
+            Ok(compressed) => Some(compressed),
+            Err(e) => {
+                warn!(%e, format = ?config.compress_format, "failed to compress the original, attaching it uncompressed");
+                None
+            }
+        }
+    };
+
+    let mut truncated = if config.max_original_size > 0 && original_size > config.max_original_size
+    {
+        head_and_tail(config.truncated_keep_kib * 1024)
+    } else {
+        None
+    };
+    let attached_len = truncated.as_ref().map_or(original_size, Vec::len);
+    let mut compressed = if (config.compress_threshold > 0
+        && attached_len > config.compress_threshold)
+        || !fits(attached_len)
+    {
+        compress(&truncated)
+    } else {
+        None
+    };
+    // Rather than having the relay reject the message, cut the original down to what fits.
+    let mut truncated_for_relay = None;
+    if let Some(budget) = size_budget {
+        let attached_len = compressed
+            .as_ref()
+            .or(truncated.as_ref())
+            .map_or(original_size, Vec::len);
+        if !fits(attached_len) {
+            let keep = (budget / 78 * 57 / 2).saturating_sub(1024);
+            debug!(
+                attached_len,
+                keep, "truncating to fit the relay's size limit"
+            );
+            truncated = head_and_tail(keep);
+            compressed = None;
+            truncated_for_relay = Some(keep);
+        }
+    }
+    // Encrypted last, so that it covers what would otherwise be attached. If that fails, the
+    // submission is spooled rather than sent in the clear.
+    let encrypted = match &config.pgp_recipient_key {
+        Some(key) if !empty => {
+            let input: io::Result<Box<dyn Read + '_>> =
+                match (compressed.as_ref().or(truncated.as_ref()), &stdin_raw) {
+                    (Some(data), _) => Ok(Box::new(data.as_slice())),
+                    (None, OriginalMessageBody::Read(raw)) => Ok(Box::new(raw.as_slice())),
+                    (None, OriginalMessageBody::Spooled(spooled)) => {
+                        spooled.reader().map(|r| Box::new(r) as _)
+                    }
+                    (None, OriginalMessageBody::Error(e)) => {
+                        Err(io::Error::new(e.kind(), e.to_string()))
+                    }
+                };
+            match input.and_then(|input| pgp::encrypt(key, input)) {
+                Ok(encrypted) => Some(encrypted),
+                Err(e) => {
+                    warn!(%e, ?key, "failed to encrypt the original, spooling it");
+                    if from_spool.is_some() {
+                        audit::exit(&config, "failed", None, sysexits::EX_TEMPFAIL);
+                    }
+                    let stored = spool_original("pgp encryption failed");
+                    signals::done();
+                    drop(relay);
+                    match stored {
+                        Ok(_) => audit::exit(&config, "spooled", Some("spool"), 0),
+                        Err(e) => panic!("failed to spool the unencrypted original: {e}"),
+                    }
+                }
+            }
+        }
+        _ => None,
+    };
+    // Attaching a large original inline as well would defeat the purpose of truncating or
+    // compressing it, and of encrypting it.
+    let re_encoded = if truncated.is_some() || compressed.is_some() || encrypted.is_some() {
+        None
+    } else {
+        re_encoded
+    };
+    let attachment_filename = match &compressed {
+        Some(_) => format!(
+            "{attachment_filename}.{}",
+            config.compress_format.extension()
+        ),
+        None => attachment_filename,
+    };
+    let attachment_filename = match &encrypted {
+        Some(_) => format!("{attachment_filename}.gpg"),
+        None => attachment_filename,
+    };
+    let catalog = config.language.catalog();
+    let config_warnings = match config_fd.metadata() {
+        Ok(md) => {
+            // Rust std widens the mode bits to the biggest common type across all supported platforms.
+            // https://github.com/rust-lang/rust/commit/aa23c98450063992473d40d707273903f8a3937d
+            let mode = md.mode();
+            #[allow(clippy::unnecessary_cast)] // the mode_t width differs across platforms
+            let more_than_user_has_access = (mode & (libc::S_IRWXG as u32 | libc::S_IRWXO as u32)) != 0;
+            if more_than_user_has_access {
+                let permissions = uucore::fs::display_permissions(&md, false);
+                vec![i18n::fill(
+                    catalog.config_too_lax,
+                    &[("permissions", &permissions)],
+                )]
+            } else {
+                vec![]
+            }
+        }
+        Err(e) => vec![i18n::fill(
+            catalog.config_permissions_unknown,
+            &[("error", &e.to_string())],
+        )],
+    };
+    let seq = config
+        .sequence_numbers
+        .then(|| seq::next(&config.state_dir))
+        .and_then(|seq| {
+            seq.map_err(|e| warn!(%e, "can't take a sequence number"))
+                .ok()
+        });
+    let body_vars = (|| {
+        let mut vars = body::BodyVars::default();
+        vars.set(
+            "config_warnings",
+            config_warnings
+                .iter()
+                .map(|w| format!("{w}\n"))
+                .collect::<String>(),
+        );
+        {
+            let mut note = String::new();
+            if let Some(source) = from_spool.as_ref().and_then(migrate::source) {
+                let time = time::format_utc_and_local(submitted_at);
+                writeln!(
+                    &mut note,
+                    "{}\n",
+                    i18n::fill(catalog.historical, &[("source", source), ("time", &time)])
+                )?;
+            }
+            match (empty, binary) {
+                (true, _) => write!(&mut note, "{}", catalog.empty)?,
+                (false, true) => write!(
+                    &mut note,
+                    "{}",
+                    i18n::fill(catalog.binary, &[("filename", &attachment_filename)])
+                )?,
+                (false, false) => write!(&mut note, "{}", catalog.attached)?,
+            }
+            if re_encoded.is_some() {
+                write!(&mut note, "{}", catalog.attached_inline)?;
+            }
+            vars.set("attachment_note", note);
+        }
+        vars.set(
+            "original_headers",
+            original_parsed
+                .as_ref()
+                .map(|p| preview::header_table(p, catalog))
+                .or_else(|| {
+                    lenient
+                        .as_ref()
+                        .map(|l| preview::lenient_header_table(l, catalog))
+                })
+                .unwrap_or_default(),
+        );
+        vars.set(
+            "cron_context",
+            original_parsed
+                .as_ref()
+                .map(|p| cron::context(p, catalog))
+                .unwrap_or_default(),
+        );
+        {
+            let mut tail_preview = String::new();
+            let text = match (&original_parsed, &stdin_raw) {
+                _ if binary || empty => None,
+                (_, OriginalMessageBody::Spooled(spooled)) => spooled
+                    .tail(64 * 1024)
+                    .ok()
+                    .map(|tail| String::from_utf8_lossy(&tail).into_owned()),
+                (Some(parsed), _) => preview::text_body(parsed),
+                (None, OriginalMessageBody::Read(raw)) => Some(String::from_utf8_lossy(raw).into_owned()),
+                (None, OriginalMessageBody::Error(_)) => None,
+            };
+            let text = match config.strip_ansi {
+                true => text.map(
+                    |text| match mime::strip_ansi(Cow::Borrowed(text.as_bytes())) {
+                        Cow::Borrowed(_) => text,
+                        Cow::Owned(stripped) => {
+                            String::from_utf8(stripped).expect("only ASCII is removed")
+                        }
+                    },
+                ),
+                false => text,
+            };
+            if let (Some(text), n @ 1..) = (&text, config.tail_preview_lines) {
+                let lines = preview::tail(text, n);
+                writeln!(
+                    &mut tail_preview,
+                    "{}",
+                    i18n::fill(catalog.tail_preview, &[("n", &lines.len().to_string())])
+                )?;
+                writeln!(&mut tail_preview)?;
+                for line in lines {
+                    writeln!(&mut tail_preview, "    {line}")?;
+                }
+                writeln!(&mut tail_preview)?;
+            }
+            // With the original encrypted, the summary is only in the encrypted details, see
+            // `seal`, and the tail preview only if asked for.
+            if encrypted.is_some() && !config.pgp_encrypt_preview {
+                tail_preview.clear();
+            }
+            vars.set("tail_preview", tail_preview);
+        }
+        vars.set("provenance", parent_process.section(catalog));
+        vars.set("environment", process::env_section(&environment, catalog));
+        {
+            let delivered_at = std::time::SystemTime::now();
+            let mut timestamps = String::new();
+            writeln!(
+                &mut timestamps,
+                "{}",
+                i18n::fill(
+                    catalog.submitted_at,
+                    &[("time", &time::format_utc_and_local(submitted_at))]
+                )
+            )?;
+            let delay = delivered_at.duration_since(submitted_at).unwrap_or_default();
+            if delay >= std::time::Duration::from_secs(60) {
+                writeln!(
+                    &mut timestamps,
+                    "{}",
+                    i18n::fill(
+                        catalog.delivered_at,
+                        &[
+                            ("time", &time::format_utc_and_local(delivered_at)),
+                            ("secs", &delay.as_secs().to_string())
+                        ]
+                    )
+                )?;
+            }
+            vars.set("timestamps", timestamps);
+        }
+        vars.set("args", args.to_string());
+        vars.set("queue_id", queue_id.clone());
+        vars.set(
+            "sequence",
+            seq.map(|seq| {
+                format!(
+                    "{}\n",
+                    i18n::fill(catalog.sequence, &[("seq", &seq.to_string())])
+                )
+            })
+            .unwrap_or_default(),
+        );
+        vars.set("original_size", original_size.to_string());
+        vars.set("original_sha256", original_sha256.clone());
+        {
+            let mut notes = String::new();
+            if !recipient_args_ignored.is_empty() && !config.recipient_args.allowlist.is_empty() {
+                writeln!(
+                    &mut notes,
+                    "{}",
+                    i18n::fill(
+                        catalog.recipient_args_ignored,
+                        &[("args", &format!("{recipient_args_ignored:?}"))]
+                    )
+                )?;
+            }
+            if let Some(key) = &user_override {
+                writeln!(
+                    &mut notes,
+                    "{}",
+                    i18n::fill(
+                        catalog.overrides_applied,
+                        &[("section", &format!("[users.{key:?}]"))]
+                    )
+                )?;
+            }
+            if original_froms.len() > 1 {
+                let values = original_froms
+                    .iter()
+                    .map(|(value, _)| format!("{value:?}"))
+                    .collect::<Vec<_>>();
+                writeln!(
+                    &mut notes,
+                    "{}",
+                    i18n::fill(catalog.multiple_from, &[("values", &values.join(", "))])
+                )?;
+            }
+            if let (Some(header), Some(from)) = (original_from_fallback, &original_parsed_from) {
+                writeln!(
+                    &mut notes,
+                    "{}",
+                    i18n::fill(
+                        catalog.sender_source,
+                        &[
+                            ("sender", &format!("hdr({})", escape_parens(from))),
+                            ("header", header)
+                        ]
+                    )
+                )?;
+            }
+            if let Some((name, route)) = &route {
+                writeln!(
+                    &mut notes,
+                    "{}",
+                    i18n::fill(
+                        catalog.routed,
+                        &[("rule", name), ("recipient", route.recipient.as_ref())]
+                    )
+                )?;
+            }
+            if let Some(occurrences) = &escalated {
+                writeln!(
+                    &mut notes,
+                    "{}",
+                    i18n::fill(
+                        catalog.escalated,
+                        &[
+                            ("count", &(occurrences.len() + 1).to_string()),
+                            ("secs", &config.escalate_window_secs.to_string())
+                        ]
+                    )
+                )?;
+                for occurrence in occurrences {
+                    writeln!(
+                        &mut notes,
+                        "  {} ({})",
+                        time::format_utc_and_local(occurrence.time()),
+                        occurrence.queue_id
+                    )?;
+                }
+            }
+            if let Some(timed_out) = stdin_timed_out {
+                let reason = match timed_out {
+                    original::TimedOut::Idle(d) => {
+                        format!("stdin_idle_timeout_secs = {}", d.as_secs())
+                    }
+                    original::TimedOut::Deadline(d) => {
+                        format!("stdin_deadline_secs = {}", d.as_secs())
+                    }
+                };
+                writeln!(
+                    &mut notes,
+                    "{}",
+                    i18n::fill(catalog.stdin_timed_out, &[("reason", &reason)])
+                )?;
+            }
+            if truncated.is_some() {
+                let sentence = match (truncated_for_relay, size_budget) {
+                    (Some(keep), Some(_)) => i18n::fill(
+                        catalog.truncated_for_relay,
+                        &[
+                            ("size", &original_size.to_string()),
+                            (
+                                "limit",
+                                &relay
+                                    .as_ref()
+                                    .ok()
+                                    .and_then(smtp::Relay::size_limit)
+                                    .unwrap_or_default()
+                                    .to_string(),
+                            ),
+                            ("keep", &(keep / 1024).to_string()),
+                        ],
+                    ),
+                    _ => i18n::fill(
+                        catalog.truncated,
+                        &[
+                            ("size", &original_size.to_string()),
+                            ("max", &config.max_original_size.to_string()),
+                            ("keep", &config.truncated_keep_kib.to_string()),
+                        ],
+                    ),
+                };
+                writeln!(&mut notes, "{sentence}")?;
+                match kept_at.get().cloned().flatten() {
+                    Some(path) => writeln!(
+                        &mut notes,
+                        "{}",
+                        i18n::fill(catalog.kept_at, &[("path", &path.display().to_string())])
+                    )?,
+                    None => writeln!(&mut notes, "{}", catalog.not_kept)?,
+                }
+            }
+            if let Some(compressed) = &compressed {
+                writeln!(
+                    &mut notes,
+                    "{}",
+                    i18n::fill(
+                        catalog.compressed,
+                        &[
+                            ("size", &original_size.to_string()),
+                            ("filename", &attachment_filename),
+                            ("compressed_size", &compressed.len().to_string())
+                        ]
+                    )
+                )?;
+            }
+            if encrypted.is_some() {
+                writeln!(
+                    &mut notes,
+                    "{}",
+                    i18n::fill(catalog.encrypted, &[("filename", &attachment_filename)])
+                )?;
+            }
+            if let Some(path) = &input {
+                writeln!(
+                    &mut notes,
+                    "{}",
+                    i18n::fill(catalog.input, &[("path", &path.display().to_string())])
+                )?;
+            }
+            if let Some(path) = &captured {
+                writeln!(
+                    &mut notes,
+                    "{}",
+                    i18n::fill(catalog.captured, &[("path", &path.display().to_string())])
+                )?;
+            }
+            if let (Some(unit), Some(_)) = (&systemd_unit, &journal) {
+                let minutes = config.journal_attachment_minutes.to_string();
+                writeln!(
+                    &mut notes,
+                    "{}",
+                    i18n::fill(
+                        catalog.journal_attached,
+                        &[("unit", &unit.unit), ("minutes", &minutes)]
+                    )
+                )?;
+            }
+            vars.set("notes", notes);
+        }
+        vars.set("gid", users::get_current_gid().to_string());
+        vars.set("euid", users::get_effective_uid().to_string());
+        vars.set("egid", users::get_effective_gid().to_string());
+        let display_or_none = |value: Option<OsString>| {
+            value
+                .as_ref()
+                .map(|s| s.to_string_lossy())
+                .unwrap_or(Cow::Borrowed(""))
+                .into_owned()
+        };
+        vars.set("username", display_or_none(users::get_current_username()));
+        vars.set("groupname", display_or_none(users::get_current_groupname()));
+        vars.set("effective_username", display_or_none(users::get_effective_username()));
+        vars.set("effective_groupname", display_or_none(users::get_effective_groupname()));
+        vars.set("system_hostname", whoami::hostname());
+        vars.set("device_name", whoami::devicename());
+        vars.set("distro", whoami::distro());
+        vars.set("platform", whoami::platform().to_string());
+        std::result::Result::<_, std::fmt::Error>::Ok(vars)
+    })()
+    .expect("this is all in-memory and we don't expect formatting to fail");
+    let crash_reports = crash::previous(&config.state_dir);
+    let failures = failures::previous(&config.state_dir);
+    let body = crash::section(&crash_reports, catalog.crashed)
+        + &failures::section(&failures, catalog.failures)
+        + &body::render(
+            config.body_template.as_deref().unwrap_or(catalog.template),
+            &vars,
+            &body_vars,
+        );
+
+    let envelope_from = envelope_from(&config, &vars);
+    let mut invocation_json = config.invocation_json.then(|| {
+        let process = |p: &process::ProcessInfo| {
+            json::Value::object([
+                ("pid", p.pid.into()),
+                ("cmdline", p.cmdline.clone().into()),
+                (
+                    "exe",
+                    p.exe.as_ref().map(|e| e.display().to_string()).into(),
+                ),
+                (
+                    "cwd",
+                    p.cwd.as_ref().map(|c| c.display().to_string()).into(),
+                ),
+            ])
+        };
+        json::Value::object([
+            ("version", env!("CARGO_PKG_VERSION").into()),
+            ("queue_id", queue_id.as_str().into()),
+            ("hostname", hostname.as_str().into()),
+            ("argv", args.as_slice().to_vec().into()),
+            ("argv_lossy", matches!(args, Args::Lossy(_)).into()),
+            ("uid", caller_uid.into()),
+            ("gid", users::get_current_gid().into()),
+            ("euid", users::get_effective_uid().into()),
+            ("egid", users::get_effective_gid().into()),
+            ("user", caller_username.clone().into()),
+            ("user_override", user_override.clone().into()),
+            ("parent_process", process(&parent_process)),
+            (
+                "systemd_unit",
+                systemd_unit
+                    .as_ref()
+                    .map(|u| {
+                        json::Value::object([
+                            ("unit", u.unit.as_str().into()),
+                            ("slice", u.slice.clone().into()),
+                            ("user_manager", u.user_manager.into()),
+                        ])
+                    })
+                    .unwrap_or(json::Value::Null),
+            ),
+            ("submitted_at", time::format_utc(submitted_at).into()),
+            ("submitted_at_unix", time::unix_secs(submitted_at).into()),
+            (
+                "original",
+                json::Value::object([
+                    ("size", original_size.into()),
+                    ("sha256", original_sha256.as_str().into()),
+                    ("parsed", original_parsed.is_some().into()),
+                    ("from", original_parsed_from.clone().into()),
+                    ("envelope_from", args_from.clone().into()),
+                    ("subject", summary.as_str().into()),
+                    ("truncated", truncated.is_some().into()),
+                    (
+                        "kept_at",
+                        kept_at
+                            .get()
+                            .cloned()
+                            .flatten()
+                            .map(|p| p.display().to_string())
+                            .into(),
+                    ),
+                ]),
+            ),
+            ("config_warnings", config_warnings.clone().into()),
+            (
+                "delivery",
+                json::Value::object([
+                    ("relay", config.smtp_host.as_str().into()),
+                    ("envelope_from", envelope_from.to_string().into()),
+                    (
+                        "recipients",
+                        recipients
+                            .iter()
+                            .map(|r| r.to_string())
+                            .collect::<Vec<_>>()
+                            .into(),
+                    ),
+                    (
+                        "recipient_args_ignored",
+                        recipient_args_ignored.clone().into(),
+                    ),
+                ]),
+            ),
+        ])
+        .to_string()
+    });
+    // With the original encrypted, so is everything that would reveal it.
+    let (body, details) = match (&config.pgp_recipient_key, &encrypted) {
+        (Some(key), Some(_)) => seal(
+            key,
+            catalog,
+            &attachment_filename,
+            body,
+            journal.take(),
+            invocation_json.take(),
+        ),
+        _ => (body, None),
+    };
+    let envelope = Envelope::new(Some(envelope_from), recipients.clone())
+        .expect("as per api docs, this can't fail");
+    // Without SMTPUTF8, internationalized domains can still be written in their ASCII form.
+    let smtp_utf8 = relay.as_ref().is_ok_and(smtp::Relay::smtp_utf8);
+    let header_address = |addr: &lettre::Address| match smtp_utf8 {
+        true => addr.clone(),
+        false => smtp::ascii_address(addr).unwrap_or_else(|| addr.clone()),
+    };
+    let identity = canonical::identity(
+        &config.generic,
+        original_parsed_from.as_deref(),
+        caller_username.as_deref(),
+        &hostname,
+    )
+    .map(|m| Mailbox::new(m.name.clone(), header_address(&m.email)));
+    debug!(?identity, "sender identity");
+    let mut email_message = Message::builder().from(match &identity {
+        Some(identity) => identity.clone(),
+        None => header_address(&config.sender_email).into(),
+    });
+    for recipient in &recipients {
+        email_message = email_message.to(header_address(recipient).into());
+    }
+    let mut email_message = email_message
+        .header(RawHeader::from_static(
+            "X-Loop",
+            config.loop_detection.x_loop.clone(),
+        ))
+        .header(RawHeader::from_static("X-FAAM-Host", hostname.clone()))
+        .header(RawHeader::from_static("X-FAAM-Uid", caller_uid.to_string()))
+        .header(RawHeader::from_static("X-FAAM-Queue-Id", queue_id.clone()))
+        .header(RawHeader::from_static(
+            "X-FAAM-Original-SHA256",
+            original_sha256.clone(),
+        ))
+        .header(RawHeader::from_static(
+            "X-FAAM-Severity",
+            severity.as_str().to_owned(),
+        ));
+    if let Some(seq) = seq {
+        email_message = email_message.header(RawHeader::from_static("X-FAAM-Seq", seq.to_string()));
+    }
+    if let Some(unit) = &systemd_unit {
+        email_message =
+            email_message.header(RawHeader::from_static("X-FAAM-Unit", unit.unit.clone()));
+    }
+    if let Some(username) = &caller_username {
+        email_message =
+            email_message.header(RawHeader::from_static("X-FAAM-User", username.clone()));
+    }
+    if let Some(original_sender) = original_parsed_from.as_ref().or(args_from.as_ref()) {
+        email_message = email_message.header(RawHeader::from_static(
+            "X-FAAM-Original-Sender",
+            original_sender.clone(),
+        ));
+    }
+    for (name, value) in &config.headers {
+        let header = RawHeader::from_name(name.clone(), vars.expand(value))
+            .expect("validated when loading the config");
+        email_message = email_message.header(header);
+    }
+    let message_id_domain = config
+        .message_id_domain
+        .as_deref()
+        .unwrap_or(config.sender_email.domain());
+    let message_id_domain =
+        idna::domain_to_ascii(message_id_domain).unwrap_or_else(|_| message_id_domain.to_owned());
+    let message_id = headers::message_id(submitted_at, &original_sha256, &message_id_domain);
+    if let Some(identity) = &identity {
+        email_message = email_message.reply_to(identity.clone());
+    } else if config.reply_to_original_sender {
+        if let Some(addr) = original_parsed_from
+            .as_deref()
+            .and_then(|from| headers::routable_address(from, &hostname))
+        {
+            email_message = email_message.reply_to(header_address(&addr).into());
+        }
+    }
+    if config.threading {
+        let anchor = headers::thread_anchor(
+            &hostname,
+            original_parsed_from
+                .as_deref()
+                .or(args_from.as_deref())
+                .unwrap_or(""),
+            &summary,
+            &message_id_domain,
+        );
+        email_message = email_message.in_reply_to(anchor.clone()).references(anchor);
+    }
+    // Well-formed mail can be relayed as-is instead, if it can be sent as it is.
+    let mode = passthrough::mode(
+        &config.mode_rules,
+        config.mode,
+        caller_uid,
+        caller_username.as_deref(),
+        original_parsed_from.as_deref(),
+    );
+    debug!(?mode);
+    let relayed = match (&stdin_raw, &original_parsed) {
+        (OriginalMessageBody::Read(raw), Some(parsed))
+            if mode == passthrough::Mode::Relay
+                && !binary
+                && stdin_timed_out.is_none()
+                && config.pgp_recipient_key.is_none()
+                && config.smime.is_none() =>
+        {
+            let eight_bit_ok =
+                raw.is_ascii() || relay.as_ref().is_ok_and(smtp::Relay::eight_bit_mime);
+            let fits = relay
+                .as_ref()
+                .ok()
+                .and_then(smtp::Relay::size_limit)
+                .is_none_or(|limit| raw.len() + OTHER_PARTS_RESERVE <= limit);
+            if eight_bit_ok && fits && mime::eight_bit_safe(raw) {
+                let mut replace = Headers::new();
+                let from_is_ours = original_parsed_from
+                    .as_deref()
+                    .is_some_and(|from| from.eq_ignore_ascii_case(config.sender_email.as_ref()));
+                if let Some(identity) = &identity {
+                    replace.set(lettre::message::header::From::from(Mailboxes::from(
+                        identity.clone(),
+                    )));
+                    if parsed.get_headers().get_first_header("Reply-To").is_none() {
+                        replace.set(ReplyTo::from(Mailboxes::from(identity.clone())));
+                    }
+                } else if !from_is_ours {
+                    let name = original_parsed_from.clone().unwrap_or(sender.clone());
+                    replace.set(lettre::message::header::From::from(Mailboxes::from(
+                        Mailbox::new(Some(name), header_address(&config.sender_email)),
+                    )));
+                    let routable = original_parsed_from
+                        .as_deref()
+                        .and_then(|from| headers::routable_address(from, &hostname));
+                    if let (Some(addr), None) =
+                        (routable, parsed.get_headers().get_first_header("Reply-To"))
+                    {
+                        replace.set(ReplyTo::from(Mailboxes::from(Mailbox::new(
+                            None,
+                            header_address(&addr),
+                        ))));
+                    }
+                }
+                if parsed
+                    .get_headers()
+                    .get_first_header("Message-ID")
+                    .is_none()
+                {
+                    replace.set(MessageId::from(message_id.clone()));
+                }
+                replace.set(RawHeader::from_static(
+                    "X-Loop",
+                    config.loop_detection.x_loop.clone(),
+                ));
+                replace.set(RawHeader::from_static("X-FAAM-Queue-Id", queue_id.clone()));
+                if let Some(seq) = seq {
+                    replace.set(RawHeader::from_static("X-FAAM-Seq", seq.to_string()));
+                }
+                replace.set(RawHeader::from_static(
+                    "X-FAAM-Severity",
+                    severity.as_str().to_owned(),
+                ));
+                Some(passthrough::rewrite(raw, &replace))
+            } else {
+                debug!(eight_bit_ok, fits, "can't relay as-is, wrapping");
+                None
+            }
+        }
+        _ => None,
+    };
+    let attachment_placeholder = mime::Placeholder::random();
+    let content_type = match (&encrypted, &compressed) {
+        (Some(_), _) => "application/pgp-encrypted",
+        (None, Some(_)) => config.compress_format.content_type(),
+        // (Stdin may not necessarily be a correct email to begin with, so, octet-stream is a reasonable default.)
+        (None, None) => "application/octet-stream",
+    };
+    let email_message = email_message
+        .message_id(Some(message_id))
+        .subject(subject.clone())
+        .envelope(envelope)
+        .multipart({
+            let mut mp_builder = match config.html_body {
+                true => {
+                    let html = body::html(&body);
+                    MultiPart::mixed().multipart(MultiPart::alternative_plain_html(body, html))
+                }
+                false => MultiPart::mixed().singlepart(SinglePart::plain(body)),
+            };
+
+            let rfc822 = |body: Body| {
+                SinglePart::builder()
+                    .header(ContentType::parse("message/rfc822").unwrap())
+                    .header(ContentDisposition::inline())
+                    .body(body)
+            };
+            mp_builder = match &re_encoded {
+                Some(Inline::Verbatim(raw)) if raw.is_ascii() => mp_builder.singlepart(rfc822(
+                    inline_placeholder.body(ContentTransferEncoding::SevenBit),
+                )),
+                Some(Inline::Verbatim(_)) => mp_builder.singlepart(rfc822(
+                    inline_placeholder.body(ContentTransferEncoding::EightBit),
+                )),
+                // Not dangerous because we used Base64 encoding to build the `re_encoded` => EigthBit safe
+                Some(Inline::ReEncoded(part, _)) => mp_builder.singlepart(rfc822(
+                    Body::dangerous_pre_encoded(part.clone(), ContentTransferEncoding::EightBit),
+                )),
+                Some(Inline::Html(_)) => mp_builder.singlepart(
+                    SinglePart::builder()
+                        .header(ContentType::TEXT_HTML)
+                        .header(ContentDisposition::inline())
+                        .body(inline_placeholder.body(ContentTransferEncoding::Base64)),
+                ),
+                None => {
+                    debug!("can't inline the attachment, see previous log messages");
+                    mp_builder
+                }
+            };
+
+            if !empty {
+                mp_builder = mp_builder.singlepart(
+                    SinglePart::builder()
+                        .header(ContentType::parse(content_type).unwrap())
+                        .header(ContentDisposition::attachment(&attachment_filename))
+                        .body(attachment_placeholder.body(ContentTransferEncoding::Base64)),
+                );
+            }
+
+            if let Some(details) = details {
+                mp_builder = mp_builder.singlepart(
+                    SinglePart::builder()
+                        .header(ContentType::parse("application/pgp-encrypted").unwrap())
+                        .header(ContentDisposition::attachment(DETAILS_FILENAME))
+                        .body(
+                            Body::new_with_encoding(details, ContentTransferEncoding::Base64)
+                                .unwrap(),
+                        ),
+                );
+            }
+
+            if let Some(journal) = journal {
+                mp_builder = mp_builder.singlepart(
+                    SinglePart::builder()
+                        .header(ContentType::parse("text/plain; charset=utf-8").unwrap())
+                        .header(ContentDisposition::attachment("journal.txt"))
+                        .body(
+                            Body::new_with_encoding(journal, ContentTransferEncoding::Base64)
+                                .unwrap(),
+                        ),
+                );
+            }
+
+            if let Some(invocation_json) = invocation_json {
+                mp_builder = mp_builder.singlepart(
+                    SinglePart::builder()
+                        .header(ContentType::parse("application/json").unwrap())
+                        .header(ContentDisposition::attachment("invocation.json"))
+                        .body(
+                            Body::new_with_encoding(
+                                invocation_json,
+                                ContentTransferEncoding::Base64,
+                            )
+                            .unwrap(),
+                        ),
+                );
+            }
+
+            mp_builder
+        })
+        .expect("Failed to attach stdin email message");
+
+    // The large parts are encoded straight into the formatted message, instead of holding the
+    // original, its encoding, and the formatted message at the same time.
+    let envelope = email_message.envelope().clone();
+    let template = email_message.formatted();
+    drop(email_message);
+    let splice = |attachment: mime::Content| {
+        let mut parts = Vec::new();
+        match &re_encoded {
+            Some(Inline::Verbatim(raw)) => {
+                parts.push((&inline_placeholder, mime::Content::verbatim(raw)));
+            }
+            Some(Inline::ReEncoded(_, body) | Inline::Html(body)) => parts.push((
+                &inline_placeholder,
+                mime::Content::new(body.as_bytes(), body.len()),
+            )),
+            None => (),
+        }
+        if !empty {
+            parts.push((&attachment_placeholder, attachment));
+        }
+        mime::splice(&template, parts)
+    };
+    let read_error = |e: &io::Error| {
+        let msg = format!("forward-as-attachment-mta failed to read stdin: {e:?}");
+        splice(mime::Content::new(
+            std::io::Cursor::new(msg.clone()),
+            msg.len(),
+        ))
+        .expect("in-memory")
+    };
+    let formatted = match relayed {
+        Some(relayed) => {
+            debug!("relaying the original transparently");
+            Ok(relayed)
+        }
+        None => match (
+            encrypted.as_ref().or(compressed.as_ref()),
+            &truncated,
+            &stdin_raw,
+        ) {
+            (Some(compressed), _, _) => {
+                splice(mime::Content::new(compressed.as_slice(), compressed.len()))
+            }
+            (None, Some(data), _) => splice(mime::Content::new(data.as_slice(), data.len())),
+            (None, None, OriginalMessageBody::Read(raw)) => {
+                splice(mime::Content::new(raw.as_slice(), raw.len()))
+            }
+            (None, None, OriginalMessageBody::Spooled(spooled)) => spooled
+                .reader()
+                .and_then(|r| splice(mime::Content::new(r, spooled.len()))),
+            (None, None, OriginalMessageBody::Error(e)) => Ok(read_error(e)),
+        },
+    }
+    .unwrap_or_else(|e| read_error(&e));
+    // Like PGP encryption, S/MIME fails closed: the submission is spooled instead of being sent
+    // unsigned or in the clear.
+    let formatted = match &config.smime {
+        Some(smime) => match smime::protect(smime, &formatted) {
+            Ok(protected) => protected,
+            Err(e) => {
+                warn!(%e, "failed to sign or encrypt the wrapper with S/MIME, spooling it");
+                if from_spool.is_some() {
+                    audit::exit(&config, "failed", None, sysexits::EX_TEMPFAIL);
+                }
+                let stored = spool_original("s/mime failed");
+                signals::done();
+                drop(relay);
+                match stored {
+                    Ok(_) => audit::exit(&config, "spooled", Some("spool"), 0),
+                    Err(e) => panic!("failed to spool the original: {e}"),
+                }
+            }
+        },
+        None => formatted,
+    };
+    debug!(
+        target: logging::CONTENT_TARGET,
+        message=%String::from_utf8_lossy(&formatted),
+        "sending message",
+    );
+
+    drop(compose_span);
+    let deliver_span = tracing::info_span!("deliver").entered();
+    let (default_recipients, routed) = smtp::partition(&config.smarthosts, envelope.to());
+    let mut transports = Vec::new();
+    if !default_recipients.is_empty() {
+        transports.push(config.smtp_host.as_str());
+    }
+    let mut result = match default_recipients.len() {
+        0 => {
+            drop(relay);
+            Ok(())
+        }
+        n if n == envelope.to().len() => {
+            relay.and_then(|mut relay| relay.send(&envelope, &formatted))
+        }
+        _ => relay.and_then(|mut relay| {
+            let envelope = Envelope::new(envelope.from().cloned(), default_recipients)
+                .expect("as per api docs, this can't fail");
+            relay.send(&envelope, &formatted)
+        }),
+    };
+    for (smarthost, recipients) in routed {
+        debug!(
+            smarthost = smarthost.smtp_host,
+            ?recipients,
+            "sending via smarthost"
+        );
+        transports.push(smarthost.smtp_host.as_str());
+        let envelope = Envelope::new(envelope.from().cloned(), recipients)
+            .expect("as per api docs, this can't fail");
+        let sent = smtp::connect_smarthost(&config, smarthost)
+            .and_then(|mut relay| relay.send(&envelope, &formatted));
+        if let Err(e) = &sent {
+            warn!(smarthost = smarthost.smtp_host, %e, "failed to send via smarthost");
+        }
+        result = result.and(sent);
+    }
+    signals::done();
+    let to = envelope
+        .to()
+        .iter()
+        .map(|addr| addr.to_string())
+        .collect::<Vec<_>>()
+        .join(",");
+    let original_sender = original_parsed_from
+        .as_deref()
+        .or(args_from.as_deref())
+        .unwrap_or("");
+    match &result {
+        Ok(()) => info!(
+            result = "delivered",
+            original_sender,
+            %to,
+            size = formatted.len(),
+            "delivered"
+        ),
+        Err(e) => {
+            warn!(result = "failed", original_sender, %to, %e, "delivery failed");
+            if let Err(e) = failures::record(&config.state_dir, &queue_id, e, &subject) {
+                warn!(%e, "can't note the failed delivery");
+            }
+            console::failed(&config, &hostname, e);
+        }
+    }
+    marker::record(&config.state_dir, &queue_id, &result);
+    metrics::record(
+        &config,
+        match &result {
+            Ok(()) => metrics::Event::Delivered,
+            Err(e) => metrics::Event::Failed(e.class()),
+        },
+    );
+    db::record(
+        &config,
+        &db::Delivery {
+            at: time::unix_secs(submitted_at),
+            queue_id: queue_id.clone(),
+            failure: result.as_ref().err().map(|e| e.class().to_owned()),
+            uid: caller_uid,
+            size: formatted.len() as u64,
+            sender: original_sender.to_owned(),
+        },
+    );
+    drop(deliver_span);
+    submission_span.record(
+        "result",
+        if result.is_ok() {
+            "delivered"
+        } else {
+            "failed"
+        },
+    );
+    drop(submission_span);
+    if let Some(endpoint) = &config.otlp_endpoint {
+        #[cfg(feature = "otlp")]
+        match &result {
+            Ok(()) => otlp::export(endpoint, submitted_at, "delivered", None),
+            Err(e) => otlp::export(endpoint, submitted_at, "failed", Some(e.class())),
+        }
+        #[cfg(not(feature = "otlp"))]
+        warn!(endpoint, "built without the otlp feature, not exporting");
+    }
+    #[cfg(feature = "sentry")]
+    if let (Some(dsn), Err(e)) = (&config.sentry_dsn, &result) {
+        sentry::report_failure(dsn, &queue_id, e);
+    }
+    #[cfg(not(feature = "sentry"))]
+    if config.sentry_dsn.is_some() {
+        warn!("built without the sentry feature, not reporting to Sentry");
+    }
+    // After the delivery is in the history, which the payload counts.
+    push::spawn(
+        &config,
+        if result.is_ok() {
+            "delivered"
+        } else {
+            "failed"
+        },
+    );
+    if !config.post_send_hook.is_empty() {
+        let (outcome, class, error) = match &result {
+            Ok(()) => ("delivered", "", String::new()),
+            Err(e) => ("failed", e.class(), e.to_string()),
+        };
+        let recipients: Vec<String> = recipients.iter().map(|r| r.to_string()).collect();
+        hooks::post_send(
+            &config,
+            &[
+                ("FAAM_QUEUE_ID", queue_id.clone()),
+                ("FAAM_OUTCOME", outcome.to_owned()),
+                ("FAAM_ERROR_CLASS", class.to_owned()),
+                ("FAAM_ERROR", error),
+                ("FAAM_SUBJECT", subject.clone()),
+                ("FAAM_RECIPIENTS", recipients.join(",")),
+                ("FAAM_TRANSPORTS", transports.join(",")),
+                ("FAAM_HOSTNAME", hostname.clone()),
+            ],
+        );
+    }
+    audit::finish(
+        &config,
+        if result.is_ok() {
+            "delivered"
+        } else {
+            "failed"
+        },
+        Some(&transports.join(",")),
+    );
+    if result.is_ok() {
+        crash::clear(&crash_reports);
+        if let Err(e) = failures::clear(&config.state_dir, &failures) {
+            warn!(%e, "can't clear the previous delivery failures");
+        }
+    }
+    // Quiet on success, so that cron doesn't mail about the mail.
+    match result {
+        Ok(()) if config.verbose || args.verbose() => println!("Email sent successfully"),
+        Ok(()) => (),
+        Err(e) => {
+            eprintln!("forward-as-attachment-mta: failed to send email: {e}");
+            // Also keeps the spool entry for the next `sendmail -q`.
+            std::process::exit(sysexits::EX_TEMPFAIL);
+        }
+    }
+}
+
+/// Room for the parts other than the original (and the journal) when fitting the message into
+/// the relay's size limit.
+const OTHER_PARTS_RESERVE: usize = 64 * 1024;
+
+/// The attachment with the summary, the journal, and the invocation, if the original is
+/// encrypted.
+const DETAILS_FILENAME: &str = "details.txt.gpg";
+
+/// With the original encrypted, the summary, the journal, and the invocation would still reveal
+/// it, so they go into one attachment encrypted to `key`, and the wrapper text only says where
+/// things are. Returns the text and the attachment, which is left out if encrypting fails.
+fn seal(
+    key: &Path,
+    catalog: &i18n::Catalog,
+    attachment_filename: &str,
+    summary: String,
+    journal: Option<Vec<u8>>,
+    invocation_json: Option<String>,
+) -> (String, Option<Vec<u8>>) {
+    let mut details = summary.into_bytes();
+    if let Some(journal) = journal {
+        details.extend_from_slice(b"\n-- journal.txt --\n");
+        details.extend(journal);
+    }
+    if let Some(invocation_json) = invocation_json {
+        details.extend_from_slice(b"\n-- invocation.json --\n");
+        details.extend(invocation_json.into_bytes());
+    }
+    let mut text = i18n::fill(catalog.encrypted, &[("filename", attachment_filename)]) + "\n";
+    let details = match pgp::encrypt(key, details.as_slice()) {
+        Ok(details) => {
+            text += &i18n::fill(catalog.details_encrypted, &[("filename", DETAILS_FILENAME)]);
+            text += "\n";
+            Some(details)
+        }
+        Err(e) => {
+            warn!(%e, ?key, "failed to encrypt the details, leaving them out");
+            None
+        }
+    };
+    (text, details)
+}
+
+/// The headers that name the sender if the original has no usable `From` header, in order.
+const FROM_FALLBACK_HEADERS: [&str; 3] = ["Sender", "Return-Path", "X-Original-From"];
+
+/// Run us once per `--input` path, with `args`, i.e. the others. Returns the exit code of the
+/// first that failed.
+fn forward_inputs(args: &[OsString], inputs: &[PathBuf]) -> i32 {
+    let exe = match std::env::current_exe() {
+        Ok(exe) => exe,
+        Err(e) => {
+            eprintln!("forward-as-attachment-mta: {e}");
+            return sysexits::EX_TEMPFAIL;
+        }
+    };
+    let mut code = 0;
+    for path in inputs {
+        // Before the others, which may end with `--`.
+        let status = std::process::Command::new(&exe)
+            .arg0(&args[0])
+            .arg("--input")
+            .arg(path)
+            .args(&args[1..])
+            .status();
+        let failed = match status {
+            Ok(status) => status.code().unwrap_or(sysexits::EX_TEMPFAIL),
+            Err(e) => {
+                eprintln!("forward-as-attachment-mta: --input {path:?}: {e}");
+                sysexits::EX_TEMPFAIL
+            }
+        };
+        if code == 0 {
+            code = failed;
+        }
+    }
+    code
+}
+
+fn hostname() -> String {
+    hostname::get()
+        .map(|os_str| os_str.to_string_lossy().to_string())
+        .unwrap_or("???".to_string())
+}
+
+fn envelope_from(config: &Config, vars: &template::Vars) -> lettre::Address {
+    let Some(template) = &config.envelope_from else {
+        return config.sender_email.clone();
+    };
+    let expanded = vars.expand(template);
+    match expanded.parse() {
+        Ok(addr) => addr,
+        Err(e) => {
+            warn!(%e, ?expanded, "envelope_from is not a valid address, using sender_email");
+            config.sender_email.clone()
+        }
+    }
+}
+
+/// Identifies this invocation in logs, headers, and on-disk state.
+fn new_queue_id() -> String {
+    let now = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap_or_default();
+    format!(
+        "{:X}{:05X}{:X}",
+        now.as_secs(),
+        now.subsec_micros(),
+        std::process::id()
+    )
+}
+
+/// Send a short plain-text message about the forwarder itself to the configured recipient.
+fn send_notice(config: &Config, subject: String, body: String) -> Result<(), smtp::Error> {
+    send_notice_with_attachments(config, subject, body, vec![])
+}
+
+/// Like [`send_notice`], with `(filename, data)` attachments.
+fn send_notice_with_attachments(
+    config: &Config,
+    subject: String,
+    body: String,
+    attachments: Vec<(String, Vec<u8>)>,
+) -> Result<(), smtp::Error> {
+    let hostname = hostname();
+    let username = users::get_current_username().map(|u| u.to_string_lossy().to_string());
+    let vars = template::Vars {
+        hostname: &hostname,
+        user: username.as_deref(),
+        uid: users::get_current_uid(),
+        ..Default::default()
+    };
+    let envelope = Envelope::new(
+        Some(envelope_from(config, &vars)),
+        vec![config.recipient_email.clone()],
+    )
+    .expect("as per api docs, this can't fail");
+    let message = Message::builder()
+        .from(config.sender_email.clone().into())
+        .to(config.recipient_email.clone().into())
+        .envelope(envelope)
+        .header(RawHeader::from_static(
+            "X-Loop",
+            config.loop_detection.x_loop.clone(),
+        ))
+        .subject(subject);
+    let message = match attachments.is_empty() {
+        true => message.body(body),
+        false => message.multipart(attachments.into_iter().fold(
+            MultiPart::mixed().singlepart(SinglePart::plain(body)),
+            |mp, (filename, data)| {
+                mp.singlepart(
+                    SinglePart::builder()
+                        .header(ContentType::parse("application/octet-stream").unwrap())
+                        .header(ContentDisposition::attachment(&filename))
+                        .body(
+                            Body::new_with_encoding(data, ContentTransferEncoding::Base64).unwrap(),
+                        ),
+                )
+            },
+        )),
+    }
+    .expect("all headers are valid");
+    smtp::connect(config)?.send(message.envelope(), &message.formatted())
+}
+
+fn try_extract_cron_from_header(from_header_value: &str) -> Option<&str> {
+    static RE: OnceLock<Regex> = OnceLock::new();
+    let re = RE.get_or_init(|| Regex::new(r"(\S+) \(Cron Daemon\)").unwrap());
+    match re.captures(from_header_value) {
+        None => None,
+        Some(caps) => Some(caps.get(1).unwrap().as_str()),
+    }
+}
+
+fn escape_parens(output: &str) -> Cow<'_, str> {
+    let output = Cow::Borrowed(output);
+    let output = if output.contains('(') {
+        Cow::Owned(output.replace('(', r"\("))
+    } else {
+        output
+    };
+    let output = if output.contains(')') {
+        Cow::Owned(output.replace(')', r"\)"))
+    } else {
+        output
+    };
+    output
+}
+
+/// Whether any feature matches submissions by their text, which is then worth extracting.
+fn matches_text(config: &Config) -> bool {
+    !config.filters.is_empty()
+        || !config.classifiers.is_empty()
+        || !config.routes.is_empty()
+        || config.dedup_window_secs != 0
+        || config.quiet_hours.is_some()
+        || config.plugin.is_some()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::borrow::Cow;
+
+    use super::*;
+
+    #[test]
+    fn test_cron_from_header() {
+        let f = try_extract_cron_from_header;
+        assert_eq!(f("root (Cron Daemon)"), Some("root"));
+        assert_eq!(f("(foo) (Cron Daemon))"), Some("(foo)"));
+    }
+
+    #[test]
+    fn test_matches_text() {
+        let config = |rules: &str| {
+            toml::from_str::<Config>(&format!(
+                r#"
+                sender_email = "sender@example.com"
+                recipient_email = "admin@example.com"
+                smtp_host = "smtp.example.com"
+                smtp_username = "user"
+                smtp_password = "pass"
+                {rules}
+                "#
+            ))
+            .unwrap()
+        };
+        assert!(!matches_text(&config("")));
+        let routed = config(
+            r#"
+            [[route]]
+            body = "ERROR|FATAL"
+            recipient = "oncall@example.com"
+            "#,
+        );
+        assert!(matches_text(&routed));
+    }
+
+    #[test]
+    fn test_seal() {
+        let dir = crate::testdir::TestDir::new("seal");
+        let key = pgp::test_key(&dir);
+        let catalog = i18n::Language::En.catalog();
+        let secret = "db password rotated: hunter2";
+        let (text, details) = seal(
+            &key,
+            catalog,
+            "stdin.eml.gpg",
+            format!("Subject: rotation\n\n    {secret}\n"),
+            Some(format!("backup[42]: {secret}\n").into_bytes()),
+            Some(format!(r#"{{"subject":"{secret}"}}"#)),
+        );
+        // The wrapper text is the only part left in the clear.
+        assert!(!text.contains("hunter2"), "{text}");
+        assert!(text.contains("stdin.eml.gpg") && text.contains(DETAILS_FILENAME));
+        let details = details.unwrap();
+        assert!(!details.windows(7).any(|w| w == b"hunter2"));
+        let details = String::from_utf8(pgp::test_decrypt(&dir, &details)).unwrap();
+        assert_eq!(details.matches(secret).count(), 3, "{details}");
     }
 
     #[test]
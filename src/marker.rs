@@ -0,0 +1,82 @@
+//! `state_dir/last-success` and `state_dir/last-failure`, rewritten after each delivery, for
+//! monitoring that only checks a file's age, e.g. alert if `last-success` is older than a week.
+//!
+//! Each is one line: the Unix time, the time in UTC, the queue id, and the reason, separated by
+//! spaces, so `read secs utc queue_id reason < last-failure` works.
+
+use std::io;
+use std::os::unix::fs::PermissionsExt;
+use std::path::Path;
+use std::time::SystemTime;
+
+/// Update the marker of the delivery `result` of `queue_id`.
+pub fn record(state_dir: &Path, queue_id: &str, result: &Result<(), crate::smtp::Error>) {
+    let (name, reason) = match result {
+        Ok(()) => ("last-success", "delivered".to_owned()),
+        Err(e) => ("last-failure", format!("{}: {e}", e.class())),
+    };
+    if let Err(e) = write(state_dir, name, queue_id, &reason, SystemTime::now()) {
+        tracing::warn!(%e, name, "can't update the marker file");
+    }
+}
+
+/// The time, queue id, and reason of `state_dir/last-failure`, if there is one.
+pub fn last_failure(state_dir: &Path) -> Option<(u64, String, String)> {
+    let line = std::fs::read_to_string(state_dir.join("last-failure")).ok()?;
+    let mut fields = line.trim_end().splitn(4, ' ');
+    let at = fields.next()?.parse().ok()?;
+    let _utc = fields.next()?;
+    Some((at, fields.next()?.to_owned(), fields.next()?.to_owned()))
+}
+
+fn write(
+    state_dir: &Path,
+    name: &str,
+    queue_id: &str,
+    reason: &str,
+    now: SystemTime,
+) -> io::Result<()> {
+    let line = format!(
+        "{} {} {queue_id} {}\n",
+        crate::time::unix_secs(now),
+        crate::time::format_utc(now),
+        reason.replace(['\r', '\n'], " ")
+    );
+    // Atomically, so that readers never see it empty.
+    let tmp = state_dir.join(format!(".{name}.{}.tmp", std::process::id()));
+    std::fs::write(&tmp, line)?;
+    // Not derived from submissions, so that monitoring running as another user can read it.
+    std::fs::set_permissions(&tmp, std::fs::Permissions::from_mode(0o644))?;
+    std::fs::rename(&tmp, state_dir.join(name))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_write() {
+        let dir = crate::testdir::TestDir::new("marker");
+        let at = SystemTime::UNIX_EPOCH + std::time::Duration::from_secs(1704067200);
+        write(
+            &dir,
+            "last-failure",
+            "65A0B1C2D3E4F",
+            "deferred: 451\ntry again",
+            at,
+        )
+        .unwrap();
+        assert_eq!(
+            std::fs::read_to_string(dir.join("last-failure")).unwrap(),
+            "1704067200 2024-01-01T00:00:00Z 65A0B1C2D3E4F deferred: 451 try again\n"
+        );
+        assert_eq!(
+            last_failure(&dir).unwrap(),
+            (
+                1704067200,
+                "65A0B1C2D3E4F".to_owned(),
+                "deferred: 451 try again".to_owned()
+            )
+        );
+    }
+}
@@ -0,0 +1,173 @@
+//! Metrics for node_exporter's textfile collector, with `metrics_textfile`, so that one can
+//! alert when forwarding silently breaks.
+//!
+//! The counters are kept in `state_dir/metrics/counters`, as the samples they are exported as.
+//! After each update, and after each `sendmail -q`, the textfile is rewritten atomically, with
+//! the current queue depth.
+
+use std::collections::BTreeMap;
+use std::fmt::Write as _;
+use std::io::{self, Read, Seek, Write as _};
+use std::os::fd::AsRawFd;
+use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+use std::path::Path;
+use std::time::SystemTime;
+
+use tracing::warn;
+
+use crate::config::Config;
+
+const PREFIX: &str = "forward_as_attachment_mta";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Event<'a> {
+    Submitted,
+    Delivered,
+    /// With the class of the failure, see `smtp::Error::class`.
+    Failed(&'a str),
+    /// Only the queue depth changed, after `sendmail -q`.
+    Flushed,
+}
+
+/// The metric families: name, type, and help.
+const FAMILIES: &[(&str, &str, &str)] = &[
+    (
+        "submissions_total",
+        "counter",
+        "Submissions, not counting forwarding spooled ones.",
+    ),
+    (
+        "deliveries_total",
+        "counter",
+        "Messages delivered to the relay.",
+    ),
+    ("failures_total", "counter", "Failed deliveries, by class."),
+    (
+        "last_success_timestamp_seconds",
+        "gauge",
+        "When a message was last delivered.",
+    ),
+    ("queue_depth", "gauge", "Submissions in the spool."),
+];
+
+/// Record `event` if `metrics_textfile` is set. Failures are logged.
+pub fn record(config: &Config, event: Event<'_>) {
+    let Some(textfile) = &config.metrics_textfile else {
+        return;
+    };
+    if let Err(e) = update(&config.state_dir, textfile, event) {
+        warn!(%e, ?textfile, "can't update the metrics");
+    }
+}
+
+fn update(state_dir: &Path, textfile: &Path, event: Event<'_>) -> io::Result<()> {
+    let mut file = std::fs::File::options()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .mode(0o600)
+        .open(crate::state::subdir_in(state_dir, "metrics")?.join("counters"))?;
+    // Concurrent submissions take turns, so that neither's increments are lost.
+    // SAFETY: the file descriptor is open
+    if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let mut contents = String::new();
+    file.read_to_string(&mut contents)?;
+    let mut samples = contents
+        .lines()
+        .filter_map(|line| {
+            let (name, value) = line.rsplit_once(' ')?;
+            Some((name.to_owned(), value.parse::<u64>().ok()?))
+        })
+        .collect::<BTreeMap<_, _>>();
+    let mut increment = |name: String| *samples.entry(name).or_insert(0) += 1;
+    match event {
+        Event::Submitted => increment("submissions_total".to_owned()),
+        Event::Delivered => {
+            increment("deliveries_total".to_owned());
+            let now = crate::time::unix_secs(SystemTime::now());
+            samples.insert("last_success_timestamp_seconds".to_owned(), now);
+        }
+        Event::Failed(class) => increment(format!("failures_total{{class=\"{class}\"}}")),
+        Event::Flushed => (),
+    }
+    let mut state = String::new();
+    for (name, value) in &samples {
+        writeln!(&mut state, "{name} {value}").unwrap();
+    }
+    file.set_len(0)?;
+    file.rewind()?;
+    file.write_all(state.as_bytes())?;
+
+    let queue_depth = crate::spool::depth(state_dir)?;
+    samples.insert("queue_depth".to_owned(), queue_depth as u64);
+    // node_exporter may read it anytime, so it's renamed into place.
+    let mut tmp = textfile.to_owned().into_os_string();
+    tmp.push(format!(".{}.tmp", std::process::id()));
+    std::fs::write(&tmp, render(&samples))?;
+    // For node_exporter, despite our umask.
+    std::fs::set_permissions(&tmp, std::fs::Permissions::from_mode(0o644))?;
+    std::fs::rename(&tmp, textfile)
+}
+
+fn render(samples: &BTreeMap<String, u64>) -> String {
+    let mut out = String::new();
+    for (family, kind, help) in FAMILIES {
+        writeln!(&mut out, "# HELP {PREFIX}_{family} {help}").unwrap();
+        writeln!(&mut out, "# TYPE {PREFIX}_{family} {kind}").unwrap();
+        let mut any = false;
+        for (name, value) in samples {
+            let labels = name.strip_prefix(family);
+            if labels.is_some_and(|l| l.is_empty() || l.starts_with('{')) {
+                writeln!(&mut out, "{PREFIX}_{name} {value}").unwrap();
+                any = true;
+            }
+        }
+        // So that alerting on e.g. `increase(..._failures_total[1h])` works from the start.
+        if !any && *kind == "counter" {
+            writeln!(&mut out, "{PREFIX}_{family} 0").unwrap();
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_update() {
+        let dir = crate::testdir::TestDir::new("metrics");
+        let textfile = dir.join("faam.prom");
+        for event in [
+            Event::Submitted,
+            Event::Submitted,
+            Event::Failed("deferred"),
+            Event::Failed("deferred"),
+            Event::Failed("connection"),
+            Event::Flushed,
+        ] {
+            update(&dir, &textfile, event).unwrap();
+        }
+        let metrics = std::fs::read_to_string(&textfile).unwrap();
+        let samples = metrics
+            .lines()
+            .filter(|l| !l.starts_with('#'))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            samples,
+            [
+                "forward_as_attachment_mta_submissions_total 2",
+                "forward_as_attachment_mta_deliveries_total 0",
+                "forward_as_attachment_mta_failures_total{class=\"connection\"} 1",
+                "forward_as_attachment_mta_failures_total{class=\"deferred\"} 2",
+                "forward_as_attachment_mta_queue_depth 0",
+            ]
+        );
+        update(&dir, &textfile, Event::Delivered).unwrap();
+        let metrics = std::fs::read_to_string(&textfile).unwrap();
+        assert!(metrics.contains("\nforward_as_attachment_mta_last_success_timestamp_seconds 1"));
+    }
+}
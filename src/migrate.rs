@@ -0,0 +1,335 @@
+//! The `--migrate-spool` subcommand, for hosts that ran a full MTA before: forward the mail that is
+//! still in the local mailboxes, i.e. the mbox files in `/var/mail` and the users' `~/Maildir`.
+//!
+//! Each message becomes a spool entry on behalf of the mailbox's owner, submitted at its `Date`,
+//! which `sendmail -q` then forwards with a banner that it's historical mail. The mailboxes are
+//! left as they are; the SHA-256 of each message is kept in `state_dir/migrated`, so that running
+//! it again only forwards what's new. The report goes to `state_dir/migrate-spool.report`.
+
+use std::collections::HashSet;
+use std::fmt::Write as _;
+use std::io::{self, BufRead, BufReader, Write};
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+use mailparse::MailHeaderMap;
+use tracing::warn;
+use users::os::unix::UserExt;
+
+use crate::config::Config;
+use crate::{hardening, spool, sysexits};
+
+const MAIL_SPOOL: &str = "/var/mail";
+
+/// The `reason` of the entries, followed by the mailbox, see `spool::Meta`.
+const REASON_PREFIX: &str = "historical mail from ";
+
+/// The mailbox a spool entry was migrated from, if it was.
+pub fn source(meta: &spool::Meta) -> Option<&str> {
+    meta.reason.strip_prefix(REASON_PREFIX)
+}
+
+/// `forward-as-attachment-mta --migrate-spool [<mailbox>...]`, for root only, since it reads any
+/// mailbox, and prints the subjects. Returns the exit code.
+pub fn command(config: &Config, caller_uid: u32, mailboxes: &[PathBuf]) -> i32 {
+    if let Err(code) = hardening::require_root(caller_uid, "--migrate-spool") {
+        return code;
+    }
+    let mailboxes = if mailboxes.is_empty() {
+        local_mailboxes()
+    } else {
+        mailboxes.to_vec()
+    };
+    match migrate(config, &mailboxes) {
+        Ok((report, code)) => {
+            print!("{report}");
+            code
+        }
+        Err(e) => {
+            eprintln!("forward-as-attachment-mta: --migrate-spool: {e}");
+            sysexits::EX_TEMPFAIL
+        }
+    }
+}
+
+/// The mbox files in `/var/mail`, and the users' `~/Maildir`s.
+fn local_mailboxes() -> Vec<PathBuf> {
+    let mut mailboxes = match std::fs::read_dir(MAIL_SPOOL) {
+        Ok(entries) => entries
+            .filter_map(|e| e.ok())
+            .filter(|e| e.file_type().is_ok_and(|t| t.is_file()))
+            .filter(|e| !e.file_name().to_string_lossy().starts_with('.'))
+            .map(|e| e.path())
+            .collect(),
+        Err(e) => {
+            warn!(%e, MAIL_SPOOL, "can't list the mail spool");
+            vec![]
+        }
+    };
+    mailboxes.sort();
+    // SAFETY: we are single-threaded here, `getpwent` isn't reentrant
+    let users = unsafe { users::all_users() };
+    let mut maildirs: Vec<PathBuf> = users
+        .map(|user| user.home_dir().join("Maildir"))
+        .filter(|maildir| maildir.join("cur").is_dir())
+        .collect();
+    maildirs.sort();
+    maildirs.dedup();
+    mailboxes.extend(maildirs);
+    mailboxes
+}
+
+/// Returns the report, and the exit code.
+fn migrate(config: &Config, mailboxes: &[PathBuf]) -> io::Result<(String, i32)> {
+    let migrated_path = config.state_dir.join("migrated");
+    let mut migrated: HashSet<String> = match std::fs::read_to_string(&migrated_path) {
+        Ok(s) => s.lines().map(str::to_owned).collect(),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => HashSet::new(),
+        Err(e) => return Err(e),
+    };
+    crate::state::subdir(config, "spool")?;
+    let mut migrated_file = std::fs::File::options()
+        .create(true)
+        .append(true)
+        .open(&migrated_path)?;
+    let mut code = 0;
+    // Per mailbox: the entries, and how many were migrated before.
+    let mut stored = Vec::new();
+    for mailbox in mailboxes {
+        let mut entries = Vec::new();
+        let mut before = 0;
+        let result = messages(mailbox, |message, mtime| {
+            let sha256 = crate::headers::sha256_hex(&message);
+            if migrated.contains(&sha256) {
+                before += 1;
+                return Ok(());
+            }
+            let meta = meta(mailbox, &message, mtime)?;
+            spool::store(&config.state_dir, &meta, message.as_slice())?;
+            writeln!(migrated_file, "{sha256}")?;
+            migrated.insert(sha256);
+            entries.push((meta, subject(&message)));
+            Ok(())
+        });
+        if let Err(e) = &result {
+            warn!(%e, ?mailbox, "failed to migrate the mailbox");
+            code = sysexits::EX_TEMPFAIL;
+        }
+        stored.push((mailbox, entries, before, result));
+    }
+    migrated_file.sync_all()?;
+    if spool::flush(config) != 0 {
+        code = sysexits::EX_TEMPFAIL;
+    }
+    let spool_dir = crate::state::subdir(config, "spool")?;
+    let mut report = format!(
+        "migrate-spool at {}\n",
+        crate::time::format_utc_and_local(SystemTime::now())
+    );
+    for (mailbox, entries, before, result) in stored {
+        let kept = |meta: &spool::Meta| spool_dir.join(format!("{}.toml", meta.queue_id)).exists();
+        let n_kept = entries.iter().filter(|(meta, _)| kept(meta)).count();
+        write!(
+            report,
+            "\n{}: {} forwarded, {n_kept} kept in the spool, {before} migrated before",
+            mailbox.display(),
+            entries.len() - n_kept,
+        )
+        .unwrap();
+        match result {
+            Ok(()) => report.push('\n'),
+            Err(e) => writeln!(report, ", then failed: {e}").unwrap(),
+        }
+        for (meta, subject) in &entries {
+            let outcome = if kept(meta) { "kept" } else { "forwarded" };
+            writeln!(
+                report,
+                "  {} {outcome:9} {} {subject}",
+                meta.queue_id,
+                crate::time::format_utc(meta.submitted_at()),
+            )
+            .unwrap();
+        }
+    }
+    let report_path = config.state_dir.join("migrate-spool.report");
+    std::fs::write(&report_path, &report)?;
+    writeln!(report, "\nThe report is in {}.", report_path.display()).unwrap();
+    Ok((report, code))
+}
+
+fn meta(mailbox: &Path, message: &[u8], mtime: SystemTime) -> io::Result<spool::Meta> {
+    let uid = std::fs::metadata(mailbox)?.uid();
+    let username = users::get_user_by_uid(uid).map(|u| u.name().to_string_lossy().to_string());
+    let date = mailparse::parse_headers(message)
+        .ok()
+        .and_then(|(headers, _)| headers.get_first_value("Date"))
+        .and_then(|date| mailparse::dateparse(&date).ok())
+        .and_then(|secs| u64::try_from(secs).ok());
+    let submitted_at = date.unwrap_or_else(|| crate::time::unix_secs(mtime.min(SystemTime::now())));
+    Ok(spool::Meta {
+        queue_id: crate::daemon::queue_id(),
+        submitted_at,
+        uid,
+        // The mail was for the mailbox's owner.
+        args: ["sendmail".to_owned()]
+            .into_iter()
+            .chain(username.iter().flat_map(|u| ["--".to_owned(), u.clone()]))
+            .collect(),
+        username,
+        reason: format!("{REASON_PREFIX}{}", mailbox.display()),
+    })
+}
+
+fn subject(message: &[u8]) -> String {
+    let subject = mailparse::parse_headers(message)
+        .ok()
+        .and_then(|(headers, _)| headers.get_first_value("Subject"))
+        .unwrap_or_default();
+    crate::headers::sanitize_subject(&subject, 80)
+}
+
+/// Call `f` with each message in `mailbox`, a Maildir or an mbox file, and the time it was
+/// delivered if it has no `Date`.
+fn messages(
+    mailbox: &Path,
+    mut f: impl FnMut(Vec<u8>, SystemTime) -> io::Result<()>,
+) -> io::Result<()> {
+    if mailbox.is_dir() {
+        let mut files = Vec::new();
+        for sub in ["cur", "new"] {
+            for entry in std::fs::read_dir(mailbox.join(sub))? {
+                let entry = entry?;
+                if entry.file_type()?.is_file()
+                    && !entry.file_name().to_string_lossy().starts_with('.')
+                {
+                    files.push(entry.path());
+                }
+            }
+        }
+        files.sort();
+        for file in files {
+            let mtime = std::fs::metadata(&file)?.modified()?;
+            f(std::fs::read(&file)?, mtime)?;
+        }
+        return Ok(());
+    }
+    let mtime = std::fs::metadata(mailbox)?.modified()?;
+    mbox(
+        BufReader::new(std::fs::File::open(mailbox)?),
+        |message, date| f(message, date.unwrap_or(mtime)),
+    )
+}
+
+/// Split an mbox into its messages, without the `From ` lines, and with `>From ` unquoted. `f`
+/// also gets the date of the `From ` line.
+fn mbox(
+    mut r: impl BufRead,
+    mut f: impl FnMut(Vec<u8>, Option<SystemTime>) -> io::Result<()>,
+) -> io::Result<()> {
+    let mut message: Option<(Vec<u8>, Option<SystemTime>)> = None;
+    let mut blank_before = true;
+    let mut line = Vec::new();
+    loop {
+        line.clear();
+        if r.read_until(b'\n', &mut line)? == 0 {
+            break;
+        }
+        if blank_before && line.starts_with(b"From ") {
+            if let Some((mut message, date)) = message.replace((Vec::new(), from_line_date(&line)))
+            {
+                // The blank line that separates the messages.
+                if message.ends_with(b"\n\n") {
+                    message.pop();
+                }
+                f(message, date)?;
+            }
+            blank_before = false;
+            continue;
+        }
+        blank_before = line == b"\n" || line == b"\r\n";
+        let Some((message, _)) = &mut message else {
+            continue;
+        };
+        let quoted = line.iter().take_while(|&&c| c == b'>').count();
+        if quoted > 0 && line[quoted..].starts_with(b"From ") {
+            message.extend_from_slice(&line[1..]);
+        } else {
+            message.extend_from_slice(&line);
+        }
+    }
+    match message {
+        Some((message, date)) if !message.is_empty() => f(message, date),
+        _ => Ok(()),
+    }
+}
+
+/// The date in e.g. `From alice@example.com Mon Jan  1 00:00:00 2024`, in local time.
+fn from_line_date(line: &[u8]) -> Option<SystemTime> {
+    let line = std::str::from_utf8(line).ok()?;
+    let (_sender, date) = line.strip_prefix("From ")?.trim_start().split_once(' ')?;
+    let date = std::ffi::CString::new(date.trim()).ok()?;
+    // SAFETY: zeroed tm is a valid value, the strings are NUL-terminated
+    let secs = unsafe {
+        let mut tm: libc::tm = std::mem::zeroed();
+        if libc::strptime(date.as_ptr(), c"%a %b %e %H:%M:%S %Y".as_ptr(), &mut tm).is_null() {
+            return None;
+        }
+        tm.tm_isdst = -1;
+        libc::mktime(&mut tm)
+    };
+    let secs = u64::try_from(secs).ok()?;
+    Some(SystemTime::UNIX_EPOCH + std::time::Duration::from_secs(secs))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_mbox() {
+        let mbox_data = b"From alice@example.com Mon Jan  1 00:00:00 2024\n\
+            Subject: one\n\n>From the start\n\n\
+            From bob@example.com Tue Jan  2 00:00:00 2024\n\
+            Subject: two\n\nbody\nFrom here on, it's not a separator\n";
+        let mut messages = Vec::new();
+        mbox(&mbox_data[..], |m, date| {
+            assert!(date.is_some());
+            messages.push(String::from_utf8(m).unwrap());
+            Ok(())
+        })
+        .unwrap();
+        assert_eq!(
+            messages,
+            [
+                "Subject: one\n\nFrom the start\n",
+                "Subject: two\n\nbody\nFrom here on, it's not a separator\n"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_command_requires_root() {
+        let dir = crate::testdir::TestDir::new("migrate");
+        let mailbox = dir.join("mbox");
+        std::fs::write(
+            &mailbox,
+            "From alice@example.com Mon Jan  1 00:00:00 2024\n\nsecret\n",
+        )
+        .unwrap();
+        let config: Config = toml::from_str(&format!(
+            r#"
+            sender_email = "sender@example.com"
+            recipient_email = "admin@example.com"
+            smtp_host = "smtp.example.com"
+            smtp_username = "user"
+            smtp_password = "pass"
+            state_dir = {:?}
+            "#,
+            dir.join("state")
+        ))
+        .unwrap();
+        assert_eq!(command(&config, 1000, &[mailbox]), sysexits::EX_NOPERM);
+        assert!(!dir.join("state").exists());
+    }
+}
@@ -0,0 +1,295 @@
+//! Streaming base64 encoding of large MIME parts.
+//!
+//! Building the large parts with `lettre` holds the raw content, its encoding, and the formatted
+//! message at the same time. Instead, the message is formatted with [`Placeholder`] bodies,
+//! and [`splice`] then encodes the contents straight into the final, exactly-sized buffer.
+
+use std::borrow::Cow;
+use std::io::{self, Read};
+use std::sync::OnceLock;
+
+use lettre::message::header::ContentTransferEncoding;
+use lettre::message::Body;
+
+/// Raw bytes per encoded line of 76 characters.
+const LINE_BYTES: usize = 57;
+
+/// A unique token that stands in for the body of a part until [`splice`].
+pub struct Placeholder(String);
+
+/// The content that replaces a [`Placeholder`].
+pub struct Content<'a> {
+    input: Box<dyn Read + 'a>,
+    /// The length of `input`, to size the output.
+    len: usize,
+    base64: bool,
+}
+
+impl<'a> Content<'a> {
+    /// Content to be base64-encoded.
+    pub fn new(input: impl Read + 'a, len: usize) -> Self {
+        Content {
+            input: Box::new(input),
+            len,
+            base64: true,
+        }
+    }
+
+    /// Content that is spliced in as-is, see [`eight_bit_safe`].
+    pub fn verbatim(input: &'a [u8]) -> Self {
+        Content {
+            input: Box::new(input),
+            len: input.len(),
+            base64: false,
+        }
+    }
+}
+
+impl Placeholder {
+    pub fn random() -> Self {
+        // Random, so that it can't be guessed from, and thus collide with, other parts of the message.
+        let mut random = [0u8; 16];
+        ring::rand::SecureRandom::fill(&ring::rand::SystemRandom::new(), &mut random)
+            .expect("randomness is available");
+        Placeholder(format!(
+            "FAAMplaceholder{}",
+            data_encoding::HEXLOWER.encode(&random)
+        ))
+    }
+
+    /// The body for the part, declared with the encoding of the content it will be replaced by.
+    pub fn body(&self, encoding: ContentTransferEncoding) -> Body {
+        Body::dangerous_pre_encoded(self.0.as_bytes().to_vec(), encoding)
+    }
+}
+
+/// The length of the base64 encoding of `len` bytes, see [`encode_base64`].
+pub fn encoded_len(len: usize) -> usize {
+    let lines = len.div_ceil(LINE_BYTES);
+    data_encoding::BASE64.encode_len(len) + 2 * lines.saturating_sub(1)
+}
+
+/// Encode `input` in base64 lines of 76 characters separated by CRLF, like `lettre` does.
+pub fn encode_base64(mut input: impl Read, out: &mut Vec<u8>) -> io::Result<()> {
+    let mut chunk = vec![0u8; LINE_BYTES * 1024];
+    let mut first = true;
+    loop {
+        let n = read_full(&mut input, &mut chunk)?;
+        for line in chunk[..n].chunks(LINE_BYTES) {
+            if !first {
+                out.extend_from_slice(b"\r\n");
+            }
+            first = false;
+            let start = out.len();
+            out.resize(start + data_encoding::BASE64.encode_len(line.len()), 0);
+            data_encoding::BASE64.encode_mut(line, &mut out[start..]);
+        }
+        if n < chunk.len() {
+            return Ok(());
+        }
+    }
+}
+
+/// Replace the placeholders in `formatted`, which must appear in the given order,
+/// by the base64 encoding of their contents.
+pub fn splice(formatted: &[u8], parts: Vec<(&Placeholder, Content)>) -> io::Result<Vec<u8>> {
+    let len = parts
+        .iter()
+        .fold(formatted.len(), |len, (placeholder, content)| {
+            len - placeholder.0.len()
+                + match content.base64 {
+                    true => encoded_len(content.len),
+                    false => content.len,
+                }
+        });
+    let mut out = Vec::with_capacity(len);
+    let mut rest = formatted;
+    for (placeholder, content) in parts {
+        let token = placeholder.0.as_bytes();
+        let pos = rest
+            .windows(token.len())
+            .position(|w| w == token)
+            .ok_or_else(|| io::Error::other("placeholder not found in the formatted message"))?;
+        out.extend_from_slice(&rest[..pos]);
+        match content.base64 {
+            true => encode_base64(content.input, &mut out)?,
+            false => {
+                let mut input = content.input;
+                input.read_to_end(&mut out)?;
+            }
+        }
+        rest = &rest[pos + token.len()..];
+    }
+    out.extend_from_slice(rest);
+    Ok(out)
+}
+
+/// Whether `data` can be sent as-is with the 7bit or 8bit transfer encodings
+/// (RFC 2045, section 2.8): no NULs, no CRs outside of line endings, and lines of at most 998
+/// bytes. Line endings may be bare LFs, see [`crlf`].
+pub fn eight_bit_safe(data: &[u8]) -> bool {
+    data.split(|&b| b == b'\n').all(|line| {
+        let line = line.strip_suffix(b"\r").unwrap_or(line);
+        line.len() <= 998 && !line.iter().any(|&b| b == 0 || b == b'\r')
+    })
+}
+
+/// Normalize bare LFs to CRLF, as required on the wire.
+pub fn crlf(data: &[u8]) -> Cow<'_, [u8]> {
+    let bare_lfs = data
+        .iter()
+        .enumerate()
+        .filter(|&(i, &b)| b == b'\n' && (i == 0 || data[i - 1] != b'\r'))
+        .count();
+    if bare_lfs == 0 {
+        return Cow::Borrowed(data);
+    }
+    let mut out = Vec::with_capacity(data.len() + bare_lfs);
+    for (i, &b) in data.iter().enumerate() {
+        if b == b'\n' && (i == 0 || data[i - 1] != b'\r') {
+            out.push(b'\r');
+        }
+        out.push(b);
+    }
+    Cow::Owned(out)
+}
+
+/// Escape NULs and other control characters as `\xNN`, for the inline copy.
+/// Tabs and line endings (CRLF) are kept, so `data` should be normalized with [`crlf`] first.
+pub fn escape_controls(data: Cow<'_, [u8]>) -> Cow<'_, [u8]> {
+    let escape = |i: usize, b: u8| match b {
+        b'\t' | b'\n' => false,
+        b'\r' => data.get(i + 1) != Some(&b'\n'),
+        b => b < 0x20 || b == 0x7f,
+    };
+    if !data.iter().enumerate().any(|(i, &b)| escape(i, b)) {
+        return data;
+    }
+    let mut out = Vec::with_capacity(data.len() + 64);
+    for (i, &b) in data.iter().enumerate() {
+        if escape(i, b) {
+            out.extend_from_slice(format!("\\x{b:02x}").as_bytes());
+        } else {
+            out.push(b);
+        }
+    }
+    Cow::Owned(out)
+}
+
+/// Remove ANSI control sequences (CSI), e.g. the colors of `systemctl` or `cargo` output,
+/// for the inline copy. Must come before [`escape_controls`], which would escape their ESC.
+pub fn strip_ansi(data: Cow<'_, [u8]>) -> Cow<'_, [u8]> {
+    static RE: OnceLock<regex::bytes::Regex> = OnceLock::new();
+    let re = RE.get_or_init(|| regex::bytes::Regex::new(r"\x1b\[[0-?]*[ -/]*[@-~]").unwrap());
+    if let Cow::Owned(stripped) = re.replace_all(&data, &b""[..]) {
+        return Cow::Owned(stripped);
+    }
+    data
+}
+
+/// Prepare `data` for the inline copy, see [`strip_ansi`] and [`escape_controls`].
+pub fn inline_text(data: Cow<'_, [u8]>, strip_ansi: bool) -> Cow<'_, [u8]> {
+    match strip_ansi {
+        true => escape_controls(self::strip_ansi(data)),
+        false => escape_controls(data),
+    }
+}
+
+/// Whether a body declared with `ctype` must be decoded to be shown correctly in a UTF-8
+/// inline copy, i.e., it isn't ASCII and declares a legacy charset like ISO-8859-1.
+pub fn needs_transcoding(ctype: &mailparse::ParsedContentType, body: &[u8]) -> bool {
+    let charset = ctype.charset.to_ascii_lowercase();
+    !body.is_ascii() && !matches!(charset.as_str(), "utf-8" | "utf8")
+}
+
+/// `ctype` with its charset replaced by UTF-8, for the inline copy of a transcoded body.
+pub fn utf8_content_type(ctype: &mailparse::ParsedContentType) -> String {
+    let mut params: Vec<_> = ctype
+        .params
+        .iter()
+        .filter(|(name, _)| !name.eq_ignore_ascii_case("charset"))
+        .map(|(name, value)| format!("; {name}=\"{}\"", value.replace(['\\', '"'], "")))
+        .collect();
+    params.sort();
+    format!("{}; charset=utf-8{}", ctype.mimetype, params.concat())
+}
+
+/// Fill `buf` as far as possible, so that only the last chunk has a partial line.
+fn read_full(input: &mut impl Read, buf: &mut [u8]) -> io::Result<usize> {
+    let mut filled = 0;
+    while filled < buf.len() {
+        match input.read(&mut buf[filled..]) {
+            Ok(0) => break,
+            Ok(n) => filled += n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(filled)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_same_as_lettre() {
+        for len in [0, 1, 56, 57, 58, 57 * 1024, 57 * 1024 + 3, 200_000] {
+            let data: Vec<u8> = (0..len).map(|i| (i % 251) as u8).collect();
+            let expected =
+                Body::new_with_encoding(data.clone(), ContentTransferEncoding::Base64).unwrap();
+            let mut out = Vec::new();
+            encode_base64(data.as_slice(), &mut out).unwrap();
+            assert_eq!(out, expected.as_ref(), "len {len}");
+            assert_eq!(encoded_len(len), out.len(), "len {len}");
+        }
+    }
+
+    #[test]
+    fn test_splice() {
+        let (a, b) = (Placeholder::random(), Placeholder::random());
+        let formatted = format!("start {} middle {} end", a.0, b.0);
+        let spliced = splice(
+            formatted.as_bytes(),
+            vec![
+                (&a, Content::new(&b"foo"[..], 3)),
+                (&b, Content::verbatim(b"ba")),
+            ],
+        )
+        .unwrap();
+        assert_eq!(spliced, b"start Zm9v middle ba end");
+        assert_eq!(spliced.capacity(), spliced.len());
+    }
+
+    #[test]
+    fn test_transcoding() {
+        let ctype = mailparse::parse_content_type("text/plain; charset=ISO-8859-1; format=flowed");
+        assert!(needs_transcoding(&ctype, b"caf\xe9"));
+        assert!(!needs_transcoding(&ctype, b"cafe"));
+        assert!(!needs_transcoding(
+            &mailparse::parse_content_type("text/plain; charset=UTF-8"),
+            "café".as_bytes()
+        ));
+        assert_eq!(
+            utf8_content_type(&ctype),
+            "text/plain; charset=utf-8; format=\"flowed\""
+        );
+    }
+
+    #[test]
+    fn test_eight_bit() {
+        assert!(eight_bit_safe("Subject: é\n\nbody\r\n".as_bytes()));
+        assert!(!eight_bit_safe(b"nul\0"));
+        assert!(!eight_bit_safe(b"bare\rcr"));
+        assert!(!eight_bit_safe(&[b'x'; 999]));
+        assert_eq!(&*crlf(b"a\nb\r\nc\n"), b"a\r\nb\r\nc\r\n");
+        assert_eq!(
+            &*escape_controls(Cow::Borrowed(b"a\0b\rc\td\r\n\x1b[0m")),
+            b"a\\x00b\\x0dc\td\r\n\\x1b[0m"
+        );
+        assert_eq!(
+            &*strip_ansi(Cow::Borrowed(b"\x1b[1;31merror\x1b[0m: x\x1b[K\r\n")),
+            b"error: x\r\n"
+        );
+    }
+}
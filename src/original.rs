@@ -0,0 +1,349 @@
+//! Reading the original from stdin.
+//!
+//! Originals above a size threshold are spooled to an unlinked file in the state dir instead of
+//! being held in memory, so that a runaway job can't exhaust the host's memory.
+
+use std::borrow::Cow;
+use std::fs::File;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::os::fd::{AsFd, AsRawFd, BorrowedFd, OwnedFd};
+use std::os::unix::fs::{FileExt, OpenOptionsExt};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+use tracing::warn;
+
+/// How much of a spooled original is kept in memory for parsing its headers.
+const HEAD_LEN: usize = 256 * 1024;
+
+/// How much of the beginning of the original is looked at by [`looks_binary`].
+const BINARY_SAMPLE_LEN: usize = 8 * 1024;
+
+pub struct Spooled {
+    file: File,
+    len: usize,
+    sha256: String,
+    head: Vec<u8>,
+}
+
+pub enum Input {
+    InMemory(Vec<u8>),
+    Spooled(Spooled),
+}
+
+/// Why reading was stopped early by [`Deadline`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TimedOut {
+    /// No input for the idle timeout.
+    Idle(Duration),
+    /// Reading took longer than the deadline.
+    Deadline(Duration),
+}
+
+/// A reader that ends early, as if at EOF, if there is no input for `idle`,
+/// once `deadline` has passed since it was created, or once `interrupt` becomes readable.
+pub struct Deadline<R> {
+    inner: R,
+    idle: Option<Duration>,
+    deadline: Option<(Instant, Duration)>,
+    interrupt: Option<OwnedFd>,
+    timed_out: Option<TimedOut>,
+    interrupted: bool,
+}
+
+impl<R: Read + AsFd> Deadline<R> {
+    /// `inner` must not be buffered, otherwise buffered input is only read after a timeout.
+    pub fn new(
+        inner: R,
+        idle: Option<Duration>,
+        deadline: Option<Duration>,
+        interrupt: Option<OwnedFd>,
+    ) -> Self {
+        Deadline {
+            inner,
+            idle,
+            deadline: deadline.map(|d| (Instant::now() + d, d)),
+            interrupt,
+            timed_out: None,
+            interrupted: false,
+        }
+    }
+
+    pub fn timed_out(&self) -> Option<TimedOut> {
+        self.timed_out
+    }
+
+    /// Whether reading was stopped through `interrupt`.
+    pub fn interrupted(&self) -> bool {
+        self.interrupted
+    }
+}
+
+impl<R: Read + AsFd> Read for Deadline<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.timed_out.is_some() || self.interrupted {
+            return Ok(0);
+        }
+        let remaining = self
+            .deadline
+            .map(|(at, d)| (at.saturating_duration_since(Instant::now()), d));
+        let (timeout, on_timeout) = match (self.idle, remaining) {
+            (None, None) if self.interrupt.is_none() => return self.inner.read(buf),
+            (None, None) => (None, None),
+            (Some(idle), Some((remaining, _))) if idle < remaining => {
+                (Some(idle), Some(TimedOut::Idle(idle)))
+            }
+            (Some(idle), None) => (Some(idle), Some(TimedOut::Idle(idle))),
+            (_, Some((remaining, d))) => (Some(remaining), Some(TimedOut::Deadline(d))),
+        };
+        let pollfd = |fd: BorrowedFd| libc::pollfd {
+            fd: fd.as_raw_fd(),
+            events: libc::POLLIN,
+            revents: 0,
+        };
+        let mut pollfds = vec![pollfd(self.inner.as_fd())];
+        pollfds.extend(self.interrupt.as_ref().map(|fd| pollfd(fd.as_fd())));
+        let timeout_ms = match timeout {
+            Some(timeout) => timeout.as_millis().min(libc::c_int::MAX as u128) as libc::c_int,
+            None => -1,
+        };
+        match unsafe { libc::poll(pollfds.as_mut_ptr(), pollfds.len() as _, timeout_ms) } {
+            -1 => Err(io::Error::last_os_error()),
+            0 => {
+                self.timed_out = on_timeout;
+                Ok(0)
+            }
+            _ if pollfds.get(1).is_some_and(|p| p.revents != 0) => {
+                self.interrupted = true;
+                Ok(0)
+            }
+            _ => self.inner.read(buf),
+        }
+    }
+}
+
+/// Read `input` to the end, spooling to a file in `spool_dir` once it exceeds `threshold` bytes.
+/// Spooled originals have their line endings normalized on the way if `crlf` and they aren't
+/// binary, in-memory ones are left to the caller.
+pub fn read(
+    mut input: impl Read,
+    threshold: usize,
+    crlf: bool,
+    spool_dir: impl FnOnce() -> io::Result<PathBuf>,
+) -> io::Result<Input> {
+    let mut buf = Vec::new();
+    input
+        .by_ref()
+        .take((threshold as u64).saturating_add(1))
+        .read_to_end(&mut buf)?;
+    if buf.len() <= threshold {
+        return Ok(Input::InMemory(buf));
+    }
+
+    let mut file = match spool_dir().and_then(|dir| spool_file(&dir)) {
+        Ok(file) => file,
+        Err(e) => {
+            warn!(%e, "failed to create spool file, reading stdin into memory");
+            input.read_to_end(&mut buf)?;
+            return Ok(Input::InMemory(buf));
+        }
+    };
+
+    let crlf = crlf && !looks_binary(&buf);
+    if crlf {
+        buf = normalize_line_endings(buf);
+    }
+    let mut after_cr = buf.last() == Some(&b'\r');
+    let mut sha256 = ring::digest::Context::new(&ring::digest::SHA256);
+    sha256.update(&buf);
+    file.write_all(&buf)?;
+    let mut len = buf.len();
+    let mut head = buf;
+    head.truncate(HEAD_LEN);
+    head.shrink_to_fit();
+    let mut chunk = vec![0u8; 64 * 1024];
+    loop {
+        let n = match input.read(&mut chunk) {
+            Ok(0) => break,
+            Ok(n) => n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        };
+        let data = match crlf {
+            true => crlf_after(&chunk[..n], after_cr),
+            false => Cow::Borrowed(&chunk[..n]),
+        };
+        after_cr = chunk[n - 1] == b'\r';
+        sha256.update(&data);
+        file.write_all(&data)?;
+        len += data.len();
+    }
+    Ok(Input::Spooled(Spooled {
+        file,
+        len,
+        sha256: data_encoding::HEXLOWER.encode(sha256.finish().as_ref()),
+        head,
+    }))
+}
+
+fn spool_file(dir: &Path) -> io::Result<File> {
+    let mut random = [0u8; 8];
+    ring::rand::SecureRandom::fill(&ring::rand::SystemRandom::new(), &mut random)
+        .map_err(|_| io::Error::other("no randomness available"))?;
+    let path = dir.join(format!(
+        "stdin.{}.{}",
+        std::process::id(),
+        data_encoding::HEXLOWER.encode(&random)
+    ));
+    let file = std::fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(&path)?;
+    // Nobody else needs to find the file, and it's gone once we exit, however that happens.
+    std::fs::remove_file(&path)?;
+    Ok(file)
+}
+
+/// Whether `data` clearly isn't mail or text, judging by the ratio of control characters and
+/// invalid UTF-8 in its first few KiB.
+pub fn looks_binary(data: &[u8]) -> bool {
+    let sample = &data[..data.len().min(BINARY_SAMPLE_LEN)];
+    if sample.is_empty() {
+        return false;
+    }
+    let non_printable: usize = sample
+        .utf8_chunks()
+        .map(|chunk| {
+            let controls = chunk
+                .valid()
+                .bytes()
+                .filter(|&b| (b < 0x20 && !b"\t\n\r\x0c\x1b".contains(&b)) || b == 0x7f)
+                .count();
+            controls + chunk.invalid().len()
+        })
+        .sum();
+    non_printable * 10 > sample.len() * 3
+}
+
+/// Like [`crate::mime::crlf`], for a chunk that follows one ending in CR if `after_cr`.
+fn crlf_after(data: &[u8], after_cr: bool) -> Cow<'_, [u8]> {
+    match data.strip_prefix(b"\n") {
+        Some(rest) if after_cr => {
+            let mut out = b"\n".to_vec();
+            out.extend_from_slice(&crate::mime::crlf(rest));
+            Cow::Owned(out)
+        }
+        _ => crate::mime::crlf(data),
+    }
+}
+
+/// Convert bare LF line endings to CRLF.
+pub fn normalize_line_endings(data: Vec<u8>) -> Vec<u8> {
+    match crate::mime::crlf(&data) {
+        Cow::Borrowed(_) => data,
+        Cow::Owned(normalized) => normalized,
+    }
+}
+
+impl Spooled {
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn sha256(&self) -> &str {
+        &self.sha256
+    }
+
+    pub fn file(&self) -> &File {
+        &self.file
+    }
+
+    /// The beginning of the original, enough to parse the headers.
+    pub fn head(&self) -> &[u8] {
+        &self.head
+    }
+
+    pub fn read_at(&self, offset: usize, len: usize) -> io::Result<Vec<u8>> {
+        let len = len.min(self.len.saturating_sub(offset));
+        let mut buf = vec![0u8; len];
+        self.file.read_exact_at(&mut buf, offset as u64)?;
+        Ok(buf)
+    }
+
+    /// The last `len` bytes.
+    pub fn tail(&self, len: usize) -> io::Result<Vec<u8>> {
+        self.read_at(self.len.saturating_sub(len), len)
+    }
+
+    /// A reader for the whole original, from the beginning.
+    pub fn reader(&self) -> io::Result<impl Read + '_> {
+        let mut file = &self.file;
+        file.seek(SeekFrom::Start(0))?;
+        Ok(file)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_spool() {
+        let dir = || Ok(std::env::temp_dir());
+        let data = "line\n".repeat(1000);
+        let Input::InMemory(read_back) = read(data.as_bytes(), data.len(), true, dir).unwrap()
+        else {
+            panic!("expected in-memory");
+        };
+        assert_eq!(read_back, data.as_bytes());
+        let Input::Spooled(spooled) = read(data.as_bytes(), 100, false, dir).unwrap() else {
+            panic!("expected spooled");
+        };
+        assert_eq!(spooled.len(), data.len());
+        assert_eq!(
+            spooled.sha256(),
+            crate::headers::sha256_hex(data.as_bytes())
+        );
+        assert_eq!(spooled.tail(7).unwrap(), b"e\nline\n");
+        let mut all = Vec::new();
+        spooled.reader().unwrap().read_to_end(&mut all).unwrap();
+        assert_eq!(all, data.as_bytes());
+
+        let Input::Spooled(spooled) = read(data.as_bytes(), 100, true, dir).unwrap() else {
+            panic!("expected spooled");
+        };
+        let mut all = Vec::new();
+        spooled.reader().unwrap().read_to_end(&mut all).unwrap();
+        assert_eq!(all, "line\r\n".repeat(1000).as_bytes());
+        assert_eq!(spooled.len(), all.len());
+        assert_eq!(&*crlf_after(b"\nb\n", true), b"\nb\r\n");
+        assert_eq!(&*crlf_after(b"\nb\n", false), b"\r\nb\r\n");
+    }
+
+    #[test]
+    fn test_deadline() {
+        let (rx, mut tx) = std::os::unix::net::UnixStream::pair().unwrap();
+        tx.write_all(b"partial").unwrap();
+        let idle = Duration::from_millis(50);
+        let mut reader = Deadline::new(rx, Some(idle), None, None);
+        let mut buf = Vec::new();
+        reader.read_to_end(&mut buf).unwrap();
+        assert_eq!(buf, b"partial");
+        assert_eq!(reader.timed_out(), Some(TimedOut::Idle(idle)));
+    }
+
+    #[test]
+    fn test_looks_binary() {
+        assert!(!looks_binary(
+            "Subject: ümlaut\r\n\r\n\x1b[1mbold\x1b[0m\n".as_bytes()
+        ));
+        assert!(!looks_binary(b""));
+        let random: Vec<u8> = (0..4096u32)
+            .map(|i| (i.wrapping_mul(2654435761) >> 13) as u8)
+            .collect();
+        assert!(looks_binary(&random));
+        assert!(looks_binary(&[0u8; 100]));
+    }
+}
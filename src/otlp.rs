@@ -0,0 +1,326 @@
+//! OpenTelemetry export, with the `otlp` feature and `otlp_endpoint`: each submission becomes a
+//! trace of the `submission` span and its `parse`, `compose`, and `deliver` spans, and the
+//! delivery outcome a data point of the `forward_as_attachment_mta.deliveries` counter.
+//!
+//! Both are sent once per invocation, with OTLP/HTTP's JSON encoding, to a collector at an
+//! `http://` endpoint, typically the local OpenTelemetry Collector at `http://localhost:4318`.
+
+use std::io::{self, Read, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::sync::{Mutex, OnceLock};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use tracing::span::{Attributes, Id, Record};
+use tracing::{Event, Subscriber};
+use tracing_subscriber::layer::Context;
+use tracing_subscriber::registry::LookupSpan;
+
+use crate::json::Value;
+use crate::logging::Fields;
+
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+/// A span that's still open.
+struct Open {
+    span_id: [u8; 8],
+    start: SystemTime,
+    fields: Fields,
+    events: Vec<Value>,
+}
+
+static FINISHED: Mutex<Vec<Value>> = Mutex::new(Vec::new());
+
+fn trace_id() -> &'static [u8; 16] {
+    static TRACE_ID: OnceLock<[u8; 16]> = OnceLock::new();
+    TRACE_ID.get_or_init(random)
+}
+
+fn random<const N: usize>() -> [u8; N] {
+    let mut bytes = [0; N];
+    ring::rand::SecureRandom::fill(&ring::rand::SystemRandom::new(), &mut bytes)
+        .expect("system randomness");
+    bytes
+}
+
+fn nanos(t: SystemTime) -> Value {
+    let nanos = t.duration_since(UNIX_EPOCH).unwrap_or_default().as_nanos();
+    // 64-bit integers are strings in OTLP's JSON.
+    nanos.to_string().into()
+}
+
+fn attributes(fields: &Fields) -> Value {
+    Value::Array(
+        fields
+            .fields
+            .iter()
+            .map(|(key, value)| {
+                let value = match value {
+                    Value::Bool(b) => Value::object([("boolValue", Value::Bool(*b))]),
+                    Value::Int(n) => Value::object([("intValue", n.to_string().into())]),
+                    Value::UInt(n) => Value::object([("intValue", n.to_string().into())]),
+                    value => Value::object([(
+                        "stringValue",
+                        crate::logging::text(value).into_owned().into(),
+                    )]),
+                };
+                Value::object([("key", Value::from(*key)), ("value", value)])
+            })
+            .collect(),
+    )
+}
+
+#[derive(Default)]
+pub struct Layer;
+
+impl<S: Subscriber + for<'a> LookupSpan<'a>> tracing_subscriber::Layer<S> for Layer {
+    fn on_new_span(&self, attrs: &Attributes<'_>, id: &Id, ctx: Context<'_, S>) {
+        let mut fields = Fields::default();
+        attrs.record(&mut fields);
+        let open = Open {
+            span_id: random(),
+            start: SystemTime::now(),
+            fields,
+            events: Vec::new(),
+        };
+        if let Some(span) = ctx.span(id) {
+            span.extensions_mut().insert(open);
+        }
+    }
+
+    fn on_record(&self, id: &Id, values: &Record<'_>, ctx: Context<'_, S>) {
+        let Some(span) = ctx.span(id) else {
+            return;
+        };
+        let mut extensions = span.extensions_mut();
+        if let Some(open) = extensions.get_mut::<Open>() {
+            values.record(&mut open.fields);
+        }
+    }
+
+    fn on_event(&self, event: &Event<'_>, ctx: Context<'_, S>) {
+        let Some(span) = ctx.event_span(event) else {
+            return;
+        };
+        let fields = Fields::of(event);
+        let mut extensions = span.extensions_mut();
+        if let Some(open) = extensions.get_mut::<Open>() {
+            open.events.push(Value::object([
+                ("timeUnixNano", nanos(SystemTime::now())),
+                ("name", fields.message.as_str().into()),
+                ("attributes", attributes(&fields)),
+            ]));
+        }
+    }
+
+    fn on_close(&self, id: Id, ctx: Context<'_, S>) {
+        let Some(span) = ctx.span(&id) else {
+            return;
+        };
+        let parent = span.parent().and_then(|parent| {
+            let extensions = parent.extensions();
+            Some(extensions.get::<Open>()?.span_id)
+        });
+        let Some(open) = span.extensions_mut().remove::<Open>() else {
+            return;
+        };
+        let mut entries = vec![
+            ("traceId", data_encoding::HEXLOWER.encode(trace_id()).into()),
+            (
+                "spanId",
+                data_encoding::HEXLOWER.encode(&open.span_id).into(),
+            ),
+            ("name", span.name().into()),
+            // Internal.
+            ("kind", Value::UInt(1)),
+            ("startTimeUnixNano", nanos(open.start)),
+            ("endTimeUnixNano", nanos(SystemTime::now())),
+            ("attributes", attributes(&open.fields)),
+            ("events", Value::Array(open.events)),
+        ];
+        if let Some(parent) = parent {
+            entries.push((
+                "parentSpanId",
+                data_encoding::HEXLOWER.encode(&parent).into(),
+            ));
+        }
+        FINISHED.lock().unwrap().push(Value::object(entries));
+    }
+}
+
+fn resource() -> Value {
+    let attribute = |key: &str, value: String| {
+        Value::object([
+            ("key", Value::from(key)),
+            ("value", Value::object([("stringValue", value.into())])),
+        ])
+    };
+    Value::object([(
+        "attributes",
+        Value::Array(vec![
+            attribute("service.name", "forward-as-attachment-mta".to_owned()),
+            attribute("service.version", env!("CARGO_PKG_VERSION").to_owned()),
+            attribute("host.name", crate::hostname()),
+        ]),
+    )])
+}
+
+fn scope() -> Value {
+    Value::object([
+        ("name", Value::from("forward-as-attachment-mta")),
+        ("version", env!("CARGO_PKG_VERSION").into()),
+    ])
+}
+
+/// The delivery outcome as a data point, with delta temporality, as each invocation only knows
+/// about its own delivery.
+fn deliveries(start: SystemTime, result: &str, class: Option<&str>) -> Value {
+    let mut attributes = vec![Value::object([
+        ("key", Value::from("result")),
+        ("value", Value::object([("stringValue", result.into())])),
+    ])];
+    if let Some(class) = class {
+        attributes.push(Value::object([
+            ("key", Value::from("class")),
+            ("value", Value::object([("stringValue", class.into())])),
+        ]));
+    }
+    let data_point = Value::object([
+        ("asInt", Value::from("1")),
+        ("startTimeUnixNano", nanos(start)),
+        ("timeUnixNano", nanos(SystemTime::now())),
+        ("attributes", Value::Array(attributes)),
+    ]);
+    Value::object([(
+        "resourceMetrics",
+        Value::Array(vec![Value::object([
+            ("resource", resource()),
+            (
+                "scopeMetrics",
+                Value::Array(vec![Value::object([
+                    ("scope", scope()),
+                    (
+                        "metrics",
+                        Value::Array(vec![Value::object([
+                            ("name", Value::from("forward_as_attachment_mta.deliveries")),
+                            ("unit", "{message}".into()),
+                            (
+                                "sum",
+                                Value::object([
+                                    ("dataPoints", Value::Array(vec![data_point])),
+                                    // Delta.
+                                    ("aggregationTemporality", Value::UInt(1)),
+                                    ("isMonotonic", Value::Bool(true)),
+                                ]),
+                            ),
+                        ])]),
+                    ),
+                ])]),
+            ),
+        ])]),
+    )])
+}
+
+fn spans() -> Value {
+    let spans = std::mem::take(&mut *FINISHED.lock().unwrap());
+    Value::object([(
+        "resourceSpans",
+        Value::Array(vec![Value::object([
+            ("resource", resource()),
+            (
+                "scopeSpans",
+                Value::Array(vec![Value::object([
+                    ("scope", scope()),
+                    ("spans", Value::Array(spans)),
+                ])]),
+            ),
+        ])]),
+    )])
+}
+
+/// `http://host:port/prefix` into the address and the prefix.
+fn parse_endpoint(endpoint: &str) -> io::Result<(&str, &str)> {
+    let rest = endpoint.strip_prefix("http://").ok_or_else(|| {
+        io::Error::other("only http:// endpoints are supported, e.g. a local collector")
+    })?;
+    Ok(match rest.find('/') {
+        Some(i) => (&rest[..i], rest[i..].trim_end_matches('/')),
+        None => (rest, ""),
+    })
+}
+
+fn post(endpoint: &str, path: &str, body: &Value) -> io::Result<()> {
+    let (authority, prefix) = parse_endpoint(endpoint)?;
+    let address = authority
+        .to_socket_addrs()?
+        .next()
+        .ok_or_else(|| io::Error::other(format!("{authority} doesn't resolve")))?;
+    let mut stream = TcpStream::connect_timeout(&address, TIMEOUT)?;
+    stream.set_read_timeout(Some(TIMEOUT))?;
+    stream.set_write_timeout(Some(TIMEOUT))?;
+    let body = body.to_string();
+    write!(
+        stream,
+        "POST {prefix}{path} HTTP/1.1\r\nHost: {authority}\r\nContent-Type: application/json\r\n\
+         Content-Length: {}\r\nConnection: close\r\n\r\n{body}",
+        body.len()
+    )?;
+    let mut response = Vec::new();
+    stream.read_to_end(&mut response)?;
+    let response = String::from_utf8_lossy(&response);
+    let status = response.lines().next().unwrap_or_default();
+    match status.split(' ').nth(1) {
+        Some(code) if code.starts_with('2') => Ok(()),
+        _ => Err(io::Error::other(format!("collector responded {status:?}"))),
+    }
+}
+
+/// Send the finished spans, and the delivery outcome, with the error class if it failed.
+pub fn export(endpoint: &str, start: SystemTime, result: &str, class: Option<&str>) {
+    if let Err(e) = post(endpoint, "/v1/traces", &spans()) {
+        tracing::warn!(%e, "can't export the trace");
+    }
+    if let Err(e) = post(endpoint, "/v1/metrics", &deliveries(start, result, class)) {
+        tracing::warn!(%e, "can't export the metrics");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tracing_subscriber::layer::SubscriberExt;
+
+    #[test]
+    fn test_spans() {
+        let subscriber = tracing_subscriber::registry().with(Layer);
+        tracing::subscriber::with_default(subscriber, || {
+            let submission = tracing::info_span!("submission", queue_id = "6ACF2B012841F6DB");
+            let _entered = submission.enter();
+            tracing::info_span!("deliver").in_scope(|| tracing::info!(size = 3594, "delivered"));
+        });
+        let spans = FINISHED.lock().unwrap().clone();
+        let [Value::Object(deliver), Value::Object(submission)] = spans.as_slice() else {
+            panic!("{spans:?}");
+        };
+        let get = |span: &[(String, Value)], key| {
+            span.iter()
+                .find(|(k, _)| k == key)
+                .map(|(_, v)| v.to_string())
+        };
+        assert_eq!(get(deliver, "name").unwrap(), r#""deliver""#);
+        assert_eq!(get(deliver, "parentSpanId"), get(submission, "spanId"));
+        assert_eq!(get(deliver, "traceId"), get(submission, "traceId"));
+        assert_eq!(get(submission, "parentSpanId"), None);
+        assert_eq!(
+            get(submission, "attributes").unwrap(),
+            r#"[{"key":"queue_id","value":{"stringValue":"6ACF2B012841F6DB"}}]"#
+        );
+        assert!(get(deliver, "events").unwrap().contains(
+            r#""name":"delivered","attributes":[{"key":"size","value":{"intValue":"3594"}}]"#
+        ));
+        assert_eq!(
+            parse_endpoint("http://localhost:4318/otlp/").unwrap(),
+            ("localhost:4318", "/otlp")
+        );
+        assert!(parse_endpoint("https://collector").is_err());
+    }
+}
@@ -0,0 +1,146 @@
+//! Transparent relay of originals that are well-formed mail, instead of wrapping them.
+//!
+//! The original is forwarded as-is, except for the headers that we replace, see [`rewrite`].
+
+use lettre::message::header::Headers;
+
+/// How a submission is forwarded.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Mode {
+    /// Attach the original to a wrapper message.
+    #[default]
+    Wrap,
+    /// Forward the original itself if it is well-formed mail, else wrap it.
+    Relay,
+}
+
+/// A `[[mode_rules]]` entry: submissions that match all of its conditions get its `mode`.
+#[derive(Debug, Clone, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Rule {
+    /// Local users (names or uids) whose submissions match. Empty matches all.
+    #[serde(default)]
+    pub users: Vec<String>,
+    /// Domains of the original's `From` address that match. Empty matches all.
+    #[serde(default)]
+    pub from_domains: Vec<String>,
+    pub mode: Mode,
+}
+
+impl Rule {
+    fn matches(&self, uid: u32, username: Option<&str>, from: Option<&str>) -> bool {
+        let user = self.users.is_empty()
+            || self
+                .users
+                .iter()
+                .any(|u| *u == uid.to_string() || Some(u.as_str()) == username);
+        let domain = from.and_then(|from| from.rsplit_once('@')).map(|(_, d)| d);
+        let from_domain = self.from_domains.is_empty()
+            || domain.is_some_and(|d| self.from_domains.iter().any(|f| f.eq_ignore_ascii_case(d)));
+        user && from_domain
+    }
+}
+
+/// The mode of the first rule that matches the submission, else `default`.
+pub fn mode(
+    rules: &[Rule],
+    default: Mode,
+    uid: u32,
+    username: Option<&str>,
+    from: Option<&str>,
+) -> Mode {
+    rules
+        .iter()
+        .find(|rule| rule.matches(uid, username, from))
+        .map_or(default, |rule| rule.mode)
+}
+
+/// Headers of the original that are dropped when relaying, in addition to the replaced ones:
+/// `Bcc` must not be disclosed to the recipients, and `Return-Path` is added on final delivery.
+const DROPPED_HEADERS: &[&str] = &["Bcc", "Return-Path"];
+
+/// The CRLF-normalized `original` with the headers in `replace` put first, replacing the
+/// original's headers of the same names.
+pub fn rewrite(original: &[u8], replace: &Headers) -> Vec<u8> {
+    let original = crate::mime::crlf(original);
+    let (header_block, body) = match original.windows(4).position(|w| w == b"\r\n\r\n") {
+        Some(i) => original.split_at(i + 2),
+        None => (&original[..], &b""[..]),
+    };
+    let replaced = |line: &[u8]| {
+        let Some(colon) = line.iter().position(|&b| b == b':') else {
+            return false;
+        };
+        let name = String::from_utf8_lossy(&line[..colon]);
+        let name = name.trim_end();
+        replace.get_raw(name).is_some()
+            || DROPPED_HEADERS.iter().any(|h| h.eq_ignore_ascii_case(name))
+    };
+    let mut out = Vec::with_capacity(original.len() + 512);
+    out.extend_from_slice(replace.to_string().as_bytes());
+    // Continuation lines go with the header they continue.
+    let mut skipping = false;
+    for line in header_block.split_inclusive(|&b| b == b'\n') {
+        if !line.starts_with(b" ") && !line.starts_with(b"\t") {
+            skipping = replaced(line);
+        }
+        if !skipping {
+            out.extend_from_slice(line);
+        }
+    }
+    out.extend_from_slice(body);
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use lettre::message::header::{From, HeaderName, HeaderValue};
+    use lettre::message::{Mailbox, Mailboxes};
+
+    #[test]
+    fn test_rules() {
+        #[derive(serde::Deserialize)]
+        struct Config {
+            mode_rules: Vec<Rule>,
+        }
+        let rules = toml::from_str::<Config>(
+            r#"
+            [[mode_rules]]
+            users = ["alice", "1001"]
+            mode = "relay"
+            [[mode_rules]]
+            from_domains = ["example.com"]
+            mode = "relay"
+            [[mode_rules]]
+            mode = "wrap"
+            "#,
+        )
+        .unwrap()
+        .mode_rules;
+        let mode = |uid, username, from| mode(&rules, Mode::Relay, uid, username, from);
+        assert_eq!(mode(1000, Some("alice"), None), Mode::Relay);
+        assert_eq!(mode(1001, None, None), Mode::Relay);
+        assert_eq!(mode(0, Some("root"), Some("cron@EXAMPLE.com")), Mode::Relay);
+        assert_eq!(mode(0, Some("root"), Some("cron@host")), Mode::Wrap);
+        assert_eq!(mode(0, Some("root"), None), Mode::Wrap);
+    }
+
+    #[test]
+    fn test_rewrite() {
+        let original = b"From: Someone\n <someone@host>\nTo: root\nBcc: secret@example.com\nSubject: hi\n\nbody\nFrom: not a header\n";
+        let mut replace = Headers::new();
+        replace.set(From::from(Mailboxes::from(
+            "alerts@example.com".parse::<Mailbox>().unwrap(),
+        )));
+        replace.insert_raw(HeaderValue::new(
+            HeaderName::new_from_ascii_str("X-Loop"),
+            "faam".to_owned(),
+        ));
+        assert_eq!(
+            String::from_utf8(rewrite(original, &replace)).unwrap(),
+            "From: alerts@example.com\r\nX-Loop: faam\r\nTo: root\r\nSubject: hi\r\n\r\nbody\r\nFrom: not a header\r\n"
+        );
+    }
+}
@@ -0,0 +1,130 @@
+//! OpenPGP encryption of the attached original, through the `gpg` command.
+//!
+//! The recipient's public key is read from a file, so no keyring has to be set up; `gpg` gets
+//! a temporary home directory.
+
+use std::io::Read;
+use std::os::unix::fs::DirBuilderExt;
+use std::path::Path;
+use std::process::Stdio;
+
+/// The binary OpenPGP message of `data`, encrypted to the public key in `key_file`.
+pub fn encrypt(key_file: &Path, mut data: impl Read) -> std::io::Result<Vec<u8>> {
+    // Random, so that another user can't take the name in the shared temp dir beforehand.
+    let mut random = [0u8; 8];
+    ring::rand::SecureRandom::fill(&ring::rand::SystemRandom::new(), &mut random)
+        .expect("randomness is available");
+    let home = std::env::temp_dir().join(format!(
+        "forward-as-attachment-mta-gpg.{}",
+        data_encoding::HEXLOWER.encode(&random)
+    ));
+    std::fs::DirBuilder::new().mode(0o700).create(&home)?;
+    let result = (|| {
+        let mut child = crate::hardening::command("gpg")
+            .arg("--homedir")
+            .arg(&home)
+            .args(["--batch", "--quiet", "--no-tty", "--trust-model", "always"])
+            .arg("--recipient-file")
+            .arg(key_file)
+            .args(["--output", "-", "--encrypt"])
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()?;
+        let mut stdout = child.stdout.take().expect("we requested a pipe");
+        let mut stderr = child.stderr.take().expect("we requested a pipe");
+        // Read concurrently, gpg blocks once the pipes are full.
+        let reader = std::thread::spawn(move || {
+            let mut output = Vec::new();
+            stdout.read_to_end(&mut output).map(|_| output)
+        });
+        let errors = std::thread::spawn(move || {
+            let mut output = String::new();
+            let _ = stderr.read_to_string(&mut output);
+            output
+        });
+        let written = std::io::copy(
+            &mut data,
+            &mut child.stdin.take().expect("we requested a pipe"),
+        );
+        let status = child.wait()?;
+        let output = reader.join().expect("reader doesn't panic")?;
+        let errors = errors.join().expect("reader doesn't panic");
+        written?;
+        if !status.success() {
+            return Err(std::io::Error::other(format!(
+                "gpg exited with {status}: {}",
+                errors.trim()
+            )));
+        }
+        Ok(output)
+    })();
+    let _ = std::fs::remove_dir_all(&home);
+    result
+}
+
+/// A new key pair in the empty gpg home `dir`, for tests. Returns the file with the public key.
+#[cfg(test)]
+pub fn test_key(dir: &Path) -> std::path::PathBuf {
+    let gpg = |args: &[&str]| {
+        let output = std::process::Command::new("gpg")
+            .arg("--homedir")
+            .arg(dir)
+            .args(["--batch", "--quiet", "--passphrase", ""])
+            .args(args)
+            .output()
+            .unwrap();
+        assert!(output.status.success(), "{output:?}");
+        output.stdout
+    };
+    gpg(&[
+        "--quick-gen-key",
+        "test@example.com",
+        "future-default",
+        "default",
+        "never",
+    ]);
+    let key_file = dir.join("key.asc");
+    std::fs::write(&key_file, gpg(&["--armor", "--export", "test@example.com"])).unwrap();
+    key_file
+}
+
+/// Decrypt with the key pair of [`test_key`], and stop its agent.
+#[cfg(test)]
+pub fn test_decrypt(dir: &Path, encrypted: &[u8]) -> Vec<u8> {
+    use std::io::Write;
+    let mut child = std::process::Command::new("gpg")
+        .arg("--homedir")
+        .arg(dir)
+        .args(["--batch", "--quiet", "--decrypt"])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .spawn()
+        .unwrap();
+    child.stdin.take().unwrap().write_all(encrypted).unwrap();
+    let output = child.wait_with_output().unwrap();
+    let _ = std::process::Command::new("gpgconf")
+        .arg("--homedir")
+        .arg(dir)
+        .args(["--kill", "all"])
+        .status();
+    output.stdout
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_encrypt() {
+        let dir = crate::testdir::TestDir::new("pgp");
+        let key_file = test_key(&dir);
+
+        let data = b"Subject: rotated credentials\r\n\r\nsecret\r\n";
+        let encrypted = encrypt(&key_file, &data[..]).unwrap();
+        assert!(!encrypted.windows(6).any(|w| w == b"secret"));
+        assert!(encrypt(&dir.join("missing.asc"), &data[..]).is_err());
+        assert_eq!(test_decrypt(&dir, &encrypted), data);
+    }
+}
@@ -0,0 +1,212 @@
+//! `plugin`: a WebAssembly module that decides about each submission, for policies that
+//! `[[filter]]`, `[[classifier]]`, and `[[route]]` can't express, without rebuilding this.
+//!
+//! The module exports its `memory`, `faam_alloc(len: i32) -> i32`, which returns where to put
+//! `len` bytes of input, and `faam_filter(ptr: i32, len: i32) -> i64`, which gets the submission
+//! there as JSON and returns where its answer is, as `ptr << 32 | len`. The answer is lines of
+//! decisions, all optional:
+//!
+//! - `drop`: don't forward the submission.
+//! - `severity info|warning|critical`: instead of the classifiers' severity.
+//! - `recipient <address>`: instead of the routes and `severity_recipients`.
+//! - `subject <text>`: instead of the original's, for the wrapper and the later rules.
+//!
+//! The module may import `faam.log(ptr: i32, len: i32)` to log a message. A module that fails to
+//! load, traps, or runs out of `plugin_max_instructions` is logged and ignored, so that a broken
+//! plugin can't lose mail.
+//!
+//! The interpreter is only built with the `plugin` feature; without it, a configured plugin is
+//! ignored with a warning.
+
+use std::io;
+use std::path::Path;
+
+#[cfg(feature = "plugin")]
+use tracing::info;
+use tracing::{debug, warn};
+
+use crate::config::Config;
+use crate::json::Value;
+use crate::severity::Severity;
+#[cfg(feature = "plugin")]
+use crate::wasm;
+
+/// 64 MiB, for the submission, the answer, and the plugin's own data.
+#[cfg(feature = "plugin")]
+const MAX_MEMORY_PAGES: u32 = 1024;
+
+/// What the plugin gets to see.
+pub struct Submission<'a> {
+    pub original: Option<&'a mailparse::ParsedMail<'a>>,
+    pub subject: &'a str,
+    /// The text, as for `[[filter]]`.
+    pub body: &'a str,
+    pub sender: &'a str,
+    pub recipients: &'a [lettre::Address],
+    /// By the classifiers.
+    pub severity: Severity,
+    pub uid: u32,
+    pub user: Option<&'a str>,
+    pub hostname: &'a str,
+}
+
+/// What the plugin decided; nothing, if there is none.
+#[derive(Debug, Default, PartialEq)]
+pub struct Decision {
+    pub drop: bool,
+    pub severity: Option<Severity>,
+    pub recipient: Option<lettre::Address>,
+    pub subject: Option<String>,
+}
+
+/// Run `plugin` on `submission`.
+pub fn run(config: &Config, submission: &Submission) -> Decision {
+    let Some(path) = &config.plugin else {
+        return Decision::default();
+    };
+    let input = input(submission).to_string();
+    match call(path, config.plugin_max_instructions, input.as_bytes()) {
+        Ok(answer) => {
+            let decision = parse(&answer);
+            debug!(?decision, "the plugin decided");
+            decision
+        }
+        Err(e) => {
+            warn!(%e, ?path, "the plugin failed, ignoring it");
+            Decision::default()
+        }
+    }
+}
+
+fn input(submission: &Submission) -> Value {
+    let headers = submission
+        .original
+        .into_iter()
+        .flat_map(|parsed| parsed.get_headers())
+        .map(|h| Value::Array(vec![h.get_key().into(), h.get_value().into()]))
+        .collect();
+    let recipients = submission
+        .recipients
+        .iter()
+        .map(|r| r.to_string().into())
+        .collect();
+    Value::object([
+        ("hostname", Value::from(submission.hostname)),
+        ("uid", submission.uid.into()),
+        ("user", submission.user.into()),
+        ("sender", submission.sender.into()),
+        ("recipients", Value::Array(recipients)),
+        ("severity", submission.severity.as_str().into()),
+        ("subject", submission.subject.into()),
+        ("headers", Value::Array(headers)),
+        ("body", submission.body.into()),
+    ])
+}
+
+/// Returns the answer.
+#[cfg(feature = "plugin")]
+fn call(path: &Path, fuel: u64, input: &[u8]) -> io::Result<String> {
+    let module = wasm::Module::decode(&std::fs::read(path)?).map_err(io::Error::other)?;
+    let limits = wasm::Limits {
+        max_pages: MAX_MEMORY_PAGES,
+        fuel,
+    };
+    let mut instance = wasm::Instance::new(&module, limits, |module, name, type_| {
+        let log: wasm::HostFunc = Box::new(log);
+        let matches = type_.params == [wasm::ValType::I32; 2] && type_.results.is_empty();
+        (module == "faam" && name == "log" && matches).then_some(log)
+    })
+    .map_err(io::Error::other)?;
+    let len = i32::try_from(input.len())
+        .map_err(|_| io::Error::other("the submission is too large for the plugin"))?;
+    let ptr = match instance
+        .call("faam_alloc", &[len as u64])
+        .map_err(io::Error::other)?[..]
+    {
+        [ptr] => ptr as u32 as usize,
+        _ => return Err(io::Error::other("faam_alloc must return a pointer")),
+    };
+    instance
+        .memory_mut()
+        .get_mut(ptr..ptr + input.len())
+        .ok_or_else(|| io::Error::other("faam_alloc returned an invalid pointer"))?
+        .copy_from_slice(input);
+    let answer = match instance
+        .call("faam_filter", &[ptr as u64, len as u64])
+        .map_err(io::Error::other)?[..]
+    {
+        [answer] => answer,
+        _ => return Err(io::Error::other("faam_filter must return the answer")),
+    };
+    let (ptr, len) = ((answer >> 32) as usize, answer as u32 as usize);
+    let answer = instance
+        .memory()
+        .get(ptr..ptr + len)
+        .ok_or_else(|| io::Error::other("faam_filter returned an invalid answer"))?;
+    String::from_utf8(answer.to_vec())
+        .map_err(|_| io::Error::other("the plugin's answer isn't UTF-8"))
+}
+
+#[cfg(not(feature = "plugin"))]
+fn call(_path: &Path, _fuel: u64, _input: &[u8]) -> io::Result<String> {
+    Err(io::Error::other("built without the plugin feature"))
+}
+
+/// `faam.log(ptr, len)`.
+#[cfg(feature = "plugin")]
+fn log(memory: &mut [u8], args: &[u64]) -> Result<Vec<u64>, wasm::Error> {
+    let (ptr, len) = (args[0] as u32 as usize, args[1] as u32 as usize);
+    let message = memory
+        .get(ptr..ptr + len)
+        .ok_or(wasm::Error::Trap("out of bounds memory access"))?;
+    info!("plugin: {}", String::from_utf8_lossy(message));
+    Ok(vec![])
+}
+
+fn parse(answer: &str) -> Decision {
+    let mut decision = Decision::default();
+    for line in answer.lines().map(str::trim).filter(|l| !l.is_empty()) {
+        let (verb, arg) = line.split_once(' ').unwrap_or((line, ""));
+        let arg = arg.trim_start();
+        match verb {
+            "drop" => decision.drop = true,
+            "severity" => {
+                decision.severity = [Severity::Info, Severity::Warning, Severity::Critical]
+                    .into_iter()
+                    .find(|s| s.as_str() == arg);
+                if decision.severity.is_none() {
+                    warn!(line, "ignoring an invalid severity from the plugin");
+                }
+            }
+            "recipient" => match arg.parse() {
+                Ok(recipient) => decision.recipient = Some(recipient),
+                Err(e) => warn!(%e, line, "ignoring an invalid recipient from the plugin"),
+            },
+            "subject" => decision.subject = Some(arg.to_owned()),
+            _ => warn!(line, "ignoring an unknown decision from the plugin"),
+        }
+    }
+    decision
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        let answer = "severity critical\nrecipient storage@example.com\n\nsubject [RAID] md0 degraded\nreboot\n";
+        assert_eq!(
+            parse(answer),
+            Decision {
+                drop: false,
+                severity: Some(Severity::Critical),
+                recipient: Some("storage@example.com".parse().unwrap()),
+                subject: Some("[RAID] md0 degraded".to_owned()),
+            }
+        );
+        let decision = parse("drop\nseverity urgent");
+        assert!(decision.drop);
+        assert_eq!(decision.severity, None);
+    }
+}
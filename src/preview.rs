@@ -0,0 +1,90 @@
+//! Excerpts of the original that are shown in the wrapper text.
+
+use std::fmt::Write;
+
+use mailparse::{MailHeaderMap, ParsedMail};
+
+use crate::headers::LenientHeaders;
+use crate::i18n::Catalog;
+
+const SUMMARY_HEADERS: &[&str] = &["To", "Cc", "Date", "Message-ID", "Auto-Submitted"];
+
+/// The first `text/plain` body of the original, decoded.
+pub fn text_body(parsed: &ParsedMail) -> Option<String> {
+    if parsed.ctype.mimetype == "text/plain" {
+        return parsed.get_body().ok();
+    }
+    parsed.subparts.iter().find_map(text_body)
+}
+
+/// A table of the original's context headers, followed by a blank line.
+/// Empty if none of them are present.
+pub fn header_table(parsed: &ParsedMail, catalog: &Catalog) -> String {
+    table(|name| parsed.get_headers().get_all_values(name), catalog)
+}
+
+/// Like [`header_table`], for an original that mailparse rejects.
+pub fn lenient_header_table(headers: &LenientHeaders, catalog: &Catalog) -> String {
+    table(|name| headers.get_all(name), catalog)
+}
+
+fn table(get_all_values: impl Fn(&str) -> Vec<String>, catalog: &Catalog) -> String {
+    let width = SUMMARY_HEADERS.iter().map(|h| h.len()).max().unwrap_or(0) + 1;
+    let mut table = String::new();
+    for name in SUMMARY_HEADERS {
+        for value in get_all_values(name) {
+            let value = crate::headers::decode_rfc2047(&value);
+            writeln!(
+                &mut table,
+                "    {:width$} {}",
+                format!("{name}:"),
+                value.trim()
+            )
+            .unwrap();
+        }
+    }
+    if table.is_empty() {
+        return table;
+    }
+    format!("{}\n{table}\n", catalog.original_headers)
+}
+
+/// The last `n` lines of `text`, ignoring trailing blank lines.
+pub fn tail(text: &str, n: usize) -> Vec<&str> {
+    let lines: Vec<&str> = text.trim_end().lines().collect();
+    lines[lines.len().saturating_sub(n)..].to_vec()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_tail() {
+        assert_eq!(tail("a\nb\nc\n\n\n", 2), vec!["b", "c"]);
+        assert_eq!(tail("a\r\nb\r\n", 5), vec!["a", "b"]);
+        assert!(tail("", 3).is_empty());
+    }
+
+    #[test]
+    fn test_header_table() {
+        let raw = "To: root\r\nSubject: x\r\nAuto-Submitted: auto-generated\r\n\r\nbody\r\n";
+        let parsed = mailparse::parse_mail(raw.as_bytes()).unwrap();
+        assert_eq!(
+            header_table(&parsed, &crate::i18n::EN),
+            "Original headers:\n    To:             root\n    Auto-Submitted: auto-generated\n\n"
+        );
+        let parsed = mailparse::parse_mail(b"Subject: x\r\n\r\nbody\r\n").unwrap();
+        assert_eq!(header_table(&parsed, &crate::i18n::EN), "");
+    }
+
+    #[test]
+    fn test_text_body_multipart() {
+        let raw = "Content-Type: multipart/mixed; boundary=XX\r\n\r\n\
+            --XX\r\nContent-Type: text/html\r\n\r\n<b>html</b>\r\n\
+            --XX\r\nContent-Type: text/plain\r\n\r\nplain\r\n\
+            --XX--\r\n";
+        let parsed = mailparse::parse_mail(raw.as_bytes()).unwrap();
+        assert_eq!(text_body(&parsed).unwrap().trim(), "plain");
+    }
+}
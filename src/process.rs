@@ -0,0 +1,284 @@
+//! Information about the process that invoked us, from `/proc`.
+
+use std::ffi::OsString;
+use std::fmt::Write;
+use std::os::unix::fs::MetadataExt;
+use std::path::PathBuf;
+
+use crate::i18n::{self, Catalog};
+
+#[derive(Debug)]
+pub struct ProcessInfo {
+    pub pid: u32,
+    pub cmdline: Option<Vec<String>>,
+    pub exe: Option<PathBuf>,
+    pub cwd: Option<PathBuf>,
+    /// The cgroup v2 path (or systemd's v1 hierarchy), e.g. `/system.slice/cron.service`.
+    pub cgroup: Option<String>,
+}
+
+/// The systemd unit and slice a process belongs to.
+#[derive(Debug, PartialEq, Eq)]
+pub struct SystemdUnit {
+    pub unit: String,
+    pub slice: Option<String>,
+    /// Whether the unit is managed by a user's service manager (`user@.service`).
+    pub user_manager: bool,
+}
+
+impl ProcessInfo {
+    pub fn read(pid: u32) -> Self {
+        let proc = PathBuf::from(format!("/proc/{pid}"));
+        let cmdline = std::fs::read(proc.join("cmdline")).ok().map(|raw| {
+            raw.split(|b| *b == 0)
+                .filter(|arg| !arg.is_empty())
+                .map(|arg| String::from_utf8_lossy(arg).into_owned())
+                .collect()
+        });
+        Self {
+            pid,
+            cmdline,
+            exe: std::fs::read_link(proc.join("exe")).ok(),
+            cwd: std::fs::read_link(proc.join("cwd")).ok(),
+            cgroup: std::fs::read_to_string(proc.join("cgroup"))
+                .ok()
+                .and_then(|s| parse_cgroup(&s)),
+        }
+    }
+
+    pub fn systemd_unit(&self) -> Option<SystemdUnit> {
+        systemd_unit(self.cgroup.as_deref()?)
+    }
+
+    /// Only the pid unless the parent runs as `caller_uid`, or the caller is root: as setuid
+    /// root we could read any process, e.g. when a daemon of another user runs us.
+    pub fn parent(caller_uid: u32) -> Self {
+        // SAFETY: getppid is always successful
+        let ppid = unsafe { libc::getppid() } as u32;
+        let owner = std::fs::metadata(format!("/proc/{ppid}")).map(|m| m.uid());
+        if caller_uid == 0 || owner.is_ok_and(|owner| owner == caller_uid) {
+            return Self::read(ppid);
+        }
+        Self {
+            pid: ppid,
+            cmdline: None,
+            exe: None,
+            cwd: None,
+            cgroup: None,
+        }
+    }
+
+    /// The command line with arguments that contain whitespace quoted.
+    pub fn display_cmdline(&self) -> Option<String> {
+        let cmdline = self.cmdline.as_ref()?;
+        let quoted: Vec<String> = cmdline
+            .iter()
+            .map(|arg| {
+                if arg.is_empty() || arg.contains(char::is_whitespace) || arg.contains('"') {
+                    format!("{arg:?}")
+                } else {
+                    arg.clone()
+                }
+            })
+            .collect();
+        Some(quoted.join(" "))
+    }
+
+    /// An "Invoking process" section, followed by a blank line.
+    pub fn section(&self, catalog: &Catalog) -> String {
+        let unknown = || "(unknown)".to_owned();
+        let mut section = String::new();
+        let pid = self.pid.to_string();
+        writeln!(
+            &mut section,
+            "{}",
+            i18n::fill(catalog.invoking_process, &[("pid", &pid)])
+        )
+        .unwrap();
+        writeln!(
+            &mut section,
+            "    cmdline: {}",
+            self.display_cmdline().unwrap_or_else(unknown)
+        )
+        .unwrap();
+        let display = |p: &Option<PathBuf>| p.as_ref().map(|p| p.display().to_string());
+        writeln!(
+            &mut section,
+            "    exe:     {}",
+            display(&self.exe).unwrap_or_else(unknown)
+        )
+        .unwrap();
+        writeln!(
+            &mut section,
+            "    cwd:     {}",
+            display(&self.cwd).unwrap_or_else(unknown)
+        )
+        .unwrap();
+        section.push('\n');
+        section
+    }
+}
+
+/// The variables of the invoking environment whose names are in the allowlist.
+/// Entries ending in `*` match by prefix.
+pub fn env_snapshot(
+    invoking: &[(OsString, OsString)],
+    allowlist: &[String],
+) -> Vec<(String, String)> {
+    let allowed = |name: &str| {
+        allowlist.iter().any(|entry| match entry.strip_suffix('*') {
+            Some(prefix) => name.starts_with(prefix),
+            None => entry == name,
+        })
+    };
+    let mut vars: Vec<(String, String)> = invoking
+        .iter()
+        .filter_map(|(k, v)| {
+            let k = k.to_string_lossy().into_owned();
+            allowed(&k).then(|| (k, v.to_string_lossy().into_owned()))
+        })
+        .collect();
+    vars.sort();
+    vars
+}
+
+/// An "Environment" section, followed by a blank line. Empty if no variables were captured.
+pub fn env_section(vars: &[(String, String)], catalog: &Catalog) -> String {
+    if vars.is_empty() {
+        return String::new();
+    }
+    let mut section = format!("{}\n", catalog.environment);
+    for (k, v) in vars {
+        writeln!(&mut section, "    {k}={v}").unwrap();
+    }
+    section.push('\n');
+    section
+}
+
+/// Pick the unified (v2) hierarchy, or systemd's named v1 hierarchy, from `/proc/<pid>/cgroup`.
+fn parse_cgroup(content: &str) -> Option<String> {
+    let mut v1_systemd = None;
+    for line in content.lines() {
+        let mut fields = line.splitn(3, ':');
+        let (Some(id), Some(controllers), Some(path)) =
+            (fields.next(), fields.next(), fields.next())
+        else {
+            continue;
+        };
+        if id == "0" && controllers.is_empty() {
+            return Some(path.to_owned());
+        }
+        if controllers == "name=systemd" {
+            v1_systemd = Some(path.to_owned());
+        }
+    }
+    v1_systemd
+}
+
+/// The innermost unit in the cgroup path, and the slice containing it.
+fn systemd_unit(cgroup: &str) -> Option<SystemdUnit> {
+    let components: Vec<&str> = cgroup.split('/').filter(|c| !c.is_empty()).collect();
+    let is_unit = |c: &&str| {
+        [".service", ".scope", ".socket", ".mount", ".swap", ".timer"]
+            .iter()
+            .any(|suffix| c.ends_with(suffix))
+    };
+    let idx = components.iter().rposition(is_unit)?;
+    let slice = components[..idx]
+        .iter()
+        .rev()
+        .find(|c| c.ends_with(".slice"))
+        .map(|s| s.to_string());
+    Some(SystemdUnit {
+        unit: components[idx].to_owned(),
+        slice,
+        user_manager: components[..idx].iter().any(|c| c.starts_with("user@")),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_display_cmdline() {
+        let info = ProcessInfo {
+            pid: 1,
+            cmdline: Some(vec![
+                "/bin/sh".to_owned(),
+                "-c".to_owned(),
+                "backup.sh --all".to_owned(),
+            ]),
+            exe: None,
+            cwd: None,
+            cgroup: None,
+        };
+        assert_eq!(
+            info.display_cmdline().unwrap(),
+            r#"/bin/sh -c "backup.sh --all""#
+        );
+    }
+
+    #[test]
+    fn test_read_self() {
+        let info = ProcessInfo::read(std::process::id());
+        assert_eq!(info.exe, std::env::current_exe().ok());
+        assert_eq!(info.cwd, std::env::current_dir().ok());
+        let parent = ProcessInfo::parent(users::get_current_uid());
+        assert!(parent.cmdline.is_some());
+        // Cargo doesn't run as this uid.
+        let parent = ProcessInfo::parent(u32::MAX - 1);
+        assert!(parent.cmdline.is_none() && parent.exe.is_none());
+    }
+
+    #[test]
+    fn test_systemd_unit() {
+        let f = |content: &str| parse_cgroup(content).and_then(|p| systemd_unit(&p));
+        assert_eq!(
+            f("0::/system.slice/backup.service\n"),
+            Some(SystemdUnit {
+                unit: "backup.service".to_owned(),
+                slice: Some("system.slice".to_owned()),
+                user_manager: false,
+            })
+        );
+        assert_eq!(
+            f("12:cpu,cpuacct:/\n1:name=systemd:/system.slice/cron.service\n"),
+            Some(SystemdUnit {
+                unit: "cron.service".to_owned(),
+                slice: Some("system.slice".to_owned()),
+                user_manager: false,
+            })
+        );
+        assert_eq!(
+            f("0::/user.slice/user-1000.slice/user@1000.service/app.slice/sync.service\n"),
+            Some(SystemdUnit {
+                unit: "sync.service".to_owned(),
+                slice: Some("app.slice".to_owned()),
+                user_manager: true,
+            })
+        );
+        assert_eq!(f("0::/\n"), None);
+    }
+
+    #[test]
+    fn test_env_snapshot() {
+        let invoking = [
+            ("FAAM_TEST_JOB".into(), "nightly".into()),
+            ("FAAM_TEST_SECRET".into(), "hunter2".into()),
+        ];
+        let vars = env_snapshot(
+            &invoking,
+            &["FAAM_TEST_JOB".to_owned(), "FAAM_TEST_J*".to_owned()],
+        );
+        assert_eq!(
+            vars,
+            vec![("FAAM_TEST_JOB".to_owned(), "nightly".to_owned())]
+        );
+        assert_eq!(
+            env_section(&vars, &i18n::EN),
+            "Environment (allowlisted variables only):\n    FAAM_TEST_JOB=nightly\n\n"
+        );
+        assert!(env_snapshot(&invoking, &[]).is_empty());
+    }
+}
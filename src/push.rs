@@ -0,0 +1,293 @@
+//! Push monitors, e.g. Uptime Kuma's push monitors or healthchecks.io checks: `push_url` is
+//! requested after each delivery that went through, and `push_failure_url` after each that failed,
+//! so that the monitor alerts when the pushes stop, or fail.
+//!
+//! The request is fire-and-forget: a detached forward-as-attachment-mta makes it, with a few
+//! retries, so that a slow or unreachable monitor never delays or fails the submission. The
+//! requests are GETs, which the monitors expect to be repeated; with `push_payload`, they are
+//! POSTs with the forwarding health as JSON, for monitors that show the body, e.g. healthchecks.io.
+//! Root can push by hand with `forward-as-attachment-mta --push delivered|failed`.
+
+use std::io::{self, Read, Write};
+use std::os::unix::process::CommandExt;
+use std::process::{Command, Stdio};
+use std::time::{Duration, SystemTime};
+
+use tracing::{debug, warn};
+
+use crate::config::Config;
+use crate::json::Value;
+use crate::{hardening, sysexits};
+
+/// Set for the detached push, to the request's method.
+const REQUEST_ENV: &str = "FORWARD_AS_ATTACHMENT_MTA_PUSH";
+
+/// Before the second and third attempt.
+const BACKOFF: [Duration; 2] = [Duration::from_secs(2), Duration::from_secs(10)];
+
+fn url<'a>(config: &'a Config, outcome: &str) -> Option<&'a str> {
+    match outcome {
+        "delivered" => config.push_url.as_deref(),
+        "failed" => config.push_failure_url.as_deref(),
+        _ => None,
+    }
+}
+
+/// What to request for a push; a POST if there is a body.
+#[derive(Debug, PartialEq)]
+struct Request {
+    url: String,
+    body: Option<String>,
+}
+
+impl Request {
+    /// The URL on the first line, then the body.
+    fn serialize(&self) -> String {
+        format!("{}\n{}", self.url, self.body.as_deref().unwrap_or_default())
+    }
+
+    fn parse(method: &str, s: &str) -> Option<Request> {
+        let (url, body) = s.split_once('\n')?;
+        let body = match method {
+            "GET" => None,
+            "POST" => Some(body.to_owned()),
+            _ => return None,
+        };
+        Some(Request {
+            url: url.to_owned(),
+            body,
+        })
+    }
+}
+
+/// The request for the `outcome` of the delivery, `delivered` or `failed`, if there is a URL for
+/// it.
+fn prepare(config: &Config, outcome: &str) -> Option<Request> {
+    let url = url(config, outcome)?.to_owned();
+    if !config.push_payload {
+        return Some(Request { url, body: None });
+    }
+    let today = crate::db::open(&config.state_dir)
+        .and_then(|db| db.history(crate::time::start_of_local_day(SystemTime::now())))
+        .unwrap_or_else(|e| {
+            warn!(%e, "can't read the delivery history for the push");
+            vec![]
+        });
+    let depth = crate::spool::depth(&config.state_dir).unwrap_or(0);
+    let last_failure = crate::marker::last_failure(&config.state_dir);
+    let body = payload(outcome, depth, &today, last_failure).to_string();
+    Some(Request {
+        url,
+        body: Some(body),
+    })
+}
+
+/// Start the detached push for the `outcome` of the delivery, `delivered` or `failed`, if there
+/// is a URL for it; doesn't wait for it. It gets the request on stdin, and runs with our
+/// effective ids as its only ones, see [`from_env`].
+pub fn spawn(config: &Config, outcome: &str) {
+    let Some(request) = prepare(config, outcome) else {
+        return;
+    };
+    let exe = match std::env::current_exe() {
+        Ok(exe) => exe,
+        Err(e) => return warn!(%e, "can't push to the monitor"),
+    };
+    let mut command = Command::new(exe);
+    // SAFETY: these are async-signal-safe
+    unsafe {
+        command.pre_exec(|| {
+            let (uid, gid) = (libc::geteuid(), libc::getegid());
+            if libc::setresgid(gid, gid, gid) == -1
+                || libc::setresuid(uid, uid, uid) == -1
+                // So that our setuid bit doesn't apply again.
+                || libc::prctl(libc::PR_SET_NO_NEW_PRIVS, 1, 0, 0, 0) == -1
+            {
+                return Err(io::Error::last_os_error());
+            }
+            Ok(())
+        });
+    }
+    let child = command
+        .env(
+            REQUEST_ENV,
+            if request.body.is_some() {
+                "POST"
+            } else {
+                "GET"
+            },
+        )
+        .env_remove("LISTEN_PID")
+        .env_remove("LISTEN_FDS")
+        .env_remove("LISTEN_FDNAMES")
+        .env_remove(crate::spool::FROM_SPOOL_ENV)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        // Not killed by the caller's Ctrl-C.
+        .process_group(0)
+        .spawn();
+    let written = child.and_then(|mut child| {
+        // Small enough for the pipe's buffer.
+        let mut stdin = child.stdin.take().expect("we requested a pipe");
+        stdin.write_all(request.serialize().as_bytes())?;
+        Ok(child)
+    });
+    match written {
+        Ok(child) => debug!(pid = child.id(), outcome, "pushing to the monitor"),
+        Err(e) => warn!(%e, "can't push to the monitor"),
+    }
+}
+
+/// The exit code of the detached push, if we are it. It needs neither the config nor privileges.
+pub fn from_env() -> Option<i32> {
+    let method = std::env::var(REQUEST_ENV).ok()?;
+    // Callers of the setuid binary must not make us push.
+    if hardening::is_setuid() {
+        panic!("{REQUEST_ENV} is only honored without setuid");
+    }
+    let mut request = String::new();
+    if let Err(e) = io::stdin().read_to_string(&mut request) {
+        warn!(%e, "can't read the push request");
+        return Some(sysexits::EX_TEMPFAIL);
+    }
+    Some(match Request::parse(&method, &request) {
+        Some(request) => send(&request),
+        None => sysexits::EX_USAGE,
+    })
+}
+
+/// `forward-as-attachment-mta --push delivered|failed`, for root only. Returns the exit code.
+pub fn command(config: &Config, caller_uid: u32, outcome: Option<&str>) -> i32 {
+    if let Err(code) = hardening::require_root(caller_uid, "--push") {
+        return code;
+    }
+    let Some(request) = outcome.and_then(|outcome| prepare(config, outcome)) else {
+        eprintln!(
+            "forward-as-attachment-mta: --push: expected `delivered` with push_url or `failed` with push_failure_url"
+        );
+        return sysexits::EX_USAGE;
+    };
+    send(&request)
+}
+
+/// Make the request, with retries. Returns the exit code.
+fn send(request: &Request) -> i32 {
+    let (method, headers, body): (_, &[_], _) = match &request.body {
+        Some(body) => (
+            "POST",
+            &[("Content-Type", "application/json")],
+            body.as_str(),
+        ),
+        None => ("GET", &[], ""),
+    };
+    let mut attempt = 0;
+    loop {
+        let retry = match crate::http::request(method, &request.url, headers, body.as_bytes()) {
+            Ok(status) if (200..300).contains(&status) => {
+                debug!(status, "pushed to the monitor");
+                return 0;
+            }
+            // Rate limits and server errors may be over on the next attempt, other errors won't.
+            Ok(status) => {
+                warn!(status, "the monitor rejected the push");
+                status == 429 || status >= 500
+            }
+            Err(e) => {
+                warn!(%e, "can't push to the monitor");
+                true
+            }
+        };
+        if !retry || attempt == BACKOFF.len() {
+            return sysexits::EX_TEMPFAIL;
+        }
+        std::thread::sleep(BACKOFF[attempt]);
+        attempt += 1;
+    }
+}
+
+/// The forwarding health, for `push_payload`: the spool's depth, today's deliveries, and the last
+/// failure, as `(time, queue id, reason)`.
+fn payload(
+    outcome: &str,
+    queue_depth: usize,
+    today: &[crate::db::Delivery],
+    last_failure: Option<(u64, String, String)>,
+) -> Value {
+    let failed = today.iter().filter(|d| d.failure.is_some()).count();
+    Value::object([
+        ("host", Value::from(crate::hostname())),
+        ("version", env!("CARGO_PKG_VERSION").into()),
+        ("outcome", outcome.into()),
+        ("queue_depth", queue_depth.into()),
+        ("sent_today", (today.len() - failed).into()),
+        ("failed_today", failed.into()),
+        (
+            "last_error",
+            last_failure
+                .map(|(at, queue_id, reason)| {
+                    Value::object([
+                        (
+                            "at",
+                            Value::from(crate::time::format_utc(
+                                SystemTime::UNIX_EPOCH + Duration::from_secs(at),
+                            )),
+                        ),
+                        ("queue_id", queue_id.into()),
+                        ("reason", reason.into()),
+                    ])
+                })
+                .unwrap_or(Value::Null),
+        ),
+    ])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_request() {
+        for request in [
+            Request {
+                url: "https://kuma.example.com/api/push/abc?status=up".to_owned(),
+                body: None,
+            },
+            Request {
+                url: "https://hc-ping.com/uuid".to_owned(),
+                body: Some("{\"outcome\":\"delivered\"}\n".to_owned()),
+            },
+        ] {
+            let method = if request.body.is_some() {
+                "POST"
+            } else {
+                "GET"
+            };
+            assert_eq!(Request::parse(method, &request.serialize()), Some(request));
+        }
+        assert_eq!(Request::parse("DELETE", "https://hc-ping.com/uuid\n"), None);
+    }
+
+    #[test]
+    fn test_payload() {
+        let delivery = |failure: Option<&str>| crate::db::Delivery {
+            at: 1704067200,
+            queue_id: "65A0B1C2D3E4F".to_owned(),
+            failure: failure.map(str::to_owned),
+            uid: 0,
+            size: 1024,
+            sender: "root".to_owned(),
+        };
+        let today = [delivery(None), delivery(Some("deferred")), delivery(None)];
+        let last_failure = (
+            1704067200,
+            "65A0B1C2D3E4F".to_owned(),
+            "deferred: 451".to_owned(),
+        );
+        let payload = payload("delivered", 2, &today, Some(last_failure)).to_string();
+        assert!(payload.contains(concat!(
+            r#""outcome":"delivered","queue_depth":2,"sent_today":2,"failed_today":1,"#,
+            r#""last_error":{"at":"2024-01-01T00:00:00Z","queue_id":"65A0B1C2D3E4F","reason":"deferred: 451"}}"#
+        )));
+    }
+}
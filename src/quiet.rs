@@ -0,0 +1,80 @@
+//! Quiet hours: a daily time window during which non-critical submissions are held in the
+//! spool, to be forwarded by `sendmail -q` (or the first submission) after the window.
+
+use std::time::SystemTime;
+
+use crate::filter::{Patterns, Submission};
+
+/// The `reason` of spool entries held for quiet hours, see `spool::Meta`.
+pub const SPOOL_REASON: &str = "quiet hours";
+
+#[derive(Debug, Clone, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct QuietHours {
+    /// Local time of day, e.g. `23:00`.
+    pub start: String,
+    /// Local time of day, e.g. `07:00`. May be before `start`, the window then spans midnight.
+    pub end: String,
+    /// Submissions that match one of these are forwarded right away.
+    #[serde(default)]
+    pub critical: Vec<Patterns>,
+}
+
+impl QuietHours {
+    /// Check the times and regexes, for loading the config.
+    pub fn validate(&self) -> Result<(), String> {
+        for time in [&self.start, &self.end] {
+            minute_of_day(time).ok_or_else(|| format!("invalid time {time:?}, expected HH:MM"))?;
+        }
+        for patterns in &self.critical {
+            patterns.validate().map_err(|e| format!("critical: {e}"))?;
+        }
+        Ok(())
+    }
+
+    /// Whether `t` is within the window, in local time.
+    pub fn is_quiet(&self, t: SystemTime) -> bool {
+        let now = crate::time::strftime_local("%H:%M", t).and_then(|s| minute_of_day(&s));
+        let (Some(now), Some(start), Some(end)) =
+            (now, minute_of_day(&self.start), minute_of_day(&self.end))
+        else {
+            return false;
+        };
+        in_window(now, start, end)
+    }
+
+    pub fn is_critical(&self, submission: &Submission) -> bool {
+        self.critical.iter().any(|p| p.matches(submission))
+    }
+}
+
+fn minute_of_day(time: &str) -> Option<u32> {
+    let (h, m) = time.trim().split_once(':')?;
+    let (h, m) = (h.parse::<u32>().ok()?, m.parse::<u32>().ok()?);
+    (h < 24 && m < 60).then_some(h * 60 + m)
+}
+
+fn in_window(now: u32, start: u32, end: u32) -> bool {
+    match start <= end {
+        true => start <= now && now < end,
+        false => now >= start || now < end,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_window() {
+        let m = |s| minute_of_day(s).unwrap();
+        assert_eq!(minute_of_day("7:05"), Some(425));
+        assert_eq!(minute_of_day("24:00"), None);
+        assert!(in_window(m("23:30"), m("23:00"), m("07:00")));
+        assert!(in_window(m("06:59"), m("23:00"), m("07:00")));
+        assert!(!in_window(m("07:00"), m("23:00"), m("07:00")));
+        assert!(!in_window(m("12:00"), m("23:00"), m("07:00")));
+        assert!(in_window(m("13:00"), m("12:00"), m("14:00")));
+        assert!(!in_window(m("14:00"), m("12:00"), m("14:00")));
+    }
+}
@@ -0,0 +1,207 @@
+//! Per-user rate limit, so that a runaway job can't flood the relay and the recipient's inbox.
+//!
+//! Each local user has a token bucket of `rate_limit_per_hour` tokens in `state_dir/ratelimit`,
+//! refilled continuously. Submissions over the limit are held in `state_dir/ratelimited` and sent
+//! in a digest at most once an hour, with as many originals attached as fit into
+//! [`DIGEST_ATTACHMENTS_SIZE`]; the others are only listed.
+
+use std::fmt::Write as _;
+use std::io::{self, Read, Seek, Write as _};
+use std::os::fd::AsRawFd;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::PathBuf;
+use std::time::{Duration, SystemTime};
+
+use mailparse::MailHeaderMap;
+use tracing::{debug, warn};
+
+use crate::config::Config;
+use crate::spool;
+
+const DIGEST_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+/// The total size of the originals attached to a digest, so that relays still accept it.
+const DIGEST_ATTACHMENTS_SIZE: u64 = 10 * 1024 * 1024;
+
+/// Enough of an original for its headers.
+const HEADERS_SIZE: u64 = 64 * 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Bucket {
+    tokens: f64,
+    /// Unix time of the last update.
+    updated_at: u64,
+}
+
+impl Bucket {
+    /// Refill for the time passed since the last update, then take a token if there is one.
+    fn take(self, per_hour: u32, now: u64) -> (Bucket, bool) {
+        let elapsed = now.saturating_sub(self.updated_at) as f64;
+        let tokens =
+            (self.tokens + elapsed * f64::from(per_hour) / 3600.0).min(f64::from(per_hour));
+        let taken = tokens >= 1.0;
+        let tokens = if taken { tokens - 1.0 } else { tokens };
+        (
+            Bucket {
+                tokens,
+                updated_at: now,
+            },
+            taken,
+        )
+    }
+}
+
+/// Take a token from the bucket of `uid`. Returns whether the submission is within the limit.
+pub fn take(config: &Config, uid: u32) -> io::Result<bool> {
+    let path = crate::state::subdir(config, "ratelimit")?.join(uid.to_string());
+    let mut file = std::fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .mode(0o600)
+        .open(path)?;
+    // Concurrent submissions of the same user take turns.
+    if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let mut contents = String::new();
+    file.read_to_string(&mut contents)?;
+    let now = crate::time::unix_secs(SystemTime::now());
+    let bucket = contents
+        .split_once(' ')
+        .and_then(|(tokens, updated_at)| {
+            Some(Bucket {
+                tokens: tokens.parse().ok()?,
+                updated_at: updated_at.trim().parse().ok()?,
+            })
+        })
+        .unwrap_or(Bucket {
+            tokens: f64::from(config.rate_limit_per_hour),
+            updated_at: now,
+        });
+    let (bucket, taken) = bucket.take(config.rate_limit_per_hour, now);
+    debug!(?bucket, taken, "rate limit");
+    file.set_len(0)?;
+    file.rewind()?;
+    writeln!(file, "{} {}", bucket.tokens, bucket.updated_at)?;
+    Ok(taken)
+}
+
+/// Hold a submission over the limit for the digest.
+pub fn hold(config: &Config, meta: &spool::Meta, original: impl Read) -> io::Result<PathBuf> {
+    spool::store_in(
+        &crate::state::subdir(config, "ratelimited")?,
+        meta,
+        original,
+    )
+}
+
+/// Send the digest of held submissions if it is due, with the originals attached as far as they
+/// fit. All of them are removed once it's sent.
+pub fn maybe_send_digest(config: &Config, hostname: &str) -> io::Result<()> {
+    let dir = crate::state::subdir(config, "ratelimited")?;
+    let held = spool::list(&dir)?;
+    let Some(oldest) = held.first() else {
+        return Ok(());
+    };
+    let now = crate::time::unix_secs(SystemTime::now());
+    if now.saturating_sub(oldest.submitted_at) < DIGEST_INTERVAL.as_secs() {
+        return Ok(());
+    }
+
+    let mut body = String::new();
+    writeln!(
+        &mut body,
+        "The following submissions on host {hostname:?} exceeded rate_limit_per_hour = {} and were held, they are attached:",
+        config.rate_limit_per_hour
+    )
+    .unwrap();
+    writeln!(&mut body).unwrap();
+    let mut attachments = Vec::new();
+    let mut attached_size = 0;
+    let mut not_attached = String::new();
+    for meta in &held {
+        let eml_name = format!("{}.eml", meta.queue_id);
+        let mut file = std::fs::File::open(dir.join(&eml_name))?;
+        let size = file.metadata()?.len();
+        let mut original = Vec::new();
+        let fits = attached_size + size <= DIGEST_ATTACHMENTS_SIZE;
+        match fits {
+            true => file.read_to_end(&mut original)?,
+            false => Read::take(&mut file, HEADERS_SIZE).read_to_end(&mut original)?,
+        };
+        let subject = mailparse::parse_headers(&original)
+            .ok()
+            .and_then(|(headers, _)| headers.get_first_value("Subject"))
+            .unwrap_or_default();
+        let line = format!(
+            "{} uid:{} user:{} subject:{}",
+            crate::time::format_utc(meta.submitted_at()),
+            meta.uid,
+            meta.username.as_deref().unwrap_or(""),
+            subject.replace(['\r', '\n'], " "),
+        );
+        if fits {
+            writeln!(&mut body, "{line}").unwrap();
+            attached_size += size;
+            attachments.push((eml_name, original));
+        } else {
+            writeln!(
+                &mut not_attached,
+                "{line} queue_id:{} size:{size}",
+                meta.queue_id
+            )
+            .unwrap();
+        }
+    }
+    if !not_attached.is_empty() {
+        writeln!(
+            &mut body,
+            "\nThese did not fit into the digest, their originals are not kept:\n\n{not_attached}"
+        )
+        .unwrap();
+    }
+
+    match crate::send_notice_with_attachments(
+        config,
+        format!("{hostname}: {} rate-limited submissions", held.len()),
+        body,
+        attachments,
+    ) {
+        Ok(()) => {
+            for meta in &held {
+                spool::remove(&dir, meta)?;
+            }
+        }
+        Err(e) => warn!(%e, "failed to send rate limit digest, will retry"),
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_bucket() {
+        let full = Bucket {
+            tokens: 2.0,
+            updated_at: 1000,
+        };
+        let (bucket, taken) = full.take(2, 1000);
+        assert!(taken);
+        let (bucket, taken) = bucket.take(2, 1000);
+        assert!(taken);
+        let (bucket, taken) = bucket.take(2, 1000);
+        assert!(!taken);
+        // one token per 30 minutes
+        let (_, taken) = bucket.take(2, 1000 + 1799);
+        assert!(!taken);
+        let (bucket, taken) = bucket.take(2, 1000 + 1800);
+        assert!(taken);
+        // capped at the limit
+        let (bucket, _) = bucket.take(2, 1_000_000);
+        assert_eq!(bucket.tokens, 1.0);
+    }
+}
@@ -0,0 +1,78 @@
+//! `[[route]]` rules that send matching submissions to another recipient, e.g. RAID alerts to
+//! the storage team.
+
+use crate::filter::{self, Submission};
+
+#[derive(Debug, Clone, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Route {
+    /// Shown in the wrapper text. Defaults to the position of the rule.
+    pub name: Option<String>,
+    /// Regexes, all given ones must match, like those of `[[filter]]`.
+    pub subject: Option<String>,
+    pub body: Option<String>,
+    pub sender: Option<String>,
+    /// Replaces `recipient_email`.
+    pub recipient: lettre::Address,
+}
+
+impl Route {
+    /// Check the regexes, for loading the config.
+    pub fn validate(&self) -> Result<(), String> {
+        filter::validate([&self.subject, &self.body, &self.sender])
+    }
+}
+
+/// The first route that matches `submission`, with its name.
+pub fn find<'a>(routes: &'a [Route], submission: &Submission) -> Option<(String, &'a Route)> {
+    let (i, route) = routes.iter().enumerate().find(|(_, route)| {
+        filter::matches([&route.subject, &route.body, &route.sender], submission)
+    })?;
+    let name = match &route.name {
+        Some(name) => format!("{name:?}"),
+        None => format!("#{}", i + 1),
+    };
+    Some((name, route))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_find() {
+        #[derive(serde::Deserialize)]
+        struct Config {
+            route: Vec<Route>,
+        }
+        let routes = toml::from_str::<Config>(
+            r#"
+            [[route]]
+            name = "raid"
+            subject = "RAID"
+            recipient = "storage-team@example.com"
+            [[route]]
+            body = "ERROR|FATAL"
+            recipient = "oncall@example.com"
+            "#,
+        )
+        .unwrap()
+        .route;
+        assert!(routes.iter().all(|r| r.validate().is_ok()));
+        let submission = |subject, body| Submission {
+            subject,
+            body,
+            sender: "root@host",
+        };
+        let found = |s| find(&routes, &s).map(|(name, r)| (name, r.recipient.to_string()));
+        assert_eq!(
+            found(submission("RAID degraded", "ERROR")),
+            Some(("\"raid\"".to_owned(), "storage-team@example.com".to_owned()))
+        );
+        assert_eq!(
+            found(submission("backup", "FATAL: no space")),
+            Some(("#2".to_owned(), "oncall@example.com".to_owned()))
+        );
+        assert_eq!(found(submission("backup", "ok")), None);
+    }
+}
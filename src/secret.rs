@@ -0,0 +1,47 @@
+//! Credentials from the config that are zeroed when dropped and not shown by `Debug`, so that
+//! they don't end up in logs or linger in freed memory.
+
+use zeroize::Zeroizing;
+
+#[derive(Clone, Default, PartialEq, Eq, serde::Deserialize)]
+#[serde(from = "String")]
+pub struct Secret(Zeroizing<String>);
+
+impl Secret {
+    pub fn expose(&self) -> &str {
+        &self.0
+    }
+
+    pub fn expose_mut(&mut self) -> &mut String {
+        &mut self.0
+    }
+}
+
+impl From<String> for Secret {
+    fn from(value: String) -> Self {
+        Self(Zeroizing::new(value))
+    }
+}
+
+impl std::fmt::Debug for Secret {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("[redacted]")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_redacted() {
+        #[derive(Debug, serde::Deserialize)]
+        struct Config {
+            #[allow(dead_code)]
+            smtp_password: Secret,
+        }
+        let config = toml::from_str::<Config>(r#"smtp_password = "hunter2""#).unwrap();
+        assert_eq!(config.smtp_password.expose(), "hunter2");
+        assert!(!format!("{config:?}").contains("hunter2"));
+    }
+}
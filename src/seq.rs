@@ -0,0 +1,56 @@
+//! Sequence numbers, with `sequence_numbers`: each wrapper gets the next number of this host's
+//! counter, in `X-FAAM-Seq` and the body, so that the recipient notices gaps, i.e. messages lost
+//! between the host and the inbox.
+//!
+//! The counter is kept in `state_dir/seq/counter`.
+
+use std::io::{self, Read, Seek, Write};
+use std::os::fd::AsRawFd;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::Path;
+
+/// Take the next number, starting at 1.
+pub fn next(state_dir: &Path) -> io::Result<u64> {
+    let mut file = std::fs::File::options()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .mode(0o600)
+        .open(crate::state::subdir_in(state_dir, "seq")?.join("counter"))?;
+    // Concurrent submissions must not get the same number.
+    // SAFETY: the file descriptor is open
+    if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let mut contents = String::new();
+    file.read_to_string(&mut contents)?;
+    let last = match contents.trim() {
+        "" => 0,
+        n => n
+            .parse::<u64>()
+            .map_err(|e| io::Error::other(format!("sequence counter {n:?}: {e}")))?,
+    };
+    let next = last + 1;
+    file.set_len(0)?;
+    file.rewind()?;
+    writeln!(file, "{next}")?;
+    file.sync_all()?;
+    Ok(next)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_next() {
+        let dir = crate::testdir::TestDir::new("seq");
+        assert_eq!(next(&dir).unwrap(), 1);
+        assert_eq!(next(&dir).unwrap(), 2);
+        assert_eq!(
+            std::fs::read_to_string(dir.join("seq/counter")).unwrap(),
+            "2\n"
+        );
+    }
+}
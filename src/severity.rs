@@ -0,0 +1,150 @@
+//! `[[classifier]]` rules that assign a severity to submissions.
+//!
+//! The severity is available as the `%severity%` placeholder and in the `X-FAAM-Severity`
+//! header, selects the recipient via `[severity_recipients]`, and critical submissions bypass
+//! the rate limit and quiet hours.
+
+use regex::Regex;
+
+use crate::filter::{self, Submission};
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Severity {
+    #[default]
+    Info,
+    Warning,
+    Critical,
+}
+
+impl Severity {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Severity::Info => "info",
+            Severity::Warning => "warning",
+            Severity::Critical => "critical",
+        }
+    }
+}
+
+/// `[severity_recipients]`: where submissions of each severity go instead of `recipient_email`.
+#[derive(Debug, Default, Clone, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Recipients {
+    pub info: Option<lettre::Address>,
+    pub warning: Option<lettre::Address>,
+    pub critical: Option<lettre::Address>,
+}
+
+impl Recipients {
+    pub fn get(&self, severity: Severity) -> Option<&lettre::Address> {
+        match severity {
+            Severity::Info => self.info.as_ref(),
+            Severity::Warning => self.warning.as_ref(),
+            Severity::Critical => self.critical.as_ref(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Classifier {
+    pub severity: Severity,
+    /// Regexes, all given ones must match, like those of `[[filter]]`.
+    pub subject: Option<String>,
+    pub body: Option<String>,
+    pub sender: Option<String>,
+    /// Sender addresses, one of which must be the sender if given. Case-insensitive.
+    #[serde(default)]
+    pub senders: Vec<String>,
+}
+
+impl Classifier {
+    /// Check the regexes, for loading the config.
+    pub fn validate(&self) -> Result<(), String> {
+        let regexes = [&self.subject, &self.body, &self.sender];
+        if regexes.iter().all(|r| r.is_none()) && self.senders.is_empty() {
+            return Err("needs at least one of subject, body, sender, or senders".to_owned());
+        }
+        for pattern in regexes.into_iter().flatten() {
+            Regex::new(pattern).map_err(|e| e.to_string())?;
+        }
+        Ok(())
+    }
+
+    fn matches(&self, submission: &Submission) -> bool {
+        let sender = self.senders.is_empty()
+            || self
+                .senders
+                .iter()
+                .any(|s| s.eq_ignore_ascii_case(submission.sender));
+        sender && filter::matches([&self.subject, &self.body, &self.sender], submission)
+    }
+}
+
+/// The highest severity of the classifiers that match `submission`, `Info` if none does.
+pub fn classify(classifiers: &[Classifier], submission: &Submission) -> Severity {
+    classifiers
+        .iter()
+        .filter(|c| c.matches(submission))
+        .map(|c| c.severity)
+        .max()
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_classify() {
+        #[derive(serde::Deserialize)]
+        struct Config {
+            classifier: Vec<Classifier>,
+            severity_recipients: Recipients,
+        }
+        let config = toml::from_str::<Config>(
+            r#"
+            [severity_recipients]
+            critical = "oncall@example.com"
+            [[classifier]]
+            severity = "warning"
+            subject = "(?i)warn"
+            [[classifier]]
+            severity = "critical"
+            body = "\\b(ERROR|FATAL)\\b"
+            [[classifier]]
+            severity = "critical"
+            senders = ["smartd@host"]
+            "#,
+        )
+        .unwrap();
+        let classifiers = config.classifier;
+        assert!(classifiers.iter().all(|c| c.validate().is_ok()));
+        let submission = |subject, body, sender| Submission {
+            subject,
+            body,
+            sender,
+        };
+        let classify = |s| classify(&classifiers, &s);
+        assert_eq!(classify(submission("ok", "", "root@host")), Severity::Info);
+        assert_eq!(
+            classify(submission("WARNING", "", "root@host")),
+            Severity::Warning
+        );
+        assert_eq!(
+            classify(submission("Warning", "FATAL: disk", "root@host")),
+            Severity::Critical
+        );
+        assert_eq!(
+            classify(submission("", "", "SMARTD@host")),
+            Severity::Critical
+        );
+        let recipients = config.severity_recipients;
+        assert_eq!(
+            recipients.get(Severity::Critical).map(|a| a.to_string()),
+            Some("oncall@example.com".to_owned())
+        );
+        assert_eq!(recipients.get(Severity::Info), None);
+    }
+}
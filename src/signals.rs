@@ -0,0 +1,138 @@
+//! Termination signals, e.g. when the host shuts down while we are sending.
+//!
+//! The signals are handled by a thread instead of interrupting us. While reading stdin, reading
+//! stops (see `original::Deadline`) and what was read is spooled by the main thread. Afterwards,
+//! the thread spools the submission itself. Either way, we exit with `EX_TEMPFAIL`.
+
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::os::fd::{FromRawFd, OwnedFd};
+use std::os::unix::fs::FileExt;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex, PoisonError};
+
+use tracing::warn;
+
+use crate::spool;
+use crate::sysexits;
+
+const SIGNALS: [libc::c_int; 3] = [libc::SIGTERM, libc::SIGINT, libc::SIGHUP];
+
+pub enum Original {
+    InMemory(Arc<Vec<u8>>),
+    /// A handle of the unlinked spool file of a large original, see `original::Spooled`.
+    File(File),
+}
+
+/// What the thread spools when a signal is received.
+pub struct Pending {
+    pub state_dir: PathBuf,
+    pub meta: spool::Meta,
+    pub original: Original,
+}
+
+enum State {
+    Reading { signalled: bool },
+    Pending(Pending),
+    Done,
+}
+
+static STATE: Mutex<State> = Mutex::new(State::Reading { signalled: false });
+
+/// Start handling the signals. Returns a file descriptor that becomes readable once a signal
+/// was received while reading stdin.
+///
+/// Must be called before other threads are spawned, as they inherit the signal mask.
+pub fn install() -> io::Result<OwnedFd> {
+    let (set, read, mut write) = unsafe {
+        let mut set: libc::sigset_t = std::mem::zeroed();
+        libc::sigemptyset(&mut set);
+        for signal in SIGNALS {
+            libc::sigaddset(&mut set, signal);
+        }
+        match libc::pthread_sigmask(libc::SIG_BLOCK, &set, std::ptr::null_mut()) {
+            0 => (),
+            e => return Err(io::Error::from_raw_os_error(e)),
+        }
+        let mut fds = [0; 2];
+        if libc::pipe2(fds.as_mut_ptr(), libc::O_CLOEXEC) != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        (set, OwnedFd::from_raw_fd(fds[0]), File::from_raw_fd(fds[1]))
+    };
+    std::thread::spawn(move || loop {
+        let mut signal = 0;
+        if unsafe { libc::sigwait(&set, &mut signal) } == 0 {
+            handle(signal, &mut write);
+        }
+    });
+    Ok(read)
+}
+
+/// Reading stdin is done, spool `pending` if a signal is received from now on.
+pub fn pending(pending: Pending) {
+    *STATE.lock().unwrap_or_else(PoisonError::into_inner) = State::Pending(pending);
+}
+
+/// The submission was forwarded, a signal from now on just ends the process.
+pub fn done() {
+    *STATE.lock().unwrap_or_else(PoisonError::into_inner) = State::Done;
+}
+
+fn handle(signal: libc::c_int, wakeup: &mut File) {
+    let mut state = STATE.lock().unwrap_or_else(PoisonError::into_inner);
+    match &mut *state {
+        State::Reading { signalled: false } => {
+            warn!(
+                signal,
+                "terminated while reading stdin, spooling what was read"
+            );
+            *state = State::Reading { signalled: true };
+            if let Err(e) = wakeup.write_all(b"x") {
+                warn!(%e, "failed to stop reading stdin");
+                std::process::exit(sysexits::EX_TEMPFAIL);
+            }
+        }
+        State::Reading { signalled: true } => {
+            warn!(signal, "terminated again while reading stdin, exiting");
+            std::process::exit(sysexits::EX_TEMPFAIL);
+        }
+        State::Pending(pending) => {
+            warn!(
+                signal,
+                queue_id = pending.meta.queue_id,
+                "terminated, spooling the submission"
+            );
+            let result = match &pending.original {
+                Original::InMemory(data) => {
+                    spool::store(&pending.state_dir, &pending.meta, data.as_slice())
+                }
+                Original::File(file) => spool::store(
+                    &pending.state_dir,
+                    &pending.meta,
+                    ReadAt { file, offset: 0 },
+                ),
+            };
+            match result {
+                Ok(path) => eprintln!("forward-as-attachment-mta: terminated, spooled to {path:?}"),
+                Err(e) => warn!(%e, "failed to spool the submission"),
+            }
+            std::process::exit(sysexits::EX_TEMPFAIL);
+        }
+        State::Done => std::process::exit(0),
+    }
+}
+
+/// Reads `file` without moving its offset, which the main thread uses.
+struct ReadAt<'a> {
+    file: &'a File,
+    offset: u64,
+}
+
+impl Read for ReadAt<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.file.read_at(buf, self.offset)?;
+        self.offset += n as u64;
+        Ok(n)
+    }
+}
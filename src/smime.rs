@@ -0,0 +1,208 @@
+//! `[smime]`: signing the wrapper, and optionally encrypting it, through the `openssl cms`
+//! command.
+//!
+//! The headers that address the message stay outside; the MIME entity, i.e. the `Content-*`
+//! headers and the body, is replaced by `multipart/signed`, which is then wrapped in
+//! `application/pkcs7-mime; smime-type=enveloped-data` if a recipient certificate is given.
+
+use std::io::{self, Write};
+use std::path::PathBuf;
+use std::process::Stdio;
+
+#[derive(Debug, Clone, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Smime {
+    /// PEM certificate and private key to sign with.
+    pub cert: Option<PathBuf>,
+    pub key: Option<PathBuf>,
+    /// PEM certificate of the recipient to encrypt to, after signing.
+    pub recipient_cert: Option<PathBuf>,
+}
+
+impl Smime {
+    /// Check the combination of settings, for loading the config.
+    pub fn validate(&self) -> Result<(), String> {
+        match (&self.cert, &self.key, &self.recipient_cert) {
+            (Some(_), None, _) | (None, Some(_), _) => {
+                Err("cert and key must be given together".to_owned())
+            }
+            (None, None, None) => Err("needs cert and key, or recipient_cert".to_owned()),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// `message`, a formatted message, with its MIME entity signed and/or encrypted.
+pub fn protect(config: &Smime, message: &[u8]) -> io::Result<Vec<u8>> {
+    let (outer, mut entity) = split(message)?;
+    if let (Some(cert), Some(key)) = (&config.cert, &config.key) {
+        let mut args = vec!["-sign".into(), "-signer".into(), cert.into()];
+        args.extend(["-inkey".into(), key.into()]);
+        entity = strip_mime_version(&openssl(&args, &entity)?);
+    }
+    if let Some(recipient) = &config.recipient_cert {
+        let args = ["-encrypt".into(), "-aes256".into(), recipient.into()];
+        entity = strip_mime_version(&openssl(&args, &entity)?);
+    }
+    let mut protected = outer;
+    protected.extend_from_slice(b"MIME-Version: 1.0\r\n");
+    protected.extend_from_slice(&entity);
+    Ok(protected)
+}
+
+/// The header lines other than `MIME-Version` and `Content-*`, and the entity made of the
+/// `Content-*` ones and the body.
+fn split(message: &[u8]) -> io::Result<(Vec<u8>, Vec<u8>)> {
+    let end = message
+        .windows(4)
+        .position(|w| w == b"\r\n\r\n")
+        .ok_or_else(|| io::Error::other("message without a body"))?;
+    let (mut outer, mut entity) = (Vec::new(), Vec::new());
+    // Where the current header goes, continuation lines included. `None` drops it.
+    let mut to: Option<&mut Vec<u8>> = None;
+    for line in message[..end + 2].split_inclusive(|&b| b == b'\n') {
+        if !line.starts_with(b" ") && !line.starts_with(b"\t") {
+            let name = line.split(|&b| b == b':').next().unwrap_or_default();
+            to = if name.eq_ignore_ascii_case(b"MIME-Version") {
+                None
+            } else if name.len() > 8 && name[..8].eq_ignore_ascii_case(b"Content-") {
+                Some(&mut entity)
+            } else {
+                Some(&mut outer)
+            };
+        }
+        if let Some(to) = &mut to {
+            to.extend_from_slice(line);
+        }
+    }
+    entity.extend_from_slice(&message[end + 2..]);
+    Ok((outer, entity))
+}
+
+/// `entity` without the `MIME-Version` header that `openssl` puts first.
+fn strip_mime_version(entity: &[u8]) -> Vec<u8> {
+    match entity.strip_prefix(b"MIME-Version: 1.0\r\n".as_slice()) {
+        Some(rest) => rest.to_vec(),
+        None => entity.to_vec(),
+    }
+}
+
+/// The output of `openssl cms` with `args` on `input`, with CRLF line endings, which it only
+/// uses for the headers.
+fn openssl(args: &[std::ffi::OsString], input: &[u8]) -> io::Result<Vec<u8>> {
+    let mut child = crate::hardening::command("openssl")
+        .args(["cms", "-binary", "-crlfeol"])
+        .args(args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()?;
+    let mut stdin = child.stdin.take().expect("we requested a pipe");
+    let input = input.to_vec();
+    // Written concurrently, openssl blocks once the output pipe is full.
+    let writer = std::thread::spawn(move || stdin.write_all(&input));
+    let output = child.wait_with_output()?;
+    writer.join().expect("writer doesn't panic")?;
+    if !output.status.success() {
+        return Err(io::Error::other(format!(
+            "openssl cms exited with {}: {}",
+            output.status,
+            String::from_utf8_lossy(&output.stderr).trim()
+        )));
+    }
+    let mut crlf = Vec::with_capacity(output.stdout.len() + output.stdout.len() / 64);
+    for line in output.stdout.split_inclusive(|&b| b == b'\n') {
+        match line.strip_suffix(b"\n") {
+            Some(line) => {
+                crlf.extend_from_slice(line.strip_suffix(b"\r").unwrap_or(line));
+                crlf.extend_from_slice(b"\r\n");
+            }
+            None => crlf.extend_from_slice(line),
+        }
+    }
+    Ok(crlf)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::process::Command;
+
+    #[test]
+    fn test_protect() {
+        let dir = crate::testdir::TestDir::new("smime");
+        let status = Command::new("openssl")
+            .args([
+                "req", "-x509", "-newkey", "rsa:2048", "-nodes", "-days", "1",
+            ])
+            .args(["-subj", "/CN=test@example.com", "-keyout"])
+            .arg(dir.join("key.pem"))
+            .arg("-out")
+            .arg(dir.join("cert.pem"))
+            .stderr(Stdio::null())
+            .status()
+            .unwrap();
+        assert!(status.success());
+        let openssl_out = |args: &[&str], input: &[u8]| {
+            let mut child = Command::new("openssl")
+                .arg("cms")
+                .args(args)
+                .current_dir(&dir)
+                .stdin(Stdio::piped())
+                .stdout(Stdio::piped())
+                .stderr(Stdio::null())
+                .spawn()
+                .unwrap();
+            child.stdin.take().unwrap().write_all(input).unwrap();
+            let output = child.wait_with_output().unwrap();
+            assert!(output.status.success(), "{output:?}");
+            output.stdout
+        };
+
+        let message = b"From: root@host\r\nMIME-Version: 1.0\r\nSubject: report\r\nContent-Type: text/plain;\r\n charset=utf-8\r\nContent-Transfer-Encoding: 7bit\r\n\r\nsecret\r\n";
+        let signed = Smime {
+            cert: Some(dir.join("cert.pem")),
+            key: Some(dir.join("key.pem")),
+            recipient_cert: None,
+        };
+        assert!(signed.validate().is_ok());
+        let protected = protect(&signed, message).unwrap();
+        let text = String::from_utf8(protected.clone()).unwrap();
+        assert!(text.starts_with(
+            "From: root@host\r\nSubject: report\r\nMIME-Version: 1.0\r\nContent-Type: multipart/signed;"
+        ));
+        assert!(!text.replace("\r\n", "").contains('\n'));
+        let verified = openssl_out(&["-verify", "-CAfile", "cert.pem"], &protected);
+        assert_eq!(
+            verified,
+            b"Content-Type: text/plain;\r\n charset=utf-8\r\nContent-Transfer-Encoding: 7bit\r\n\r\nsecret\r\n"
+        );
+
+        let encrypted = Smime {
+            recipient_cert: Some(dir.join("cert.pem")),
+            ..signed.clone()
+        };
+        let protected = protect(&encrypted, message).unwrap();
+        let text = String::from_utf8(protected.clone()).unwrap();
+        assert!(text.contains("Content-Type: application/pkcs7-mime; smime-type=enveloped-data"));
+        assert!(!text.contains("secret"));
+        let decrypted = openssl_out(
+            &["-decrypt", "-inkey", "key.pem", "-recip", "cert.pem"],
+            &protected,
+        );
+        assert!(String::from_utf8(decrypted)
+            .unwrap()
+            .starts_with("Content-Type: multipart/signed;"));
+
+        let broken = Smime {
+            key: Some(dir.join("missing.pem")),
+            ..signed
+        };
+        assert!(protect(&broken, message).is_err());
+        let invalid = Smime {
+            cert: None,
+            ..broken
+        };
+        assert!(invalid.validate().is_err());
+    }
+}
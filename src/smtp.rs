@@ -0,0 +1,415 @@
+//! Delivery to the relay.
+//!
+//! `lettre` takes care of the protocol details; the transaction itself is done here, so that
+//! the extensions advertised by the relay can be taken into account.
+
+use std::os::unix::net::UnixStream;
+use std::time::Duration;
+
+use lettre::address::Envelope;
+use lettre::transport::smtp::authentication::{Credentials, Mechanism};
+use lettre::transport::smtp::client::{SmtpConnection, TlsParameters};
+use lettre::transport::smtp::commands::{Data, Ehlo, Mail, Rcpt};
+use lettre::transport::smtp::extension::{
+    ClientId, MailBodyParameter, MailParameter, RcptParameter,
+};
+use lettre::transport::smtp::response::Response;
+use lettre::transport::smtp::SUBMISSION_PORT;
+use lettre::Address;
+
+use tracing::warn;
+
+use crate::config::Config;
+
+const TIMEOUT: Duration = Duration::from_secs(60);
+
+/// When the relay should send a delivery status notification (RFC 3461) for the wrapper.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DsnNotify {
+    Never,
+    Success,
+    Failure,
+    Delay,
+}
+
+impl DsnNotify {
+    fn keyword(self) -> &'static str {
+        match self {
+            DsnNotify::Never => "NEVER",
+            DsnNotify::Success => "SUCCESS",
+            DsnNotify::Failure => "FAILURE",
+            DsnNotify::Delay => "DELAY",
+        }
+    }
+}
+
+/// A `[[smarthost]]`: another relay account, for recipients in its domains.
+#[derive(Debug, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Smarthost {
+    /// Recipient domains, case-insensitive.
+    pub domains: Vec<String>,
+    pub smtp_host: String,
+    pub smtp_username: String,
+    pub smtp_password: crate::secret::Secret,
+}
+
+/// Split `recipients` into those for the default relay and those for each smarthost of the
+/// first whose domains contain theirs.
+pub fn partition<'a>(
+    smarthosts: &'a [Smarthost],
+    recipients: &[Address],
+) -> (Vec<Address>, Vec<(&'a Smarthost, Vec<Address>)>) {
+    let mut default = Vec::new();
+    let mut routed: Vec<(&Smarthost, Vec<Address>)> = Vec::new();
+    for recipient in recipients {
+        let smarthost = smarthosts.iter().find(|s| {
+            s.domains
+                .iter()
+                .any(|d| d.eq_ignore_ascii_case(recipient.domain()))
+        });
+        match smarthost {
+            None => default.push(recipient.clone()),
+            Some(smarthost) => match routed.iter_mut().find(|(s, _)| std::ptr::eq(*s, smarthost)) {
+                Some((_, group)) => group.push(recipient.clone()),
+                None => routed.push((smarthost, vec![recipient.clone()])),
+            },
+        }
+    }
+    (default, routed)
+}
+
+#[derive(Debug)]
+pub enum Error {
+    Smtp(lettre::transport::smtp::Error),
+    /// The message requires an extension the relay doesn't advertise.
+    Unsupported(&'static str),
+    /// The daemon's relay connection failed, with the class of its error, see `daemon`.
+    Daemon {
+        class: &'static str,
+        message: String,
+    },
+}
+
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Error::Smtp(e) => write!(f, "{e}"),
+            Error::Unsupported(ext) => write!(f, "the relay does not support {ext}"),
+            Error::Daemon { message, .. } => write!(f, "{message}"),
+        }
+    }
+}
+
+impl Error {
+    /// For the metrics: `rejected` (5xx), `deferred` (4xx), `timeout`, `tls`, `connection`
+    /// (anything else lettre reports), or `unsupported`, see [`CLASSES`].
+    pub fn class(&self) -> &'static str {
+        match self {
+            Error::Smtp(e) if e.is_permanent() => "rejected",
+            Error::Smtp(e) if e.is_transient() => "deferred",
+            Error::Smtp(e) if e.is_timeout() => "timeout",
+            Error::Smtp(e) if e.is_tls() => "tls",
+            Error::Smtp(_) => "connection",
+            Error::Unsupported(_) => "unsupported",
+            Error::Daemon { class, .. } => class,
+        }
+    }
+}
+
+/// The classes of [`Error::class`].
+pub const CLASSES: &[&str] = &[
+    "rejected",
+    "deferred",
+    "timeout",
+    "tls",
+    "connection",
+    "unsupported",
+];
+
+impl From<lettre::transport::smtp::Error> for Error {
+    fn from(e: lettre::transport::smtp::Error) -> Self {
+        Error::Smtp(e)
+    }
+}
+
+pub struct Relay {
+    conn: Connection,
+    /// The keyword lines of the relay's EHLO response, e.g. `SIZE 10240000`.
+    extensions: Vec<String>,
+    dsn_notify: Vec<DsnNotify>,
+}
+
+enum Connection {
+    Smtp(Box<SmtpConnection>),
+    /// To the daemon, which does the transaction on its connection, see `daemon`.
+    Daemon(UnixStream),
+}
+
+/// Connect and authenticate to the configured relay, like `SmtpTransport::starttls_relay` does.
+///
+/// Forwarding for the daemon, this is the daemon's connection instead.
+pub fn connect(config: &Config) -> Result<Relay, Error> {
+    if let Some(relay) = crate::daemon::relay(config) {
+        return relay;
+    }
+    connect_to(
+        &config.smtp_host,
+        Credentials::new(
+            config.smtp_username.clone(),
+            config.smtp_password.expose().to_owned(),
+        ),
+        config,
+    )
+}
+
+/// Like [`connect`], to the relay for the recipients in the domains of `smarthost`.
+pub fn connect_smarthost(config: &Config, smarthost: &Smarthost) -> Result<Relay, Error> {
+    connect_to(
+        &smarthost.smtp_host,
+        Credentials::new(
+            smarthost.smtp_username.clone(),
+            smarthost.smtp_password.expose().to_owned(),
+        ),
+        config,
+    )
+}
+
+// lettre's `Credentials` keeps its own copy of the password, which we can't zero.
+fn connect_to(host: &str, credentials: Credentials, config: &Config) -> Result<Relay, Error> {
+    let hello_name = ClientId::default();
+    let mut conn = SmtpConnection::connect(
+        (host, SUBMISSION_PORT),
+        Some(TIMEOUT),
+        &hello_name,
+        None,
+        None,
+    )?;
+    conn.starttls(&TlsParameters::new(host.to_owned())?, &hello_name)?;
+    conn.auth(&[Mechanism::Plain], &credentials)?;
+    // lettre keeps only the extensions it knows about, so ask again.
+    let ehlo = conn.command(Ehlo::new(hello_name))?;
+    Ok(Relay {
+        conn: Connection::Smtp(Box::new(conn)),
+        extensions: extensions(&ehlo),
+        dsn_notify: config.dsn_notify.clone(),
+    })
+}
+
+fn extensions(ehlo: &Response) -> Vec<String> {
+    // The first line is the greeting.
+    ehlo.message().skip(1).map(|l| l.to_owned()).collect()
+}
+
+/// The parameters of the extension `keyword`, if advertised.
+fn extension<'a>(extensions: &'a [String], keyword: &str) -> Option<&'a str> {
+    extensions.iter().find_map(|line| {
+        let (kw, params) = line.split_once(' ').unwrap_or((line, ""));
+        kw.eq_ignore_ascii_case(keyword).then_some(params)
+    })
+}
+
+fn size_limit(extensions: &[String]) -> Option<usize> {
+    match extension(extensions, "SIZE")?.trim().parse() {
+        Ok(0) | Err(_) => None, // 0 means no fixed limit
+        Ok(limit) => Some(limit),
+    }
+}
+
+/// `addr` with an internationalized domain in its ASCII form (IDNA A-labels), so that it can be
+/// used without SMTPUTF8. `None` if the local part isn't ASCII.
+pub fn ascii_address(addr: &Address) -> Option<Address> {
+    if AsRef::<str>::as_ref(addr).is_ascii() {
+        return Some(addr.clone());
+    }
+    if !addr.user().is_ascii() {
+        return None;
+    }
+    let domain = idna::domain_to_ascii(addr.domain()).ok()?;
+    Address::new(addr.user(), domain).ok()
+}
+
+impl Relay {
+    /// The daemon's connection, with the extensions it advertised.
+    pub fn through_daemon(stream: UnixStream, extensions: Vec<String>, config: &Config) -> Relay {
+        Relay {
+            conn: Connection::Daemon(stream),
+            extensions,
+            dsn_notify: config.dsn_notify.clone(),
+        }
+    }
+
+    /// The keyword lines of the relay's EHLO response.
+    pub fn extensions(&self) -> &[String] {
+        &self.extensions
+    }
+
+    /// Whether the connection can still be used, i.e. the relay answers a `NOOP`.
+    pub fn is_alive(&mut self) -> bool {
+        match &mut self.conn {
+            Connection::Smtp(conn) => !conn.has_broken() && conn.test_connected(),
+            Connection::Daemon(_) => true,
+        }
+    }
+
+    fn smtp(&mut self) -> &mut SmtpConnection {
+        match &mut self.conn {
+            Connection::Smtp(conn) => conn,
+            Connection::Daemon(_) => unreachable!("the daemon does the transaction"),
+        }
+    }
+
+    fn extension(&self, keyword: &str) -> Option<&str> {
+        extension(&self.extensions, keyword)
+    }
+
+    /// Whether the relay accepts 8bit content (RFC 6152).
+    pub fn eight_bit_mime(&self) -> bool {
+        self.extension("8BITMIME").is_some()
+    }
+
+    /// Whether the relay accepts UTF-8 addresses and headers (RFC 6531).
+    pub fn smtp_utf8(&self) -> bool {
+        self.extension("SMTPUTF8").is_some()
+    }
+
+    /// The maximum message size advertised with the SIZE extension (RFC 1870), if any.
+    pub fn size_limit(&self) -> Option<usize> {
+        size_limit(&self.extensions)
+    }
+
+    pub fn send(&mut self, envelope: &Envelope, message: &[u8]) -> Result<(), Error> {
+        if let Connection::Daemon(stream) = &mut self.conn {
+            return crate::daemon::send(stream, envelope, message);
+        }
+        let result = self.transaction(envelope, message);
+        if result.is_err() {
+            self.smtp().abort();
+        }
+        result.map(|_| ())
+    }
+
+    fn transaction(&mut self, envelope: &Envelope, message: &[u8]) -> Result<Response, Error> {
+        let downgraded;
+        let message = match message.is_ascii() || self.eight_bit_mime() {
+            true => message,
+            false => {
+                downgraded =
+                    crate::downgrade::downgrade(message).ok_or(Error::Unsupported("8BITMIME"))?;
+                &downgraded[..]
+            }
+        };
+        let mut mail_parameters = vec![];
+        if self.extension("SIZE").is_some() {
+            mail_parameters.push(MailParameter::Size(message.len()));
+        }
+        let smtp_utf8 = self.smtp_utf8();
+        let on_wire = |addr: &Address| match smtp_utf8 {
+            true => Ok(addr.clone()),
+            false => ascii_address(addr).ok_or(Error::Unsupported("SMTPUTF8")),
+        };
+        let from = envelope.from().map(on_wire).transpose()?;
+        let to = envelope
+            .to()
+            .iter()
+            .map(on_wire)
+            .collect::<Result<Vec<_>, _>>()?;
+        if to
+            .iter()
+            .chain(&from)
+            .any(|addr| !AsRef::<str>::as_ref(addr).is_ascii())
+        {
+            mail_parameters.push(MailParameter::SmtpUtfEight);
+        }
+        if !message.is_ascii() {
+            mail_parameters.push(MailParameter::Body(MailBodyParameter::EightBitMime));
+        }
+        let mut rcpt_parameters = vec![];
+        if !self.dsn_notify.is_empty() {
+            if self.extension("DSN").is_some() {
+                let notify = self.dsn_notify.iter().map(|n| n.keyword());
+                rcpt_parameters.push(RcptParameter::Other {
+                    keyword: "NOTIFY".to_owned(),
+                    value: Some(notify.collect::<Vec<_>>().join(",")),
+                });
+                // A bounce of the whole wrapper would just repeat the original.
+                mail_parameters.push(MailParameter::Other {
+                    keyword: "RET".to_owned(),
+                    value: Some("HDRS".to_owned()),
+                });
+            } else {
+                warn!("the relay does not support DSN, not requesting delivery notifications");
+            }
+        }
+        let conn = self.smtp();
+        conn.command(Mail::new(from, mail_parameters))?;
+        for to in to {
+            conn.command(Rcpt::new(to, rcpt_parameters.clone()))?;
+        }
+        conn.command(Data)?;
+        Ok(conn.message(message)?)
+    }
+}
+
+impl Drop for Relay {
+    fn drop(&mut self) {
+        if let Connection::Smtp(conn) = &mut self.conn {
+            if !conn.has_broken() {
+                let _ = conn.quit();
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_extensions() {
+        let extensions = |lines: &[&str]| lines.iter().map(|l| l.to_string()).collect::<Vec<_>>();
+        let advertised = extensions(&["PIPELINING", "SIZE 10240000", "8bitmime"]);
+        assert_eq!(size_limit(&advertised), Some(10240000));
+        assert_eq!(extension(&advertised, "8BITMIME"), Some(""));
+        assert_eq!(extension(&advertised, "SMTPUTF8"), None);
+        assert_eq!(size_limit(&extensions(&["SIZE"])), None);
+        assert_eq!(size_limit(&extensions(&["SIZE 0"])), None);
+    }
+
+    #[test]
+    fn test_ascii_address() {
+        let addr = |s: &str| s.parse::<Address>().unwrap();
+        assert_eq!(
+            ascii_address(&addr("root@bücher.example")),
+            Some(addr("root@xn--bcher-kva.example"))
+        );
+        assert_eq!(ascii_address(&addr("jörg@example.com")), None);
+    }
+
+    #[test]
+    fn test_partition() {
+        let addr = |s: &str| s.parse::<Address>().unwrap();
+        let smarthosts = [Smarthost {
+            domains: vec!["corp.example".to_owned()],
+            smtp_host: "mail.corp.example".to_owned(),
+            smtp_username: "u".to_owned(),
+            smtp_password: "p".to_owned().into(),
+        }];
+        let (default, routed) = partition(
+            &smarthosts,
+            &[
+                addr("oncall@Corp.Example"),
+                addr("me@example.com"),
+                addr("storage@corp.example"),
+            ],
+        );
+        assert_eq!(default, [addr("me@example.com")]);
+        assert_eq!(routed.len(), 1);
+        assert_eq!(routed[0].0.smtp_host, "mail.corp.example");
+        assert_eq!(
+            routed[0].1,
+            [addr("oncall@Corp.Example"), addr("storage@corp.example")]
+        );
+    }
+}
@@ -0,0 +1,365 @@
+//! The SMTP listener, with `smtpd_listen`: `forward-as-attachment-mta --smtpd`, run as root,
+//! accepts submissions over SMTP, or LMTP with `smtpd_protocol = "lmtp"`, from software that won't
+//! run `sendmail` but insists on speaking SMTP to e.g. `localhost:25`.
+//!
+//! It's the minimum for local clients: no TLS, no authentication, no extensions but `8BITMIME`,
+//! `PIPELINING`, and `SIZE`. Each message is forwarded by running us like `sendmail -f <from> --
+//! <recipients>` with the message on stdin, on behalf of the client: the user on the other end of
+//! a unix socket, or `nobody` on loopback, whom we can't tell. Its exit code decides the reply to
+//! `DATA`.
+
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::path::Path;
+use std::process::{ChildStdin, Command, ExitStatus, Stdio};
+use std::time::Duration;
+
+use tracing::{debug, info, warn};
+
+use crate::config::Config;
+use crate::daemon::{Clients, Listener, Stream, INET_UID};
+use crate::{hardening, sysexits, systemd};
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Protocol {
+    #[default]
+    Smtp,
+    /// Replies to `DATA` once per recipient, for clients that deliver through an LMTP socket.
+    Lmtp,
+}
+
+/// Set for the submissions we run, to the uid of the client they are on behalf of.
+const SUBMITTER_ENV: &str = "FORWARD_AS_ATTACHMENT_MTA_SUBMITTER_UID";
+
+/// Of a command or message line. RFC 5321 allows 1000 bytes; more is lenient, but bounded.
+const MAX_LINE: u64 = 64 * 1024;
+
+/// RFC 5321 suggests at least 5 minutes for commands and data.
+const TIMEOUT: Duration = Duration::from_secs(5 * 60);
+
+/// `forward-as-attachment-mta --smtpd`, for root only. Returns the exit code, if it returns.
+pub fn serve(config: &Config, caller_uid: u32, hostname: &str) -> i32 {
+    if let Err(code) = hardening::require_root(caller_uid, "--smtpd") {
+        return code;
+    }
+    let activated = systemd::listen_fds();
+    let listeners = if !activated.is_empty() {
+        activated.into_iter().map(Listener::activated).collect()
+    } else if !config.smtpd_listen.is_empty() {
+        config
+            .smtpd_listen
+            .iter()
+            // Any local client may submit, as on loopback.
+            .map(|socket| crate::daemon::bind(socket, 0o666))
+            .collect()
+    } else {
+        eprintln!("forward-as-attachment-mta: --smtpd: smtpd_listen is empty, and systemd passed no sockets");
+        return sysexits::EX_USAGE;
+    };
+    let listeners: Vec<Listener> = match listeners {
+        Ok(listeners) => listeners,
+        Err(e) => {
+            eprintln!("forward-as-attachment-mta: --smtpd: {e}");
+            return sysexits::EX_TEMPFAIL;
+        }
+    };
+    let exe = match std::env::current_exe() {
+        Ok(exe) => exe,
+        Err(e) => {
+            eprintln!("forward-as-attachment-mta: --smtpd: {e}");
+            return sysexits::EX_TEMPFAIL;
+        }
+    };
+    let clients = Clients::default();
+    std::thread::scope(|scope| {
+        for listener in &listeners {
+            let (exe, clients) = (&exe, &clients);
+            scope.spawn(move || loop {
+                clients.wait_for_slot();
+                let (stream, uid) = match listener.accept() {
+                    Ok((stream, peer)) => (stream, peer.unwrap_or(INET_UID)),
+                    Err(e) => {
+                        clients.done();
+                        warn!(%e, "accept");
+                        continue;
+                    }
+                };
+                scope.spawn(move || {
+                    if let Err(e) = session(config, hostname, exe, stream, uid) {
+                        debug!(%e, "SMTP session ended");
+                    }
+                    clients.done();
+                });
+            });
+        }
+        info!(listeners = listeners.len(), protocol = ?config.smtpd_protocol, "accepting SMTP");
+        systemd::notify("READY=1");
+        // The sessions have their own timeouts, so this only tells whether we still run at all.
+        if let Some(mut watchdog) = systemd::Watchdog::new() {
+            loop {
+                std::thread::sleep(watchdog.remaining());
+                watchdog.ping();
+            }
+        }
+    });
+    0
+}
+
+/// With the client `uid`.
+fn session(
+    config: &Config,
+    hostname: &str,
+    exe: &Path,
+    stream: Stream,
+    uid: u32,
+) -> io::Result<()> {
+    let (protocol, max_size) = (config.smtpd_protocol, config.smtpd_max_message_size);
+    stream.set_read_timeout(Some(TIMEOUT))?;
+    let mut conn = BufReader::new(stream);
+    reply(
+        &mut conn,
+        &format!("220 {hostname} ESMTP forward-as-attachment-mta"),
+    )?;
+    let mut from: Option<String> = None;
+    let mut recipients = Vec::new();
+    while let Some(line) = read_line(&mut conn)? {
+        let line = String::from_utf8_lossy(&line);
+        let (verb, rest) = line.split_once(' ').unwrap_or((&line, ""));
+        let hello = match protocol {
+            Protocol::Smtp => "EHLO",
+            Protocol::Lmtp => "LHLO",
+        };
+        let response = match verb.to_ascii_uppercase().as_str() {
+            verb if verb == hello => match max_size {
+                0 => format!("250-{hostname}\r\n250-8BITMIME\r\n250 PIPELINING"),
+                n => format!("250-{hostname}\r\n250-8BITMIME\r\n250-PIPELINING\r\n250 SIZE {n}"),
+            },
+            "HELO" if protocol == Protocol::Smtp => format!("250 {hostname}"),
+            "MAIL" => match path(rest, "FROM:") {
+                Some(addr) => {
+                    from = Some(addr);
+                    recipients.clear();
+                    "250 OK".to_owned()
+                }
+                None => "501 Syntax: MAIL FROM:<address>".to_owned(),
+            },
+            "RCPT" if from.is_none() => "503 MAIL first".to_owned(),
+            "RCPT" => match path(rest, "TO:") {
+                // They would be taken for options.
+                Some(addr) if addr.starts_with('-') => "553 Not a valid recipient".to_owned(),
+                Some(addr) if !addr.is_empty() => {
+                    recipients.push(addr);
+                    "250 OK".to_owned()
+                }
+                _ => "501 Syntax: RCPT TO:<address>".to_owned(),
+            },
+            "DATA" if recipients.is_empty() => "503 RCPT first".to_owned(),
+            "DATA" => {
+                reply(&mut conn, "354 End data with <CR><LF>.<CR><LF>")?;
+                let sender = from.take().unwrap_or_default();
+                let n = match protocol {
+                    Protocol::Smtp => 1,
+                    Protocol::Lmtp => recipients.len(),
+                };
+                let response = match deliver(exe, uid, &sender, &recipients, &mut conn, max_size)? {
+                    Delivery::Ran(status) => response(status),
+                    Delivery::TooLarge => {
+                        // The rest of the data would be taken for commands.
+                        warn!(
+                            uid,
+                            max_size, "refused a message over smtpd_max_message_size"
+                        );
+                        let response = vec!["552 Message too large"; n].join("\r\n");
+                        return reply(&mut conn, &response);
+                    }
+                    Delivery::Failed(e) => {
+                        warn!(%e, "can't run the submission");
+                        "451 Local error".to_owned()
+                    }
+                };
+                recipients.clear();
+                vec![response; n].join("\r\n")
+            }
+            "RSET" => {
+                from = None;
+                recipients.clear();
+                "250 OK".to_owned()
+            }
+            "NOOP" => "250 OK".to_owned(),
+            "VRFY" => "252 Cannot VRFY".to_owned(),
+            "QUIT" => {
+                reply(&mut conn, "221 Bye")?;
+                return Ok(());
+            }
+            _ => "502 Command not implemented".to_owned(),
+        };
+        reply(&mut conn, &response)?;
+    }
+    Ok(())
+}
+
+fn reply(conn: &mut BufReader<Stream>, response: &str) -> io::Result<()> {
+    let stream = conn.get_mut();
+    stream.write_all(response.as_bytes())?;
+    stream.write_all(b"\r\n")?;
+    stream.flush()
+}
+
+/// Without the line ending, or `None` at the end of the connection.
+fn read_line(conn: &mut impl BufRead) -> io::Result<Option<Vec<u8>>> {
+    let mut line = Vec::new();
+    conn.take(MAX_LINE).read_until(b'\n', &mut line)?;
+    if line.is_empty() {
+        return Ok(None);
+    }
+    if line.pop() != Some(b'\n') {
+        return Err(io::Error::other("line too long or incomplete"));
+    }
+    if line.last() == Some(&b'\r') {
+        line.pop();
+    }
+    Ok(Some(line))
+}
+
+/// The address in e.g. `FROM:<alice@example.com> BODY=8BITMIME`, which may be empty (`<>`).
+fn path(arg: &str, prefix: &str) -> Option<String> {
+    let arg = arg.get(..prefix.len()).and_then(|p| {
+        p.eq_ignore_ascii_case(prefix)
+            .then(|| arg[prefix.len()..].trim_start())
+    })?;
+    let addr = arg.strip_prefix('<')?.split_once('>')?.0;
+    Some(addr.to_owned())
+}
+
+/// What became of a message's data.
+enum Delivery {
+    /// The submission ran, with this exit status.
+    Ran(ExitStatus),
+    /// Larger than `smtpd_max_message_size`; the rest of the data is still unread.
+    TooLarge,
+    /// The submission couldn't run.
+    Failed(io::Error),
+}
+
+/// Run the submission as `uid` with the data read from `conn`, which may take up to `max_size`
+/// bytes (0: any). The error is the connection's.
+fn deliver(
+    exe: &Path,
+    uid: u32,
+    from: &str,
+    recipients: &[String],
+    conn: &mut impl BufRead,
+    max_size: usize,
+) -> io::Result<Delivery> {
+    let mut command = Command::new(exe);
+    if !from.is_empty() {
+        command.arg(format!("-f{from}"));
+    }
+    let child = command
+        .arg("--")
+        .args(recipients)
+        .env(SUBMITTER_ENV, uid.to_string())
+        .env_remove("LISTEN_PID")
+        .env_remove("LISTEN_FDS")
+        .env_remove("LISTEN_FDNAMES")
+        .stdin(Stdio::piped())
+        .stdout(Stdio::null())
+        .spawn();
+    let mut child = match child {
+        Ok(child) => child,
+        Err(e) => {
+            return data(conn, &mut None::<ChildStdin>, max_size).map(|complete| match complete {
+                true => Delivery::Failed(e),
+                false => Delivery::TooLarge,
+            })
+        }
+    };
+    let mut stdin = child.stdin.take();
+    let complete = data(conn, &mut stdin, max_size);
+    if !matches!(complete, Ok(true)) {
+        // Not a complete message, so it mustn't go out.
+        let _ = child.kill();
+        let _ = child.wait();
+        return complete.map(|_| Delivery::TooLarge);
+    }
+    drop(stdin);
+    Ok(match child.wait() {
+        Ok(status) => Delivery::Ran(status),
+        Err(e) => Delivery::Failed(e),
+    })
+}
+
+/// Read the data up to the terminating `.`, unstuffed, into `to`, if it takes it. Returns false,
+/// and stops reading, once it's more than `max_size` bytes (0: any).
+fn data(conn: &mut impl BufRead, to: &mut Option<impl Write>, max_size: usize) -> io::Result<bool> {
+    let mut size = 0;
+    loop {
+        let Some(line) = read_line(conn)? else {
+            return Err(io::Error::other("connection closed during DATA"));
+        };
+        if line == b"." {
+            return Ok(true);
+        }
+        let line = line.strip_prefix(b".").unwrap_or(&line);
+        size += line.len() + 1;
+        if max_size != 0 && size > max_size {
+            return Ok(false);
+        }
+        if let Some(w) = to {
+            // If the submission went away, its exit code tells; the rest is read regardless.
+            if w.write_all(line).and_then(|()| w.write_all(b"\n")).is_err() {
+                *to = None;
+            }
+        }
+    }
+}
+
+/// The uid of the client we forward a submission for, see `deliver`.
+///
+/// Only honored if our real uid is root, like a client of the daemon claiming a uid, see
+/// `daemon::submitter`; otherwise anyone could pose as anyone else.
+pub fn submitter_from_env() -> Option<u32> {
+    let uid = std::env::var(SUBMITTER_ENV).ok()?;
+    if users::get_current_uid() != 0 {
+        panic!("{SUBMITTER_ENV} is only honored for root");
+    }
+    match uid.parse() {
+        Ok(uid) => Some(uid),
+        Err(e) => panic!("{SUBMITTER_ENV}={uid:?}: {e}"),
+    }
+}
+
+fn response(status: ExitStatus) -> String {
+    match status.code() {
+        Some(0) => "250 OK".to_owned(),
+        Some(code @ (sysexits::EX_USAGE | sysexits::EX_NOPERM)) => {
+            format!("550 Rejected (exit code {code})")
+        }
+        Some(code) => format!("451 Failed (exit code {code})"),
+        None => "451 Failed".to_owned(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_data() {
+        let mut conn = io::Cursor::new(b"Subject: x\r\n\r\n..hidden\r\n.\r\nQUIT\r\n".to_vec());
+        let mut to = Some(Vec::new());
+        assert!(data(&mut conn, &mut to, 0).unwrap());
+        assert_eq!(to.unwrap(), b"Subject: x\n\n.hidden\n");
+        assert_eq!(read_line(&mut conn).unwrap().unwrap(), b"QUIT");
+        // 20 bytes unstuffed, with the line endings.
+        let message = b"Subject: x\r\n\r\n..hidden\r\n.\r\n";
+        assert!(data(&mut io::Cursor::new(message), &mut None::<Vec<u8>>, 20).unwrap());
+        assert!(!data(&mut io::Cursor::new(message), &mut None::<Vec<u8>>, 19).unwrap());
+        assert_eq!(
+            path("from:<alice@example.com> BODY=8BITMIME", "FROM:").as_deref(),
+            Some("alice@example.com")
+        );
+        assert_eq!(path("FROM:<>", "FROM:").as_deref(), Some(""));
+        assert_eq!(path("TO:alice", "TO:"), None);
+    }
+}
@@ -0,0 +1,235 @@
+//! Submissions that couldn't be forwarded right away, e.g. because we were terminated.
+//!
+//! Each entry is the original as `<queue id>.eml` and its metadata as `<queue id>.toml` in
+//! `state_dir/spool`. `sendmail -q` forwards them by running us again for each entry.
+
+use std::fs::File;
+use std::io::{self, Read};
+use std::os::fd::AsRawFd;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::time::{Duration, SystemTime};
+
+use tracing::{debug, warn};
+
+use crate::config::Config;
+use crate::sysexits;
+
+/// Set for the runs of `sendmail -q`, and the daemon's, to the queue id of the entry to forward.
+pub const FROM_SPOOL_ENV: &str = "FORWARD_AS_ATTACHMENT_MTA_FROM_SPOOL";
+
+#[derive(Debug, serde::Serialize, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Meta {
+    pub queue_id: String,
+    /// Unix time.
+    pub submitted_at: u64,
+    pub uid: u32,
+    pub username: Option<String>,
+    /// The arguments of the submission, including `argv[0]`.
+    pub args: Vec<String>,
+    /// Why the submission was spooled.
+    pub reason: String,
+}
+
+impl Meta {
+    pub fn submitted_at(&self) -> SystemTime {
+        SystemTime::UNIX_EPOCH + Duration::from_secs(self.submitted_at)
+    }
+}
+
+fn dir(state_dir: &Path) -> io::Result<PathBuf> {
+    crate::state::subdir_in(state_dir, "spool")
+}
+
+/// Add an entry, with the original read from `original`.
+///
+/// Takes the state dir rather than the config, so that it can be called from the signal handler
+/// thread, see `signals`.
+pub fn store(state_dir: &Path, meta: &Meta, original: impl Read) -> io::Result<PathBuf> {
+    store_in(&dir(state_dir)?, meta, original)
+}
+
+/// Like [`store`], into another directory of the state dir, whose entries `sendmail -q`
+/// leaves alone.
+pub fn store_in(dir: &Path, meta: &Meta, mut original: impl Read) -> io::Result<PathBuf> {
+    let write = |name: String, data: &mut dyn Read| -> io::Result<PathBuf> {
+        let tmp = dir.join(format!(".{name}.tmp"));
+        let mut file = std::fs::OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .mode(0o600)
+            .open(&tmp)?;
+        io::copy(data, &mut file)?;
+        file.sync_all()?;
+        let path = dir.join(name);
+        std::fs::rename(&tmp, &path)?;
+        Ok(path)
+    };
+    // The metadata goes last, entries without it are incomplete.
+    let path = write(format!("{}.eml", meta.queue_id), &mut original)?;
+    let meta_toml = toml::to_string(meta).map_err(io::Error::other)?;
+    write(format!("{}.toml", meta.queue_id), &mut meta_toml.as_bytes())?;
+    Ok(path)
+}
+
+/// The metadata of the complete entries in `dir`, oldest first. Malformed ones are skipped.
+pub fn list(dir: &Path) -> io::Result<Vec<Meta>> {
+    let mut entries = Vec::new();
+    for entry in std::fs::read_dir(dir)? {
+        let name = entry?.file_name();
+        let Some(queue_id) = name.to_str().and_then(|n| n.strip_suffix(".toml")) else {
+            continue;
+        };
+        if queue_id.starts_with('.') {
+            continue;
+        }
+        let meta = std::fs::read_to_string(dir.join(&name))
+            .map_err(|e| e.to_string())
+            .and_then(|s| toml::from_str::<Meta>(&s).map_err(|e| e.to_string()));
+        match meta {
+            Ok(meta) => entries.push(meta),
+            Err(e) => debug!(%e, queue_id, "skipping malformed entry"),
+        }
+    }
+    // Queue ids start with the submission time.
+    entries.sort_by(|a, b| a.queue_id.cmp(&b.queue_id));
+    Ok(entries)
+}
+
+/// How many entries there are.
+pub fn depth(state_dir: &Path) -> io::Result<usize> {
+    list(&dir(state_dir)?).map(|entries| entries.len())
+}
+
+/// Remove the entry `meta` from `dir`.
+pub fn remove(dir: &Path, meta: &Meta) -> io::Result<()> {
+    std::fs::remove_file(dir.join(format!("{}.toml", meta.queue_id)))?;
+    std::fs::remove_file(dir.join(format!("{}.eml", meta.queue_id)))
+}
+
+/// The metadata of the entry to forward if we were started by `sendmail -q`,
+/// which passes the original as stdin.
+///
+/// Only honored if we run with our real uid, i.e., not for an unprivileged caller of a setuid
+/// installation, who could otherwise impersonate the submitter of the entry.
+pub fn from_env(config: &Config) -> Option<Meta> {
+    let queue_id = std::env::var(FROM_SPOOL_ENV).ok()?;
+    if users::get_current_uid() != users::get_effective_uid() {
+        panic!("{FROM_SPOOL_ENV} is only honored without setuid");
+    }
+    // The daemon's entries are forwarded from its directory first.
+    let dir = match crate::daemon::handed_off() {
+        true => crate::daemon::dir(&config.state_dir),
+        false => dir(&config.state_dir),
+    };
+    let meta = dir
+        .and_then(|dir| std::fs::read_to_string(dir.join(format!("{queue_id}.toml"))))
+        .map_err(|e| e.to_string())
+        .and_then(|s| toml::from_str::<Meta>(&s).map_err(|e| e.to_string()));
+    match meta {
+        Ok(meta) => Some(meta),
+        Err(e) => panic!("spool entry {queue_id}: {e}"),
+    }
+}
+
+/// Forward the spooled entries, for `sendmail -q`. Returns the exit code.
+pub fn flush(config: &Config) -> i32 {
+    let dir = match dir(&config.state_dir) {
+        Ok(dir) => dir,
+        Err(e) => {
+            warn!(%e, "spool dir");
+            return sysexits::EX_TEMPFAIL;
+        }
+    };
+    let mut queue_ids = match std::fs::read_dir(&dir) {
+        Ok(entries) => entries
+            .filter_map(|e| e.ok()?.file_name().into_string().ok())
+            .filter_map(|name| Some(name.strip_suffix(".toml")?.to_owned()))
+            .filter(|name| !name.starts_with('.'))
+            .collect::<Vec<_>>(),
+        Err(e) => {
+            warn!(%e, "read spool dir");
+            return sysexits::EX_TEMPFAIL;
+        }
+    };
+    // Queue ids start with the submission time.
+    queue_ids.sort();
+    // Held for quiet hours, they are forwarded by the first flush after the window.
+    if let Some(quiet_hours) = &config.quiet_hours {
+        if quiet_hours.is_quiet(std::time::SystemTime::now()) {
+            queue_ids.retain(|queue_id| {
+                let meta = std::fs::read_to_string(dir.join(format!("{queue_id}.toml")))
+                    .ok()
+                    .and_then(|s| toml::from_str::<Meta>(&s).ok());
+                meta.is_none_or(|meta| meta.reason != crate::quiet::SPOOL_REASON)
+            });
+        }
+    }
+    let exe = std::env::current_exe().expect("own executable");
+    let mut exit_code = 0;
+    for queue_id in queue_ids {
+        match forward(&dir, &exe, &queue_id) {
+            Ok(true) => debug!(%queue_id, "forwarded spooled submission"),
+            Ok(false) => {
+                warn!(%queue_id, "failed to forward spooled submission, keeping it");
+                exit_code = sysexits::EX_TEMPFAIL;
+            }
+            Err(e) => {
+                warn!(%queue_id, %e, "failed to forward spooled submission, keeping it");
+                exit_code = sysexits::EX_TEMPFAIL;
+            }
+        }
+    }
+    exit_code
+}
+
+/// Forward one entry, removing it if that succeeded. Entries locked by a concurrent flush are
+/// skipped.
+fn forward(dir: &Path, exe: &Path, queue_id: &str) -> io::Result<bool> {
+    let meta_path = dir.join(format!("{queue_id}.toml"));
+    let eml_path = dir.join(format!("{queue_id}.eml"));
+    let lock = File::open(&meta_path)?;
+    if unsafe { libc::flock(lock.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) } != 0 {
+        debug!(%queue_id, "spool entry is locked");
+        return Ok(true);
+    }
+    let status = Command::new(exe)
+        .env(FROM_SPOOL_ENV, queue_id)
+        .stdin(Stdio::from(File::open(&eml_path)?))
+        .status()?;
+    if !status.success() {
+        return Ok(false);
+    }
+    std::fs::remove_file(&meta_path)?;
+    std::fs::remove_file(&eml_path)?;
+    Ok(true)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_store() {
+        let state_dir = crate::testdir::TestDir::new("spool");
+        let meta = Meta {
+            queue_id: "65A0B1C2D3E4F".to_owned(),
+            submitted_at: 1_700_000_000,
+            uid: 1000,
+            username: Some("alice".to_owned()),
+            args: vec!["sendmail".to_owned(), "-t".to_owned()],
+            reason: "signal".to_owned(),
+        };
+        let path = store(&state_dir, &meta, &b"Subject: hi\r\n\r\nbody\r\n"[..]).unwrap();
+        assert_eq!(std::fs::read(path).unwrap(), b"Subject: hi\r\n\r\nbody\r\n");
+        let read_back: Meta = toml::from_str(
+            &std::fs::read_to_string(state_dir.join("spool/65A0B1C2D3E4F.toml")).unwrap(),
+        )
+        .unwrap();
+        assert_eq!(read_back.args, meta.args);
+        assert_eq!(read_back.username.as_deref(), Some("alice"));
+    }
+}
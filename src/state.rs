@@ -0,0 +1,24 @@
+use std::os::unix::fs::DirBuilderExt;
+use std::path::{Path, PathBuf};
+
+use crate::config::Config;
+
+pub const DEFAULT_STATE_DIR: &str = "/var/lib/forward-as-attachment-mta";
+
+/// Returns the path to the given subdirectory of the state dir, creating it if necessary.
+///
+/// Directories are created accessible to the (effective) owner only, as their contents
+/// are derived from submissions.
+pub fn subdir(config: &Config, name: &str) -> std::io::Result<PathBuf> {
+    subdir_in(&config.state_dir, name)
+}
+
+/// Like [`subdir`], for when the config isn't at hand.
+pub fn subdir_in(state_dir: &Path, name: &str) -> std::io::Result<PathBuf> {
+    let path = state_dir.join(name);
+    std::fs::DirBuilder::new()
+        .recursive(true)
+        .mode(0o700)
+        .create(&path)?;
+    Ok(path)
+}
@@ -0,0 +1,209 @@
+//! `sendmail --stats [days]`: a summary of the last days from the state database, for incident
+//! reviews. Root sees everyone's submissions, other users only their own.
+
+use std::collections::BTreeMap;
+use std::fmt::Write as _;
+use std::io;
+use std::time::SystemTime;
+
+use crate::config::Config;
+use crate::db::{Delivery, Submission};
+use crate::sysexits;
+
+const DEFAULT_DAYS: u64 = 7;
+const TOP_SENDERS: usize = 5;
+
+/// Returns the exit code.
+pub fn command(config: &Config, caller_uid: u32, days: Option<&str>) -> i32 {
+    let Some(since) = since(days) else {
+        eprintln!("usage: sendmail --stats [days]");
+        return sysexits::EX_USAGE;
+    };
+    match load(config, caller_uid, since) {
+        Ok(summary) => {
+            print!("{summary}");
+            0
+        }
+        Err(e) => {
+            eprintln!("forward-as-attachment-mta: --stats: {e}");
+            sysexits::EX_TEMPFAIL
+        }
+    }
+}
+
+/// The start of the last `days`, if they're a number of days we can go back.
+fn since(days: Option<&str>) -> Option<SystemTime> {
+    let days = match days {
+        None => DEFAULT_DAYS,
+        Some(days) => days.parse().ok()?,
+    };
+    crate::db::days_ago(days)
+}
+
+/// The summary of the submissions and deliveries of `caller_uid` since `since`, or everyone's for
+/// root.
+fn load(config: &Config, caller_uid: u32, since: SystemTime) -> io::Result<String> {
+    let db = crate::db::open(&config.state_dir)?;
+    let mut submissions = db.submissions(since)?;
+    let mut history = db.history(since)?;
+    if caller_uid != 0 {
+        submissions.retain(|submission| submission.uid == caller_uid);
+        history.retain(|delivery| delivery.uid == caller_uid);
+    }
+    let queue_depth = crate::spool::depth(&config.state_dir)?;
+    Ok(summarize(since, &submissions, &history, queue_depth))
+}
+
+/// The summary of [`command`], also in the heartbeat.
+pub fn summarize(
+    since: SystemTime,
+    submissions: &[Submission],
+    history: &[Delivery],
+    queue_depth: usize,
+) -> String {
+    let mut failures = BTreeMap::<&str, usize>::new();
+    let mut senders = BTreeMap::<&str, usize>::new();
+    let mut delivered = 0;
+    for delivery in history {
+        match &delivery.failure {
+            None => delivered += 1,
+            Some(class) => *failures.entry(class).or_default() += 1,
+        }
+        let sender = match delivery.sender.as_str() {
+            "" => "(unknown)",
+            sender => sender,
+        };
+        *senders.entry(sender).or_default() += 1;
+    }
+    let mut senders = senders.into_iter().collect::<Vec<_>>();
+    senders.sort_by(|(a, m), (b, n)| n.cmp(m).then(a.cmp(b)));
+    let average_size = match history.len() {
+        0 => 0,
+        n => history.iter().map(|d| d.size).sum::<u64>() / n as u64,
+    };
+
+    let mut out = String::new();
+    let mut line = |label: &str, value: &dyn std::fmt::Display| {
+        let line = format!("{label:<24}{value}");
+        writeln!(&mut out, "{}", line.trim_end()).unwrap();
+    };
+    line("since", &crate::time::format_utc(since));
+    line("submitted", &submissions.len());
+    line("delivered", &delivered);
+    line("failed", &failures.values().sum::<usize>());
+    for (class, n) in &failures {
+        line(&format!("  {class}"), n);
+    }
+    line("average size", &format!("{average_size} bytes"));
+    line("queue depth", &queue_depth);
+    line("top senders", &"");
+    for (sender, n) in senders.iter().take(TOP_SENDERS) {
+        line(&format!("  {sender}"), n);
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    #[test]
+    fn test_summarize() {
+        let delivery = |failure: Option<&str>, sender: &str, size| Delivery {
+            at: 1_700_000_000,
+            queue_id: "65A0B1C2D3E4F".to_owned(),
+            failure: failure.map(str::to_owned),
+            uid: 0,
+            size,
+            sender: sender.to_owned(),
+        };
+        let submission = Submission {
+            at: 1_700_000_000,
+            queue_id: "65A0B1C2D3E4F".to_owned(),
+            uid: 0,
+        };
+        let since = SystemTime::UNIX_EPOCH + Duration::from_secs(1_699_999_000);
+        let summary = summarize(
+            since,
+            &[submission.clone(), submission.clone(), submission],
+            &[
+                delivery(None, "root@host", 1000),
+                delivery(Some("deferred"), "backup@host", 2000),
+                delivery(None, "backup@host", 3000),
+                delivery(None, "", 6000),
+            ],
+            1,
+        );
+        assert_eq!(
+            summary.lines().collect::<Vec<_>>(),
+            [
+                "since                   2023-11-14T21:56:40Z",
+                "submitted               3",
+                "delivered               3",
+                "failed                  1",
+                "  deferred              1",
+                "average size            3000 bytes",
+                "queue depth             1",
+                "top senders",
+                "  backup@host           2",
+                "  (unknown)             1",
+                "  root@host             1",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_since() {
+        assert!(since(None).is_some());
+        assert!(since(Some("30")).is_some());
+        assert_eq!(since(Some("999999999999999")), None);
+        assert_eq!(since(Some("a week")), None);
+    }
+
+    #[test]
+    fn test_load_own() {
+        let dir = crate::testdir::TestDir::new("stats");
+        let config: Config = toml::from_str(&format!(
+            r#"
+            sender_email = "sender@example.com"
+            recipient_email = "admin@example.com"
+            smtp_host = "smtp.example.com"
+            smtp_username = "user"
+            smtp_password = "pass"
+            state_dir = {:?}
+            "#,
+            &*dir
+        ))
+        .unwrap();
+        let db = crate::db::open(&dir).unwrap();
+        let at = crate::time::unix_secs(SystemTime::now());
+        for uid in [0, 1000, 1000] {
+            let queue_id = "65A0B1C2D3E4F".to_owned();
+            db.record_submission(&Submission {
+                at,
+                queue_id: queue_id.clone(),
+                uid,
+            })
+            .unwrap();
+            db.record(&Delivery {
+                at,
+                queue_id,
+                failure: None,
+                uid,
+                size: 100,
+                sender: format!("uid{uid}@host"),
+            })
+            .unwrap();
+        }
+        drop(db);
+        let since = SystemTime::UNIX_EPOCH;
+        let own = load(&config, 1000, since).unwrap();
+        assert!(own.contains("submitted               2\n"));
+        assert!(!own.contains("uid0@host"));
+        let all = load(&config, 0, since).unwrap();
+        assert!(all.contains("submitted               3\n"));
+        assert!(all.contains("uid0@host"));
+    }
+}
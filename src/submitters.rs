@@ -0,0 +1,115 @@
+//! Bookkeeping for submissions denied by `[submitters]`.
+
+use std::fmt::Write as _;
+use std::fs::OpenOptions;
+use std::io::Write as _;
+use std::time::{Duration, SystemTime};
+
+use tracing::{debug, warn};
+
+use crate::config::Config;
+
+const DIGEST_INTERVAL: Duration = Duration::from_secs(24 * 60 * 60);
+
+pub struct Denied<'a> {
+    pub uid: u32,
+    pub username: Option<&'a str>,
+    pub subject: Option<&'a str>,
+}
+
+/// Append the denied submission to the pending digest.
+pub fn record(config: &Config, denied: &Denied) -> std::io::Result<()> {
+    let dir = crate::state::subdir(config, "denied")?;
+    let now = crate::time::unix_secs(SystemTime::now());
+    let last_sent = dir.join("last-sent");
+    if !last_sent.exists() {
+        // the first digest goes out one interval after the first denied submission
+        std::fs::write(&last_sent, now.to_string())?;
+    }
+    let sanitize = |s: &str| s.replace(['\t', '\r', '\n'], " ");
+    let mut pending = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(dir.join("pending"))?;
+    writeln!(
+        pending,
+        "{now}\t{}\t{}\t{}",
+        denied.uid,
+        sanitize(denied.username.unwrap_or("")),
+        sanitize(denied.subject.unwrap_or("")),
+    )
+}
+
+/// Send the digest of denied submissions if it is due.
+pub fn maybe_send_digest(config: &Config, hostname: &str) -> std::io::Result<()> {
+    let dir = crate::state::subdir(config, "denied")?;
+    let pending_path = dir.join("pending");
+    let last_sent_path = dir.join("last-sent");
+    let now = SystemTime::now();
+    let last_sent = match std::fs::read_to_string(&last_sent_path) {
+        Ok(s) => s.trim().parse::<u64>().unwrap_or(0),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e),
+    };
+    if crate::time::unix_secs(now).saturating_sub(last_sent) < DIGEST_INTERVAL.as_secs() {
+        return Ok(());
+    }
+
+    // Take ownership of the pending entries so concurrent invocations start a new file.
+    let sending_path = dir.join(format!("sending.{}", std::process::id()));
+    match std::fs::rename(&pending_path, &sending_path) {
+        Ok(()) => (),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e),
+    }
+    let entries = std::fs::read_to_string(&sending_path)?;
+
+    let mut body = String::new();
+    let mut count = 0;
+    writeln!(
+        &mut body,
+        "The following submissions on host {hostname:?} were denied by the [submitters] config:"
+    )
+    .unwrap();
+    writeln!(&mut body).unwrap();
+    for line in entries.lines() {
+        let mut fields = line.splitn(4, '\t');
+        let (Some(ts), Some(uid), Some(username), Some(subject)) =
+            (fields.next(), fields.next(), fields.next(), fields.next())
+        else {
+            debug!(?line, "skipping malformed digest entry");
+            continue;
+        };
+        let ts = ts
+            .parse::<u64>()
+            .map(|s| crate::time::format_utc(SystemTime::UNIX_EPOCH + Duration::from_secs(s)))
+            .unwrap_or_else(|_| ts.to_owned());
+        writeln!(
+            &mut body,
+            "{ts} uid:{uid} user:{username} subject:{subject}"
+        )
+        .unwrap();
+        count += 1;
+    }
+
+    match crate::send_notice(
+        config,
+        format!("{hostname}: {count} denied submissions"),
+        body,
+    ) {
+        Ok(()) => {
+            std::fs::remove_file(&sending_path)?;
+            std::fs::write(&last_sent_path, crate::time::unix_secs(now).to_string())?;
+        }
+        Err(e) => {
+            warn!(%e, "failed to send denied submissions digest, will retry");
+            let mut pending = OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(&pending_path)?;
+            pending.write_all(entries.as_bytes())?;
+            std::fs::remove_file(&sending_path)?;
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,7 @@
+//! Exit codes from `sysexits.h`, which callers of sendmail interpret.
+//! (The `libc` crate doesn't expose them.)
+
+pub const EX_USAGE: i32 = 64;
+pub const EX_NOINPUT: i32 = 66;
+pub const EX_TEMPFAIL: i32 = 75;
+pub const EX_NOPERM: i32 = 77;
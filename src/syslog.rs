@@ -0,0 +1,36 @@
+//! The backend of `log_target = "syslog"`: facility mail, so that delivery attempts and failures
+//! show up in the host's mail log.
+
+use std::ffi::CString;
+use std::sync::Once;
+
+use tracing::{Event, Level, Subscriber};
+use tracing_subscriber::layer::Context;
+
+use crate::logging::Fields;
+
+pub struct Layer;
+
+impl<S: Subscriber> tracing_subscriber::Layer<S> for Layer {
+    fn on_event(&self, event: &Event<'_>, _: Context<'_, S>) {
+        static OPEN: Once = Once::new();
+        // SAFETY: the identifier is static
+        OPEN.call_once(|| unsafe {
+            libc::openlog(
+                c"forward-as-attachment-mta".as_ptr(),
+                libc::LOG_PID,
+                libc::LOG_MAIL,
+            )
+        });
+        let priority = match *event.metadata().level() {
+            Level::ERROR => libc::LOG_ERR,
+            Level::WARN => libc::LOG_WARNING,
+            Level::INFO => libc::LOG_INFO,
+            Level::DEBUG | Level::TRACE => libc::LOG_DEBUG,
+        };
+        let line = Fields::of(event).render(event.metadata());
+        let line = CString::new(line.replace('\0', "")).expect("NULs removed");
+        // SAFETY: both strings are NUL-terminated, and the format consumes one
+        unsafe { libc::syslog(libc::LOG_MAIL | priority, c"%s".as_ptr(), line.as_ptr()) };
+    }
+}
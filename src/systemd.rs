@@ -0,0 +1,167 @@
+//! For the long-running modes (`--daemon`, `--smtpd`, `--watch`) as systemd services: readiness
+//! and watchdog notifications (`sd_notify(3)`), for `Type=notify` with `WatchdogSec=`, so that
+//! systemd restarts them when they wedge, and socket activation (`sd_listen_fds(3)`).
+
+use std::io;
+use std::os::fd::{FromRawFd, OwnedFd, RawFd};
+use std::os::linux::net::SocketAddrExt;
+use std::os::unix::net::{SocketAddr, UnixDatagram};
+use std::sync::mpsc::{Receiver, RecvTimeoutError};
+use std::time::{Duration, Instant};
+
+/// Send `state`, e.g. `READY=1`, to systemd, if we run under it. Failures are logged.
+pub fn notify(state: &str) {
+    let Some(socket) = std::env::var_os("NOTIFY_SOCKET") else {
+        return;
+    };
+    let socket = socket.to_string_lossy();
+    if let Err(e) = send_to(&socket, state) {
+        tracing::warn!(%e, %socket, state, "can't notify systemd");
+    }
+}
+
+/// `socket` is a path, or an abstract socket name prefixed with `@`.
+fn send_to(socket: &str, state: &str) -> io::Result<()> {
+    let addr = match socket.strip_prefix('@') {
+        Some(name) => SocketAddr::from_abstract_name(name)?,
+        None => SocketAddr::from_pathname(socket)?,
+    };
+    UnixDatagram::unbound()?.send_to_addr(state.as_bytes(), &addr)?;
+    Ok(())
+}
+
+/// How often to send `WATCHDOG=1`: half of `WatchdogSec=`, if set for us.
+fn watchdog_interval() -> Option<Duration> {
+    if let Ok(pid) = std::env::var("WATCHDOG_PID") {
+        if pid.parse() != Ok(std::process::id()) {
+            return None;
+        }
+    }
+    let usec = std::env::var("WATCHDOG_USEC").ok()?.parse::<u64>().ok()?;
+    (usec > 0).then(|| Duration::from_micros(usec / 2))
+}
+
+/// `WATCHDOG=1` pings, to be sent from the loop that does the work, so that they stop when it
+/// wedges.
+pub struct Watchdog {
+    interval: Duration,
+    last: Instant,
+}
+
+impl Watchdog {
+    /// If `WatchdogSec=` is set for us.
+    pub fn new() -> Option<Self> {
+        watchdog_interval().map(|interval| Watchdog {
+            interval,
+            last: Instant::now(),
+        })
+    }
+
+    /// How long until the next ping is due.
+    pub fn remaining(&self) -> Duration {
+        self.interval.saturating_sub(self.last.elapsed())
+    }
+
+    pub fn ping(&mut self) {
+        notify("WATCHDOG=1");
+        self.last = Instant::now();
+    }
+}
+
+/// Receive from `receiver`, waiting at most `timeout` if given, and pinging `watchdog` whenever
+/// it's due meanwhile.
+pub fn recv<T>(
+    receiver: &Receiver<T>,
+    timeout: Option<Duration>,
+    watchdog: &mut Option<Watchdog>,
+) -> Result<T, RecvTimeoutError> {
+    let deadline = timeout.map(|timeout| Instant::now() + timeout);
+    loop {
+        let wait = match (deadline, &watchdog) {
+            (Some(deadline), Some(watchdog)) => Some(
+                deadline
+                    .saturating_duration_since(Instant::now())
+                    .min(watchdog.remaining()),
+            ),
+            (Some(deadline), None) => Some(deadline.saturating_duration_since(Instant::now())),
+            (None, Some(watchdog)) => Some(watchdog.remaining()),
+            (None, None) => None,
+        };
+        let received = match wait {
+            Some(wait) => receiver.recv_timeout(wait),
+            None => receiver.recv().map_err(|_| RecvTimeoutError::Disconnected),
+        };
+        if let Some(watchdog) = watchdog.as_mut().filter(|w| w.remaining().is_zero()) {
+            watchdog.ping();
+        }
+        match received {
+            Err(RecvTimeoutError::Timeout) if deadline.is_none_or(|d| Instant::now() < d) => (),
+            received => return received,
+        }
+    }
+}
+
+/// The sockets passed by systemd if we were socket-activated.
+///
+/// Only to be called once, as it takes ownership of the file descriptors.
+pub fn listen_fds() -> Vec<OwnedFd> {
+    const LISTEN_FDS_START: RawFd = 3;
+    let pid = std::env::var("LISTEN_PID")
+        .ok()
+        .and_then(|pid| pid.parse().ok());
+    if pid != Some(std::process::id()) {
+        return Vec::new();
+    }
+    let n = std::env::var("LISTEN_FDS")
+        .ok()
+        .and_then(|n| n.parse::<RawFd>().ok())
+        .unwrap_or(0);
+    (LISTEN_FDS_START..LISTEN_FDS_START + n)
+        .map(|fd| {
+            // Not to be inherited by the processes we run.
+            // SAFETY: systemd passes these file descriptors to us, and they are only taken here
+            unsafe {
+                libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC);
+                OwnedFd::from_raw_fd(fd)
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_send_to() {
+        let dir = crate::testdir::TestDir::new("notify");
+        let path = dir.join("notify");
+        let systemd = UnixDatagram::bind(&path).unwrap();
+        send_to(path.to_str().unwrap(), "READY=1").unwrap();
+        let mut buf = [0; 64];
+        let n = systemd.recv(&mut buf).unwrap();
+        assert_eq!(&buf[..n], b"READY=1");
+    }
+
+    #[test]
+    fn test_recv() {
+        let (sender, receiver) = std::sync::mpsc::channel();
+        let mut watchdog = Some(Watchdog {
+            interval: Duration::from_millis(10),
+            last: Instant::now(),
+        });
+        let started = Instant::now();
+        assert_eq!(
+            recv(&receiver, Some(Duration::from_millis(50)), &mut watchdog),
+            Err(RecvTimeoutError::Timeout)
+        );
+        assert!(started.elapsed() >= Duration::from_millis(50));
+        sender.send(1).unwrap();
+        assert_eq!(recv(&receiver, None, &mut watchdog), Ok(1));
+        drop(sender);
+        assert_eq!(
+            recv(&receiver, None, &mut None),
+            Err(RecvTimeoutError::Disconnected)
+        );
+    }
+}
@@ -0,0 +1,141 @@
+//! `%name%`-style placeholders in config values.
+
+use std::time::SystemTime;
+
+/// The values available as placeholders.
+#[derive(Default)]
+pub struct Vars<'a> {
+    pub hostname: &'a str,
+    pub user: Option<&'a str>,
+    pub uid: u32,
+    /// The sender as shown in the default subject, e.g. `hdr(root)`.
+    pub sender: Option<&'a str>,
+    /// The subject of the original message.
+    pub subject: Option<&'a str>,
+    /// The systemd unit of the caller.
+    pub unit: Option<&'a str>,
+    pub severity: Option<&'a str>,
+}
+
+impl Vars<'_> {
+    pub fn lookup(&self, name: &str) -> Option<String> {
+        match name {
+            "hostname" => Some(self.hostname.to_owned()),
+            "user" => Some(self.user.unwrap_or("unknown").to_owned()),
+            "uid" => Some(self.uid.to_string()),
+            "sender" => Some(self.sender.unwrap_or("???").to_owned()),
+            "subject" => Some(self.subject.unwrap_or("").to_owned()),
+            "unit" => Some(self.unit.unwrap_or("").to_owned()),
+            "severity" => Some(self.severity.unwrap_or("").to_owned()),
+            _ => None,
+        }
+    }
+
+    pub fn expand(&self, template: &str) -> String {
+        expand(template, |name| self.lookup(name))
+    }
+
+    /// Like [`Self::expand`], but then also replace `strftime(3)` specifiers like `%Y`,
+    /// and make the result safe to use as a file name.
+    pub fn expand_filename(&self, template: &str, t: SystemTime) -> String {
+        // single-letter names are left alone by `expand` since we have no such variables
+        let expanded = expand(template, |name| {
+            self.lookup(name).map(|v| v.replace('%', "%%"))
+        });
+        let formatted = crate::time::strftime_local(&expanded, t).unwrap_or(expanded);
+        formatted
+            .chars()
+            .map(|c| match c {
+                '/' | '\\' => '_',
+                c if c.is_control() => '_',
+                c => c,
+            })
+            .collect()
+    }
+}
+
+/// Replace `%name%` with `lookup(name)`.
+///
+/// Unknown placeholders are left as-is, `%%` yields a literal `%`.
+pub fn expand(template: &str, lookup: impl Fn(&str) -> Option<String>) -> String {
+    let mut out = String::with_capacity(template.len());
+    let mut rest = template;
+    while let Some(start) = rest.find('%') {
+        out.push_str(&rest[..start]);
+        let after = &rest[start + 1..];
+        let Some(end) = after.find('%') else {
+            out.push_str(&rest[start..]);
+            return out;
+        };
+        let name = &after[..end];
+        if name.is_empty() {
+            out.push('%');
+            rest = &after[end + 1..];
+            continue;
+        }
+        match lookup(name) {
+            Some(value) => {
+                out.push_str(&value);
+                rest = &after[end + 1..];
+            }
+            None => {
+                // the closing % may start the next placeholder
+                out.push('%');
+                out.push_str(name);
+                rest = &after[end..];
+            }
+        }
+    }
+    out.push_str(rest);
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_expand() {
+        let f = |t| {
+            expand(t, |name| match name {
+                "hostname" => Some("host1".to_owned()),
+                "user" => Some("root".to_owned()),
+                _ => None,
+            })
+        };
+        assert_eq!(
+            f("bounces+%hostname%@example.com"),
+            "bounces+host1@example.com"
+        );
+        assert_eq!(f("%user%@%hostname%"), "root@host1");
+        assert_eq!(f("100%% %unknown% %user%"), "100% %unknown% root");
+        assert_eq!(f("50% off"), "50% off");
+    }
+
+    #[test]
+    fn test_default_subject_template() {
+        let vars = Vars {
+            hostname: "host1",
+            sender: Some("hdr(root)"),
+            subject: Some("backup failed"),
+            ..Default::default()
+        };
+        assert_eq!(
+            vars.expand(crate::config::DEFAULT_SUBJECT_TEMPLATE),
+            "hdr(root)@host1: backup failed"
+        );
+    }
+
+    #[test]
+    fn test_expand_filename() {
+        let vars = Vars {
+            hostname: "host1",
+            user: Some("a/b"),
+            ..Default::default()
+        };
+        let t = std::time::UNIX_EPOCH + std::time::Duration::from_secs(86400 * 365);
+        let name = vars.expand_filename("%hostname%-%user%-%Y.eml", t);
+        assert_eq!(name, "host1-a_b-1971.eml");
+        assert_eq!(vars.expand_filename("stdin.eml", t), "stdin.eml");
+    }
+}
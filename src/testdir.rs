@@ -0,0 +1,42 @@
+//! A directory for a test's files, removed when dropped, so also when the test fails.
+
+use std::fs::DirBuilder;
+use std::ops::Deref;
+use std::os::unix::fs::DirBuilderExt;
+use std::path::{Path, PathBuf};
+
+pub struct TestDir(PathBuf);
+
+impl TestDir {
+    /// An empty `faam-<name>-test-<pid>` in the temp dir, only accessible by us. The tests run
+    /// concurrently, so `name` must be unique among them.
+    pub fn new(name: &str) -> Self {
+        let path = std::env::temp_dir().join(format!("faam-{name}-test-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&path);
+        DirBuilder::new()
+            .mode(0o700)
+            .create(&path)
+            .expect("the temp dir is writable");
+        TestDir(path)
+    }
+}
+
+impl Deref for TestDir {
+    type Target = Path;
+
+    fn deref(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl AsRef<Path> for TestDir {
+    fn as_ref(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl Drop for TestDir {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.0);
+    }
+}
@@ -0,0 +1,102 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+pub fn unix_secs(t: SystemTime) -> u64 {
+    t.duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+/// Format as RFC 3339 in UTC, e.g. `2024-02-03T04:05:06Z`.
+pub fn format_utc(t: SystemTime) -> String {
+    let secs = unix_secs(t);
+    let (days, rem) = (secs / 86400, secs % 86400);
+    let (y, m, d) = civil_from_days(days as i64);
+    format!(
+        "{y:04}-{m:02}-{d:02}T{:02}:{:02}:{:02}Z",
+        rem / 3600,
+        (rem % 3600) / 60,
+        rem % 60
+    )
+}
+
+/// Format in the local time zone, e.g. `2024-02-03 05:05:06 CET (+0100)`.
+pub fn format_local(t: SystemTime) -> String {
+    strftime_local("%Y-%m-%d %H:%M:%S %Z (%z)", t).unwrap_or_else(|| format_utc(t))
+}
+
+/// `strftime(3)` in the local time zone.
+pub fn strftime_local(format: &str, t: SystemTime) -> Option<String> {
+    let format = std::ffi::CString::new(format).ok()?;
+    let secs = unix_secs(t) as libc::time_t;
+    // SAFETY: zeroed tm is a valid value, localtime_r and strftime only write within bounds
+    unsafe {
+        let mut tm: libc::tm = std::mem::zeroed();
+        if libc::localtime_r(&secs, &mut tm).is_null() {
+            return None;
+        }
+        let mut buf = [0u8; 512];
+        let n = libc::strftime(
+            buf.as_mut_ptr() as *mut libc::c_char,
+            buf.len(),
+            format.as_ptr(),
+            &tm,
+        );
+        if n == 0 && !format.as_bytes().is_empty() {
+            return None; // didn't fit
+        }
+        Some(String::from_utf8_lossy(&buf[..n]).into_owned())
+    }
+}
+
+/// The start of the local day of `t`, i.e. the last midnight.
+pub fn start_of_local_day(t: SystemTime) -> SystemTime {
+    let secs = unix_secs(t) as libc::time_t;
+    // SAFETY: zeroed tm is a valid value, localtime_r only writes to it
+    let midnight = unsafe {
+        let mut tm: libc::tm = std::mem::zeroed();
+        if libc::localtime_r(&secs, &mut tm).is_null() {
+            return t;
+        }
+        (tm.tm_hour, tm.tm_min, tm.tm_sec) = (0, 0, 0);
+        tm.tm_isdst = -1;
+        libc::mktime(&mut tm)
+    };
+    match u64::try_from(midnight) {
+        Ok(midnight) => UNIX_EPOCH + std::time::Duration::from_secs(midnight),
+        Err(_) => t,
+    }
+}
+
+/// Both UTC and local time, e.g. `2024-02-03T04:05:06Z / 2024-02-03 05:05:06 CET (+0100)`.
+pub fn format_utc_and_local(t: SystemTime) -> String {
+    format!("{} / {}", format_utc(t), format_local(t))
+}
+
+/// Days since 1970-01-01 to (year, month, day), see
+/// <https://howardhinnant.github.io/date_algorithms.html#civil_from_days>.
+fn civil_from_days(z: i64) -> (i64, u32, u32) {
+    let z = z + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let y = yoe + era * 400;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let d = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let m = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    (if m <= 2 { y + 1 } else { y }, m, d)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn test_format_utc() {
+        let f = |secs| format_utc(UNIX_EPOCH + Duration::from_secs(secs));
+        assert_eq!(f(0), "1970-01-01T00:00:00Z");
+        assert_eq!(f(951782400), "2000-02-29T00:00:00Z");
+        assert_eq!(f(1706933106), "2024-02-03T04:05:06Z");
+    }
+}
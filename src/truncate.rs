@@ -0,0 +1,92 @@
+//! Size cap for the attached original.
+//!
+//! Oversized originals are attached with their middle cut out, and kept in full on the host.
+
+use std::io::Write;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::PathBuf;
+
+use crate::config::Config;
+
+/// The first and last `keep` bytes of `data`, with a marker line in between.
+pub fn head_and_tail(data: &[u8], keep: usize) -> Vec<u8> {
+    if data.len() <= 2 * keep {
+        return data.to_vec();
+    }
+    join(&data[..keep], &data[data.len() - keep..], data.len())
+}
+
+/// Join the `head` and `tail` of an original of `len` bytes with a marker line in between.
+///
+/// The cuts are moved to line boundaries if there is one in the kept parts.
+pub fn join(head: &[u8], tail: &[u8], len: usize) -> Vec<u8> {
+    let head = match head.iter().rposition(|&b| b == b'\n') {
+        Some(i) => &head[..=i],
+        None => head,
+    };
+    let tail = match tail.iter().position(|&b| b == b'\n') {
+        Some(i) => &tail[i + 1..],
+        None => tail,
+    };
+    let omitted = len - head.len() - tail.len();
+    let mut out = Vec::with_capacity(head.len() + tail.len() + 100);
+    out.extend_from_slice(head);
+    if !head.ends_with(b"\n") {
+        out.push(b'\n');
+    }
+    writeln!(
+        out,
+        "[... forward-as-attachment-mta: {} omitted ...]",
+        human_size(omitted)
+    )
+    .expect("writing to a Vec doesn't fail");
+    out.extend_from_slice(tail);
+    out
+}
+
+/// E.g. `512 bytes`, `1.5 MB`.
+pub fn human_size(bytes: usize) -> String {
+    const UNITS: &[&str] = &["kB", "MB", "GB", "TB"];
+    if bytes < 1000 {
+        return format!("{bytes} bytes");
+    }
+    let mut size = bytes as f64 / 1000.0;
+    let mut unit = 0;
+    while size >= 1000.0 && unit + 1 < UNITS.len() {
+        size /= 1000.0;
+        unit += 1;
+    }
+    format!("{size:.1} {}", UNITS[unit])
+}
+
+/// Keep the full original in the state dir, readable by the owner only.
+pub fn keep_original(
+    config: &Config,
+    queue_id: &str,
+    mut data: impl std::io::Read,
+) -> std::io::Result<PathBuf> {
+    let path = crate::state::subdir(config, "originals")?.join(format!("{queue_id}.eml"));
+    let mut file = std::fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(&path)?;
+    std::io::copy(&mut data, &mut file)?;
+    Ok(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_head_and_tail() {
+        let data = b"first\nsecond\nmiddle\nmiddle\nmiddle\nlast\n";
+        assert_eq!(head_and_tail(data, 100), data);
+        assert_eq!(
+            String::from_utf8(head_and_tail(data, 8)).unwrap(),
+            "first\n[... forward-as-attachment-mta: 28 bytes omitted ...]\nlast\n"
+        );
+        assert_eq!(human_size(1_500_000), "1.5 MB");
+    }
+}
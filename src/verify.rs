@@ -0,0 +1,284 @@
+//! The `--verify` subcommand, e.g. from root's weekly cron job: send a message with a unique token
+//! in the subject, then poll the `[verify]` IMAP mailbox until it arrives, so that a forwarding path that
+//! the relay accepts but that loses the mail (spam folders, full mailboxes, ...) is noticed. The
+//! message is deleted once found.
+
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::TcpStream;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use crate::config::Config;
+use crate::{hardening, sysexits};
+
+#[derive(Debug, Clone, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Verify {
+    pub imap_host: String,
+    /// IMAP over TLS.
+    #[serde(default = "default_imap_port")]
+    pub imap_port: u16,
+    pub imap_username: String,
+    pub imap_password: crate::secret::Secret,
+    #[serde(default = "default_mailbox")]
+    pub mailbox: String,
+    /// How long to wait for the message to arrive.
+    #[serde(default = "default_timeout_secs")]
+    pub timeout_secs: u64,
+}
+
+fn default_imap_port() -> u16 {
+    993
+}
+
+fn default_mailbox() -> String {
+    "INBOX".to_owned()
+}
+
+fn default_timeout_secs() -> u64 {
+    5 * 60
+}
+
+const POLL_INTERVAL: Duration = Duration::from_secs(5);
+
+const IO_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Returns the exit code of the `--verify` subcommand, which is for root only, since it logs in
+/// to the mailbox with the configured credentials.
+pub fn command(config: &Config, caller_uid: u32, hostname: &str) -> i32 {
+    if let Err(code) = hardening::require_root(caller_uid, "--verify") {
+        return code;
+    }
+    let Some(verify) = &config.verify else {
+        eprintln!("forward-as-attachment-mta: --verify: [verify] isn't configured");
+        return sysexits::EX_USAGE;
+    };
+    let mut random = [0; 8];
+    ring::rand::SecureRandom::fill(&ring::rand::SystemRandom::new(), &mut random)
+        .expect("system randomness");
+    let token = data_encoding::HEXLOWER.encode(&random);
+    let sent_at = Instant::now();
+    if let Err(e) = crate::send_notice(
+        config,
+        format!("{hostname}: verify {token}"),
+        format!(
+            "This message from host {hostname:?} checks that mail is forwarded end to end.\n\
+             forward-as-attachment-mta --verify deletes it from {:?} once it has arrived.\n",
+            verify.mailbox
+        ),
+    ) {
+        eprintln!("forward-as-attachment-mta: --verify: failed to send the message: {e}");
+        return sysexits::EX_TEMPFAIL;
+    }
+    let deadline = sent_at + Duration::from_secs(verify.timeout_secs);
+    match connect(verify).and_then(|mut imap| await_message(&mut imap, verify, &token, deadline)) {
+        Ok(true) => {
+            println!(
+                "The message arrived in {} after {:.1}s.",
+                verify.mailbox,
+                sent_at.elapsed().as_secs_f64()
+            );
+            0
+        }
+        Ok(false) => {
+            eprintln!(
+                "forward-as-attachment-mta: --verify: the message didn't arrive in {} within {}s",
+                verify.mailbox, verify.timeout_secs
+            );
+            sysexits::EX_TEMPFAIL
+        }
+        Err(e) => {
+            eprintln!("forward-as-attachment-mta: --verify: IMAP: {e}");
+            sysexits::EX_TEMPFAIL
+        }
+    }
+}
+
+pub type TlsStream = rustls::StreamOwned<rustls::ClientConnection, TcpStream>;
+
+fn connect(verify: &Verify) -> io::Result<Imap<TlsStream>> {
+    Imap::new(tls_connect(&verify.imap_host, verify.imap_port)?)
+}
+
+/// A TLS connection to `host`, whose certificate must chain to the Mozilla roots.
+pub fn tls_connect(host: &str, port: u16) -> io::Result<TlsStream> {
+    let mut roots = rustls::RootCertStore::empty();
+    roots.extend(webpki_roots::TLS_SERVER_ROOTS.iter().cloned());
+    let tls_config = rustls::ClientConfig::builder()
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+    let name =
+        rustls::pki_types::ServerName::try_from(host.to_owned()).map_err(io::Error::other)?;
+    let tls =
+        rustls::ClientConnection::new(Arc::new(tls_config), name).map_err(io::Error::other)?;
+    let tcp = TcpStream::connect((host, port))?;
+    tcp.set_read_timeout(Some(IO_TIMEOUT))?;
+    tcp.set_write_timeout(Some(IO_TIMEOUT))?;
+    Ok(rustls::StreamOwned::new(tls, tcp))
+}
+
+/// Log in, and poll for the message with `token` in the subject until `deadline`. Returns
+/// whether it arrived.
+fn await_message<S: Read + Write>(
+    imap: &mut Imap<S>,
+    verify: &Verify,
+    token: &str,
+    deadline: Instant,
+) -> io::Result<bool> {
+    imap.command(&format!(
+        "LOGIN {} {}",
+        quote(&verify.imap_username)?,
+        quote(verify.imap_password.expose())?
+    ))?;
+    imap.command(&format!("SELECT {}", quote(&verify.mailbox)?))?;
+    let found = loop {
+        let untagged = imap.command(&format!("SEARCH SUBJECT {}", quote(token)?))?;
+        let found: Vec<&str> = untagged
+            .iter()
+            .filter_map(|line| line.strip_prefix("* SEARCH"))
+            .flat_map(|numbers| numbers.split_whitespace())
+            .collect();
+        if !found.is_empty() {
+            break Some(found.join(","));
+        }
+        if Instant::now() + POLL_INTERVAL > deadline {
+            break None;
+        }
+        std::thread::sleep(POLL_INTERVAL);
+        // Lets the server tell about new messages.
+        imap.command("NOOP")?;
+    };
+    if let Some(set) = &found {
+        imap.command(&format!("STORE {set} +FLAGS.SILENT (\\Deleted)"))?;
+        imap.command("EXPUNGE")?;
+    }
+    imap.command("LOGOUT")?;
+    Ok(found.is_some())
+}
+
+/// An IMAP quoted string.
+fn quote(s: &str) -> io::Result<String> {
+    if s.chars()
+        .any(|c| !c.is_ascii() || c == '\r' || c == '\n' || c == '\0')
+    {
+        return Err(io::Error::other(
+            "only ASCII is supported in the IMAP username, password, and mailbox",
+        ));
+    }
+    Ok(format!(
+        "\"{}\"",
+        s.replace('\\', "\\\\").replace('"', "\\\"")
+    ))
+}
+
+/// A minimal IMAP client: commands, and their responses, which must not contain literals.
+struct Imap<S> {
+    conn: BufReader<S>,
+    tag: u32,
+}
+
+impl<S: Read + Write> Imap<S> {
+    fn new(stream: S) -> io::Result<Self> {
+        let mut imap = Imap {
+            conn: BufReader::new(stream),
+            tag: 0,
+        };
+        let greeting = imap.read_line()?;
+        if !greeting.starts_with("* OK") {
+            return Err(io::Error::other(format!("greeting {greeting:?}")));
+        }
+        Ok(imap)
+    }
+
+    fn read_line(&mut self) -> io::Result<String> {
+        let mut line = String::new();
+        if self.conn.read_line(&mut line)? == 0 {
+            return Err(io::Error::other("connection closed"));
+        }
+        Ok(line.trim_end().to_owned())
+    }
+
+    /// Returns the untagged responses.
+    fn command(&mut self, command: &str) -> io::Result<Vec<String>> {
+        self.tag += 1;
+        let tag = format!("a{} ", self.tag);
+        let stream = self.conn.get_mut();
+        write!(stream, "{tag}{command}\r\n")?;
+        stream.flush()?;
+        let mut untagged = Vec::new();
+        loop {
+            let line = self.read_line()?;
+            let Some(status) = line.strip_prefix(&tag) else {
+                untagged.push(line);
+                continue;
+            };
+            if status.starts_with("OK") {
+                return Ok(untagged);
+            }
+            // Not the command, which would show the password.
+            let verb = command.split(' ').next().unwrap_or_default();
+            return Err(io::Error::other(format!("{verb}: {status}")));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Replays the server's side, and records the client's.
+    struct Script {
+        server: io::Cursor<&'static [u8]>,
+        client: Vec<u8>,
+    }
+
+    impl Read for Script {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.server.read(buf)
+        }
+    }
+
+    impl Write for Script {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.client.write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_await_message() {
+        let server = b"* OK IMAP4rev1 ready\r\n\
+            a1 OK LOGIN completed\r\n\
+            * 3 EXISTS\r\na2 OK [READ-WRITE] SELECT completed\r\n\
+            * SEARCH 3\r\na3 OK SEARCH completed\r\n\
+            a4 OK STORE completed\r\n\
+            * 3 EXPUNGE\r\na5 OK EXPUNGE completed\r\n\
+            * BYE\r\na6 OK LOGOUT completed\r\n";
+        let mut imap = Imap::new(Script {
+            server: io::Cursor::new(server),
+            client: vec![],
+        })
+        .unwrap();
+        let verify: Verify = toml::from_str(
+            r#"
+                imap_host = "imap.example.com"
+                imap_username = "admin@example.com"
+                imap_password = "p\"w"
+            "#,
+        )
+        .unwrap();
+        assert!(await_message(&mut imap, &verify, "0123abcd", Instant::now()).unwrap());
+        assert_eq!(
+            String::from_utf8(imap.conn.into_inner().client).unwrap(),
+            "a1 LOGIN \"admin@example.com\" \"p\\\"w\"\r\n\
+             a2 SELECT \"INBOX\"\r\n\
+             a3 SEARCH SUBJECT \"0123abcd\"\r\n\
+             a4 STORE 3 +FLAGS.SILENT (\\Deleted)\r\n\
+             a5 EXPUNGE\r\n\
+             a6 LOGOUT\r\n"
+        );
+    }
+}
@@ -0,0 +1,2200 @@
+//! A small WebAssembly interpreter, for `plugin`s: the MVP instruction set with the
+//! sign-extension, saturating conversion, bulk memory, and multi-value extensions, i.e. what
+//! `rustc --target wasm32-unknown-unknown` and clang emit by default. No SIMD, threads, or
+//! WASI, and the only imports are the host functions the caller resolves.
+//!
+//! Modules are untrusted: they can only touch their own memory, which is limited to
+//! `Limits::max_pages`, and they are stopped after `Limits::fuel` instructions. They are
+//! validated as they are decoded, with the algorithm of the spec's appendix, so that an
+//! instruction never finds its operands missing or of the wrong type, nor refers to a label,
+//! local, global, function, or type that doesn't exist; the interpreter still checks all of that
+//! too, and traps instead of panicking if the validator missed something.
+
+use std::fmt;
+
+const PAGE: usize = 64 * 1024;
+
+/// Of the value stack, and of nested calls.
+const MAX_STACK: usize = 1 << 20;
+const MAX_FRAMES: usize = 16 * 1024;
+const MAX_TABLE: u32 = 1 << 20;
+/// Of all functions together, since each local takes memory even if it never runs.
+const MAX_LOCALS: usize = MAX_STACK;
+
+/// Null references, in the value stack and tables.
+const NULL: u64 = u64::MAX;
+
+#[derive(Debug, PartialEq)]
+pub enum Error {
+    /// The module can't be loaded.
+    Invalid(String),
+    /// The module misbehaved while running.
+    Trap(&'static str),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Invalid(e) => write!(f, "invalid WebAssembly module: {e}"),
+            Error::Trap(e) => write!(f, "WebAssembly trap: {e}"),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+fn invalid(e: impl Into<String>) -> Error {
+    Error::Invalid(e.into())
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ValType {
+    I32,
+    I64,
+    F32,
+    F64,
+    FuncRef,
+    ExternRef,
+}
+
+impl ValType {
+    fn is_ref(self) -> bool {
+        matches!(self, ValType::FuncRef | ValType::ExternRef)
+    }
+
+    fn zero(self) -> u64 {
+        match self {
+            ValType::FuncRef | ValType::ExternRef => NULL,
+            _ => 0,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct FuncType {
+    pub params: Vec<ValType>,
+    pub results: Vec<ValType>,
+}
+
+/// Values are passed as their bits: `i32` and `f32` in the low half, references as indexes.
+pub type HostFunc<'a> = Box<dyn FnMut(&mut [u8], &[u64]) -> Result<Vec<u64>, Error> + 'a>;
+
+pub struct Limits {
+    /// Of 64 KiB.
+    pub max_pages: u32,
+    /// Instructions, including those of the start function.
+    pub fuel: u64,
+}
+
+/// A constant expression, for initializers and offsets.
+#[derive(Debug, Clone, Copy)]
+enum Init {
+    Value(u64),
+    Global(u32),
+    Func(u32),
+}
+
+struct Import {
+    module: String,
+    name: String,
+    type_: u32,
+}
+
+struct Func {
+    type_: u32,
+    /// Declared ones, after the parameters.
+    locals: Vec<ValType>,
+    code: Vec<Op>,
+}
+
+struct Global {
+    type_: ValType,
+    mutable: bool,
+    init: Init,
+}
+
+/// An active element or data segment; passive ones have no offset.
+struct Segment<T> {
+    offset: Option<Init>,
+    items: T,
+}
+
+#[derive(Default)]
+pub struct Module {
+    types: Vec<FuncType>,
+    imports: Vec<Import>,
+    funcs: Vec<Func>,
+    table: Option<(u32, Option<u32>)>,
+    memory: Option<(u32, Option<u32>)>,
+    globals: Vec<Global>,
+    /// Name, kind, index.
+    exports: Vec<(String, u8, u32)>,
+    start: Option<u32>,
+    elements: Vec<Segment<Vec<Init>>>,
+    data: Vec<Segment<Vec<u8>>>,
+}
+
+/// Instructions, with the immediates decoded and the block structure resolved to indexes into
+/// the function's code.
+#[derive(Debug)]
+enum Op {
+    Unreachable,
+    Nop,
+    Block {
+        params: u32,
+        results: u32,
+        end: u32,
+    },
+    Loop {
+        params: u32,
+    },
+    If {
+        params: u32,
+        results: u32,
+        else_: u32,
+        end: u32,
+    },
+    Else {
+        end: u32,
+    },
+    End,
+    Br(u32),
+    BrIf(u32),
+    /// The last target is the default.
+    BrTable(Box<[u32]>),
+    Return,
+    Call(u32),
+    CallIndirect(u32),
+    Drop,
+    Select,
+    LocalGet(u32),
+    LocalSet(u32),
+    LocalTee(u32),
+    GlobalGet(u32),
+    GlobalSet(u32),
+    Load {
+        op: u8,
+        offset: u32,
+    },
+    Store {
+        op: u8,
+        offset: u32,
+    },
+    MemorySize,
+    MemoryGrow,
+    Const(u64),
+    /// The single-byte numeric instructions, `0x45..=0xc4`.
+    Numeric(u8),
+    RefNull,
+    RefIsNull,
+    RefFunc(u32),
+    /// `0xfc 0..=7`.
+    TruncSat(u8),
+    MemoryInit(u32),
+    DataDrop(u32),
+    MemoryCopy,
+    MemoryFill,
+}
+
+struct Reader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn new(data: &'a [u8]) -> Self {
+        Reader { data, pos: 0 }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.pos == self.data.len()
+    }
+
+    fn bytes(&mut self, n: usize) -> Result<&'a [u8], Error> {
+        let bytes = self
+            .data
+            .get(self.pos..self.pos.saturating_add(n))
+            .ok_or_else(|| invalid("unexpected end"))?;
+        self.pos += n;
+        Ok(bytes)
+    }
+
+    fn byte(&mut self) -> Result<u8, Error> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    /// LEB128, sign-extended if `signed`.
+    fn leb(&mut self, bits: u32, signed: bool) -> Result<u64, Error> {
+        let mut result = 0;
+        let mut shift = 0;
+        loop {
+            let b = self.byte()?;
+            result |= u64::from(b & 0x7f) << shift;
+            shift += 7;
+            if b & 0x80 == 0 {
+                if signed && shift < 64 && b & 0x40 != 0 {
+                    result |= !0 << shift;
+                }
+                return Ok(result);
+            }
+            if shift >= bits {
+                return Err(invalid("integer too long"));
+            }
+        }
+    }
+
+    fn u32(&mut self) -> Result<u32, Error> {
+        Ok(self.leb(32, false)? as u32)
+    }
+
+    fn index(&mut self) -> Result<usize, Error> {
+        Ok(self.u32()? as usize)
+    }
+
+    fn name(&mut self) -> Result<String, Error> {
+        let len = self.index()?;
+        String::from_utf8(self.bytes(len)?.to_vec()).map_err(|_| invalid("name isn't UTF-8"))
+    }
+
+    fn val_type(&mut self) -> Result<ValType, Error> {
+        Ok(match self.byte()? {
+            0x7f => ValType::I32,
+            0x7e => ValType::I64,
+            0x7d => ValType::F32,
+            0x7c => ValType::F64,
+            0x70 => ValType::FuncRef,
+            0x6f => ValType::ExternRef,
+            t => return Err(invalid(format!("unsupported value type {t:#x}"))),
+        })
+    }
+
+    fn val_types(&mut self) -> Result<Vec<ValType>, Error> {
+        (0..self.u32()?).map(|_| self.val_type()).collect()
+    }
+
+    fn limits(&mut self) -> Result<(u32, Option<u32>), Error> {
+        match self.byte()? {
+            0 => Ok((self.u32()?, None)),
+            1 => Ok((self.u32()?, Some(self.u32()?))),
+            _ => Err(invalid("unsupported limits, e.g. shared memory")),
+        }
+    }
+
+    fn init(&mut self) -> Result<Init, Error> {
+        let init = match self.byte()? {
+            0x41 => Init::Value(from_i32(self.leb(32, true)? as i32)),
+            0x42 => Init::Value(self.leb(64, true)?),
+            0x43 => Init::Value(u32::from_le_bytes(self.bytes(4)?.try_into().unwrap()).into()),
+            0x44 => Init::Value(u64::from_le_bytes(self.bytes(8)?.try_into().unwrap())),
+            0x23 => Init::Global(self.u32()?),
+            0xd0 => {
+                self.val_type()?;
+                Init::Value(NULL)
+            }
+            0xd2 => Init::Func(self.u32()?),
+            _ => return Err(invalid("unsupported constant expression")),
+        };
+        if self.byte()? != 0x0b {
+            return Err(invalid("unsupported constant expression"));
+        }
+        Ok(init)
+    }
+}
+
+impl Module {
+    pub fn decode(bytes: &[u8]) -> Result<Module, Error> {
+        let mut r = Reader::new(bytes);
+        if r.bytes(8).ok() != Some(b"\0asm\x01\0\0\0") {
+            return Err(invalid("not a WebAssembly module, or not version 1"));
+        }
+        let mut module = Module::default();
+        // The types of all functions, the imported ones first.
+        let mut func_types = Vec::new();
+        let mut data_count = None;
+        let mut locals_total = 0;
+        let mut last = 0;
+        while !r.is_empty() {
+            let id = r.byte()?;
+            let len = r.index()?;
+            let mut s = Reader::new(r.bytes(len)?);
+            // The data count goes before the code, which needs it.
+            let order = match id {
+                12 => 10,
+                10 | 11 => id + 1,
+                _ => id,
+            };
+            if id != 0 {
+                if order <= last {
+                    return Err(invalid(format!("section {id} out of order")));
+                }
+                last = order;
+            }
+            match id {
+                // Custom sections.
+                0 => (),
+                1 => {
+                    for _ in 0..s.u32()? {
+                        if s.byte()? != 0x60 {
+                            return Err(invalid("malformed type"));
+                        }
+                        let params = s.val_types()?;
+                        let results = s.val_types()?;
+                        module.types.push(FuncType { params, results });
+                    }
+                }
+                2 => {
+                    for _ in 0..s.u32()? {
+                        let (name_module, name) = (s.name()?, s.name()?);
+                        if s.byte()? != 0 {
+                            return Err(invalid(format!(
+                                "unsupported import {name_module}.{name}, only functions can be imported"
+                            )));
+                        }
+                        let type_ = module.type_index(s.u32()?)?;
+                        func_types.push(type_);
+                        module.imports.push(Import {
+                            module: name_module,
+                            name,
+                            type_,
+                        });
+                    }
+                }
+                3 => {
+                    for _ in 0..s.u32()? {
+                        let type_ = module.type_index(s.u32()?)?;
+                        func_types.push(type_);
+                    }
+                }
+                4 => {
+                    for _ in 0..s.u32()? {
+                        if module.table.is_some() {
+                            return Err(invalid("multiple tables"));
+                        }
+                        if !s.val_type()?.is_ref() {
+                            return Err(invalid("table of numbers"));
+                        }
+                        module.table = Some(s.limits()?);
+                    }
+                }
+                5 => {
+                    for _ in 0..s.u32()? {
+                        if module.memory.is_some() {
+                            return Err(invalid("multiple memories"));
+                        }
+                        module.memory = Some(s.limits()?);
+                    }
+                }
+                6 => {
+                    for _ in 0..s.u32()? {
+                        let type_ = s.val_type()?;
+                        let mutable = match s.byte()? {
+                            0 => false,
+                            1 => true,
+                            _ => return Err(invalid("malformed global")),
+                        };
+                        let init = s.init()?;
+                        // Only the globals before this one are initialized.
+                        check_init(init, module.globals.len(), func_types.len())?;
+                        module.globals.push(Global {
+                            type_,
+                            mutable,
+                            init,
+                        });
+                    }
+                }
+                7 => {
+                    for _ in 0..s.u32()? {
+                        let name = s.name()?;
+                        let kind = s.byte()?;
+                        let index = s.u32()?;
+                        if kind == 0 && index as usize >= func_types.len() {
+                            return Err(invalid(format!("export {name} of an unknown function")));
+                        }
+                        module.exports.push((name, kind, index));
+                    }
+                }
+                8 => {
+                    let start = s.u32()?;
+                    let type_ = func_types
+                        .get(start as usize)
+                        .ok_or_else(|| invalid("unknown start function"))?;
+                    if module.types[*type_ as usize] != FuncType::default() {
+                        return Err(invalid("start function with parameters or results"));
+                    }
+                    module.start = Some(start);
+                }
+                9 => {
+                    for _ in 0..s.u32()? {
+                        let segment = decode_element(&mut s)?;
+                        if segment.offset.is_some() && module.table.is_none() {
+                            return Err(invalid("element segment without a table"));
+                        }
+                        for &init in segment.offset.iter().chain(&segment.items) {
+                            check_init(init, module.globals.len(), func_types.len())?;
+                        }
+                        module.elements.push(segment);
+                    }
+                }
+                12 => data_count = Some(s.u32()?),
+                10 => {
+                    let imported = module.imports.len();
+                    if s.index()? != func_types.len() - imported {
+                        return Err(invalid("function and code counts differ"));
+                    }
+                    for &type_ in &func_types[imported..] {
+                        let len = s.index()?;
+                        let mut body = Reader::new(s.bytes(len)?);
+                        let mut locals = Vec::new();
+                        for _ in 0..body.u32()? {
+                            let n = body.index()?;
+                            let t = body.val_type()?;
+                            locals_total += n;
+                            if locals_total > MAX_LOCALS {
+                                return Err(invalid("too many locals"));
+                            }
+                            locals.extend(std::iter::repeat_n(t, n));
+                        }
+                        let validator = Validator::new(
+                            &module,
+                            &func_types,
+                            data_count,
+                            &module.types[type_ as usize],
+                            &locals,
+                        );
+                        let code = compile(&mut body, validator)?;
+                        module.funcs.push(Func {
+                            type_,
+                            locals,
+                            code,
+                        });
+                    }
+                }
+                11 => {
+                    for _ in 0..s.u32()? {
+                        let offset = match s.u32()? {
+                            0 => Some(s.init()?),
+                            1 => None,
+                            2 if s.u32()? == 0 => Some(s.init()?),
+                            _ => return Err(invalid("unsupported data segment")),
+                        };
+                        if let Some(offset) = offset {
+                            if module.memory.is_none() {
+                                return Err(invalid("data segment without a memory"));
+                            }
+                            check_init(offset, module.globals.len(), func_types.len())?;
+                        }
+                        let len = s.index()?;
+                        let items = s.bytes(len)?.to_vec();
+                        module.data.push(Segment { offset, items });
+                    }
+                }
+                _ => return Err(invalid(format!("unknown section {id}"))),
+            }
+            if id != 0 && !s.is_empty() {
+                return Err(invalid(format!("section {id} is longer than its contents")));
+            }
+        }
+        if module.imports.len() + module.funcs.len() != func_types.len() {
+            return Err(invalid("function and code counts differ"));
+        }
+        if data_count.is_some_and(|n| n as usize != module.data.len()) {
+            return Err(invalid("data count and data segments differ"));
+        }
+        Ok(module)
+    }
+
+    fn type_index(&self, type_: u32) -> Result<u32, Error> {
+        if type_ as usize >= self.types.len() {
+            return Err(invalid("unknown type"));
+        }
+        Ok(type_)
+    }
+
+    fn func_type(&self, func: u32) -> Result<&FuncType, Error> {
+        let func = func as usize;
+        let type_ = match func.checked_sub(self.imports.len()) {
+            None => self.imports[func].type_,
+            Some(i) => {
+                self.funcs
+                    .get(i)
+                    .ok_or(Error::Trap("unknown function"))?
+                    .type_
+            }
+        };
+        Ok(&self.types[type_ as usize])
+    }
+
+    fn export(&self, name: &str, kind: u8) -> Option<u32> {
+        self.exports
+            .iter()
+            .find(|(n, k, _)| n == name && *k == kind)
+            .map(|(_, _, index)| *index)
+    }
+}
+
+/// That a constant expression refers to one of the first `globals`, and `funcs`.
+fn check_init(init: Init, globals: usize, funcs: usize) -> Result<(), Error> {
+    match init {
+        Init::Value(_) => Ok(()),
+        Init::Global(g) if (g as usize) < globals => Ok(()),
+        Init::Global(_) => Err(invalid("unknown global in a constant expression")),
+        Init::Func(f) if (f as usize) < funcs => Ok(()),
+        Init::Func(_) => Err(invalid("unknown function in a constant expression")),
+    }
+}
+
+fn decode_element(s: &mut Reader) -> Result<Segment<Vec<Init>>, Error> {
+    let flags = s.u32()?;
+    let offset = match flags {
+        0 | 4 => Some(s.init()?),
+        2 | 6 if s.u32()? == 0 => Some(s.init()?),
+        1 | 3 | 5 | 7 => None,
+        _ => return Err(invalid("unsupported element segment")),
+    };
+    // The element kind or reference type.
+    if flags & 3 != 0 {
+        s.byte()?;
+    }
+    let items = (0..s.u32()?)
+        .map(|_| match flags & 4 {
+            0 => s.u32().map(Init::Func),
+            _ => s.init(),
+        })
+        .collect::<Result<_, _>>()?;
+    // Passive and declarative segments are only for instructions we don't support.
+    Ok(Segment { offset, items })
+}
+
+fn block_type(r: &mut Reader, types: &[FuncType]) -> Result<FuncType, Error> {
+    match r.data.get(r.pos) {
+        Some(0x40) => {
+            r.pos += 1;
+            Ok(FuncType::default())
+        }
+        Some(0x6f | 0x70 | 0x7c..=0x7f) => Ok(FuncType {
+            params: Vec::new(),
+            results: vec![r.val_type()?],
+        }),
+        _ => types
+            .get(r.leb(33, true)? as usize)
+            .cloned()
+            .ok_or_else(|| invalid("unknown block type")),
+    }
+}
+
+/// Decode and validate a function body, resolving the ends of blocks.
+fn compile(r: &mut Reader, mut v: Validator) -> Result<Vec<Op>, Error> {
+    let mut code = Vec::new();
+    // The indexes of the open `Block`, `Loop`, and `If`s.
+    let mut open: Vec<usize> = Vec::new();
+    loop {
+        let at = code.len();
+        let op = match r.byte()? {
+            0x00 => Op::Unreachable,
+            0x01 => Op::Nop,
+            op @ 0x02..=0x04 => {
+                let type_ = block_type(r, v.module.types.as_slice())?;
+                let (params, results) = (type_.params.len() as u32, type_.results.len() as u32);
+                v.block(op, type_)?;
+                open.push(at);
+                match op {
+                    0x02 => Op::Block {
+                        params,
+                        results,
+                        end: 0,
+                    },
+                    0x03 => Op::Loop { params },
+                    _ => Op::If {
+                        params,
+                        results,
+                        else_: 0,
+                        end: 0,
+                    },
+                }
+            }
+            0x05 => {
+                match open.last().map(|&start| &mut code[start]) {
+                    Some(Op::If { else_, .. }) if *else_ == 0 => *else_ = at as u32,
+                    _ => return Err(invalid("else without if")),
+                }
+                Op::Else { end: 0 }
+            }
+            0x0b => {
+                let Some(start) = open.pop() else {
+                    v.check(&Op::End)?;
+                    code.push(Op::End);
+                    if !r.is_empty() {
+                        return Err(invalid("code after the end of a function"));
+                    }
+                    return Ok(code);
+                };
+                let end = at as u32;
+                let else_ = match &mut code[start] {
+                    Op::Block { end: e, .. } => {
+                        *e = end;
+                        0
+                    }
+                    Op::If { else_, end: e, .. } => {
+                        *e = end;
+                        *else_
+                    }
+                    _ => 0,
+                };
+                if else_ != 0 {
+                    code[else_ as usize] = Op::Else { end };
+                }
+                Op::End
+            }
+            0x0c => Op::Br(r.u32()?),
+            0x0d => Op::BrIf(r.u32()?),
+            0x0e => {
+                let targets = (0..=r.u32()?).map(|_| r.u32()).collect::<Result<_, _>>()?;
+                Op::BrTable(targets)
+            }
+            0x0f => Op::Return,
+            0x10 => Op::Call(r.u32()?),
+            0x11 => {
+                let type_ = r.u32()?;
+                if r.u32()? != 0 {
+                    return Err(invalid("multiple tables"));
+                }
+                Op::CallIndirect(type_)
+            }
+            0x1a => Op::Drop,
+            0x1b => Op::Select,
+            0x1c => {
+                if r.val_types()?.len() != 1 {
+                    return Err(invalid("select with other than one type"));
+                }
+                Op::Select
+            }
+            0x20 => Op::LocalGet(r.u32()?),
+            0x21 => Op::LocalSet(r.u32()?),
+            0x22 => Op::LocalTee(r.u32()?),
+            0x23 => Op::GlobalGet(r.u32()?),
+            0x24 => Op::GlobalSet(r.u32()?),
+            op @ 0x28..=0x3e => {
+                let _align = r.u32()?;
+                let offset = r.u32()?;
+                if op <= 0x35 {
+                    Op::Load { op, offset }
+                } else {
+                    Op::Store { op, offset }
+                }
+            }
+            0x3f => {
+                r.byte()?;
+                Op::MemorySize
+            }
+            0x40 => {
+                r.byte()?;
+                Op::MemoryGrow
+            }
+            0x41 => {
+                v.push(ValType::I32);
+                Op::Const(from_i32(r.leb(32, true)? as i32))
+            }
+            0x42 => {
+                v.push(ValType::I64);
+                Op::Const(r.leb(64, true)?)
+            }
+            0x43 => {
+                v.push(ValType::F32);
+                Op::Const(u32::from_le_bytes(r.bytes(4)?.try_into().unwrap()).into())
+            }
+            0x44 => {
+                v.push(ValType::F64);
+                Op::Const(u64::from_le_bytes(r.bytes(8)?.try_into().unwrap()))
+            }
+            op @ 0x45..=0xc4 => Op::Numeric(op),
+            0xd0 => {
+                let t = r.val_type()?;
+                if !t.is_ref() {
+                    return Err(invalid("ref.null of a number"));
+                }
+                v.push(t);
+                Op::RefNull
+            }
+            0xd1 => Op::RefIsNull,
+            0xd2 => Op::RefFunc(r.u32()?),
+            0xfc => match r.u32()? {
+                op @ 0..=7 => Op::TruncSat(op as u8),
+                8 => {
+                    let segment = r.u32()?;
+                    r.byte()?;
+                    Op::MemoryInit(segment)
+                }
+                9 => Op::DataDrop(r.u32()?),
+                10 => {
+                    r.bytes(2)?;
+                    Op::MemoryCopy
+                }
+                11 => {
+                    r.byte()?;
+                    Op::MemoryFill
+                }
+                op => return Err(invalid(format!("unsupported instruction 0xfc {op}"))),
+            },
+            op => return Err(invalid(format!("unsupported instruction {op:#x}"))),
+        };
+        v.check(&op)?;
+        code.push(op);
+    }
+}
+
+/// Checks the instructions of a function body as they are decoded, with the validation algorithm
+/// of the spec's appendix. An operand of type `None` is one that unreachable code may assume to be
+/// of any type.
+struct Validator<'a> {
+    module: &'a Module,
+    /// The types of all functions, the imported ones first.
+    funcs: &'a [u32],
+    data_count: Option<u32>,
+    /// The parameters, then the declared ones.
+    locals: Vec<ValType>,
+    vals: Vec<Option<ValType>>,
+    /// The function, then the open `Block`, `Loop`, and `If`s.
+    ctrls: Vec<Ctrl>,
+}
+
+struct Ctrl {
+    type_: FuncType,
+    /// Whether a branch to it goes back to the start, with the parameters.
+    loop_: bool,
+    /// An `If` without an `Else` so far.
+    if_: bool,
+    /// Of `vals`, when it was entered.
+    height: usize,
+    /// After an unconditional branch.
+    unreachable: bool,
+}
+
+impl<'a> Validator<'a> {
+    fn new(
+        module: &'a Module,
+        funcs: &'a [u32],
+        data_count: Option<u32>,
+        type_: &FuncType,
+        locals: &[ValType],
+    ) -> Self {
+        Validator {
+            module,
+            funcs,
+            data_count,
+            locals: [&type_.params, locals].concat(),
+            vals: Vec::new(),
+            ctrls: vec![Ctrl {
+                type_: FuncType {
+                    params: Vec::new(),
+                    results: type_.results.clone(),
+                },
+                loop_: false,
+                if_: false,
+                height: 0,
+                unreachable: false,
+            }],
+        }
+    }
+
+    fn push(&mut self, t: ValType) {
+        self.vals.push(Some(t));
+    }
+
+    fn push_all(&mut self, ts: &[ValType]) {
+        self.vals.extend(ts.iter().map(|&t| Some(t)));
+    }
+
+    fn pop(&mut self) -> Result<Option<ValType>, Error> {
+        let ctrl = self
+            .ctrls
+            .last()
+            .ok_or_else(|| invalid("code after the end"))?;
+        if self.vals.len() == ctrl.height {
+            return match ctrl.unreachable {
+                true => Ok(None),
+                false => Err(invalid("type mismatch: missing operand")),
+            };
+        }
+        Ok(self.vals.pop().expect("above the height"))
+    }
+
+    fn pop_expect(&mut self, expected: ValType) -> Result<Option<ValType>, Error> {
+        match self.pop()? {
+            Some(actual) if actual != expected => Err(invalid(format!(
+                "type mismatch: expected {expected:?}, got {actual:?}"
+            ))),
+            actual => Ok(actual),
+        }
+    }
+
+    fn pop_all(&mut self, ts: &[ValType]) -> Result<Vec<Option<ValType>>, Error> {
+        let mut popped = ts
+            .iter()
+            .rev()
+            .map(|&t| self.pop_expect(t))
+            .collect::<Result<Vec<_>, _>>()?;
+        popped.reverse();
+        Ok(popped)
+    }
+
+    fn push_ctrl(&mut self, type_: FuncType, loop_: bool, if_: bool) {
+        let height = self.vals.len();
+        self.push_all(&type_.params);
+        self.ctrls.push(Ctrl {
+            type_,
+            loop_,
+            if_,
+            height,
+            unreachable: false,
+        });
+    }
+
+    fn pop_ctrl(&mut self) -> Result<Ctrl, Error> {
+        let results = match self.ctrls.last() {
+            Some(ctrl) => ctrl.type_.results.clone(),
+            None => return Err(invalid("code after the end")),
+        };
+        self.pop_all(&results)?;
+        let ctrl = self.ctrls.pop().expect("just checked");
+        if self.vals.len() != ctrl.height {
+            return Err(invalid(
+                "type mismatch: operands left at the end of a block",
+            ));
+        }
+        Ok(ctrl)
+    }
+
+    /// The types a branch to the label at `depth` takes along.
+    fn label(&self, depth: u32) -> Result<Vec<ValType>, Error> {
+        let ctrl = self
+            .ctrls
+            .len()
+            .checked_sub(depth as usize + 1)
+            .map(|i| &self.ctrls[i])
+            .ok_or_else(|| invalid("unknown label"))?;
+        Ok(match ctrl.loop_ {
+            true => ctrl.type_.params.clone(),
+            false => ctrl.type_.results.clone(),
+        })
+    }
+
+    fn unreachable(&mut self) {
+        let ctrl = self.ctrls.last_mut().expect("in a function");
+        self.vals.truncate(ctrl.height);
+        ctrl.unreachable = true;
+    }
+
+    /// `block`, `loop`, or `if`.
+    fn block(&mut self, op: u8, type_: FuncType) -> Result<(), Error> {
+        if op == 0x04 {
+            self.pop_expect(ValType::I32)?;
+        }
+        self.pop_all(&type_.params)?;
+        self.push_ctrl(type_, op == 0x03, op == 0x04);
+        Ok(())
+    }
+
+    fn func_type(&self, func: u32) -> Result<FuncType, Error> {
+        let type_ = self
+            .funcs
+            .get(func as usize)
+            .ok_or_else(|| invalid("unknown function"))?;
+        Ok(self.module.types[*type_ as usize].clone())
+    }
+
+    fn local(&self, i: u32) -> Result<ValType, Error> {
+        self.locals
+            .get(i as usize)
+            .copied()
+            .ok_or_else(|| invalid("unknown local"))
+    }
+
+    fn global(&self, i: u32) -> Result<&Global, Error> {
+        self.module
+            .globals
+            .get(i as usize)
+            .ok_or_else(|| invalid("unknown global"))
+    }
+
+    fn memory(&self) -> Result<(), Error> {
+        match self.module.memory {
+            Some(_) => Ok(()),
+            None => Err(invalid("memory instruction without a memory")),
+        }
+    }
+
+    fn data(&self, segment: u32) -> Result<(), Error> {
+        match self.data_count {
+            Some(n) if segment < n => Ok(()),
+            Some(_) => Err(invalid("unknown data segment")),
+            None => Err(invalid("data segment instruction without a data count")),
+        }
+    }
+
+    /// The instructions that aren't checked as they are decoded.
+    fn check(&mut self, op: &Op) -> Result<(), Error> {
+        use ValType::*;
+        match *op {
+            Op::Block { .. } | Op::Loop { .. } | Op::If { .. } | Op::Const(_) | Op::RefNull => (),
+            Op::Unreachable => self.unreachable(),
+            Op::Nop => (),
+            Op::Else { .. } => {
+                let ctrl = self.pop_ctrl()?;
+                if !ctrl.if_ {
+                    return Err(invalid("else without if"));
+                }
+                self.push_ctrl(ctrl.type_, false, false);
+            }
+            Op::End => {
+                let ctrl = self.pop_ctrl()?;
+                if ctrl.if_ && ctrl.type_.params != ctrl.type_.results {
+                    return Err(invalid("type mismatch: if without else"));
+                }
+                self.push_all(&ctrl.type_.results);
+            }
+            Op::Br(depth) => {
+                self.pop_all(&self.label(depth)?)?;
+                self.unreachable();
+            }
+            Op::BrIf(depth) => {
+                self.pop_expect(I32)?;
+                let label = self.label(depth)?;
+                self.pop_all(&label)?;
+                self.push_all(&label);
+            }
+            Op::BrTable(ref targets) => {
+                self.pop_expect(I32)?;
+                let (&default, targets) = targets.split_last().expect("at least the default");
+                let arity = self.label(default)?.len();
+                for &depth in targets {
+                    let label = self.label(depth)?;
+                    if label.len() != arity {
+                        return Err(invalid(
+                            "type mismatch: br_table targets of different arity",
+                        ));
+                    }
+                    let popped = self.pop_all(&label)?;
+                    self.vals.extend(popped);
+                }
+                self.pop_all(&self.label(default)?)?;
+                self.unreachable();
+            }
+            Op::Return => {
+                self.pop_all(&self.ctrls[0].type_.results.clone())?;
+                self.unreachable();
+            }
+            Op::Call(func) => {
+                let type_ = self.func_type(func)?;
+                self.pop_all(&type_.params)?;
+                self.push_all(&type_.results);
+            }
+            Op::CallIndirect(type_) => {
+                if self.module.table.is_none() {
+                    return Err(invalid("call_indirect without a table"));
+                }
+                let type_ = self
+                    .module
+                    .types
+                    .get(type_ as usize)
+                    .ok_or_else(|| invalid("unknown type"))?;
+                self.pop_expect(I32)?;
+                self.pop_all(&type_.params)?;
+                self.push_all(&type_.results);
+            }
+            Op::Drop => {
+                self.pop()?;
+            }
+            Op::Select => {
+                self.pop_expect(I32)?;
+                let b = self.pop()?;
+                let a = self.pop()?;
+                if let (Some(a), Some(b)) = (a, b) {
+                    if a != b {
+                        return Err(invalid("type mismatch: select of different types"));
+                    }
+                }
+                self.vals.push(a.or(b));
+            }
+            Op::LocalGet(i) => self.push(self.local(i)?),
+            Op::LocalSet(i) => {
+                self.pop_expect(self.local(i)?)?;
+            }
+            Op::LocalTee(i) => {
+                let t = self.local(i)?;
+                self.pop_expect(t)?;
+                self.push(t);
+            }
+            Op::GlobalGet(i) => self.push(self.global(i)?.type_),
+            Op::GlobalSet(i) => {
+                let global = self.global(i)?;
+                if !global.mutable {
+                    return Err(invalid("global.set of an immutable global"));
+                }
+                self.pop_expect(global.type_)?;
+            }
+            Op::Load { op, .. } => {
+                self.memory()?;
+                self.pop_expect(I32)?;
+                self.push(match op {
+                    0x29 | 0x30..=0x35 => I64,
+                    0x2a => F32,
+                    0x2b => F64,
+                    _ => I32,
+                });
+            }
+            Op::Store { op, .. } => {
+                self.memory()?;
+                self.pop_expect(match op {
+                    0x37 | 0x3c..=0x3e => I64,
+                    0x38 => F32,
+                    0x39 => F64,
+                    _ => I32,
+                })?;
+                self.pop_expect(I32)?;
+            }
+            Op::MemorySize => {
+                self.memory()?;
+                self.push(I32);
+            }
+            Op::MemoryGrow => {
+                self.memory()?;
+                self.pop_expect(I32)?;
+                self.push(I32);
+            }
+            Op::Numeric(op) => {
+                let (params, result) = numeric_type(op);
+                self.pop_all(params)?;
+                self.push(result);
+            }
+            Op::RefIsNull => {
+                if self.pop()?.is_some_and(|t| !t.is_ref()) {
+                    return Err(invalid("type mismatch: ref.is_null of a number"));
+                }
+                self.push(I32);
+            }
+            Op::RefFunc(func) => {
+                self.func_type(func)?;
+                self.push(FuncRef);
+            }
+            Op::TruncSat(op) => {
+                self.pop_expect(if op & 2 == 0 { F32 } else { F64 })?;
+                self.push(if op < 4 { I32 } else { I64 });
+            }
+            Op::MemoryInit(segment) => {
+                self.memory()?;
+                self.data(segment)?;
+                self.pop_all(&[I32; 3])?;
+            }
+            Op::DataDrop(segment) => self.data(segment)?,
+            Op::MemoryCopy | Op::MemoryFill => {
+                self.memory()?;
+                self.pop_all(&[I32; 3])?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The operand and result types of `Op::Numeric`.
+fn numeric_type(op: u8) -> (&'static [ValType], ValType) {
+    use ValType::*;
+    match op {
+        0x45 => (&[I32], I32),
+        0x46..=0x4f => (&[I32, I32], I32),
+        0x50 => (&[I64], I32),
+        0x51..=0x5a => (&[I64, I64], I32),
+        0x5b..=0x60 => (&[F32, F32], I32),
+        0x61..=0x66 => (&[F64, F64], I32),
+        0x67..=0x69 => (&[I32], I32),
+        0x6a..=0x78 => (&[I32, I32], I32),
+        0x79..=0x7b => (&[I64], I64),
+        0x7c..=0x8a => (&[I64, I64], I64),
+        0x8b..=0x91 => (&[F32], F32),
+        0x92..=0x98 => (&[F32, F32], F32),
+        0x99..=0x9f => (&[F64], F64),
+        0xa0..=0xa6 => (&[F64, F64], F64),
+        0xa7 => (&[I64], I32),
+        0xa8 | 0xa9 | 0xbc => (&[F32], I32),
+        0xaa | 0xab => (&[F64], I32),
+        0xac | 0xad => (&[I32], I64),
+        0xae | 0xaf => (&[F32], I64),
+        0xb0 | 0xb1 | 0xbd => (&[F64], I64),
+        0xb2 | 0xb3 | 0xbe => (&[I32], F32),
+        0xb4 | 0xb5 => (&[I64], F32),
+        0xb6 => (&[F64], F32),
+        0xb7 | 0xb8 => (&[I32], F64),
+        0xb9 | 0xba | 0xbf => (&[I64], F64),
+        0xbb => (&[F32], F64),
+        0xc0 | 0xc1 => (&[I32], I32),
+        _ => (&[I64], I64),
+    }
+}
+
+struct Frame {
+    /// Of the defined functions.
+    func: usize,
+    pc: usize,
+    /// Where the locals start in the value stack.
+    base: usize,
+    locals: usize,
+    results: usize,
+    /// The function's label in the label stack.
+    label: usize,
+}
+
+struct Label {
+    /// Where a branch continues.
+    target: usize,
+    /// Of the value stack below the block.
+    height: usize,
+    /// The values a branch keeps.
+    arity: usize,
+}
+
+pub struct Instance<'a> {
+    module: &'a Module,
+    host: Vec<HostFunc<'a>>,
+    memory: Vec<u8>,
+    max_pages: u32,
+    globals: Vec<u64>,
+    table: Vec<u64>,
+    data: Vec<Vec<u8>>,
+    fuel: u64,
+    stack: Vec<u64>,
+    frames: Vec<Frame>,
+    labels: Vec<Label>,
+}
+
+impl<'a> Instance<'a> {
+    /// Instantiate `module`, with `resolve` returning the host function for each import, and run
+    /// its start function.
+    pub fn new(
+        module: &'a Module,
+        limits: Limits,
+        mut resolve: impl FnMut(&str, &str, &FuncType) -> Option<HostFunc<'a>>,
+    ) -> Result<Self, Error> {
+        let host = module
+            .imports
+            .iter()
+            .map(|i| {
+                resolve(&i.module, &i.name, &module.types[i.type_ as usize])
+                    .ok_or_else(|| invalid(format!("unknown import {}.{}", i.module, i.name)))
+            })
+            .collect::<Result<_, _>>()?;
+        let (min_pages, max_pages) = module.memory.unwrap_or((0, Some(0)));
+        let max_pages = max_pages.unwrap_or(u32::MAX).min(limits.max_pages);
+        if min_pages > max_pages {
+            return Err(invalid(format!(
+                "needs {min_pages} pages of memory, more than the {max_pages} allowed"
+            )));
+        }
+        let (table_size, _) = module.table.unwrap_or((0, None));
+        if table_size > MAX_TABLE {
+            return Err(invalid("table too large"));
+        }
+        let mut instance = Instance {
+            module,
+            host,
+            memory: vec![0; min_pages as usize * PAGE],
+            max_pages,
+            globals: Vec::new(),
+            table: vec![NULL; table_size as usize],
+            data: Vec::new(),
+            fuel: limits.fuel,
+            stack: Vec::new(),
+            frames: Vec::new(),
+            labels: Vec::new(),
+        };
+        for global in &module.globals {
+            let value = instance.eval(global.init)?;
+            instance.globals.push(value);
+        }
+        for segment in &module.elements {
+            let Some(offset) = segment.offset else {
+                continue;
+            };
+            let offset = instance.eval(offset)? as u32 as usize;
+            let items = segment
+                .items
+                .iter()
+                .map(|&item| instance.eval(item))
+                .collect::<Result<Vec<_>, _>>()?;
+            instance
+                .table
+                .get_mut(offset..offset + items.len())
+                .ok_or_else(|| invalid("element segment out of bounds"))?
+                .copy_from_slice(&items);
+        }
+        for segment in &module.data {
+            let Some(offset) = segment.offset else {
+                instance.data.push(segment.items.clone());
+                continue;
+            };
+            let offset = instance.eval(offset)? as u32 as usize;
+            instance
+                .memory
+                .get_mut(offset..offset + segment.items.len())
+                .ok_or_else(|| invalid("data segment out of bounds"))?
+                .copy_from_slice(&segment.items);
+            // Dropped, as far as `memory.init` is concerned.
+            instance.data.push(Vec::new());
+        }
+        if let Some(start) = module.start {
+            instance.invoke(start, &[])?;
+        }
+        Ok(instance)
+    }
+
+    fn eval(&self, init: Init) -> Result<u64, Error> {
+        match init {
+            Init::Value(v) => Ok(v),
+            Init::Func(f) => Ok(f.into()),
+            Init::Global(g) => self
+                .globals
+                .get(g as usize)
+                .copied()
+                .ok_or_else(|| invalid("unknown global in a constant expression")),
+        }
+    }
+
+    pub fn memory(&self) -> &[u8] {
+        &self.memory
+    }
+
+    pub fn memory_mut(&mut self) -> &mut [u8] {
+        &mut self.memory
+    }
+
+    /// Call the exported function `name`.
+    pub fn call(&mut self, name: &str, args: &[u64]) -> Result<Vec<u64>, Error> {
+        let func = self
+            .module
+            .export(name, 0)
+            .ok_or_else(|| invalid(format!("no function {name} is exported")))?;
+        self.invoke(func, args)
+    }
+
+    fn invoke(&mut self, func: u32, args: &[u64]) -> Result<Vec<u64>, Error> {
+        if self.module.func_type(func)?.params.len() != args.len() {
+            return Err(invalid("wrong number of arguments"));
+        }
+        self.stack.clear();
+        self.frames.clear();
+        self.labels.clear();
+        self.stack.extend_from_slice(args);
+        self.call_func(func)?;
+        self.run()?;
+        Ok(std::mem::take(&mut self.stack))
+    }
+
+    fn pop(&mut self) -> Result<u64, Error> {
+        self.stack.pop().ok_or(Error::Trap("stack underflow"))
+    }
+
+    fn jump(&mut self, target: usize) {
+        self.frames.last_mut().expect("running").pc = target;
+    }
+
+    fn run(&mut self) -> Result<(), Error> {
+        let module = self.module;
+        while let Some(frame) = self.frames.last_mut() {
+            let at = frame.pc;
+            let Some(op) = module.funcs[frame.func].code.get(at) else {
+                self.ret()?;
+                continue;
+            };
+            frame.pc += 1;
+            let (base, locals) = (frame.base, frame.locals);
+            if self.fuel == 0 {
+                return Err(Error::Trap("out of fuel"));
+            }
+            self.fuel -= 1;
+            if self.stack.len() > MAX_STACK {
+                return Err(Error::Trap("stack overflow"));
+            }
+            match op {
+                Op::Unreachable => return Err(Error::Trap("unreachable")),
+                Op::Nop => (),
+                &Op::Block {
+                    params,
+                    results,
+                    end,
+                } => self.enter(end as usize + 1, params, results)?,
+                &Op::Loop { params } => self.enter(at, params, params)?,
+                &Op::If {
+                    params,
+                    results,
+                    else_,
+                    end,
+                } => {
+                    let condition = self.pop()? as u32;
+                    self.enter(end as usize + 1, params, results)?;
+                    if condition == 0 {
+                        let target = if else_ != 0 { else_ + 1 } else { end };
+                        self.jump(target as usize);
+                    }
+                }
+                &Op::Else { end } => self.jump(end as usize),
+                Op::End => {
+                    self.labels.pop();
+                }
+                &Op::Br(depth) => self.branch(depth)?,
+                &Op::BrIf(depth) => {
+                    if self.pop()? as u32 != 0 {
+                        self.branch(depth)?;
+                    }
+                }
+                Op::BrTable(targets) => {
+                    let i = self.pop()? as u32 as usize;
+                    let depth = targets.get(i).or(targets.last()).copied();
+                    self.branch(depth.unwrap_or_default())?;
+                }
+                Op::Return => self.ret()?,
+                &Op::Call(func) => self.call_func(func)?,
+                &Op::CallIndirect(type_) => {
+                    let i = self.pop()? as u32 as usize;
+                    let func = *self.table.get(i).ok_or(Error::Trap("undefined element"))?;
+                    if func == NULL {
+                        return Err(Error::Trap("uninitialized element"));
+                    }
+                    if Some(module.func_type(func as u32)?) != module.types.get(type_ as usize) {
+                        return Err(Error::Trap("indirect call type mismatch"));
+                    }
+                    self.call_func(func as u32)?;
+                }
+                Op::Drop => {
+                    self.pop()?;
+                }
+                Op::Select => {
+                    let condition = self.pop()? as u32;
+                    let b = self.pop()?;
+                    let a = self.pop()?;
+                    self.stack.push(if condition != 0 { a } else { b });
+                }
+                &Op::LocalGet(i) => {
+                    let value = *self.local(base, locals, i)?;
+                    self.stack.push(value);
+                }
+                &Op::LocalSet(i) => {
+                    let value = self.pop()?;
+                    *self.local(base, locals, i)? = value;
+                }
+                &Op::LocalTee(i) => {
+                    let value = *self.stack.last().ok_or(Error::Trap("stack underflow"))?;
+                    *self.local(base, locals, i)? = value;
+                }
+                &Op::GlobalGet(i) => {
+                    let value = *self
+                        .globals
+                        .get(i as usize)
+                        .ok_or(Error::Trap("unknown global"))?;
+                    self.stack.push(value);
+                }
+                &Op::GlobalSet(i) => {
+                    let value = self.pop()?;
+                    match (
+                        self.globals.get_mut(i as usize),
+                        module.globals.get(i as usize),
+                    ) {
+                        (Some(global), Some(g)) if g.mutable => *global = value,
+                        _ => return Err(Error::Trap("immutable global")),
+                    }
+                }
+                &Op::Load { op, offset } => {
+                    let (size, signed) = match op {
+                        0x28 | 0x2a => (4, false),
+                        0x29 | 0x2b => (8, false),
+                        0x2c | 0x30 => (1, true),
+                        0x2d | 0x31 => (1, false),
+                        0x2e | 0x32 => (2, true),
+                        0x2f | 0x33 => (2, false),
+                        0x34 => (4, true),
+                        _ => (4, false),
+                    };
+                    let addr = self.pop()? as u32;
+                    let bytes = self.access(addr, offset, size)?;
+                    let mut raw = [0; 8];
+                    raw[..size].copy_from_slice(bytes);
+                    let mut value = u64::from_le_bytes(raw);
+                    if signed {
+                        let unused = 64 - 8 * size as u32;
+                        value = ((value << unused) as i64 >> unused) as u64;
+                        // i32s are kept zero-extended.
+                        if op <= 0x2f {
+                            value = value as u32 as u64;
+                        }
+                    }
+                    self.stack.push(value);
+                }
+                &Op::Store { op, offset } => {
+                    let size = match op {
+                        0x36 | 0x38 | 0x3e => 4,
+                        0x37 | 0x39 => 8,
+                        0x3a | 0x3c => 1,
+                        _ => 2,
+                    };
+                    let value = self.pop()?;
+                    let addr = self.pop()? as u32;
+                    self.access(addr, offset, size)?
+                        .copy_from_slice(&value.to_le_bytes()[..size]);
+                }
+                Op::MemorySize => {
+                    let pages = self.memory.len() / PAGE;
+                    self.stack.push(pages as u64);
+                }
+                Op::MemoryGrow => {
+                    let delta = self.pop()? as u32;
+                    let pages = (self.memory.len() / PAGE) as u32;
+                    match pages.checked_add(delta).filter(|&p| p <= self.max_pages) {
+                        Some(new) => {
+                            self.memory.resize(new as usize * PAGE, 0);
+                            self.stack.push(pages.into());
+                        }
+                        None => self.stack.push(from_i32(-1)),
+                    }
+                }
+                &Op::Const(value) => self.stack.push(value),
+                &Op::Numeric(op) => numeric(&mut self.stack, op)?,
+                Op::RefNull => self.stack.push(NULL),
+                Op::RefIsNull => {
+                    let value = self.pop()?;
+                    self.stack.push(from_bool(value == NULL));
+                }
+                &Op::RefFunc(func) => self.stack.push(func.into()),
+                &Op::TruncSat(op) => trunc_sat(&mut self.stack, op)?,
+                &Op::MemoryInit(segment) => {
+                    let n = self.pop()? as u32 as usize;
+                    let src = self.pop()? as u32 as usize;
+                    let dst = self.pop()? as u32;
+                    let data = self
+                        .data
+                        .get(segment as usize)
+                        .ok_or(Error::Trap("unknown data segment"))?;
+                    let bytes = data
+                        .get(src..src.saturating_add(n))
+                        .ok_or(Error::Trap("out of bounds memory access"))?;
+                    let dst = self.memory_range(dst, n)?;
+                    self.memory[dst].copy_from_slice(bytes);
+                }
+                &Op::DataDrop(segment) => {
+                    if let Some(data) = self.data.get_mut(segment as usize) {
+                        *data = Vec::new();
+                    }
+                }
+                Op::MemoryCopy => {
+                    let n = self.pop()? as u32 as usize;
+                    let src = self.pop()? as u32;
+                    let dst = self.pop()? as u32;
+                    let src = self.memory_range(src, n)?;
+                    let dst = self.memory_range(dst, n)?;
+                    self.memory.copy_within(src, dst.start);
+                }
+                Op::MemoryFill => {
+                    let n = self.pop()? as u32 as usize;
+                    let value = self.pop()? as u8;
+                    let dst = self.pop()? as u32;
+                    let dst = self.memory_range(dst, n)?;
+                    self.memory[dst].fill(value);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn local(&mut self, base: usize, locals: usize, i: u32) -> Result<&mut u64, Error> {
+        let i = i as usize;
+        if i >= locals {
+            return Err(Error::Trap("unknown local"));
+        }
+        self.stack
+            .get_mut(base + i)
+            .ok_or(Error::Trap("stack underflow"))
+    }
+
+    fn memory_range(&self, addr: u32, n: usize) -> Result<std::ops::Range<usize>, Error> {
+        let start = addr as usize;
+        match start.checked_add(n) {
+            Some(end) if end <= self.memory.len() => Ok(start..end),
+            _ => Err(Error::Trap("out of bounds memory access")),
+        }
+    }
+
+    fn access(&mut self, addr: u32, offset: u32, size: usize) -> Result<&mut [u8], Error> {
+        let start = addr as usize + offset as usize;
+        self.memory
+            .get_mut(start..start + size)
+            .ok_or(Error::Trap("out of bounds memory access"))
+    }
+
+    fn enter(&mut self, target: usize, params: u32, arity: u32) -> Result<(), Error> {
+        let height = self
+            .stack
+            .len()
+            .checked_sub(params as usize)
+            .ok_or(Error::Trap("stack underflow"))?;
+        self.labels.push(Label {
+            target,
+            height,
+            arity: arity as usize,
+        });
+        Ok(())
+    }
+
+    fn branch(&mut self, depth: u32) -> Result<(), Error> {
+        let frame = self.frames.last().expect("running").label;
+        let index = self
+            .labels
+            .len()
+            .checked_sub(depth as usize + 1)
+            .filter(|&i| i >= frame)
+            .ok_or(Error::Trap("unknown label"))?;
+        let Label {
+            target,
+            height,
+            arity,
+        } = self.labels[index];
+        let len = self.stack.len();
+        if len < height + arity {
+            return Err(Error::Trap("stack underflow"));
+        }
+        self.stack.drain(height..len - arity);
+        self.labels.truncate(index);
+        self.jump(target);
+        Ok(())
+    }
+
+    fn call_func(&mut self, func: u32) -> Result<(), Error> {
+        let module = self.module;
+        let type_ = module.func_type(func)?;
+        let params = type_.params.len();
+        let base = self
+            .stack
+            .len()
+            .checked_sub(params)
+            .ok_or(Error::Trap("stack underflow"))?;
+        match (func as usize).checked_sub(module.imports.len()) {
+            None => {
+                let results = (self.host[func as usize])(&mut self.memory, &self.stack[base..])?;
+                if results.len() != type_.results.len() {
+                    return Err(Error::Trap(
+                        "host function returned the wrong number of values",
+                    ));
+                }
+                self.stack.truncate(base);
+                self.stack.extend(results);
+            }
+            Some(index) => {
+                if self.frames.len() == MAX_FRAMES {
+                    return Err(Error::Trap("call stack exhausted"));
+                }
+                let f = &module.funcs[index];
+                self.stack.extend(f.locals.iter().map(|t| t.zero()));
+                self.labels.push(Label {
+                    target: f.code.len(),
+                    height: self.stack.len(),
+                    arity: type_.results.len(),
+                });
+                self.frames.push(Frame {
+                    func: index,
+                    pc: 0,
+                    base,
+                    locals: params + f.locals.len(),
+                    results: type_.results.len(),
+                    label: self.labels.len() - 1,
+                });
+            }
+        }
+        Ok(())
+    }
+
+    fn ret(&mut self) -> Result<(), Error> {
+        let frame = self.frames.pop().expect("running");
+        let len = self.stack.len();
+        if len < frame.base + frame.results {
+            return Err(Error::Trap("stack underflow"));
+        }
+        self.stack.drain(frame.base..len - frame.results);
+        self.labels.truncate(frame.label);
+        Ok(())
+    }
+}
+
+fn as_u32(v: u64) -> u32 {
+    v as u32
+}
+
+fn as_i32(v: u64) -> i32 {
+    v as u32 as i32
+}
+
+fn as_u64(v: u64) -> u64 {
+    v
+}
+
+fn as_i64(v: u64) -> i64 {
+    v as i64
+}
+
+fn as_f32(v: u64) -> f32 {
+    f32::from_bits(v as u32)
+}
+
+fn as_f64(v: u64) -> f64 {
+    f64::from_bits(v)
+}
+
+fn from_u32(v: u32) -> u64 {
+    v.into()
+}
+
+fn from_i32(v: i32) -> u64 {
+    v as u32 as u64
+}
+
+fn from_u64(v: u64) -> u64 {
+    v
+}
+
+fn from_i64(v: i64) -> u64 {
+    v as u64
+}
+
+fn from_f32(v: f32) -> u64 {
+    v.to_bits().into()
+}
+
+fn from_f64(v: f64) -> u64 {
+    v.to_bits()
+}
+
+fn from_bool(v: bool) -> u64 {
+    v.into()
+}
+
+/// Unlike `f64::min`, NaN if either is, and -0 is less than 0.
+fn min(a: f64, b: f64) -> f64 {
+    if a.is_nan() || b.is_nan() {
+        f64::NAN
+    } else if a == b {
+        if a.is_sign_negative() {
+            a
+        } else {
+            b
+        }
+    } else {
+        a.min(b)
+    }
+}
+
+fn max(a: f64, b: f64) -> f64 {
+    if a.is_nan() || b.is_nan() {
+        f64::NAN
+    } else if a == b {
+        if a.is_sign_positive() {
+            a
+        } else {
+            b
+        }
+    } else {
+        a.max(b)
+    }
+}
+
+/// For the trapping conversions to integers in `lo..hi`.
+fn trunc(x: f64, lo: f64, hi: f64) -> Result<f64, Error> {
+    if x.is_nan() {
+        return Err(Error::Trap("invalid conversion to integer"));
+    }
+    let t = x.trunc();
+    if !(lo..hi).contains(&t) {
+        return Err(Error::Trap("integer overflow"));
+    }
+    Ok(t)
+}
+
+const I32: (f64, f64) = (-2147483648.0, 2147483648.0);
+const U32: (f64, f64) = (0.0, 4294967296.0);
+const I64: (f64, f64) = (-9223372036854775808.0, 9223372036854775808.0);
+const U64: (f64, f64) = (0.0, 18446744073709551616.0);
+
+fn numeric(stack: &mut Vec<u64>, op: u8) -> Result<(), Error> {
+    macro_rules! pop {
+        () => {
+            stack.pop().ok_or(Error::Trap("stack underflow"))?
+        };
+    }
+    macro_rules! un {
+        ($get:ident, $put:ident, $a:ident => $e:expr) => {{
+            let $a = $get(pop!());
+            stack.push($put($e));
+        }};
+    }
+    macro_rules! bin {
+        ($get:ident, $put:ident, $a:ident, $b:ident => $e:expr) => {{
+            let $b = $get(pop!());
+            let $a = $get(pop!());
+            stack.push($put($e));
+        }};
+    }
+    macro_rules! div {
+        ($get:ident, $put:ident, $a:ident, $b:ident => $e:expr) => {
+            bin!($get, $put, $a, $b => {
+                if $b == 0 {
+                    return Err(Error::Trap("integer divide by zero"));
+                }
+                $e
+            })
+        };
+    }
+    macro_rules! trunc {
+        ($get:ident, $put:ident, $range:ident, $t:ty) => {
+            un!($get, $put, a => trunc(a.into(), $range.0, $range.1)? as $t)
+        };
+    }
+    match op {
+        0x45 => un!(as_u32, from_bool, a => a == 0),
+        0x46 => bin!(as_u32, from_bool, a, b => a == b),
+        0x47 => bin!(as_u32, from_bool, a, b => a != b),
+        0x48 => bin!(as_i32, from_bool, a, b => a < b),
+        0x49 => bin!(as_u32, from_bool, a, b => a < b),
+        0x4a => bin!(as_i32, from_bool, a, b => a > b),
+        0x4b => bin!(as_u32, from_bool, a, b => a > b),
+        0x4c => bin!(as_i32, from_bool, a, b => a <= b),
+        0x4d => bin!(as_u32, from_bool, a, b => a <= b),
+        0x4e => bin!(as_i32, from_bool, a, b => a >= b),
+        0x4f => bin!(as_u32, from_bool, a, b => a >= b),
+        0x50 => un!(as_u64, from_bool, a => a == 0),
+        0x51 => bin!(as_u64, from_bool, a, b => a == b),
+        0x52 => bin!(as_u64, from_bool, a, b => a != b),
+        0x53 => bin!(as_i64, from_bool, a, b => a < b),
+        0x54 => bin!(as_u64, from_bool, a, b => a < b),
+        0x55 => bin!(as_i64, from_bool, a, b => a > b),
+        0x56 => bin!(as_u64, from_bool, a, b => a > b),
+        0x57 => bin!(as_i64, from_bool, a, b => a <= b),
+        0x58 => bin!(as_u64, from_bool, a, b => a <= b),
+        0x59 => bin!(as_i64, from_bool, a, b => a >= b),
+        0x5a => bin!(as_u64, from_bool, a, b => a >= b),
+        0x5b => bin!(as_f32, from_bool, a, b => a == b),
+        0x5c => bin!(as_f32, from_bool, a, b => a != b),
+        0x5d => bin!(as_f32, from_bool, a, b => a < b),
+        0x5e => bin!(as_f32, from_bool, a, b => a > b),
+        0x5f => bin!(as_f32, from_bool, a, b => a <= b),
+        0x60 => bin!(as_f32, from_bool, a, b => a >= b),
+        0x61 => bin!(as_f64, from_bool, a, b => a == b),
+        0x62 => bin!(as_f64, from_bool, a, b => a != b),
+        0x63 => bin!(as_f64, from_bool, a, b => a < b),
+        0x64 => bin!(as_f64, from_bool, a, b => a > b),
+        0x65 => bin!(as_f64, from_bool, a, b => a <= b),
+        0x66 => bin!(as_f64, from_bool, a, b => a >= b),
+        0x67 => un!(as_u32, from_u32, a => a.leading_zeros()),
+        0x68 => un!(as_u32, from_u32, a => a.trailing_zeros()),
+        0x69 => un!(as_u32, from_u32, a => a.count_ones()),
+        0x6a => bin!(as_u32, from_u32, a, b => a.wrapping_add(b)),
+        0x6b => bin!(as_u32, from_u32, a, b => a.wrapping_sub(b)),
+        0x6c => bin!(as_u32, from_u32, a, b => a.wrapping_mul(b)),
+        0x6d => {
+            div!(as_i32, from_i32, a, b => a.checked_div(b).ok_or(Error::Trap("integer overflow"))?)
+        }
+        0x6e => div!(as_u32, from_u32, a, b => a / b),
+        0x6f => div!(as_i32, from_i32, a, b => a.wrapping_rem(b)),
+        0x70 => div!(as_u32, from_u32, a, b => a % b),
+        0x71 => bin!(as_u32, from_u32, a, b => a & b),
+        0x72 => bin!(as_u32, from_u32, a, b => a | b),
+        0x73 => bin!(as_u32, from_u32, a, b => a ^ b),
+        0x74 => bin!(as_u32, from_u32, a, b => a.wrapping_shl(b)),
+        0x75 => bin!(as_i32, from_i32, a, b => a.wrapping_shr(b as u32)),
+        0x76 => bin!(as_u32, from_u32, a, b => a.wrapping_shr(b)),
+        0x77 => bin!(as_u32, from_u32, a, b => a.rotate_left(b % 32)),
+        0x78 => bin!(as_u32, from_u32, a, b => a.rotate_right(b % 32)),
+        0x79 => un!(as_u64, from_u64, a => a.leading_zeros().into()),
+        0x7a => un!(as_u64, from_u64, a => a.trailing_zeros().into()),
+        0x7b => un!(as_u64, from_u64, a => a.count_ones().into()),
+        0x7c => bin!(as_u64, from_u64, a, b => a.wrapping_add(b)),
+        0x7d => bin!(as_u64, from_u64, a, b => a.wrapping_sub(b)),
+        0x7e => bin!(as_u64, from_u64, a, b => a.wrapping_mul(b)),
+        0x7f => {
+            div!(as_i64, from_i64, a, b => a.checked_div(b).ok_or(Error::Trap("integer overflow"))?)
+        }
+        0x80 => div!(as_u64, from_u64, a, b => a / b),
+        0x81 => div!(as_i64, from_i64, a, b => a.wrapping_rem(b)),
+        0x82 => div!(as_u64, from_u64, a, b => a % b),
+        0x83 => bin!(as_u64, from_u64, a, b => a & b),
+        0x84 => bin!(as_u64, from_u64, a, b => a | b),
+        0x85 => bin!(as_u64, from_u64, a, b => a ^ b),
+        0x86 => bin!(as_u64, from_u64, a, b => a.wrapping_shl(b as u32)),
+        0x87 => bin!(as_i64, from_i64, a, b => a.wrapping_shr(b as u32)),
+        0x88 => bin!(as_u64, from_u64, a, b => a.wrapping_shr(b as u32)),
+        0x89 => bin!(as_u64, from_u64, a, b => a.rotate_left((b % 64) as u32)),
+        0x8a => bin!(as_u64, from_u64, a, b => a.rotate_right((b % 64) as u32)),
+        // abs, neg, and copysign only touch the sign bit, even of NaNs.
+        0x8b => un!(as_u32, from_u32, a => a & 0x7fff_ffff),
+        0x8c => un!(as_u32, from_u32, a => a ^ 0x8000_0000),
+        0x8d => un!(as_f32, from_f32, a => a.ceil()),
+        0x8e => un!(as_f32, from_f32, a => a.floor()),
+        0x8f => un!(as_f32, from_f32, a => a.trunc()),
+        0x90 => un!(as_f32, from_f32, a => a.round_ties_even()),
+        0x91 => un!(as_f32, from_f32, a => a.sqrt()),
+        0x92 => bin!(as_f32, from_f32, a, b => a + b),
+        0x93 => bin!(as_f32, from_f32, a, b => a - b),
+        0x94 => bin!(as_f32, from_f32, a, b => a * b),
+        0x95 => bin!(as_f32, from_f32, a, b => a / b),
+        0x96 => bin!(as_f32, from_f32, a, b => min(a.into(), b.into()) as f32),
+        0x97 => bin!(as_f32, from_f32, a, b => max(a.into(), b.into()) as f32),
+        0x98 => bin!(as_u32, from_u32, a, b => (a & 0x7fff_ffff) | (b & 0x8000_0000)),
+        0x99 => un!(as_u64, from_u64, a => a & !(1 << 63)),
+        0x9a => un!(as_u64, from_u64, a => a ^ (1 << 63)),
+        0x9b => un!(as_f64, from_f64, a => a.ceil()),
+        0x9c => un!(as_f64, from_f64, a => a.floor()),
+        0x9d => un!(as_f64, from_f64, a => a.trunc()),
+        0x9e => un!(as_f64, from_f64, a => a.round_ties_even()),
+        0x9f => un!(as_f64, from_f64, a => a.sqrt()),
+        0xa0 => bin!(as_f64, from_f64, a, b => a + b),
+        0xa1 => bin!(as_f64, from_f64, a, b => a - b),
+        0xa2 => bin!(as_f64, from_f64, a, b => a * b),
+        0xa3 => bin!(as_f64, from_f64, a, b => a / b),
+        0xa4 => bin!(as_f64, from_f64, a, b => min(a, b)),
+        0xa5 => bin!(as_f64, from_f64, a, b => max(a, b)),
+        0xa6 => bin!(as_u64, from_u64, a, b => (a & !(1 << 63)) | (b & (1 << 63))),
+        0xa7 => un!(as_u64, from_u32, a => a as u32),
+        0xa8 => trunc!(as_f32, from_i32, I32, i32),
+        0xa9 => trunc!(as_f32, from_u32, U32, u32),
+        0xaa => trunc!(as_f64, from_i32, I32, i32),
+        0xab => trunc!(as_f64, from_u32, U32, u32),
+        0xac => un!(as_i32, from_i64, a => a.into()),
+        0xad => un!(as_u32, from_u64, a => a.into()),
+        0xae => trunc!(as_f32, from_i64, I64, i64),
+        0xaf => trunc!(as_f32, from_u64, U64, u64),
+        0xb0 => trunc!(as_f64, from_i64, I64, i64),
+        0xb1 => trunc!(as_f64, from_u64, U64, u64),
+        0xb2 => un!(as_i32, from_f32, a => a as f32),
+        0xb3 => un!(as_u32, from_f32, a => a as f32),
+        0xb4 => un!(as_i64, from_f32, a => a as f32),
+        0xb5 => un!(as_u64, from_f32, a => a as f32),
+        0xb6 => un!(as_f64, from_f32, a => a as f32),
+        0xb7 => un!(as_i32, from_f64, a => a.into()),
+        0xb8 => un!(as_u32, from_f64, a => a.into()),
+        0xb9 => un!(as_i64, from_f64, a => a as f64),
+        0xba => un!(as_u64, from_f64, a => a as f64),
+        0xbb => un!(as_f32, from_f64, a => a.into()),
+        // The reinterpretations don't change the bits.
+        0xbc..=0xbf => (),
+        0xc0 => un!(as_u32, from_i32, a => a as i8 as i32),
+        0xc1 => un!(as_u32, from_i32, a => a as i16 as i32),
+        0xc2 => un!(as_u64, from_i64, a => a as i8 as i64),
+        0xc3 => un!(as_u64, from_i64, a => a as i16 as i64),
+        _ => un!(as_u64, from_i64, a => a as i32 as i64),
+    }
+    Ok(())
+}
+
+/// Rust's `as` saturates, like these.
+fn trunc_sat(stack: &mut Vec<u64>, op: u8) -> Result<(), Error> {
+    let a = stack.pop().ok_or(Error::Trap("stack underflow"))?;
+    let a = if op & 2 == 0 {
+        as_f32(a).into()
+    } else {
+        as_f64(a)
+    };
+    stack.push(match op {
+        0 | 2 => from_i32(a as i32),
+        1 | 3 => from_u32(a as u32),
+        4 | 6 => from_i64(a as i64),
+        _ => from_u64(a as u64),
+    });
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_run() {
+        // (module
+        //   (import "env" "double" (func $double (param i32) (result i32)))
+        //   (memory (export "memory") 1)
+        //   (data (i32.const 16) "\05")
+        //   ;; double(memory[16]) factorial, stored at 32.
+        //   (func (export "main") (result i32) (local $n i32) (local $acc i32)
+        //     (local.set $n (call $double (i32.load8_u (i32.const 16))))
+        //     (local.set $acc (i32.const 1))
+        //     (loop $next
+        //       (local.set $acc (i32.mul (local.get $acc) (local.get $n)))
+        //       (br_if $next (local.tee $n (i32.sub (local.get $n) (i32.const 1)))))
+        //     (i32.store (i32.const 32) (local.get $acc))
+        //     (local.get $acc))
+        //   (func (export "spin") (loop $forever (br $forever))))
+        let bytes = [
+            b"\0asm\x01\0\0\0".as_slice(),
+            // Types: (i32) -> i32, () -> i32, () -> ().
+            &[
+                1, 13, 3, 0x60, 1, 0x7f, 1, 0x7f, 0x60, 0, 1, 0x7f, 0x60, 0, 0,
+            ],
+            &[
+                2, 14, 1, 3, b'e', b'n', b'v', 6, b'd', b'o', b'u', b'b', b'l', b'e', 0, 0,
+            ],
+            &[3, 3, 2, 1, 2],
+            &[5, 3, 1, 0, 1],
+            &[7, 24, 3, 6, b'm', b'e', b'm', b'o', b'r', b'y', 2, 0],
+            &[
+                4, b'm', b'a', b'i', b'n', 0, 1, 4, b's', b'p', b'i', b'n', 0, 2,
+            ],
+            &[10, 55, 2],
+            &[45, 1, 2, 0x7f],
+            &[0x41, 16, 0x2d, 0, 0, 0x10, 0, 0x21, 0],
+            &[0x41, 1, 0x21, 1],
+            &[0x03, 0x40],
+            &[0x20, 1, 0x20, 0, 0x6c, 0x21, 1],
+            &[0x20, 0, 0x41, 1, 0x6b, 0x22, 0, 0x0d, 0],
+            &[0x0b],
+            &[0x41, 32, 0x20, 1, 0x36, 2, 0],
+            &[0x20, 1, 0x0b],
+            &[7, 0, 0x03, 0x40, 0x0c, 0, 0x0b, 0x0b],
+            &[11, 7, 1, 0, 0x41, 16, 0x0b, 1, 5],
+        ]
+        .concat();
+        let module = Module::decode(&bytes).unwrap();
+        let limits = Limits {
+            max_pages: 1,
+            fuel: 1000,
+        };
+        let mut instance = Instance::new(&module, limits, |module, name, _| {
+            assert_eq!((module, name), ("env", "double"));
+            let double: HostFunc = Box::new(|_, args| Ok(vec![args[0] * 2]));
+            Some(double)
+        })
+        .unwrap();
+        assert_eq!(instance.call("main", &[]).unwrap(), [3628800]);
+        assert_eq!(instance.memory()[32..36], 3628800u32.to_le_bytes());
+        assert_eq!(
+            instance.call("spin", &[]).unwrap_err(),
+            Error::Trap("out of fuel")
+        );
+    }
+
+    fn leb(mut n: usize) -> Vec<u8> {
+        let mut out = Vec::new();
+        loop {
+            let b = (n & 0x7f) as u8;
+            n >>= 7;
+            if n == 0 {
+                out.push(b);
+                return out;
+            }
+            out.push(b | 0x80);
+        }
+    }
+
+    fn section(id: u8, contents: &[u8]) -> Vec<u8> {
+        [&[id][..], &leb(contents.len()), contents].concat()
+    }
+
+    /// A module with one page of memory (unless `memory` is false), an immutable `i32` global,
+    /// and the exported function `f` of `params` and `results`, with the `locals` declarations
+    /// and the `body` (without its `end`).
+    fn module(params: &[u8], results: &[u8], locals: &[u8], body: &[u8], memory: bool) -> Vec<u8> {
+        let type_ = [
+            &[1, 0x60][..],
+            &leb(params.len()),
+            params,
+            &leb(results.len()),
+            results,
+        ]
+        .concat();
+        let code = [locals, body, &[0x0b]].concat();
+        let code = [&[1][..], &leb(code.len()), &code].concat();
+        [
+            b"\0asm\x01\0\0\0".as_slice(),
+            &section(1, &type_),
+            &section(3, &[1, 0]),
+            &if memory {
+                section(5, &[1, 0, 1])
+            } else {
+                Vec::new()
+            },
+            &section(6, &[1, 0x7f, 0, 0x41, 7, 0x0b]),
+            &section(7, &[1, 1, b'f', 0, 0]),
+            &section(10, &code),
+        ]
+        .concat()
+    }
+
+    fn run(bytes: &[u8], args: &[u64], fuel: u64) -> Result<Vec<u64>, Error> {
+        let module = Module::decode(bytes)?;
+        let limits = Limits { max_pages: 2, fuel };
+        let mut instance = Instance::new(&module, limits, |_, _, _| None)?;
+        instance.call("f", args)
+    }
+
+    #[test]
+    fn test_validate() {
+        let decode = |params: &[u8], results: &[u8], body: &[u8]| {
+            Module::decode(&module(params, results, &[0], body, true)).map(|_| ())
+        };
+        let i32 = 0x7f;
+        assert_eq!(
+            decode(&[i32, i32], &[i32], &[0x20, 0, 0x20, 1, 0x6a]),
+            Ok(())
+        );
+        // After `unreachable`, anything goes.
+        assert_eq!(decode(&[], &[i32], &[0x00, 0x6a]), Ok(()));
+        for (body, results) in [
+            // The operand of `i32.add` is missing, or 64 bits.
+            (&[0x41, 1, 0x6a][..], &[i32][..]),
+            (&[0x41, 1, 0x42, 1, 0x6a], &[i32]),
+            // The result is missing, or one too many.
+            (&[], &[i32]),
+            (&[0x41, 1], &[]),
+            // Unknown local, global, function, label, and type.
+            (&[0x20, 5], &[i32]),
+            (&[0x23, 1], &[i32]),
+            (&[0x10, 1], &[]),
+            (&[0x0c, 1], &[]),
+            (&[0x41, 0, 0x11, 9, 0], &[]),
+            // The global is immutable.
+            (&[0x41, 1, 0x24, 0], &[]),
+            // A block that leaves an operand, and an `if` without `else` that makes one up.
+            (&[0x02, 0x40, 0x41, 1, 0x0b], &[]),
+            (&[0x41, 1, 0x04, i32, 0x41, 1, 0x0b], &[i32]),
+            // The end of the block is missing, or an extra one.
+            (&[0x02, 0x40], &[]),
+            (&[0x0b, 0x0b], &[]),
+            // Without a data count section.
+            (&[0xfc, 9, 0], &[]),
+        ] {
+            assert!(
+                matches!(decode(&[], results, body), Err(Error::Invalid(_))),
+                "{body:x?}"
+            );
+        }
+        let load = module(&[], &[0x7f], &[0], &[0x41, 0, 0x28, 2, 0], false);
+        assert!(Module::decode(&load).is_err());
+    }
+
+    #[test]
+    fn test_bounds() {
+        // (func (param $addr i32) (result i32) (i32.load offset=1 (local.get $addr)))
+        let load = module(&[0x7f], &[0x7f], &[0], &[0x20, 0, 0x28, 2, 1], true);
+        assert_eq!(run(&load, &[PAGE as u64 - 5], 100).unwrap(), [0]);
+        for addr in [PAGE as u64 - 4, u32::MAX.into()] {
+            assert_eq!(
+                run(&load, &[addr], 100),
+                Err(Error::Trap("out of bounds memory access"))
+            );
+        }
+        // (func (param $pages i32) (result i32) (memory.grow (local.get $pages)))
+        let grow = module(&[0x7f], &[0x7f], &[0], &[0x20, 0, 0x40, 0], true);
+        assert_eq!(run(&grow, &[1], 100).unwrap(), [1]);
+        assert_eq!(run(&grow, &[2], 100).unwrap(), [from_i32(-1)]);
+        assert_eq!(run(&grow, &[u32::MAX.into()], 100).unwrap(), [from_i32(-1)]);
+        // (func (param $n i32) (memory.fill (i32.const 1) (i32.const 0) (local.get $n)))
+        let fill = module(
+            &[0x7f],
+            &[],
+            &[0],
+            &[0x41, 1, 0x41, 0, 0x20, 0, 0xfc, 11, 0],
+            true,
+        );
+        assert_eq!(run(&fill, &[PAGE as u64 - 1], 100).unwrap(), []);
+        assert_eq!(
+            run(&fill, &[PAGE as u64], 100),
+            Err(Error::Trap("out of bounds memory access"))
+        );
+    }
+
+    #[test]
+    fn test_limits() {
+        // (func (call 0))
+        let recurse = module(&[], &[], &[0], &[0x10, 0], true);
+        assert_eq!(
+            run(&recurse, &[], u64::MAX),
+            Err(Error::Trap("call stack exhausted"))
+        );
+        // The same, with 1000 locals.
+        let recurse = module(&[], &[], &[1, 0xe8, 0x07, 0x7f], &[0x10, 0], true);
+        assert_eq!(
+            run(&recurse, &[], u64::MAX),
+            Err(Error::Trap("stack overflow"))
+        );
+        let spin = module(&[], &[], &[0], &[0x03, 0x40, 0x0c, 0, 0x0b], true);
+        assert_eq!(run(&spin, &[], 10_000), Err(Error::Trap("out of fuel")));
+        // Memory beyond `Limits::max_pages`.
+        let mut big = module(&[], &[], &[0], &[], true);
+        let at = big.windows(4).position(|w| w == [5, 3, 1, 0]).unwrap();
+        big[at + 4] = 3;
+        assert!(matches!(run(&big, &[], 100), Err(Error::Invalid(_))));
+        // Locals add up across functions.
+        let locals = [&[1][..], &leb(MAX_LOCALS + 1), &[0x7f]].concat();
+        let many = module(&[], &[], &locals, &[], true);
+        assert_eq!(
+            Module::decode(&many).err(),
+            Some(Error::Invalid("too many locals".to_owned()))
+        );
+    }
+
+    /// Mutations of valid modules must be rejected, trap, or run, but never panic. Set
+    /// `FAAM_WASM_FUZZ_ITERATIONS` to run longer.
+    #[test]
+    fn test_fuzz() {
+        let iterations = std::env::var("FAAM_WASM_FUZZ_ITERATIONS")
+            .ok()
+            .and_then(|n| n.parse().ok())
+            .unwrap_or(20_000);
+        let seeds = [
+            module(&[0x7f], &[0x7f], &[0], &[0x20, 0, 0x28, 2, 1], true),
+            module(&[0x7f], &[0x7f], &[0], &[0x20, 0, 0x40, 0], true),
+            module(
+                &[0x7f, 0x7e],
+                &[0x7e],
+                &[1, 2, 0x7c],
+                &[
+                    0x02, 0x7e, 0x20, 1, 0x20, 0, 0x0d, 0, 0x1a, 0x42, 3, 0x0b, 0x20, 0, 0x04,
+                    0x7e, 0x42, 1, 0x05, 0x42, 2, 0x0b, 0x7c,
+                ],
+                true,
+            ),
+            module(&[], &[], &[0], &[0x03, 0x40, 0x0c, 0, 0x0b], true),
+        ];
+        // xorshift64, for reproducible runs.
+        let mut state = 0x2545_f491_4f6c_dd1d_u64;
+        let mut next = move |n: usize| {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            (state % n as u64) as usize
+        };
+        for _ in 0..iterations {
+            let mut bytes = seeds[next(seeds.len())].clone();
+            for _ in 0..=next(2) {
+                let at = next(bytes.len());
+                match next(4) {
+                    0 => bytes[at] = next(256) as u8,
+                    1 => bytes.insert(at, next(256) as u8),
+                    2 => {
+                        bytes.remove(at);
+                    }
+                    _ => bytes[at] ^= 1 << next(8),
+                }
+            }
+            let Ok(module) = Module::decode(&bytes) else {
+                continue;
+            };
+            let limits = Limits {
+                max_pages: 2,
+                fuel: 10_000,
+            };
+            let resolve = |_: &str, _: &str, _: &FuncType| {
+                let ignore: HostFunc = Box::new(|_, _| Ok(vec![]));
+                Some(ignore)
+            };
+            let Ok(mut instance) = Instance::new(&module, limits, resolve) else {
+                continue;
+            };
+            let params = match module.export("f", 0).map(|f| module.func_type(f)) {
+                Some(Ok(type_)) => type_.params.len(),
+                _ => 0,
+            };
+            let _ = instance.call("f", &vec![1; params]);
+        }
+    }
+}
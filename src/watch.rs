@@ -0,0 +1,190 @@
+//! The `--watch` subcommand, for root: follow the journal, and forward the entries at `watch_priority` or
+//! higher, of the `watch_units` if set, like submissions, for daemons that log errors but never
+//! send mail.
+//!
+//! Entries that come in a burst, i.e. less than `watch_burst_secs` apart, become one message.
+
+use std::io::{BufRead, BufReader, Write};
+use std::path::Path;
+use std::process::{Command, Stdio};
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::time::Duration;
+
+use tracing::{debug, info, warn};
+
+use crate::config::Config;
+use crate::{hardening, sysexits};
+
+/// Of one message; the rest of a longer burst goes into the next.
+const MAX_ENTRIES: usize = 1000;
+
+/// Of the first entry, in the subject.
+const SUBJECT_CHARS: usize = 100;
+
+/// The syslog identifier of our own entries, which must not be forwarded again.
+const OURS: &str = "forward-as-attachment-mta";
+
+/// Returns the exit code; only returns if following the journal fails, or if `caller_uid` isn't
+/// root, since the journal has every user's entries.
+pub fn follow(config: &Config, caller_uid: u32) -> i32 {
+    if let Err(code) = hardening::require_root(caller_uid, "--watch") {
+        return code;
+    }
+    let exe = match std::env::current_exe() {
+        Ok(exe) => exe,
+        Err(e) => {
+            eprintln!("forward-as-attachment-mta: --watch: {e}");
+            return sysexits::EX_TEMPFAIL;
+        }
+    };
+    let mut journalctl = crate::hardening::command("journalctl");
+    journalctl
+        .args(["--no-pager", "--quiet", "--follow", "--lines=0"])
+        .args(["--output=short-iso", "--priority", &config.watch_priority])
+        .args(config.watch_units.iter().flat_map(|unit| ["--unit", unit]))
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped());
+    let mut child = match journalctl.spawn() {
+        Ok(child) => child,
+        Err(e) => {
+            eprintln!("forward-as-attachment-mta: --watch: can't run journalctl: {e}");
+            return sysexits::EX_TEMPFAIL;
+        }
+    };
+    let stdout = child.stdout.take().expect("we requested a pipe");
+    let (entries, received) = mpsc::channel();
+    std::thread::spawn(move || {
+        for line in BufReader::new(stdout).lines() {
+            match line {
+                Ok(line) if !is_ours(&line) => {
+                    if entries.send(line).is_err() {
+                        break;
+                    }
+                }
+                Ok(_) => (),
+                Err(e) => {
+                    warn!(%e, "reading journalctl");
+                    break;
+                }
+            }
+        }
+    });
+    info!(priority = config.watch_priority, units = ?config.watch_units, "following the journal");
+    crate::systemd::notify("READY=1");
+    let mut watchdog = crate::systemd::Watchdog::new();
+    let quiet = Duration::from_secs(config.watch_burst_secs);
+    let mut burst = Vec::new();
+    loop {
+        let timeout = (!burst.is_empty()).then_some(quiet);
+        let next = crate::systemd::recv(&received, timeout, &mut watchdog);
+        match next {
+            Ok(entry) => {
+                burst.push(entry);
+                if burst.len() < MAX_ENTRIES {
+                    continue;
+                }
+            }
+            Err(RecvTimeoutError::Timeout) => (),
+            Err(RecvTimeoutError::Disconnected) => {
+                if !burst.is_empty() {
+                    forward(&exe, &burst);
+                }
+                let status = child
+                    .wait()
+                    .map_or_else(|e| e.to_string(), |s| s.to_string());
+                eprintln!("forward-as-attachment-mta: --watch: journalctl exited: {status}");
+                return sysexits::EX_TEMPFAIL;
+            }
+        }
+        forward(&exe, &burst);
+        burst.clear();
+    }
+}
+
+/// Whether `line`, as in `2024-01-01T00:00:00+0000 host ident[pid]: message`, is one of ours.
+fn is_ours(line: &str) -> bool {
+    line.split_whitespace().nth(2).is_some_and(|ident| {
+        ident
+            .strip_prefix(OURS)
+            .is_some_and(|rest| rest.starts_with(['[', ':']))
+    })
+}
+
+/// Submit `burst` by running us like `sendmail root`.
+fn forward(exe: &Path, burst: &[String]) {
+    let original = original(burst);
+    let child = Command::new(exe)
+        .args(["--", "root"])
+        .env_remove("LISTEN_PID")
+        .env_remove("LISTEN_FDS")
+        .env_remove("LISTEN_FDNAMES")
+        .stdin(Stdio::piped())
+        .stdout(Stdio::null())
+        .spawn();
+    let status = child.and_then(|mut child| {
+        let mut stdin = child.stdin.take().expect("we requested a pipe");
+        let written = stdin.write_all(&original);
+        drop(stdin);
+        let status = child.wait()?;
+        written.map(|()| status)
+    });
+    match status {
+        Ok(status) if status.success() => {
+            debug!(entries = burst.len(), "forwarded journal entries")
+        }
+        Ok(status) => warn!(%status, entries = burst.len(), "failed to forward journal entries"),
+        Err(e) => warn!(%e, entries = burst.len(), "failed to forward journal entries"),
+    }
+}
+
+/// The message that is forwarded, as if a program had submitted it.
+fn original(burst: &[String]) -> Vec<u8> {
+    // The message, without the timestamp, host, and identifier.
+    let first = burst[0].splitn(4, ' ').nth(3).unwrap_or(&burst[0]);
+    let first = crate::headers::sanitize_subject(first, SUBJECT_CHARS);
+    let subject = match burst.len() {
+        1 => format!("Journal: {first}"),
+        n => format!("Journal: {first} (+{} more)", n - 1),
+    };
+    let mut original = format!(
+        "Subject: {}\nMIME-Version: 1.0\nContent-Type: text/plain; charset=utf-8\n\n",
+        if subject.is_ascii() {
+            subject
+        } else {
+            format!(
+                "=?utf-8?b?{}?=",
+                data_encoding::BASE64.encode(subject.as_bytes())
+            )
+        }
+    );
+    for entry in burst {
+        original.push_str(entry);
+        original.push('\n');
+    }
+    original.into_bytes()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_original() {
+        let burst = [
+            "2024-01-01T00:00:00+0000 vm smartd[412]: Device: /dev/sda, 8 Currently unreadable sectors".to_owned(),
+            "2024-01-01T00:00:01+0000 vm smartd[412]: Device: /dev/sda, 8 Offline uncorrectable sectors".to_owned(),
+        ];
+        assert_eq!(
+            String::from_utf8(original(&burst)).unwrap(),
+            format!(
+                "Subject: Journal: Device: /dev/sda, 8 Currently unreadable sectors (+1 more)\n\
+                 MIME-Version: 1.0\nContent-Type: text/plain; charset=utf-8\n\n{}\n{}\n",
+                burst[0], burst[1]
+            )
+        );
+        assert!(is_ours(
+            "2024-01-01T00:00:02+0000 vm forward-as-attachment-mta[7]: failed"
+        ));
+        assert!(!is_ours(&burst[0]));
+    }
+}